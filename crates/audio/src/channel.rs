@@ -0,0 +1,69 @@
+//! The controller state of a MIDI channel, shared by the synthesizers.
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Channel {
+    pub program: u8,
+    pub volume: u8,
+    pub expression: u8,
+    pub pan: u8,
+    /// -8192 to 8191.
+    pub bend: i16,
+    /// In semitones.
+    pub bend_range: u8,
+    pub sustain: bool,
+    /// The selected registered parameter, (MSB, LSB).
+    pub rpn: (u8, u8),
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            bend: 0,
+            bend_range: 2,
+            sustain: false,
+            rpn: (127, 127),
+        }
+    }
+}
+
+impl Channel {
+    /// Updates the state for a control change. Effects on playing notes, such as releasing
+    /// them when the sustain pedal goes up, are left to the synthesizer.
+    pub fn controller(&mut self, controller: u8, value: u8) {
+        match controller {
+            6 if self.rpn == (0, 0) => self.bend_range = value,
+            7 => self.volume = value,
+            10 => self.pan = value,
+            11 => self.expression = value,
+            64 => self.sustain = value >= 64,
+            100 => self.rpn.1 = value,
+            101 => self.rpn.0 = value,
+            121 => self.reset_controllers(),
+            _ => {}
+        }
+    }
+
+    fn reset_controllers(&mut self) {
+        *self = Self {
+            program: self.program,
+            volume: self.volume,
+            pan: self.pan,
+            bend_range: self.bend_range,
+            ..Self::default()
+        };
+    }
+
+    pub fn gain(&self) -> f32 {
+        let volume = self.volume as f32 / 127.0;
+        let expression = self.expression as f32 / 127.0;
+        volume * volume * expression * expression
+    }
+
+    pub fn bend_cents(&self) -> f32 {
+        self.bend as f32 / 8192.0 * self.bend_range as f32 * 100.0
+    }
+}
@@ -0,0 +1,645 @@
+//! Plays General MIDI on an emulated OPL2 or OPL3, the way DOS games drove AdLib and Sound
+//! Blaster cards: each MIDI note takes a free two-operator chip channel, loaded with the
+//! register values of an FM patch for its program.
+
+use std::convert::Infallible;
+use std::io;
+use std::sync::Arc;
+
+use formats::common::*;
+use formats::gmd::MidiSink;
+
+use crate::channel::Channel;
+use crate::opl::{Chip, Opl, MODULATOR_OFFSETS, NATIVE_SAMPLE_RATE};
+use crate::synth::PERCUSSION_CHANNEL;
+use crate::Renderer;
+
+/// The register values of one operator.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FmOperator {
+    /// Register 0x20: tremolo, vibrato, sustain, key scale rate and frequency multiple.
+    pub characteristic: u8,
+    /// Register 0x40: key scale level and total level (attenuation).
+    pub scale_level: u8,
+    /// Register 0x60: attack and decay rates.
+    pub attack_decay: u8,
+    /// Register 0x80: sustain level and release rate.
+    pub sustain_release: u8,
+    /// Register 0xE0: waveform.
+    pub waveform: u8,
+}
+
+/// A two-operator instrument.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FmPatch {
+    pub modulator: FmOperator,
+    pub carrier: FmOperator,
+    /// Register 0xC0: feedback and connection, without the OPL3 output bits.
+    pub feedback_connection: u8,
+    /// Semitones added to the played key.
+    pub transpose: i8,
+    /// The key to play instead of the MIDI key, for drums.
+    pub fixed_key: Option<u8>,
+}
+
+const fn operator(registers: [u8; 5]) -> FmOperator {
+    FmOperator {
+        characteristic: registers[0],
+        scale_level: registers[1],
+        attack_decay: registers[2],
+        sustain_release: registers[3],
+        waveform: registers[4],
+    }
+}
+
+const fn patch(modulator: [u8; 5], carrier: [u8; 5], feedback_connection: u8) -> FmPatch {
+    FmPatch {
+        modulator: operator(modulator),
+        carrier: operator(carrier),
+        feedback_connection,
+        transpose: 0,
+        fixed_key: None,
+    }
+}
+
+/// One patch per General MIDI family: piano, chromatic percussion, organ, guitar, bass,
+/// strings, ensemble, brass, reed, pipe, synth lead, synth pad, synth effects, ethnic,
+/// percussive and sound effects.
+const FAMILIES: [FmPatch; 16] = [
+    patch(
+        [0x01, 0x1A, 0xF4, 0x66, 0],
+        [0x01, 0x00, 0xF3, 0x56, 0],
+        0x06,
+    ),
+    patch(
+        [0x07, 0x20, 0xF5, 0x55, 0],
+        [0x01, 0x00, 0xF4, 0x45, 0],
+        0x04,
+    ),
+    patch(
+        [0x22, 0x08, 0xF0, 0x05, 0],
+        [0x21, 0x00, 0xF0, 0x05, 0],
+        0x01,
+    ),
+    patch(
+        [0x01, 0x1C, 0xF3, 0x84, 1],
+        [0x01, 0x00, 0xF2, 0x75, 0],
+        0x0A,
+    ),
+    patch(
+        [0x01, 0x12, 0xF5, 0x56, 0],
+        [0x21, 0x00, 0xF4, 0x38, 0],
+        0x08,
+    ),
+    patch(
+        [0x61, 0x18, 0x71, 0x15, 0],
+        [0x61, 0x00, 0x61, 0x16, 0],
+        0x0C,
+    ),
+    patch(
+        [0x61, 0x1C, 0x62, 0x16, 0],
+        [0x61, 0x00, 0x52, 0x17, 0],
+        0x0C,
+    ),
+    patch(
+        [0x21, 0x16, 0x85, 0x15, 0],
+        [0x21, 0x00, 0x83, 0x16, 0],
+        0x0E,
+    ),
+    patch(
+        [0x22, 0x1E, 0x92, 0x14, 0],
+        [0x21, 0x00, 0x92, 0x15, 0],
+        0x08,
+    ),
+    patch(
+        [0x61, 0x2C, 0x85, 0x13, 0],
+        [0x61, 0x00, 0x75, 0x14, 0],
+        0x02,
+    ),
+    patch(
+        [0x21, 0x10, 0xF1, 0x0F, 2],
+        [0x21, 0x00, 0xF1, 0x0F, 0],
+        0x0E,
+    ),
+    patch(
+        [0x61, 0x20, 0x32, 0x24, 0],
+        [0x61, 0x00, 0x31, 0x25, 0],
+        0x0A,
+    ),
+    patch(
+        [0x64, 0x24, 0x42, 0x33, 0],
+        [0x61, 0x00, 0x41, 0x34, 0],
+        0x06,
+    ),
+    patch(
+        [0x03, 0x1A, 0xF4, 0x56, 1],
+        [0x01, 0x00, 0xF3, 0x66, 0],
+        0x08,
+    ),
+    patch(
+        [0x05, 0x16, 0xF8, 0x88, 0],
+        [0x01, 0x00, 0xF7, 0x77, 0],
+        0x06,
+    ),
+    patch(
+        [0x2F, 0x00, 0xF1, 0x0F, 0],
+        [0x21, 0x00, 0xF2, 0x24, 0],
+        0x0E,
+    ),
+];
+
+#[derive(Clone, Debug)]
+pub struct FmBank {
+    /// Melodic patches by program number. Missing programs use the first patch.
+    pub programs: Vec<FmPatch>,
+    /// Patches for `PERCUSSION_CHANNEL` by key.
+    pub percussion: Vec<Option<FmPatch>>,
+}
+
+impl FmBank {
+    /// A General MIDI bank with a patch per instrument family and a basic drum kit.
+    pub fn general_midi() -> Self {
+        let programs = (0..128).map(|program| FAMILIES[program / 8]).collect();
+
+        let drum = |modulator, carrier, feedback_connection, key| FmPatch {
+            fixed_key: Some(key),
+            ..patch(modulator, carrier, feedback_connection)
+        };
+        let kick = drum(
+            [0x00, 0x0B, 0xF8, 0xF6, 0],
+            [0x00, 0x00, 0xF6, 0xF6, 0],
+            0x08,
+            28,
+        );
+        let snare = drum(
+            [0x0F, 0x00, 0xF8, 0xF7, 0],
+            [0x00, 0x00, 0xF8, 0xF6, 0],
+            0x0E,
+            60,
+        );
+        let closed_hat = drum(
+            [0x0F, 0x00, 0xFA, 0xF9, 0],
+            [0x0F, 0x08, 0xFA, 0xF9, 0],
+            0x0E,
+            96,
+        );
+        let open_hat = drum(
+            [0x0F, 0x00, 0xF6, 0xF5, 0],
+            [0x0F, 0x08, 0xF6, 0xF5, 0],
+            0x0E,
+            96,
+        );
+        let cymbal = drum(
+            [0x0F, 0x00, 0xF4, 0xF3, 0],
+            [0x0E, 0x04, 0xF3, 0xF3, 0],
+            0x0E,
+            84,
+        );
+
+        let mut percussion = vec![None; 128];
+        for key in 35u8..=81 {
+            percussion[key as usize] = Some(match key {
+                35 | 36 => kick,
+                38 | 40 => snare,
+                41 | 43 | 45 | 47 | 48 | 50 => drum(
+                    [0x01, 0x10, 0xF6, 0xF5, 0],
+                    [0x00, 0x00, 0xF5, 0xF5, 0],
+                    0x06,
+                    key - 12,
+                ),
+                42 | 44 => closed_hat,
+                46 => open_hat,
+                49 | 51 | 52 | 53 | 55 | 57 | 59 => cymbal,
+                _ => drum(
+                    [0x04, 0x10, 0xF8, 0xF8, 0],
+                    [0x01, 0x00, 0xF8, 0xF8, 0],
+                    0x04,
+                    key,
+                ),
+            });
+        }
+
+        Self {
+            programs,
+            percussion,
+        }
+    }
+
+    /// Reads a DMX `GENMIDI.OP2` bank, as used by many DOS games: 128 melodic instruments
+    /// followed by 47 percussion instruments for keys 35 to 81.
+    ///
+    /// Only the first voice of double-voice instruments is used.
+    pub fn read_op2(mut input: impl io::Read) -> ReadResult<Self> {
+        if &read_buf(&mut input, [0u8; 8])? != b"#OPL_II#" {
+            return Err(ReadError::signature("OP2"));
+        }
+
+        let mut patches = Vec::with_capacity(175);
+        for _ in 0..175 {
+            let instrument = read_buf(&mut input, [0u8; 36])
+                .field("instruments")
+                .map_err(|error| error.or_format("OP2"))?;
+            let flags = u16::from_le_bytes([instrument[0], instrument[1]]);
+            let fixed_key = instrument[3];
+            let voice = &instrument[4..20];
+            // Each operator is stored as characteristic, attack/decay, sustain/release,
+            // waveform, key scale and level, with the feedback byte between them.
+            let operator = |bytes: &[u8]| FmOperator {
+                characteristic: bytes[0],
+                attack_decay: bytes[1],
+                sustain_release: bytes[2],
+                waveform: bytes[3],
+                scale_level: (bytes[4] & 0xC0) | (bytes[5] & 0x3F),
+            };
+            let transpose = i16::from_le_bytes([voice[14], voice[15]]);
+            patches.push(FmPatch {
+                modulator: operator(&voice[0..6]),
+                carrier: operator(&voice[7..13]),
+                feedback_connection: voice[6],
+                transpose: transpose.clamp(-128, 127) as i8,
+                fixed_key: if flags & 0x01 != 0 {
+                    Some(fixed_key)
+                } else {
+                    None
+                },
+            });
+        }
+
+        let mut percussion = vec![None; 128];
+        for (key, patch) in (35..).zip(patches.drain(128..)) {
+            percussion[key] = Some(patch);
+        }
+        Ok(Self {
+            programs: patches,
+            percussion,
+        })
+    }
+
+    pub fn patch(&self, channel: u8, program: u8, key: u8) -> Option<&FmPatch> {
+        if channel == PERCUSSION_CHANNEL {
+            self.percussion.get(key as usize)?.as_ref()
+        } else {
+            self.programs
+                .get(program as usize)
+                .or_else(|| self.programs.first())
+        }
+    }
+}
+
+/// A note playing on a chip channel.
+#[derive(Copy, Clone, Debug)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    patch: FmPatch,
+    key_on: bool,
+    /// Released while the sustain pedal was down.
+    sustained: bool,
+    /// When the voice was last started or released, to find the best voice to reuse.
+    age: u64,
+}
+
+/// Renders MIDI messages with FM patches on an emulated OPL chip.
+pub struct FmSynth {
+    opl: Opl,
+    bank: Arc<FmBank>,
+    channels: [Channel; 16],
+    voices: Vec<Option<Voice>>,
+    age: u64,
+}
+
+impl FmSynth {
+    pub fn new(chip: Chip, sample_rate: u32, bank: Arc<FmBank>) -> Self {
+        let mut opl = Opl::new(chip, sample_rate);
+        // Enable the extra waveforms, and on an OPL3 the second bank and stereo.
+        opl.write(0x01, 0x20);
+        if chip == Chip::Opl3 {
+            opl.write(0x105, 0x01);
+        }
+        Self {
+            opl,
+            bank,
+            channels: [Channel::default(); 16],
+            voices: vec![None; chip.channel_count()],
+            age: 0,
+        }
+    }
+
+    /// Renders at the chip's native sample rate.
+    pub fn native(chip: Chip, bank: Arc<FmBank>) -> Self {
+        Self::new(chip, NATIVE_SAMPLE_RATE, bank)
+    }
+
+    pub fn opl(&self) -> &Opl {
+        &self.opl
+    }
+
+    /// The number of chip channels playing a note, including those releasing.
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.is_some()).count()
+    }
+
+    fn register_base(index: usize) -> (u16, u16) {
+        let bank = (index / 9) as u16 * 0x100;
+        (bank, bank + MODULATOR_OFFSETS[index % 9])
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        self.note_off(channel, key);
+
+        let state = self.channels[channel as usize];
+        let patch = match self.bank.patch(channel, state.program, key) {
+            Some(patch) => *patch,
+            None => return,
+        };
+
+        // Prefer a free channel, then the longest released, then the oldest note.
+        let index = (0..self.voices.len()).min_by_key(|&index| match &self.voices[index] {
+            None => (0, 0),
+            Some(voice) if !voice.key_on => (1, voice.age),
+            Some(voice) => (2, voice.age),
+        });
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+
+        self.age += 1;
+        let voice = Voice {
+            channel,
+            key,
+            velocity,
+            patch,
+            key_on: true,
+            sustained: false,
+            age: self.age,
+        };
+        self.voices[index] = Some(voice);
+
+        let (bank, operator) = Self::register_base(index);
+        let channel_register = bank + (index % 9) as u16;
+        // Retrigger the envelopes if the chip channel was still sounding.
+        self.opl.write(0xB0 + channel_register, 0);
+        for &(offset, registers) in &[(0, &patch.modulator), (3, &patch.carrier)] {
+            let register = operator + offset;
+            self.opl.write(0x20 + register, registers.characteristic);
+            self.opl.write(0x60 + register, registers.attack_decay);
+            self.opl.write(0x80 + register, registers.sustain_release);
+            self.opl.write(0xE0 + register, registers.waveform);
+        }
+        self.update_volume(index);
+        self.update_frequency(index);
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        let sustain = self.channels[channel as usize].sustain;
+        for index in self.channel_voices(channel) {
+            match &mut self.voices[index] {
+                Some(voice) if voice.key == key && voice.key_on => {
+                    if sustain {
+                        voice.sustained = true;
+                    } else {
+                        self.release(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        self.age += 1;
+        if let Some(voice) = &mut self.voices[index] {
+            voice.key_on = false;
+            voice.sustained = false;
+            voice.age = self.age;
+        }
+        self.update_frequency(index);
+    }
+
+    fn update_volume(&mut self, index: usize) {
+        let voice = match self.voices[index] {
+            Some(voice) => voice,
+            None => return,
+        };
+        let state = self.channels[voice.channel as usize];
+
+        // Velocity and the channel volume and expression each attenuate by 40 log10, like the
+        // General MIDI volume curve, in the chip's 0.75 dB steps.
+        let attenuation = |value: u8| {
+            if value == 0 {
+                64.0
+            } else {
+                -40.0 * (value as f32 / 127.0).log10() / 0.75
+            }
+        };
+        let extra =
+            attenuation(voice.velocity) + attenuation(state.volume) + attenuation(state.expression);
+        let level = |operator: &FmOperator| {
+            let level = (operator.scale_level & 0x3F) as f32 + extra;
+            (operator.scale_level & 0xC0) | (level.round().min(63.0) as u8)
+        };
+
+        let (_, operator) = Self::register_base(index);
+        let additive = voice.patch.feedback_connection & 0x01 != 0;
+        let modulator = if additive {
+            level(&voice.patch.modulator)
+        } else {
+            voice.patch.modulator.scale_level
+        };
+        self.opl.write(0x40 + operator, modulator);
+        self.opl
+            .write(0x40 + operator + 3, level(&voice.patch.carrier));
+
+        let (bank, _) = Self::register_base(index);
+        let output = match state.pan {
+            0..=47 => 0x10,
+            81..=127 => 0x20,
+            _ => 0x30,
+        };
+        self.opl.write(
+            0xC0 + bank + (index % 9) as u16,
+            (voice.patch.feedback_connection & 0x0F) | output,
+        );
+    }
+
+    fn update_frequency(&mut self, index: usize) {
+        let voice = match self.voices[index] {
+            Some(voice) => voice,
+            None => return,
+        };
+        let state = self.channels[voice.channel as usize];
+
+        let key = voice.patch.fixed_key.unwrap_or(voice.key) as f64 + voice.patch.transpose as f64;
+        let bend = state.bend_cents() as f64 / 100.0;
+        let hz = 440.0 * 2f64.powf((key + bend - 69.0) / 12.0);
+
+        // f = fnum * 2^block * 49716 / 2^20, using the lowest block that fits for precision.
+        let mut block = 0;
+        let mut frequency_number = hz * (1 << 20) as f64 / NATIVE_SAMPLE_RATE as f64;
+        while frequency_number >= 1023.5 && block < 7 {
+            frequency_number /= 2.0;
+            block += 1;
+        }
+        let frequency_number = (frequency_number.round() as u16).min(1023);
+
+        let (bank, _) = Self::register_base(index);
+        let register = bank + (index % 9) as u16;
+        self.opl.write(0xA0 + register, frequency_number as u8);
+        self.opl.write(
+            0xB0 + register,
+            if voice.key_on { 0x20 } else { 0 } | (block << 2) | (frequency_number >> 8) as u8,
+        );
+    }
+
+    fn channel_voices(&self, channel: u8) -> Vec<usize> {
+        (0..self.voices.len())
+            .filter(|&index| matches!(&self.voices[index], Some(voice) if voice.channel == channel))
+            .collect()
+    }
+
+    fn controller(&mut self, channel: u8, controller: u8, value: u8) {
+        let state = &mut self.channels[channel as usize];
+        state.controller(controller, value);
+        match controller {
+            7 | 10 | 11 => {
+                for index in self.channel_voices(channel) {
+                    self.update_volume(index);
+                }
+            }
+            64 if !state.sustain => {
+                for index in self.channel_voices(channel) {
+                    if matches!(&self.voices[index], Some(voice) if voice.sustained) {
+                        self.release(index);
+                    }
+                }
+            }
+            120 | 123 => {
+                for index in self.channel_voices(channel) {
+                    self.release(index);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MidiSink for FmSynth {
+    type Error = Infallible;
+
+    fn send(&mut self, channel: u8, message: midly::MidiMessage) -> Result<(), Infallible> {
+        let channel = channel & 0x0F;
+        match message {
+            midly::MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                self.note_on(channel, key.as_int(), vel.as_int())
+            }
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                self.note_off(channel, key.as_int())
+            }
+            midly::MidiMessage::Controller { controller, value } => {
+                self.controller(channel, controller.as_int(), value.as_int())
+            }
+            midly::MidiMessage::ProgramChange { program } => {
+                self.channels[channel as usize].program = program.as_int()
+            }
+            midly::MidiMessage::PitchBend { bend } => {
+                self.channels[channel as usize].bend = bend.as_int();
+                for index in self.channel_voices(channel) {
+                    self.update_frequency(index);
+                }
+            }
+            midly::MidiMessage::Aftertouch { .. }
+            | midly::MidiMessage::ChannelAftertouch { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl Renderer for FmSynth {
+    fn sample_rate(&self) -> u32 {
+        self.opl.sample_rate()
+    }
+
+    fn render(&mut self, output: &mut [f32]) {
+        self.opl.render(output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::u7;
+
+    fn note(synth: &mut FmSynth, channel: u8, key: u8, vel: u8) {
+        synth
+            .send(
+                channel,
+                midly::MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            )
+            .unwrap();
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn play_notes() {
+        for &chip in &[Chip::Opl2, Chip::Opl3] {
+            let mut synth = FmSynth::new(chip, 44_100, Arc::new(FmBank::general_midi()));
+            let mut output = vec![0.0; 2 * 4410];
+
+            // More notes than chip channels steals the oldest.
+            for key in 40..60 {
+                note(&mut synth, 0, key, 100);
+            }
+            note(&mut synth, PERCUSSION_CHANNEL, 36, 100);
+            assert_eq!(synth.active_voices(), chip.channel_count());
+            synth.render(&mut output);
+            assert!(peak(&output) > 0.05);
+
+            for key in 0..128 {
+                note(&mut synth, 0, key, 0);
+            }
+            note(&mut synth, PERCUSSION_CHANNEL, 36, 0);
+            for _ in 0..30 {
+                synth.render(&mut output);
+            }
+            assert!(peak(&output) < 0.001, "peak {}", peak(&output));
+        }
+    }
+
+    #[test]
+    fn read_op2() {
+        let mut bytes = b"#OPL_II#".to_vec();
+        for index in 0..175u8 {
+            let mut instrument = [0u8; 36];
+            instrument[0] = (index >= 128) as u8;
+            instrument[3] = 60;
+            instrument[4..20].copy_from_slice(&[
+                0x21, 0xF2, 0x34, 1, 0x40, 0x12, 0x0E, 0x01, 0xF3, 0x45, 0, 0x80, 0x05, 0, 0xF4,
+                0xFF,
+            ]);
+            instrument[20] = index;
+            bytes.extend_from_slice(&instrument);
+        }
+        let bank = FmBank::read_op2(&bytes[..]).unwrap();
+        assert_eq!(bank.programs.len(), 128);
+        assert_eq!(
+            bank.programs[0],
+            FmPatch {
+                modulator: operator([0x21, 0x52, 0xF2, 0x34, 1]),
+                carrier: operator([0x01, 0x85, 0xF3, 0x45, 0]),
+                feedback_connection: 0x0E,
+                transpose: -12,
+                fixed_key: None,
+            }
+        );
+        assert_eq!(bank.percussion[35].unwrap().fixed_key, Some(60));
+        assert!(bank.percussion[82].is_none());
+    }
+}
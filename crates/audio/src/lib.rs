@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use formats::gmd::{self, MidiSink};
+use formats::pcm::Pcm;
+use formats::schedule::{Cursor, Scheduled};
+
+use crate::music::Music;
+
+mod channel;
+pub mod fm;
+pub mod mixer;
+pub mod music;
+pub mod opl;
+pub mod output;
+pub mod synth;
+
+/// A `MidiSink` producing audio on demand, so a song can be rendered faster than real time.
+pub trait Renderer: MidiSink {
+    fn sample_rate(&self) -> u32;
+
+    /// Replaces `output` with the next `output.len() / 2` frames of interleaved stereo audio.
+    fn render(&mut self, output: &mut [f32]);
+}
+
+/// Renders the events from `cursor` into 16-bit stereo audio, followed by `tail` to let the
+/// last notes ring out.
+///
+/// The cursor should not loop forever, as rendering only finishes when it ends.
+pub fn render<R: Renderer + ?Sized>(
+    cursor: Cursor<'_, '_>,
+    renderer: &mut R,
+    tail: Duration,
+) -> Result<Pcm, R::Error> {
+    let sample_rate = renderer.sample_rate();
+    let frame_at = |time: Duration| (time.as_secs_f64() * sample_rate as f64).round() as usize;
+
+    let mut samples = Vec::new();
+    let mut end = 0;
+    for Scheduled { time, event } in cursor {
+        let frame = frame_at(time);
+        if frame > end {
+            render_frames(renderer, &mut samples, frame - end);
+            end = frame;
+        }
+        gmd::send_event(renderer, event.kind)?;
+    }
+    render_frames(renderer, &mut samples, frame_at(tail));
+
+    Ok(Pcm::from_f32(sample_rate, 2, &samples))
+}
+
+/// Renders the next `duration` of `music` into 16-bit stereo audio. Moods can be requested
+/// between calls, e.g. to render a transition from stalk to fight music.
+pub fn render_music<R: Renderer + ?Sized>(
+    music: &mut Music<'_>,
+    renderer: &mut R,
+    duration: Duration,
+) -> Result<Pcm, R::Error> {
+    let sample_rate = renderer.sample_rate();
+    let start = music.time();
+    let end = start + duration;
+    let frame_at =
+        |time: Duration| ((time - start).as_secs_f64() * sample_rate as f64).round() as usize;
+
+    let mut samples = Vec::new();
+    let mut frames = 0;
+    loop {
+        let next = music.next_time().map_or(end, |time| time.min(end));
+        let frame = frame_at(next);
+        if frame > frames {
+            render_frames(renderer, &mut samples, frame - frames);
+            frames = frame;
+        }
+        music.advance(next, renderer)?;
+        if next >= end {
+            break;
+        }
+    }
+
+    Ok(Pcm::from_f32(sample_rate, 2, &samples))
+}
+
+fn render_frames<R: Renderer + ?Sized>(renderer: &mut R, samples: &mut Vec<f32>, frames: usize) {
+    let start = samples.len();
+    samples.resize(start + frames * 2, 0.0);
+    renderer.render(&mut samples[start..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use formats::schedule::Schedule;
+    use midly::num::{u15, u28, u4, u7};
+
+    use super::*;
+    use crate::music::{Mood, MusicSet};
+    use crate::synth::{Bank, Synth};
+    use formats::gmd::Gmd;
+
+    #[test]
+    fn render_song() {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::SingleTrack,
+            midly::Timing::Metrical(u15::new(96)),
+        ));
+        let note = |delta: u32, vel: u8| midly::TrackEvent {
+            delta: u28::new(delta),
+            kind: midly::TrackEventKind::Midi {
+                channel: u4::new(0),
+                message: midly::MidiMessage::NoteOn {
+                    key: u7::new(60),
+                    vel: u7::new(vel),
+                },
+            },
+        };
+        smf.tracks.push(vec![note(0, 100), note(96, 0)]);
+        let schedule = Schedule::new(&smf);
+
+        let bank = Arc::new(Bank::general_midi());
+        let render = || {
+            let mut synth = Synth::new(22_050, bank.clone());
+            render(schedule.cursor(), &mut synth, Duration::from_millis(500)).unwrap()
+        };
+        let pcm = render();
+        // A beat at 120 BPM, then the tail.
+        assert_eq!(pcm.frame_count(), 22_050);
+        assert!(pcm.data.iter().any(|&byte| byte != 0));
+        assert_eq!(pcm.data, render().data);
+
+        let mut set = MusicSet::new();
+        set.insert(Mood::Stalk, Gmd::from_mid(smf_data(&smf)).unwrap());
+        let mut music = Music::new(&set, Mood::Stalk).unwrap();
+        let mut synth = Synth::new(22_050, bank);
+        let pcm = render_music(&mut music, &mut synth, Duration::from_millis(1500)).unwrap();
+        assert_eq!(pcm.frame_count(), 33_075);
+        assert_eq!(music.time(), Duration::from_millis(1500));
+    }
+
+    fn smf_data(smf: &midly::Smf<'_>) -> Vec<u8> {
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        data
+    }
+}
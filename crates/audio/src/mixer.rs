@@ -0,0 +1,455 @@
+//! Mixes any number of sounds into one stereo stream, each with its own volume and looping, and
+//! optionally placed in the level relative to a listener.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use formats::common::*;
+use formats::pcm::Pcm;
+use formats::resample::Resampler;
+use formats::voc::Voc;
+
+/// Decoded audio ready for mixing, at its original sample rate.
+#[derive(Clone, Debug)]
+pub struct Sound {
+    pub sample_rate: u32,
+    /// 1 or 2.
+    pub channels: u16,
+    /// Interleaved samples in `-1.0..1.0`.
+    pub samples: Vec<f32>,
+    /// The frames to repeat when looping, or `None` to repeat the whole sound.
+    pub loop_range: Option<Range<usize>>,
+}
+
+impl Sound {
+    pub fn from_pcm(pcm: &Pcm) -> Self {
+        Self {
+            sample_rate: pcm.sample_rate,
+            channels: pcm.channels.clamp(1, 2),
+            samples: if pcm.channels > 2 {
+                // Keep the first two channels.
+                pcm.to_f32()
+                    .chunks_exact(pcm.channels as usize)
+                    .flat_map(|frame| frame[..2].to_vec())
+                    .collect()
+            } else {
+                pcm.to_f32()
+            },
+            loop_range: None,
+        }
+    }
+
+    /// Decodes a VOC, looping its endlessly repeating section if it has one.
+    pub fn from_voc(voc: &Voc) -> ReadResult<Self> {
+        let (pcm, loop_range) = voc.decode_with_loop(0)?;
+        Ok(Self {
+            loop_range,
+            ..Self::from_pcm(&pcm)
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    /// Converts the sound to `sample_rate`, keeping its loop seamless.
+    pub fn resample(&self, sample_rate: u32) -> Self {
+        if sample_rate == self.sample_rate {
+            return self.clone();
+        }
+        let resampler = Resampler::new(self.sample_rate as f64, sample_rate);
+        let channels = self.channels as usize;
+        let (samples, loop_range) = match &self.loop_range {
+            Some(loop_range) => {
+                let (samples, loop_range) =
+                    resampler.process_looped(&self.samples, channels, loop_range.clone());
+                (samples, Some(loop_range))
+            }
+            None => (resampler.process(&self.samples, channels), None),
+        };
+        Self {
+            sample_rate,
+            channels: self.channels,
+            samples,
+            loop_range,
+        }
+    }
+
+    /// The left and right samples of `frame`.
+    fn frame(&self, frame: usize) -> (f32, f32) {
+        if self.channels == 1 {
+            let sample = self.samples[frame];
+            (sample, sample)
+        } else {
+            (self.samples[frame * 2], self.samples[frame * 2 + 1])
+        }
+    }
+}
+
+/// Where sounds are heard from. Uses the level viewer's axes: Z is up, and a `yaw` of zero faces
+/// +Y, increasing clockwise seen from above.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Listener {
+    pub position: mint::Point3<f32>,
+    /// In radians.
+    pub yaw: f32,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self {
+            position: [0.0; 3].into(),
+            yaw: 0.0,
+        }
+    }
+}
+
+/// How much quieter a sound directly behind the listener is than one in front.
+const REAR_ATTENUATION: f32 = 0.3;
+
+/// Places a sound in the level.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Emitter {
+    pub position: mint::Point3<f32>,
+    /// The sound is at full volume up to this distance.
+    pub min_distance: f32,
+    /// The sound fades out linearly to silence at this distance.
+    pub max_distance: f32,
+}
+
+impl Emitter {
+    pub fn new(position: mint::Point3<f32>) -> Self {
+        Self {
+            position,
+            min_distance: 10.0,
+            max_distance: 200.0,
+        }
+    }
+
+    /// The left and right gain of the sound heard by `listener`.
+    pub fn gains(&self, listener: &Listener) -> (f32, f32) {
+        let x = self.position.x - listener.position.x;
+        let y = self.position.y - listener.position.y;
+        let z = self.position.z - listener.position.z;
+        let horizontal = (x * x + y * y).sqrt();
+        let distance = (horizontal * horizontal + z * z).sqrt();
+
+        let attenuation = if distance <= self.min_distance {
+            1.0
+        } else if distance >= self.max_distance {
+            0.0
+        } else {
+            1.0 - (distance - self.min_distance) / (self.max_distance - self.min_distance)
+        };
+        if horizontal <= f32::EPSILON {
+            return (attenuation, attenuation);
+        }
+
+        let (sin, cos) = listener.yaw.sin_cos();
+        // The sine and cosine of the angle from straight ahead to the sound.
+        let right = (x * cos - y * sin) / horizontal;
+        let front = (x * sin + y * cos) / horizontal;
+        // Sounds above or below are heard from both sides.
+        let pan = right * horizontal / distance;
+        let gain = attenuation * (1.0 - REAR_ATTENUATION * (1.0 - front) / 2.0);
+        (gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0))
+    }
+}
+
+/// Identifies a sound started by `Mixer::play()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VoiceId(u64);
+
+#[derive(Debug)]
+struct Voice {
+    id: VoiceId,
+    /// At the mixer's sample rate.
+    sound: Arc<Sound>,
+    /// The next frame of the sound.
+    position: usize,
+    volume: f32,
+    looping: bool,
+    emitter: Option<Emitter>,
+    /// The gains at the end of the last render, to ramp from.
+    gains: Option<(f32, f32)>,
+    finished: bool,
+}
+
+impl Voice {
+    fn loop_range(&self) -> Range<usize> {
+        match &self.sound.loop_range {
+            Some(range) if range.start < range.end && range.end <= self.sound.frame_count() => {
+                range.clone()
+            }
+            _ => 0..self.sound.frame_count(),
+        }
+    }
+
+    fn target_gains(&self, listener: &Listener) -> (f32, f32) {
+        let (left, right) = match &self.emitter {
+            Some(emitter) => emitter.gains(listener),
+            None => (1.0, 1.0),
+        };
+        (left * self.volume, right * self.volume)
+    }
+
+    /// Mixes the voice into `output`, finishing it at the end of the sound unless looping.
+    ///
+    /// Changes in gain are spread over the whole output to avoid clicks.
+    fn render(&mut self, output: &mut [f32], listener: &Listener) {
+        let frame_count = self.sound.frame_count();
+        let loop_range = self.loop_range();
+        let target = self.target_gains(listener);
+        let start = self.gains.unwrap_or(target);
+        self.gains = Some(target);
+        let frames = (output.len() / 2) as f32;
+
+        for (output_index, frame) in output.chunks_exact_mut(2).enumerate() {
+            if self.looping && self.position >= loop_range.end {
+                self.position = loop_range.start;
+            }
+            if self.position >= frame_count {
+                self.finished = true;
+                return;
+            }
+            let (mut left, mut right) = self.sound.frame(self.position);
+            if self.emitter.is_some() {
+                left = (left + right) / 2.0;
+                right = left;
+            }
+            let ramp = (output_index + 1) as f32 / frames;
+            frame[0] += left * (start.0 + (target.0 - start.0) * ramp);
+            frame[1] += right * (start.1 + (target.1 - start.1) * ramp);
+
+            self.position += 1;
+        }
+    }
+}
+
+/// Plays sounds into a stereo stream at a fixed sample rate. Rendering the same calls always
+/// produces the same audio, independent of any output device.
+#[derive(Debug)]
+pub struct Mixer {
+    sample_rate: u32,
+    listener: Listener,
+    voices: Vec<Voice>,
+    next_id: u64,
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate.max(1),
+            listener: Listener::default(),
+            voices: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn listener(&self) -> &Listener {
+        &self.listener
+    }
+
+    /// Moves the listener, changing the gains of placed sounds over the next render.
+    pub fn set_listener(&mut self, listener: Listener) {
+        self.listener = listener;
+    }
+
+    /// Starts playing `sound` from the next rendered frame. Sounds at other sample rates are
+    /// resampled first, see `Sound::resample()`.
+    pub fn play(&mut self, sound: Arc<Sound>, volume: f32, looping: bool) -> VoiceId {
+        self.start(sound, volume, looping, None)
+    }
+
+    /// Starts playing `sound` from the next rendered frame, heard as coming from `emitter`.
+    /// Stereo sounds are mixed to mono.
+    pub fn play_at(
+        &mut self,
+        sound: Arc<Sound>,
+        volume: f32,
+        looping: bool,
+        emitter: Emitter,
+    ) -> VoiceId {
+        self.start(sound, volume, looping, Some(emitter))
+    }
+
+    fn start(
+        &mut self,
+        sound: Arc<Sound>,
+        volume: f32,
+        looping: bool,
+        emitter: Option<Emitter>,
+    ) -> VoiceId {
+        let sound = if sound.sample_rate == self.sample_rate {
+            sound
+        } else {
+            Arc::new(sound.resample(self.sample_rate))
+        };
+        let id = VoiceId(self.next_id);
+        self.next_id += 1;
+        self.voices.push(Voice {
+            id,
+            sound,
+            position: 0,
+            volume,
+            looping,
+            emitter,
+            gains: None,
+            finished: false,
+        });
+        id
+    }
+
+    pub fn stop(&mut self, id: VoiceId) {
+        self.voices.retain(|voice| voice.id != id);
+    }
+
+    pub fn stop_all(&mut self) {
+        self.voices.clear();
+    }
+
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.voices.iter().any(|voice| voice.id == id)
+    }
+
+    pub fn set_volume(&mut self, id: VoiceId, volume: f32) {
+        if let Some(voice) = self.voice_mut(id) {
+            voice.volume = volume;
+        }
+    }
+
+    /// Sets whether the sound repeats its loop range, or plays to its end.
+    pub fn set_looping(&mut self, id: VoiceId, looping: bool) {
+        if let Some(voice) = self.voice_mut(id) {
+            voice.looping = looping;
+        }
+    }
+
+    /// Places or moves the sound, or with `None` plays it unchanged in both channels.
+    pub fn set_emitter(&mut self, id: VoiceId, emitter: Option<Emitter>) {
+        if let Some(voice) = self.voice_mut(id) {
+            voice.emitter = emitter;
+        }
+    }
+
+    /// The number of sounds playing.
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Replaces `output` with the next `output.len() / 2` frames of interleaved stereo audio.
+    pub fn render(&mut self, output: &mut [f32]) {
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
+        for voice in &mut self.voices {
+            voice.render(output, &self.listener);
+        }
+        self.voices.retain(|voice| !voice.finished);
+    }
+
+    /// Renders the next `frames` frames as 16-bit stereo.
+    pub fn render_pcm(&mut self, frames: usize) -> Pcm {
+        let mut samples = vec![0.0; frames * 2];
+        self.render(&mut samples);
+        Pcm::from_f32(self.sample_rate, 2, &samples)
+    }
+
+    fn voice_mut(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|voice| voice.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sound(samples: Vec<f32>, loop_range: Option<Range<usize>>) -> Arc<Sound> {
+        Arc::new(Sound {
+            sample_rate: 100,
+            channels: 1,
+            samples,
+            loop_range,
+        })
+    }
+
+    #[test]
+    fn play_and_loop() {
+        let mut mixer = Mixer::new(100);
+        let once = mixer.play(sound(vec![0.5, 0.25], None), 1.0, false);
+        let looped = mixer.play(sound(vec![0.1, 0.2, 0.3], Some(1..3)), 0.5, true);
+
+        let mut output = vec![0.0; 12];
+        mixer.render(&mut output);
+        let expected = [0.55, 0.35, 0.15, 0.1, 0.15, 0.1];
+        for (frame, expected) in output.chunks_exact(2).zip(expected.iter()) {
+            assert!((frame[0] - expected).abs() < 1e-6, "{:?}", output);
+            assert_eq!(frame[0], frame[1]);
+        }
+        assert!(!mixer.is_playing(once));
+        assert!(mixer.is_playing(looped));
+
+        mixer.set_looping(looped, false);
+        mixer.render(&mut output);
+        assert!((output[0] - 0.15).abs() < 1e-6);
+        assert_eq!(output[2], 0.0);
+        assert!(!mixer.is_playing(looped));
+        assert_eq!(mixer.voice_count(), 0);
+    }
+
+    #[test]
+    fn emitter_gains() {
+        let listener = Listener {
+            position: [10.0, 10.0, 0.0].into(),
+            yaw: std::f32::consts::FRAC_PI_2,
+        };
+        let gains = |x: f32, y: f32, z: f32| {
+            let mut emitter = Emitter::new([10.0 + x, 10.0 + y, z].into());
+            emitter.min_distance = 10.0;
+            emitter.max_distance = 30.0;
+            let (left, right) = emitter.gains(&listener);
+            (
+                (left * 100.0).round() / 100.0,
+                (right * 100.0).round() / 100.0,
+            )
+        };
+
+        // Facing +X, so +Y is on the left.
+        assert_eq!(gains(5.0, 0.0, 0.0), (1.0, 1.0));
+        assert_eq!(gains(0.0, 5.0, 0.0), (0.85, 0.0));
+        assert_eq!(gains(0.0, -5.0, 0.0), (0.0, 0.85));
+        assert_eq!(gains(-5.0, 0.0, 0.0), (0.7, 0.7));
+        assert_eq!(gains(0.0, 0.0, 5.0), (1.0, 1.0));
+        assert_eq!(gains(20.0, 0.0, 0.0), (0.5, 0.5));
+        assert_eq!(gains(30.0, 0.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn render_placed_voice() {
+        let mut mixer = Mixer::new(100);
+        let id = mixer.play_at(
+            sound(vec![1.0; 100], None),
+            1.0,
+            true,
+            Emitter::new([0.0, 5.0, 0.0].into()),
+        );
+        let pcm = mixer.render_pcm(10);
+        let samples = pcm.to_f32();
+        assert!(samples[18] > 0.99 && samples[19] > 0.99);
+
+        // Move the sound to the right, fading out the left over the next render.
+        mixer.set_emitter(id, Some(Emitter::new([5.0, 0.0, 0.0].into())));
+        let samples = mixer.render_pcm(10).to_f32();
+        assert!(samples[0] > 0.8 && samples[0] < 1.0);
+        assert_eq!(samples[18], 0.0);
+        assert!((samples[19] - 0.85).abs() < 1e-3);
+
+        mixer.set_emitter(id, None);
+        mixer.render_pcm(10);
+        assert_eq!(mixer.render_pcm(10).data, pcm.data);
+    }
+}
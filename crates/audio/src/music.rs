@@ -0,0 +1,903 @@
+//! An interactive iMUSE player. Plays the songs of a level, following their iMUSE jumps, loops
+//! and part changes, and switches between moods such as stalk and fight music when asked, at the
+//! next point the song marks as safe to leave.
+
+use std::io;
+use std::time::Duration;
+
+use formats::common::*;
+use formats::gmd::{self, Gmd, MidiSink};
+use formats::imuse;
+use formats::schedule::Schedule;
+
+use crate::synth::PERCUSSION_CHANNEL;
+
+/// The kinds of music a level switches between.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Mood {
+    Stalk,
+    Fight,
+    Boss,
+}
+
+impl Mood {
+    pub const ALL: [Mood; 3] = [Mood::Stalk, Mood::Fight, Mood::Boss];
+
+    /// The name used for the mood's GMD files and iMUSE labels.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stalk => "stalk",
+            Self::Fight => "fight",
+            Self::Boss => "boss",
+        }
+    }
+
+    /// The name of the GMD with the mood's song for a (1-based) level, e.g. `STALK-01.GMD`.
+    pub fn file_name(self, level: u32) -> String {
+        format!("{}-{:02}.GMD", self.name().to_uppercase(), level)
+    }
+}
+
+/// The song to play for each mood.
+#[derive(Clone, Default)]
+pub struct MusicSet {
+    songs: Vec<(Mood, Gmd)>,
+}
+
+impl MusicSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the songs of a level, using `read` to get the contents of each file by name, e.g.
+    /// from the game's GOB files. Moods with no file are left out.
+    pub fn load_level(
+        level: u32,
+        mut read: impl FnMut(&str) -> Option<Vec<u8>>,
+    ) -> ReadResult<Self> {
+        let mut set = Self::new();
+        for &mood in Mood::ALL.iter() {
+            if let Some(data) = read(&mood.file_name(level)) {
+                set.insert(mood, Gmd::read(io::Cursor::new(data))?);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Sets the song for `mood`, replacing any previous song.
+    pub fn insert(&mut self, mood: Mood, gmd: Gmd) {
+        self.songs.retain(|(song_mood, _)| *song_mood != mood);
+        self.songs.push((mood, gmd));
+    }
+
+    pub fn get(&self, mood: Mood) -> Option<&Gmd> {
+        self.songs
+            .iter()
+            .find(|(song_mood, _)| *song_mood == mood)
+            .map(|(_, gmd)| gmd)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+}
+
+/// The values conditional iMUSE messages check, set by the game to steer the song. A message
+/// with hook 0 always applies, otherwise it only applies when its hook is set to the same value,
+/// which also clears the hook.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hooks {
+    pub jump: u8,
+    pub transpose: u8,
+    pub part_enable: [u8; 16],
+    pub part_volume: [u8; 16],
+    pub part_program: [u8; 16],
+    pub part_transpose: [u8; 16],
+}
+
+fn take_hook(hook: &mut u8, value: u8) -> bool {
+    if value == 0 {
+        true
+    } else if *hook == value {
+        *hook = 0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Something that happened during playback, for tools to follow the score.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    Marker {
+        ids: Vec<u8>,
+    },
+    Label(String),
+    /// Playback jumped from one tick to another within the song, by an iMUSE jump or loop.
+    Jump {
+        from: u64,
+        to: u64,
+    },
+    /// Playback switched to the song or label for another mood.
+    Transition {
+        from: Mood,
+        to: Mood,
+    },
+}
+
+/// The most jumps without time passing before playback gives up, e.g. for a song that jumps to
+/// where it already is.
+const MAX_JUMPS_WITHOUT_PROGRESS: u32 = 64;
+
+struct Song<'a> {
+    mood: Mood,
+    schedule: Schedule<'a>,
+    /// The decoded iMUSE message of each event, if it has one.
+    imuse: Vec<Option<imuse::Event>>,
+    labels: Vec<(String, u64)>,
+    has_markers: bool,
+    ticks_per_beat: u16,
+    /// The tick each time signature starts at, and its bar length in ticks.
+    bars: Vec<(u64, u64)>,
+}
+
+impl<'a> Song<'a> {
+    fn new(mood: Mood, gmd: &'a Gmd) -> ReadResult<Self> {
+        let schedule = Schedule::new(&gmd.parse_smf()?);
+        let ticks_per_beat = schedule.ticks_per_beat().unwrap_or_else(|| {
+            // Timecode songs have no beats, use seconds.
+            schedule
+                .time_to_tick(Duration::from_secs(1))
+                .clamp(1, 0x7FFF) as u16
+        });
+
+        let mut imuse = Vec::with_capacity(schedule.events().len());
+        let mut labels = Vec::new();
+        let mut has_markers = false;
+        let mut bars = vec![(0, ticks_per_beat as u64 * 4)];
+        for event in schedule.events() {
+            let decoded = match event.kind {
+                midly::TrackEventKind::SysEx(bytes) => imuse::decode(bytes),
+                midly::TrackEventKind::Meta(midly::MetaMessage::TimeSignature(
+                    numerator,
+                    denominator,
+                    ..,
+                )) => {
+                    let beats = numerator.max(1) as u64 * 4;
+                    let bar = (beats * ticks_per_beat as u64) >> denominator.min(6);
+                    bars.retain(|&(tick, _)| tick < event.tick);
+                    bars.push((event.tick, bar.max(1)));
+                    None
+                }
+                _ => None,
+            };
+            match &decoded {
+                Some(imuse::Event::Label(name)) => labels.push((name.clone(), event.tick)),
+                Some(imuse::Event::Marker { .. }) => has_markers = true,
+                _ => {}
+            }
+            imuse.push(decoded);
+        }
+
+        Ok(Self {
+            mood,
+            schedule,
+            imuse,
+            labels,
+            has_markers,
+            ticks_per_beat,
+            bars,
+        })
+    }
+
+    fn label(&self, name: &str) -> Option<u64> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label.eq_ignore_ascii_case(name))
+            .map(|&(_, tick)| tick)
+    }
+
+    /// The first bar line at or after `tick`.
+    fn next_bar(&self, tick: u64) -> u64 {
+        let index = self.bars.partition_point(|&(start, _)| start <= tick);
+        let (start, length) = self.bars[index.max(1) - 1];
+        let bar = match (tick - start) % length {
+            0 => tick,
+            remainder => tick + length - remainder,
+        };
+        // A new time signature is also a bar line.
+        match self.bars.get(index) {
+            Some(&(next, _)) if next < bar => next,
+            _ => bar,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Loop {
+    start: u64,
+    end: u64,
+    count: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct PartState {
+    enabled: bool,
+    transpose: i8,
+}
+
+impl Default for PartState {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            transpose: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Action {
+    Event(usize),
+    LoopEnd,
+    Transition,
+    End,
+}
+
+/// Plays a `MusicSet` interactively. Time only passes through `advance()`, so playback can be
+/// driven by a real-time clock or rendered offline, with the same result.
+pub struct Music<'a> {
+    songs: Vec<Song<'a>>,
+    song: usize,
+    mood: Mood,
+    pending: Option<Mood>,
+    /// The next event in the song.
+    index: usize,
+    /// The tick reached by playback.
+    tick: u64,
+    /// The playback time at `tick`.
+    tick_time: Duration,
+    /// The time playback has been advanced to.
+    now: Duration,
+    chase_pending: bool,
+    loop_region: Option<Loop>,
+    hooks: Hooks,
+    transpose: i8,
+    parts: [PartState; 16],
+    label: Option<String>,
+    notifications: Vec<(Duration, Notification)>,
+    last_jump_time: Duration,
+    jumps_without_progress: u32,
+}
+
+impl<'a> Music<'a> {
+    /// Prepares to play `set` from the start of `mood`, or of the first song if `set` has no
+    /// song or label for `mood`.
+    pub fn new(set: &'a MusicSet, mood: Mood) -> ReadResult<Self> {
+        let songs = set
+            .songs
+            .iter()
+            .map(|(mood, gmd)| Song::new(*mood, gmd))
+            .collect::<ReadResult<Vec<_>>>()?;
+        if songs.is_empty() {
+            return Err(ReadError::decoding("GMD", "music set has no songs"));
+        }
+        let mut music = Self {
+            songs,
+            song: 0,
+            mood,
+            pending: None,
+            index: 0,
+            tick: 0,
+            tick_time: Duration::ZERO,
+            now: Duration::ZERO,
+            chase_pending: false,
+            loop_region: None,
+            hooks: Hooks::default(),
+            transpose: 0,
+            parts: Default::default(),
+            label: None,
+            notifications: Vec::new(),
+            last_jump_time: Duration::ZERO,
+            jumps_without_progress: 0,
+        };
+        let (song, tick) = match music.start_of(mood) {
+            Some(start) => start,
+            None => {
+                music.mood = music.songs[0].mood;
+                (0, 0)
+            }
+        };
+        music.start_song(song, tick);
+        music.chase_pending = true;
+        Ok(music)
+    }
+
+    /// The mood playing, which changes when a requested transition happens.
+    pub fn mood(&self) -> Mood {
+        self.mood
+    }
+
+    /// The mood playback will switch to at the next valid point.
+    pub fn pending(&self) -> Option<Mood> {
+        self.pending
+    }
+
+    /// The time playback has been advanced to.
+    pub fn time(&self) -> Duration {
+        self.now
+    }
+
+    /// The last iMUSE label played.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    /// Asks to switch to `mood`, returning `false` if there is no song or label for it.
+    ///
+    /// The switch happens at the next iMUSE marker, or for songs without markers at the next
+    /// bar line, and starts the mood's song from the beginning, or from its label if it is a
+    /// section of the same song. Requesting the mood already playing cancels a pending switch.
+    pub fn request(&mut self, mood: Mood) -> bool {
+        if self.start_of(mood).is_none() {
+            return false;
+        }
+        self.pending = if mood == self.mood { None } else { Some(mood) };
+        true
+    }
+
+    /// Takes the notifications since the last call, with the time each happened.
+    pub fn take_notifications(&mut self) -> Vec<(Duration, Notification)> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// The time of the next event or transition, if playback has not stopped.
+    pub fn next_time(&self) -> Option<Duration> {
+        self.next_action().map(|(time, ..)| time.max(self.now))
+    }
+
+    /// Plays everything up to `until` into `sink`.
+    pub fn advance<S: MidiSink + ?Sized>(
+        &mut self,
+        until: Duration,
+        sink: &mut S,
+    ) -> Result<(), S::Error> {
+        if self.chase_pending {
+            self.chase_pending = false;
+            self.chase(sink)?;
+        }
+
+        while let Some((time, tick, action)) = self.next_action() {
+            if time > until {
+                break;
+            }
+            self.tick_time = time.max(self.tick_time);
+            self.tick = tick;
+            match action {
+                Action::Event(index) => {
+                    self.index = index + 1;
+                    self.play_event(index, sink)?;
+                }
+                Action::LoopEnd => {
+                    if let Some(region) = &mut self.loop_region {
+                        let start = region.start;
+                        region.count -= 1;
+                        if region.count == 0 {
+                            self.loop_region = None;
+                        }
+                        self.jump(start, sink)?;
+                    }
+                }
+                Action::Transition | Action::End => match self.pending.take() {
+                    Some(mood) => self.transition(mood, sink)?,
+                    // Songs repeat until told otherwise.
+                    None => self.jump(0, sink)?,
+                },
+            }
+        }
+        self.now = until.max(self.now);
+        Ok(())
+    }
+
+    fn next_action(&self) -> Option<(Duration, u64, Action)> {
+        if self.jumps_without_progress > MAX_JUMPS_WITHOUT_PROGRESS {
+            return None;
+        }
+        let song = &self.songs[self.song];
+        let events = song.schedule.events();
+        if events.is_empty() {
+            return None;
+        }
+
+        let (mut tick, mut action) = match events.get(self.index) {
+            Some(event) => (event.tick, Action::Event(self.index)),
+            None => (self.tick, Action::End),
+        };
+        if let Some(region) = &self.loop_region {
+            if self.tick < region.end && region.end <= tick {
+                tick = region.end;
+                action = Action::LoopEnd;
+            }
+        }
+        if self.pending.is_some() && !song.has_markers {
+            let bar = song.next_bar(self.tick_at(self.now));
+            if bar <= tick {
+                tick = bar;
+                action = Action::Transition;
+            }
+        }
+        Some((self.time_at(tick), tick, action))
+    }
+
+    /// The playback time of `tick` in the current song, which should not be before `self.tick`.
+    fn time_at(&self, tick: u64) -> Duration {
+        let schedule = &self.songs[self.song].schedule;
+        let elapsed = schedule
+            .tick_to_time(tick)
+            .checked_sub(schedule.tick_to_time(self.tick))
+            .unwrap_or_default();
+        self.tick_time + elapsed
+    }
+
+    fn tick_at(&self, time: Duration) -> u64 {
+        let schedule = &self.songs[self.song].schedule;
+        let elapsed = time.checked_sub(self.tick_time).unwrap_or_default();
+        schedule.time_to_tick(schedule.tick_to_time(self.tick) + elapsed)
+    }
+
+    /// Where to start playing `mood`: its own song, or its label in the current or any song.
+    fn start_of(&self, mood: Mood) -> Option<(usize, u64)> {
+        if let Some(index) = self.songs.iter().position(|song| song.mood == mood) {
+            return Some((index, 0));
+        }
+        if let Some(tick) = self.songs[self.song].label(mood.name()) {
+            return Some((self.song, tick));
+        }
+        self.songs
+            .iter()
+            .enumerate()
+            .find_map(|(index, song)| Some((index, song.label(mood.name())?)))
+    }
+
+    fn start_song(&mut self, song: usize, tick: u64) {
+        self.song = song;
+        self.tick = tick;
+        self.index = self.songs[song].schedule.index_at_tick(tick);
+        self.loop_region = None;
+        self.transpose = 0;
+        self.parts = Default::default();
+    }
+
+    fn transition<S: MidiSink + ?Sized>(
+        &mut self,
+        mood: Mood,
+        sink: &mut S,
+    ) -> Result<(), S::Error> {
+        let (song, tick) = match self.start_of(mood) {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        all_notes_off(sink, 0..16)?;
+        self.start_song(song, tick);
+        self.chase(sink)?;
+        self.notifications.push((
+            self.tick_time,
+            Notification::Transition {
+                from: self.mood,
+                to: mood,
+            },
+        ));
+        self.mood = mood;
+        Ok(())
+    }
+
+    /// Sends the channel state from before the current position in the song.
+    fn chase<S: MidiSink + ?Sized>(&self, sink: &mut S) -> Result<(), S::Error> {
+        let song = &self.songs[self.song];
+        for event in song.schedule.chase(self.index) {
+            gmd::send_event(sink, event.kind)?;
+        }
+        Ok(())
+    }
+
+    fn jump<S: MidiSink + ?Sized>(&mut self, tick: u64, sink: &mut S) -> Result<(), S::Error> {
+        if self.tick_time == self.last_jump_time {
+            self.jumps_without_progress += 1;
+        } else {
+            self.last_jump_time = self.tick_time;
+            self.jumps_without_progress = 0;
+        }
+        all_notes_off(sink, 0..16)?;
+        self.notifications.push((
+            self.tick_time,
+            Notification::Jump {
+                from: self.tick,
+                to: tick,
+            },
+        ));
+        self.tick = tick;
+        self.index = self.songs[self.song].schedule.index_at_tick(tick);
+        Ok(())
+    }
+
+    fn play_event<S: MidiSink + ?Sized>(
+        &mut self,
+        index: usize,
+        sink: &mut S,
+    ) -> Result<(), S::Error> {
+        let song = &self.songs[self.song];
+        let event = song.schedule.events()[index];
+        let imuse_event = match &song.imuse[index] {
+            Some(imuse_event) => imuse_event.clone(),
+            None => return self.send(event.kind, sink),
+        };
+        let track_start = song.schedule.track_start(event.track).unwrap_or(0);
+        let ticks_per_beat = song.ticks_per_beat;
+
+        match imuse_event {
+            imuse::Event::AllocatePart(part) => {
+                let channel = part.channel & 0x0F;
+                self.parts[channel as usize] = PartState {
+                    enabled: part.enabled,
+                    transpose: if part.percussion { 0 } else { part.transpose },
+                };
+                sink.send(channel, program(part.program))?;
+                sink.send(channel, controller(7, part.volume))?;
+                sink.send(
+                    channel,
+                    controller(10, (64 + part.pan as i16).clamp(0, 127) as u8),
+                )?;
+            }
+            imuse::Event::ShutdownPart { channel } => {
+                self.parts[channel as usize].enabled = false;
+                all_notes_off(sink, channel..channel + 1)?;
+            }
+            imuse::Event::StartSong | imuse::Event::Unknown { .. } => {}
+            imuse::Event::Label(name) => {
+                self.notifications
+                    .push((self.tick_time, Notification::Label(name.clone())));
+                self.label = Some(name);
+            }
+            imuse::Event::Marker { ids } => {
+                self.notifications
+                    .push((self.tick_time, Notification::Marker { ids }));
+                if let Some(mood) = self.pending.take() {
+                    self.transition(mood, sink)?;
+                }
+            }
+            imuse::Event::Jump {
+                hook,
+                track,
+                position,
+            } => {
+                let target = self.songs[self.song].schedule.track_start(track as usize);
+                if let Some(target) = target {
+                    if take_hook(&mut self.hooks.jump, hook) {
+                        self.jump(target + position.to_ticks(ticks_per_beat), sink)?;
+                    }
+                }
+            }
+            imuse::Event::SetLoop { count, start, end } => {
+                let start = track_start + start.to_ticks(ticks_per_beat);
+                let end = track_start + end.to_ticks(ticks_per_beat);
+                self.loop_region = if count > 0 && start < end {
+                    Some(Loop { start, end, count })
+                } else {
+                    None
+                };
+            }
+            imuse::Event::ClearLoop => {
+                self.loop_region = None;
+            }
+            imuse::Event::Transpose {
+                hook,
+                relative,
+                transpose,
+            } => {
+                if take_hook(&mut self.hooks.transpose, hook) {
+                    all_notes_off(sink, 0..16)?;
+                    let base = if relative { self.transpose } else { 0 };
+                    self.transpose = base.saturating_add(transpose).clamp(-24, 24);
+                }
+            }
+            imuse::Event::PartEnable {
+                hook,
+                channel,
+                enabled,
+            } => {
+                if take_hook(&mut self.hooks.part_enable[channel as usize], hook) {
+                    self.parts[channel as usize].enabled = enabled;
+                    if !enabled {
+                        all_notes_off(sink, channel..channel + 1)?;
+                    }
+                }
+            }
+            imuse::Event::PartVolume {
+                hook,
+                channel,
+                volume,
+            } => {
+                if take_hook(&mut self.hooks.part_volume[channel as usize], hook) {
+                    sink.send(channel, controller(7, volume))?;
+                }
+            }
+            imuse::Event::PartProgram {
+                hook,
+                channel,
+                program: value,
+            } => {
+                if take_hook(&mut self.hooks.part_program[channel as usize], hook) {
+                    sink.send(channel, program(value))?;
+                }
+            }
+            imuse::Event::PartTranspose {
+                hook,
+                channel,
+                relative,
+                transpose,
+            } => {
+                if take_hook(&mut self.hooks.part_transpose[channel as usize], hook) {
+                    all_notes_off(sink, channel..channel + 1)?;
+                    let part = &mut self.parts[channel as usize];
+                    let base = if relative { part.transpose } else { 0 };
+                    part.transpose = base.saturating_add(transpose).clamp(-24, 24);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a song event, muting disabled parts and transposing notes.
+    fn send<S: MidiSink + ?Sized>(
+        &self,
+        kind: midly::TrackEventKind<'_>,
+        sink: &mut S,
+    ) -> Result<(), S::Error> {
+        let (channel, message) = match kind {
+            midly::TrackEventKind::Midi { channel, message } => (channel.as_int(), message),
+            _ => return gmd::send_event(sink, kind),
+        };
+        let part = self.parts[channel as usize];
+        let transpose = |key: midly::num::u7| {
+            if channel == PERCUSSION_CHANNEL {
+                return Some(key);
+            }
+            let key = key.as_int() as i16 + self.transpose as i16 + part.transpose as i16;
+            if (0..128).contains(&key) {
+                Some((key as u8).into())
+            } else {
+                None
+            }
+        };
+        let message = match message {
+            midly::MidiMessage::NoteOn { vel, .. } if !part.enabled && vel.as_int() > 0 => {
+                return Ok(())
+            }
+            midly::MidiMessage::NoteOn { key, vel } => match transpose(key) {
+                Some(key) => midly::MidiMessage::NoteOn { key, vel },
+                None => return Ok(()),
+            },
+            midly::MidiMessage::NoteOff { key, vel } => match transpose(key) {
+                Some(key) => midly::MidiMessage::NoteOff { key, vel },
+                None => return Ok(()),
+            },
+            midly::MidiMessage::Aftertouch { key, vel } => match transpose(key) {
+                Some(key) => midly::MidiMessage::Aftertouch { key, vel },
+                None => return Ok(()),
+            },
+            message => message,
+        };
+        sink.send(channel, message)
+    }
+}
+
+fn program(program: u8) -> midly::MidiMessage {
+    midly::MidiMessage::ProgramChange {
+        program: (program & 0x7F).into(),
+    }
+}
+
+fn controller(controller: u8, value: u8) -> midly::MidiMessage {
+    midly::MidiMessage::Controller {
+        controller: controller.into(),
+        value: value.min(127).into(),
+    }
+}
+
+fn all_notes_off<S: MidiSink + ?Sized>(
+    sink: &mut S,
+    channels: std::ops::Range<u8>,
+) -> Result<(), S::Error> {
+    for channel in channels {
+        sink.send(channel, controller(123, 0))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use midly::num::{u15, u28, u4, u7};
+
+    use super::*;
+
+    /// Keeps the notes played.
+    #[derive(Default)]
+    struct Notes(Vec<u8>);
+
+    impl MidiSink for Notes {
+        type Error = Infallible;
+
+        fn send(&mut self, _channel: u8, message: midly::MidiMessage) -> Result<(), Infallible> {
+            if let midly::MidiMessage::NoteOn { key, vel } = message {
+                if vel.as_int() > 0 {
+                    self.0.push(key.as_int());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn event(delta: u32, kind: midly::TrackEventKind<'static>) -> midly::TrackEvent<'static> {
+        midly::TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        }
+    }
+
+    fn note(key: u8) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Midi {
+            channel: u4::new(0),
+            message: midly::MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(100),
+            },
+        }
+    }
+
+    const END: midly::TrackEventKind<'static> =
+        midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack);
+    const MARKER: midly::TrackEventKind<'static> =
+        midly::TrackEventKind::SysEx(&[0x7D, 0x40, 0x00, 0x01, 0xF7]);
+    /// Jumps back to the start of track 0, if the jump hook is 3.
+    const JUMP_IF_3: midly::TrackEventKind<'static> = midly::TrackEventKind::SysEx(&[
+        0x7D, 0x30, 0x00, 0, 3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xF7,
+    ]);
+
+    /// A song at 120 BPM and 96 ticks per beat, so each beat is half a second.
+    fn gmd(events: Vec<midly::TrackEvent<'static>>) -> Gmd {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::SingleTrack,
+            midly::Timing::Metrical(u15::new(96)),
+        ));
+        smf.tracks.push(events);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        Gmd::from_mid(data).unwrap()
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn transition_at_marker() {
+        let mut set = MusicSet::new();
+        set.insert(
+            Mood::Stalk,
+            gmd(vec![
+                event(0, note(60)),
+                event(96, note(62)),
+                event(96, MARKER),
+                event(0, note(64)),
+                event(192, END),
+            ]),
+        );
+        set.insert(Mood::Fight, gmd(vec![event(0, note(70)), event(96, END)]));
+
+        let mut music = Music::new(&set, Mood::Stalk).unwrap();
+        let mut notes = Notes::default();
+        music.advance(secs(0.25), &mut notes).unwrap();
+        assert!(music.request(Mood::Fight));
+        assert_eq!(music.pending(), Some(Mood::Fight));
+        music.advance(secs(0.9), &mut notes).unwrap();
+        assert_eq!(music.mood(), Mood::Stalk);
+        assert_eq!(music.next_time(), Some(secs(1.0)));
+
+        music.advance(secs(1.2), &mut notes).unwrap();
+        assert_eq!(music.mood(), Mood::Fight);
+        assert_eq!(notes.0, [60, 62, 70]);
+        assert_eq!(
+            music.take_notifications(),
+            [
+                (secs(1.0), Notification::Marker { ids: vec![1] }),
+                (
+                    secs(1.0),
+                    Notification::Transition {
+                        from: Mood::Stalk,
+                        to: Mood::Fight
+                    }
+                ),
+            ]
+        );
+
+        // The fight song repeats.
+        music.advance(secs(1.5), &mut notes).unwrap();
+        assert_eq!(notes.0, [60, 62, 70, 70]);
+        assert!(!music.request(Mood::Boss));
+    }
+
+    #[test]
+    fn transition_at_bar() {
+        let mut set = MusicSet::new();
+        set.insert(
+            Mood::Stalk,
+            gmd(vec![
+                event(0, note(60)),
+                event(96 * 6, note(62)),
+                event(96, END),
+            ]),
+        );
+        set.insert(Mood::Fight, gmd(vec![event(0, note(70)), event(96, END)]));
+
+        let mut music = Music::new(&set, Mood::Stalk).unwrap();
+        let mut notes = Notes::default();
+        music.advance(secs(0.5), &mut notes).unwrap();
+        music.request(Mood::Fight);
+        // The next 4/4 bar starts after 4 beats.
+        assert_eq!(music.next_time(), Some(secs(2.0)));
+        music.advance(secs(2.0), &mut notes).unwrap();
+        assert_eq!(music.mood(), Mood::Fight);
+        assert_eq!(notes.0, [60, 70]);
+    }
+
+    #[test]
+    fn hooks_and_labels() {
+        let label = |name: &'static [u8]| midly::TrackEventKind::SysEx(name);
+        let mut set = MusicSet::new();
+        set.insert(
+            Mood::Stalk,
+            gmd(vec![
+                event(0, label(b"\x7D\x03stalk\0\xF7")),
+                event(0, note(60)),
+                event(96, JUMP_IF_3),
+                event(0, label(b"\x7D\x03fight\0\xF7")),
+                event(0, note(70)),
+                event(96, END),
+            ]),
+        );
+
+        let mut music = Music::new(&set, Mood::Stalk).unwrap();
+        let mut notes = Notes::default();
+        music.advance(secs(0.75), &mut notes).unwrap();
+        assert_eq!(notes.0, [60, 70]);
+        assert_eq!(music.label(), Some("fight"));
+
+        // Jumping back once the hook is set, which clears it.
+        music.hooks_mut().jump = 3;
+        music.advance(secs(1.75), &mut notes).unwrap();
+        assert_eq!(notes.0, [60, 70, 60, 60]);
+        assert_eq!(music.hooks().jump, 0);
+
+        // The fight label in the same song, starting at the end of the song.
+        assert!(music.request(Mood::Fight));
+        music.advance(secs(2.75), &mut notes).unwrap();
+        assert_eq!(music.mood(), Mood::Fight);
+        assert_eq!(notes.0, [60, 70, 60, 60, 70, 70]);
+        assert!(music.take_notifications().contains(&(
+            secs(2.5),
+            Notification::Transition {
+                from: Mood::Stalk,
+                to: Mood::Fight
+            }
+        )));
+    }
+}
@@ -0,0 +1,591 @@
+//! An emulation of the Yamaha YM3812 (OPL2, AdLib) and YMF262 (OPL3, Sound Blaster 16) FM
+//! synthesis chips, driven by register writes like the real hardware.
+//!
+//! Operators use the chips' log-sine and exponent tables, all eight waveforms, key scaling,
+//! tremolo, vibrato and feedback, so patches written for the hardware sound as expected. The
+//! envelope generator follows the documented attack and decay times rather than the exact
+//! counter behaviour, and rhythm mode and OPL3 four-operator channels are not emulated.
+
+use std::f64::consts::PI;
+
+/// The native sample rate of both chips, from the 14.318 MHz clock divided by 288.
+pub const NATIVE_SAMPLE_RATE: u32 = 49_716;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Chip {
+    /// 9 mono channels, 4 waveforms.
+    Opl2,
+    /// 18 channels with left/right output, 8 waveforms.
+    Opl3,
+}
+
+impl Chip {
+    pub fn channel_count(self) -> usize {
+        match self {
+            Chip::Opl2 => 9,
+            Chip::Opl3 => 18,
+        }
+    }
+}
+
+/// The operator register offset of each channel's modulator; the carrier is 3 higher.
+pub const MODULATOR_OFFSETS: [u16; 9] = [0x00, 0x01, 0x02, 0x08, 0x09, 0x0A, 0x10, 0x11, 0x12];
+
+/// Operator register offsets (0x00 to 0x15) to slot numbers, `None` for unused offsets.
+const SLOTS: [Option<usize>; 0x16] = [
+    Some(0),
+    Some(1),
+    Some(2),
+    Some(3),
+    Some(4),
+    Some(5),
+    None,
+    None,
+    Some(6),
+    Some(7),
+    Some(8),
+    Some(9),
+    Some(10),
+    Some(11),
+    None,
+    None,
+    Some(12),
+    Some(13),
+    Some(14),
+    Some(15),
+    Some(16),
+    Some(17),
+];
+
+/// Frequency multiples, doubled so 0 can mean half.
+const MULTIPLES: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+/// Key scale attenuation by the top 4 bits of the frequency number, in 0.75 dB steps.
+const KSL: [i32; 16] = [
+    0, 32, 40, 45, 48, 51, 53, 55, 56, 58, 59, 60, 61, 62, 63, 64,
+];
+
+/// How far to shift the key scale attenuation for each KSL setting: none, 3, 1.5 and 6 dB
+/// per octave.
+const KSL_SHIFT: [u32; 4] = [8, 1, 2, 0];
+
+/// The largest envelope attenuation, in 0.1875 dB steps (96 dB).
+const ENVELOPE_MAX: f32 = 511.0;
+
+struct Tables {
+    /// -log2(sin(x)) over a quarter wave, in 1/256ths.
+    log_sin: [u16; 256],
+    /// 2^-x over one octave, less the implicit top bit.
+    exp: [u16; 256],
+}
+
+impl Tables {
+    fn new() -> Self {
+        let mut log_sin = [0u16; 256];
+        let mut exp = [0u16; 256];
+        for i in 0..256 {
+            let angle = (i as f64 + 0.5) * PI / 512.0;
+            log_sin[i] = (-angle.sin().log2() * 256.0).round() as u16;
+            exp[i] = ((2f64.powf((255 - i) as f64 / 256.0) * 1024.0).round() - 1024.0) as u16;
+        }
+        Self { log_sin, exp }
+    }
+
+    /// The chip's `2^-(log / 256)`, scaled to 12 bits.
+    fn exp(&self, log: u32) -> i32 {
+        if log >= 0x1000 {
+            return 0;
+        }
+        (((self.exp[(log & 0xFF) as usize] | 0x400) as i32) << 1) >> (log >> 8)
+    }
+
+    /// The output of `waveform` at 10-bit `phase` with `attenuation` in 1/256ths of an octave.
+    fn wave(&self, waveform: u8, phase: u32, attenuation: u32) -> i32 {
+        let phase = phase & 0x3FF;
+        let quarter = |phase: u32| {
+            let index = if phase & 0x100 != 0 {
+                !phase & 0xFF
+            } else {
+                phase & 0xFF
+            };
+            self.log_sin[index as usize] as u32
+        };
+        let (log, negative) = match waveform & 7 {
+            0 => (quarter(phase), phase & 0x200 != 0),
+            1 if phase & 0x200 != 0 => return 0,
+            1 => (quarter(phase), false),
+            2 => (quarter(phase), false),
+            3 if phase & 0x100 != 0 => return 0,
+            3 => (self.log_sin[(phase & 0xFF) as usize] as u32, false),
+            4 if phase & 0x200 != 0 => return 0,
+            4 => (quarter(phase << 1), phase & 0x100 != 0),
+            5 if phase & 0x200 != 0 => return 0,
+            5 => (quarter(phase << 1), false),
+            6 => (0, phase & 0x200 != 0),
+            _ => {
+                let negative = phase & 0x200 != 0;
+                let x = if negative {
+                    !phase & 0x1FF
+                } else {
+                    phase & 0x1FF
+                };
+                (x << 3, negative)
+            }
+        };
+        let value = self.exp(log + attenuation);
+        if negative {
+            !value
+        } else {
+            value
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Slot {
+    tremolo: bool,
+    vibrato: bool,
+    sustaining: bool,
+    key_scale_rate: bool,
+    multiple: u8,
+    key_scale_level: u8,
+    total_level: u8,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+    waveform: u8,
+
+    /// One cycle is 2^32.
+    phase: u32,
+    stage: Stage,
+    /// In 0.1875 dB steps.
+    envelope: f32,
+    /// The last two outputs, for feedback.
+    outputs: [i32; 2],
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            tremolo: false,
+            vibrato: false,
+            sustaining: false,
+            key_scale_rate: false,
+            multiple: 0,
+            key_scale_level: 0,
+            total_level: 0,
+            attack_rate: 0,
+            decay_rate: 0,
+            sustain_level: 0,
+            release_rate: 0,
+            waveform: 0,
+            phase: 0,
+            stage: Stage::Off,
+            envelope: ENVELOPE_MAX,
+            outputs: [0; 2],
+        }
+    }
+}
+
+impl Slot {
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.stage = Stage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.stage != Stage::Off {
+            self.stage = Stage::Release;
+        }
+    }
+
+    fn sustain_attenuation(&self) -> f32 {
+        // 3 dB steps, with the last step 93 dB.
+        match self.sustain_level {
+            15 => 31.0 * 16.0,
+            level => level as f32 * 16.0,
+        }
+    }
+
+    /// Advances the envelope by one sample.
+    fn step_envelope(&mut self, key_scale: u8, sample_rate: f32) {
+        // Rates step in quarters of an octave, with the key adding up to 15 quarters.
+        let rate = |rate: u8| {
+            if rate == 0 {
+                return None;
+            }
+            let offset = if self.key_scale_rate {
+                key_scale
+            } else {
+                key_scale >> 2
+            };
+            let rate = (rate * 4 + offset).min(63);
+            Some((rate >> 2, rate & 3))
+        };
+        // The time for the full 96 dB range, from the rate's octave and quarters.
+        let seconds = |base_ms: f32, (octave, quarters): (u8, u8)| {
+            base_ms / 1000.0 / (1u32 << (octave - 1)) as f32 / (1.0 + quarters as f32 / 4.0)
+        };
+
+        match self.stage {
+            Stage::Attack => match rate(self.attack_rate) {
+                Some((15, _)) => self.envelope = 0.0,
+                Some(rate) => {
+                    // An exponential approach, reaching full volume in the attack time.
+                    let k = 6.24 / (seconds(2826.24, rate) * sample_rate);
+                    self.envelope -= (self.envelope + 1.0) * k;
+                }
+                None => {}
+            },
+            Stage::Decay | Stage::Release => {
+                let register = if self.stage == Stage::Decay {
+                    self.decay_rate
+                } else {
+                    self.release_rate
+                };
+                if let Some(rate) = rate(register) {
+                    self.envelope += (ENVELOPE_MAX + 1.0) / (seconds(39280.64, rate) * sample_rate);
+                }
+            }
+            Stage::Sustain | Stage::Off => {}
+        }
+
+        match self.stage {
+            Stage::Attack if self.envelope <= 0.0 => {
+                self.envelope = 0.0;
+                self.stage = Stage::Decay;
+            }
+            Stage::Decay if self.envelope >= self.sustain_attenuation() => {
+                self.envelope = self.sustain_attenuation();
+                // Non-sustaining sounds carry on with the release rate.
+                self.stage = if self.sustaining {
+                    Stage::Sustain
+                } else {
+                    Stage::Release
+                };
+            }
+            Stage::Release if self.envelope >= ENVELOPE_MAX => {
+                self.envelope = ENVELOPE_MAX;
+                self.stage = Stage::Off;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Channel {
+    frequency_number: u16,
+    block: u8,
+    key_on: bool,
+    feedback: u8,
+    additive: bool,
+    left: bool,
+    right: bool,
+}
+
+/// An OPL2 or OPL3 chip, rendering at any sample rate.
+pub struct Opl {
+    chip: Chip,
+    sample_rate: u32,
+    tables: Tables,
+    slots: [Slot; 36],
+    channels: [Channel; 18],
+    waveform_select: bool,
+    opl3_mode: bool,
+    note_select: bool,
+    deep_tremolo: bool,
+    deep_vibrato: bool,
+    /// Samples rendered, for the LFOs.
+    time: u64,
+}
+
+impl Opl {
+    pub fn new(chip: Chip, sample_rate: u32) -> Self {
+        Self {
+            chip,
+            sample_rate: sample_rate.max(1),
+            tables: Tables::new(),
+            slots: [Slot::default(); 36],
+            channels: [Channel::default(); 18],
+            waveform_select: false,
+            opl3_mode: false,
+            note_select: false,
+            deep_tremolo: false,
+            deep_vibrato: false,
+            time: 0,
+        }
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Writes a register. OPL3 registers in the second bank are 0x100 to 0x1FF, and are
+    /// ignored by an OPL2.
+    pub fn write(&mut self, register: u16, value: u8) {
+        let bank = (register >> 8) as usize;
+        if bank > 1 || (bank == 1 && self.chip == Chip::Opl2) {
+            return;
+        }
+        let register = (register & 0xFF) as u8;
+
+        match (bank, register) {
+            (0, 0x01) => self.waveform_select = value & 0x20 != 0,
+            (1, 0x05) => self.opl3_mode = value & 0x01 != 0,
+            (0, 0x08) => self.note_select = value & 0x40 != 0,
+            (0, 0xBD) => {
+                self.deep_tremolo = value & 0x80 != 0;
+                self.deep_vibrato = value & 0x40 != 0;
+            }
+            (_, 0x20..=0x9F) | (_, 0xE0..=0xF5) => {
+                let slot = match SLOTS.get((register & 0x1F) as usize).copied().flatten() {
+                    Some(slot) => bank * 18 + slot,
+                    None => return,
+                };
+                let slot = &mut self.slots[slot];
+                match register & 0xE0 {
+                    0x20 => {
+                        slot.tremolo = value & 0x80 != 0;
+                        slot.vibrato = value & 0x40 != 0;
+                        slot.sustaining = value & 0x20 != 0;
+                        slot.key_scale_rate = value & 0x10 != 0;
+                        slot.multiple = value & 0x0F;
+                    }
+                    0x40 => {
+                        slot.key_scale_level = value >> 6;
+                        slot.total_level = value & 0x3F;
+                    }
+                    0x60 => {
+                        slot.attack_rate = value >> 4;
+                        slot.decay_rate = value & 0x0F;
+                    }
+                    0x80 => {
+                        slot.sustain_level = value >> 4;
+                        slot.release_rate = value & 0x0F;
+                    }
+                    _ => slot.waveform = value & 0x07,
+                }
+            }
+            (_, 0xA0..=0xA8) => {
+                let channel = &mut self.channels[bank * 9 + (register - 0xA0) as usize];
+                channel.frequency_number = (channel.frequency_number & 0x300) | value as u16;
+            }
+            (_, 0xB0..=0xB8) => {
+                let index = bank * 9 + (register - 0xB0) as usize;
+                let channel = &mut self.channels[index];
+                channel.frequency_number =
+                    (channel.frequency_number & 0xFF) | ((value as u16 & 0x03) << 8);
+                channel.block = (value >> 2) & 0x07;
+                let key_on = value & 0x20 != 0;
+                if key_on != channel.key_on {
+                    channel.key_on = key_on;
+                    let (modulator, carrier) = Self::channel_slots(index);
+                    for &slot in &[modulator, carrier] {
+                        if key_on {
+                            self.slots[slot].key_on();
+                        } else {
+                            self.slots[slot].key_off();
+                        }
+                    }
+                }
+            }
+            (_, 0xC0..=0xC8) => {
+                let channel = &mut self.channels[bank * 9 + (register - 0xC0) as usize];
+                channel.feedback = (value >> 1) & 0x07;
+                channel.additive = value & 0x01 != 0;
+                channel.left = value & 0x10 != 0;
+                channel.right = value & 0x20 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Replaces `output` with the next `output.len() / 2` frames of interleaved stereo audio.
+    pub fn render(&mut self, output: &mut [f32]) {
+        let sample_rate = self.sample_rate as f64;
+        let channel_count = if self.opl3_mode {
+            self.chip.channel_count()
+        } else {
+            9
+        };
+
+        for frame in output.chunks_exact_mut(2) {
+            let seconds = self.time as f64 / sample_rate;
+            self.time += 1;
+
+            // Both LFOs are triangles: tremolo at 3.7 Hz, vibrato at 6.1 Hz.
+            let triangle = |hz: f64| {
+                let phase = (seconds * hz).fract();
+                1.0 - (phase * 4.0 - 2.0).abs()
+            };
+            let tremolo_depth = if self.deep_tremolo { 4.8 } else { 1.0 } / 0.1875;
+            let tremolo = ((triangle(3.7) + 1.0) / 2.0 * tremolo_depth) as u32;
+            let vibrato_cents = if self.deep_vibrato { 14.0 } else { 7.0 };
+            let vibrato = 2f64.powf(triangle(6.1) * vibrato_cents / 1200.0);
+
+            let mut left = 0;
+            let mut right = 0;
+            for index in 0..channel_count {
+                let channel = self.channels[index];
+                let sample = self.render_channel(index, &channel, tremolo, vibrato);
+                // OPL2 compatible mode ignores the output selection.
+                if !self.opl3_mode || channel.left {
+                    left += sample;
+                }
+                if !self.opl3_mode || channel.right {
+                    right += sample;
+                }
+            }
+            frame[0] = left as f32 / 16384.0;
+            frame[1] = right as f32 / 16384.0;
+        }
+    }
+
+    fn channel_slots(channel: usize) -> (usize, usize) {
+        let offset = MODULATOR_OFFSETS[channel % 9] as usize;
+        let modulator = (channel / 9) * 18 + SLOTS[offset].unwrap();
+        (modulator, modulator + 3)
+    }
+
+    fn render_channel(
+        &mut self,
+        index: usize,
+        channel: &Channel,
+        tremolo: u32,
+        vibrato: f64,
+    ) -> i32 {
+        let (modulator, carrier) = Self::channel_slots(index);
+
+        let feedback = if channel.feedback == 0 {
+            0
+        } else {
+            let outputs = self.slots[modulator].outputs;
+            (outputs[0] + outputs[1]) >> (9 - channel.feedback)
+        };
+        let modulator_output = self.render_slot(modulator, channel, feedback, tremolo, vibrato);
+        let slot = &mut self.slots[modulator];
+        slot.outputs = [slot.outputs[1], modulator_output];
+
+        if channel.additive {
+            modulator_output + self.render_slot(carrier, channel, 0, tremolo, vibrato)
+        } else {
+            self.render_slot(carrier, channel, modulator_output, tremolo, vibrato)
+        }
+    }
+
+    fn render_slot(
+        &mut self,
+        index: usize,
+        channel: &Channel,
+        modulation: i32,
+        tremolo: u32,
+        vibrato: f64,
+    ) -> i32 {
+        let sample_rate = self.sample_rate;
+        let waveform_mask = if self.opl3_mode {
+            7
+        } else if self.waveform_select {
+            3
+        } else {
+            0
+        };
+        let note_select = self.note_select;
+        let slot = &mut self.slots[index];
+
+        let note_bit = if note_select {
+            channel.frequency_number >> 8
+        } else {
+            channel.frequency_number >> 9
+        } & 1;
+        let key_scale = (channel.block << 1) | note_bit as u8;
+        slot.step_envelope(key_scale, sample_rate as f32);
+
+        // f = fnum * 2^block * 49716 / 2^20
+        let mut hz = (channel.frequency_number as f64)
+            * (1u32 << channel.block) as f64
+            * NATIVE_SAMPLE_RATE as f64
+            / (1u32 << 20) as f64
+            * MULTIPLES[slot.multiple as usize] as f64
+            / 2.0;
+        if slot.vibrato {
+            hz *= vibrato;
+        }
+        let phase = slot.phase;
+        slot.phase = slot
+            .phase
+            .wrapping_add((hz / sample_rate as f64 * 4_294_967_296.0) as u32);
+
+        if slot.stage == Stage::Off {
+            return 0;
+        }
+
+        let key_scale_level = ((KSL[(channel.frequency_number >> 6) as usize] << 2)
+            - ((8 - channel.block as i32) << 5))
+            .max(0)
+            >> KSL_SHIFT[slot.key_scale_level as usize];
+        let attenuation = (slot.envelope as u32
+            + ((slot.total_level as u32) << 2)
+            + key_scale_level as u32
+            + if slot.tremolo { tremolo } else { 0 })
+        .min(ENVELOPE_MAX as u32);
+
+        let phase = ((phase >> 22) as i32 + modulation) as u32;
+        self.tables
+            .wave(slot.waveform & waveform_mask, phase, attenuation << 3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_frequency() {
+        let mut opl = Opl::new(Chip::Opl2, NATIVE_SAMPLE_RATE);
+        // A single carrier: modulator silent, additive connection.
+        opl.write(0x20, 0x20);
+        opl.write(0x40, 0x3F);
+        opl.write(0x80, 0x0F);
+        opl.write(0x23, 0x21);
+        opl.write(0x43, 0x00);
+        opl.write(0x63, 0xF0);
+        opl.write(0x83, 0x00);
+        opl.write(0xC0, 0x01);
+        // 440 Hz: fnum 580, block 4.
+        opl.write(0xA0, (580 & 0xFF) as u8);
+        opl.write(0xB0, 0x20 | (4 << 2) | (580 >> 8) as u8);
+
+        let mut output = vec![0.0; NATIVE_SAMPLE_RATE as usize * 2];
+        opl.render(&mut output);
+        let samples = output.iter().step_by(2).copied().collect::<Vec<f32>>();
+        let peak = samples.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()));
+        assert!(peak > 0.2 && peak <= 0.25, "peak {}", peak);
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((438..=442).contains(&crossings), "crossings {}", crossings);
+
+        // Key off, with release rate 0 for no release: holds forever. Use the fastest instead.
+        opl.write(0x83, 0x0F);
+        opl.write(0xB0, (4 << 2) | (580 >> 8) as u8);
+        opl.render(&mut output[..2000]);
+        opl.render(&mut output);
+        assert!(output.iter().all(|&s| s == 0.0));
+    }
+}
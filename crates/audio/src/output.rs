@@ -0,0 +1,294 @@
+//! Plays a `Mixer` on the default audio device, or headless into nothing or a recording.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use formats::pcm::Pcm;
+
+use crate::mixer::{Emitter, Listener, Mixer, Sound, VoiceId};
+
+#[derive(Debug)]
+pub enum OutputError {
+    /// There is no default output device.
+    NoDevice,
+    /// The device could not be configured or started.
+    Device(String),
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDevice => write!(f, "no audio output device"),
+            Self::Device(message) => write!(f, "audio output device error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+enum Backend {
+    /// Discards rendered audio.
+    Null,
+    /// Keeps rendered interleaved stereo audio.
+    Wav(Vec<f32>),
+    /// The device pulls audio from the mixer on its own thread while the stream is alive.
+    #[cfg(feature = "cpal")]
+    Device { _stream: cpal::Stream },
+}
+
+/// Where mixed audio goes. The device backend plays in real time, the headless backends only
+/// advance when asked to, so the same calls produce the same audio on every platform.
+pub struct AudioOutput {
+    mixer: Arc<Mutex<Mixer>>,
+    backend: Backend,
+}
+
+impl AudioOutput {
+    /// An output that discards everything, when there is no device or none is wanted.
+    pub fn null(sample_rate: u32) -> Self {
+        Self::headless(sample_rate, Backend::Null)
+    }
+
+    /// An output recording everything it plays, see `recorded()`.
+    pub fn wav(sample_rate: u32) -> Self {
+        Self::headless(sample_rate, Backend::Wav(Vec::new()))
+    }
+
+    fn headless(sample_rate: u32, backend: Backend) -> Self {
+        Self {
+            mixer: Arc::new(Mutex::new(Mixer::new(sample_rate))),
+            backend,
+        }
+    }
+
+    /// Plays on the default output device at its preferred sample rate.
+    #[cfg(feature = "cpal")]
+    pub fn device() -> Result<Self, OutputError> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(OutputError::NoDevice)?;
+        let supported = device
+            .default_output_config()
+            .map_err(|error| OutputError::Device(error.to_string()))?;
+        let sample_format = supported.sample_format();
+        let config = supported.config();
+
+        let mixer = Arc::new(Mutex::new(Mixer::new(config.sample_rate.0)));
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer.clone()),
+        }
+        .map_err(|error| OutputError::Device(error.to_string()))?;
+        stream
+            .play()
+            .map_err(|error| OutputError::Device(error.to_string()))?;
+
+        Ok(Self {
+            mixer,
+            backend: Backend::Device { _stream: stream },
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mixer().sample_rate()
+    }
+
+    /// Whether audio is only produced by `advance()`.
+    pub fn is_headless(&self) -> bool {
+        match self.backend {
+            Backend::Null | Backend::Wav(_) => true,
+            #[cfg(feature = "cpal")]
+            Backend::Device { .. } => false,
+        }
+    }
+
+    /// Starts playing `sound`, until it ends or the returned handle is dropped.
+    pub fn play(&self, sound: Arc<Sound>, volume: f32, looping: bool) -> Playback {
+        let sound = self.resample(sound);
+        let id = self.mixer().play(sound, volume, looping);
+        self.playback(id)
+    }
+
+    /// Starts playing `sound` heard from `emitter`, until it ends or the returned handle is
+    /// dropped.
+    pub fn play_at(
+        &self,
+        sound: Arc<Sound>,
+        volume: f32,
+        looping: bool,
+        emitter: Emitter,
+    ) -> Playback {
+        let sound = self.resample(sound);
+        let id = self.mixer().play_at(sound, volume, looping, emitter);
+        self.playback(id)
+    }
+
+    pub fn set_listener(&self, listener: Listener) {
+        self.mixer().set_listener(listener);
+    }
+
+    /// Resamples without holding the mixer lock, which would stall a device.
+    fn resample(&self, sound: Arc<Sound>) -> Arc<Sound> {
+        let sample_rate = self.sample_rate();
+        if sound.sample_rate == sample_rate {
+            sound
+        } else {
+            Arc::new(sound.resample(sample_rate))
+        }
+    }
+
+    fn playback(&self, id: VoiceId) -> Playback {
+        Playback {
+            mixer: self.mixer.clone(),
+            id,
+        }
+    }
+
+    /// Renders the next `frames` frames on a headless output. Does nothing for a device, which
+    /// advances in real time.
+    pub fn advance(&mut self, frames: usize) {
+        let mut mixer = self.mixer.lock().unwrap();
+        match &mut self.backend {
+            Backend::Null => {
+                let mut buffer = vec![0.0; frames * 2];
+                mixer.render(&mut buffer);
+            }
+            Backend::Wav(samples) => {
+                let start = samples.len();
+                samples.resize(start + frames * 2, 0.0);
+                mixer.render(&mut samples[start..]);
+            }
+            #[cfg(feature = "cpal")]
+            Backend::Device { .. } => {}
+        }
+    }
+
+    /// Everything rendered by a WAV output so far, as 16-bit stereo.
+    pub fn recorded(&self) -> Option<Pcm> {
+        match &self.backend {
+            Backend::Wav(samples) => Some(Pcm::from_f32(self.sample_rate(), 2, samples)),
+            _ => None,
+        }
+    }
+
+    fn mixer(&self) -> MutexGuard<'_, Mixer> {
+        self.mixer.lock().unwrap()
+    }
+}
+
+#[cfg(feature = "cpal")]
+fn build_stream<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mixer: Arc<Mutex<Mixer>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    use cpal::traits::DeviceTrait;
+
+    fn sample<T: cpal::Sample>(value: f32) -> T {
+        cpal::Sample::from(&value.clamp(-1.0, 1.0))
+    }
+
+    let channels = config.channels as usize;
+    let mut buffer = Vec::new();
+    device.build_output_stream(
+        config,
+        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let frames = output.len() / channels;
+            buffer.resize(frames * 2, 0.0);
+            mixer.lock().unwrap().render(&mut buffer);
+
+            for (output, stereo) in output
+                .chunks_exact_mut(channels)
+                .zip(buffer.chunks_exact(2))
+            {
+                if channels == 1 {
+                    output[0] = sample((stereo[0] + stereo[1]) / 2.0);
+                } else {
+                    for (index, output) in output.iter_mut().enumerate() {
+                        *output = sample(stereo.get(index).copied().unwrap_or(0.0));
+                    }
+                }
+            }
+        },
+        |error| eprintln!("audio output: {}", error),
+    )
+}
+
+/// Controls a sound started by `AudioOutput::play()`. Dropping it stops the sound.
+pub struct Playback {
+    mixer: Arc<Mutex<Mixer>>,
+    id: VoiceId,
+}
+
+impl Playback {
+    pub fn stop(&self) {
+        self.mixer.lock().unwrap().stop(self.id);
+    }
+
+    /// Whether the sound is still playing, that is it has not ended or been stopped.
+    pub fn is_playing(&self) -> bool {
+        self.mixer.lock().unwrap().is_playing(self.id)
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.mixer.lock().unwrap().set_volume(self.id, volume);
+    }
+
+    pub fn set_looping(&self, looping: bool) {
+        self.mixer.lock().unwrap().set_looping(self.id, looping);
+    }
+
+    pub fn set_emitter(&self, emitter: Option<Emitter>) {
+        self.mixer.lock().unwrap().set_emitter(self.id, emitter);
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_playback() {
+        let sound = Arc::new(Sound {
+            sample_rate: 1_000,
+            channels: 1,
+            samples: vec![0.5; 100],
+            loop_range: None,
+        });
+
+        let mut output = AudioOutput::wav(1_000);
+        assert!(output.is_headless());
+        let playback = output.play(sound.clone(), 1.0, true);
+        output.advance(150);
+        playback.set_volume(0.0);
+        output.advance(50);
+        assert!(playback.is_playing());
+        drop(playback);
+        assert_eq!(output.mixer().voice_count(), 0);
+
+        let once = output.play(sound, 0.5, false);
+        output.advance(200);
+        assert!(!once.is_playing());
+
+        let pcm = output.recorded().unwrap();
+        assert_eq!(pcm.frame_count(), 400);
+        let samples = pcm.to_f32();
+        assert!((samples[0] - 0.5).abs() < 1e-3);
+        assert!((samples[299] - 0.5).abs() < 1e-3);
+        // The volume change fades over the next render.
+        assert!(samples[300] < 0.5 && samples[300] > 0.45);
+        assert_eq!(samples[398], 0.0);
+        assert!((samples[400 + 1] - 0.25).abs() < 1e-3);
+        assert_eq!(samples[600], 0.0);
+        assert!(AudioOutput::null(1_000).recorded().is_none());
+    }
+}
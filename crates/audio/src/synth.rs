@@ -0,0 +1,643 @@
+//! A small sample-based General MIDI synthesizer.
+//!
+//! Instruments are organised like a SoundFont: each has zones covering a range of keys and
+//! velocities, which play a looped or one-shot sample through a volume envelope.
+//! `Bank::general_midi()` builds a bank of simple generated waveforms, so songs can be rendered
+//! without any external data.
+
+use std::convert::Infallible;
+use std::f32::consts::PI;
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+use formats::gmd::MidiSink;
+
+use crate::channel::Channel;
+use crate::Renderer;
+
+/// The MIDI channel (counting from 0) used for percussion.
+pub const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Mono audio played by instrument zones.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub data: Vec<f32>,
+    pub sample_rate: u32,
+    /// The key that plays the sample at its own sample rate.
+    pub root_key: u8,
+    /// The frames repeated for as long as the note sounds, or `None` to play once.
+    pub loop_range: Option<Range<usize>>,
+}
+
+/// A linear volume envelope, with times in seconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    /// The level held after decaying, from 0.0 to 1.0.
+    pub sustain: f32,
+    pub release: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub keys: RangeInclusive<u8>,
+    pub velocities: RangeInclusive<u8>,
+    pub sample: Arc<Sample>,
+    pub envelope: Envelope,
+    /// Fine tuning, in cents.
+    pub tune: i16,
+    /// Cents per key away from the sample's root key: 100 for normal instruments, 0 for a
+    /// fixed pitch.
+    pub scale_tuning: i16,
+    pub volume: f32,
+    /// From -1.0 (left) to 1.0 (right).
+    pub pan: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Instrument {
+    pub name: String,
+    pub zones: Vec<Zone>,
+}
+
+impl Instrument {
+    pub fn zone(&self, key: u8, velocity: u8) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .find(|zone| zone.keys.contains(&key) && zone.velocities.contains(&velocity))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bank {
+    /// Melodic instruments by program number. Missing programs use the first instrument.
+    pub programs: Vec<Instrument>,
+    /// Played on `PERCUSSION_CHANNEL`, with a zone per drum key.
+    pub percussion: Instrument,
+}
+
+impl Bank {
+    /// A bank of generated waveforms, with one instrument per General MIDI program chosen by
+    /// its family (piano, organ, strings, ...), and a synthesized drum kit.
+    pub fn general_midi() -> Self {
+        let waves = [
+            Arc::new(wave(Wave::Sine)),
+            Arc::new(wave(Wave::Triangle)),
+            Arc::new(wave(Wave::Square)),
+            Arc::new(wave(Wave::Saw)),
+            Arc::new(wave(Wave::Piano)),
+            Arc::new(wave(Wave::Organ)),
+            Arc::new(wave(Wave::Noise)),
+        ];
+
+        let programs = (0..128)
+            .map(|program| {
+                let (name, wave, envelope) = FAMILIES[program / 8];
+                Instrument {
+                    name: format!("{} {}", name, program % 8 + 1),
+                    zones: vec![Zone {
+                        keys: 0..=127,
+                        velocities: 0..=127,
+                        sample: waves[wave as usize].clone(),
+                        envelope,
+                        tune: 0,
+                        scale_tuning: 100,
+                        volume: 1.0,
+                        pan: 0.0,
+                    }],
+                }
+            })
+            .collect();
+
+        Self {
+            programs,
+            percussion: drum_kit(),
+        }
+    }
+
+    pub fn instrument(&self, channel: u8, program: u8) -> Option<&Instrument> {
+        if channel == PERCUSSION_CHANNEL {
+            Some(&self.percussion)
+        } else {
+            self.programs
+                .get(program as usize)
+                .or_else(|| self.programs.first())
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Wave {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+    Piano,
+    Organ,
+    Noise,
+}
+
+const fn envelope(attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
+    Envelope {
+        attack,
+        decay,
+        sustain,
+        release,
+    }
+}
+
+const FAMILIES: [(&str, Wave, Envelope); 16] = [
+    ("Piano", Wave::Piano, envelope(0.005, 2.0, 0.0, 0.3)),
+    (
+        "Chromatic Percussion",
+        Wave::Sine,
+        envelope(0.002, 0.8, 0.0, 0.2),
+    ),
+    ("Organ", Wave::Organ, envelope(0.01, 0.0, 1.0, 0.05)),
+    ("Guitar", Wave::Triangle, envelope(0.005, 1.2, 0.0, 0.2)),
+    ("Bass", Wave::Triangle, envelope(0.005, 0.5, 0.6, 0.1)),
+    ("Strings", Wave::Saw, envelope(0.08, 0.2, 0.9, 0.3)),
+    ("Ensemble", Wave::Saw, envelope(0.15, 0.2, 0.9, 0.4)),
+    ("Brass", Wave::Saw, envelope(0.04, 0.2, 0.8, 0.15)),
+    ("Reed", Wave::Square, envelope(0.03, 0.1, 0.8, 0.1)),
+    ("Pipe", Wave::Sine, envelope(0.05, 0.1, 0.9, 0.15)),
+    ("Synth Lead", Wave::Square, envelope(0.01, 0.1, 0.9, 0.1)),
+    ("Synth Pad", Wave::Saw, envelope(0.3, 0.5, 0.8, 0.8)),
+    (
+        "Synth Effects",
+        Wave::Triangle,
+        envelope(0.1, 0.5, 0.6, 0.5),
+    ),
+    ("Ethnic", Wave::Triangle, envelope(0.01, 0.8, 0.2, 0.2)),
+    ("Percussive", Wave::Sine, envelope(0.002, 0.3, 0.0, 0.1)),
+    ("Sound Effects", Wave::Noise, envelope(0.01, 0.3, 0.5, 0.3)),
+];
+
+/// Frames in a single cycle of a generated wave.
+const WAVE_LEN: usize = 256;
+
+/// A single cycle of `wave`, looped, tuned so it plays at 440 Hz for key 69 (A4).
+fn wave(wave: Wave) -> Sample {
+    // Summing harmonics limits the aliasing of the sharp waves.
+    let harmonics = |amplitude: &dyn Fn(usize) -> f32| -> Vec<f32> {
+        (0..WAVE_LEN)
+            .map(|frame| {
+                let phase = 2.0 * PI * frame as f32 / WAVE_LEN as f32;
+                (1..=24)
+                    .map(|n| amplitude(n) * (phase * n as f32).sin())
+                    .sum()
+            })
+            .collect()
+    };
+    let data = match wave {
+        Wave::Sine => harmonics(&|n| if n == 1 { 1.0 } else { 0.0 }),
+        Wave::Triangle => harmonics(&|n| match n % 4 {
+            1 => 0.8 / (n * n) as f32,
+            3 => -0.8 / (n * n) as f32,
+            _ => 0.0,
+        }),
+        Wave::Square => harmonics(&|n| if n % 2 == 1 { 0.6 / n as f32 } else { 0.0 }),
+        Wave::Saw => harmonics(&|n| 0.5 / n as f32),
+        Wave::Piano => harmonics(&|n| 0.8 / (n * n) as f32),
+        Wave::Organ => harmonics(&|n| match n {
+            1 => 0.5,
+            2 => 0.3,
+            4 => 0.2,
+            8 => 0.1,
+            _ => 0.0,
+        }),
+        Wave::Noise => {
+            let mut noise = Noise::default();
+            (0..WAVE_LEN * 64).map(|_| noise.next() * 0.5).collect()
+        }
+    };
+    let len = data.len();
+    Sample {
+        data,
+        sample_rate: WAVE_LEN as u32 * 440,
+        root_key: 69,
+        loop_range: Some(0..len),
+    }
+}
+
+/// A deterministic white noise generator.
+struct Noise(u32);
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self(0x1234_5678)
+    }
+}
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        // xorshift32
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+const DRUM_SAMPLE_RATE: u32 = 22_050;
+
+/// A one-shot drum sound, mixing a sine sweeping from `start_hz` to `end_hz` with noise,
+/// decaying over `length` seconds.
+fn drum(start_hz: f32, end_hz: f32, tone: f32, noise: f32, length: f32) -> Arc<Sample> {
+    let frames = (length * DRUM_SAMPLE_RATE as f32) as usize;
+    let mut generator = Noise::default();
+    let mut phase = 0.0f32;
+    let data = (0..frames)
+        .map(|frame| {
+            let t = frame as f32 / frames as f32;
+            let hz = start_hz + (end_hz - start_hz) * t;
+            phase += 2.0 * PI * hz / DRUM_SAMPLE_RATE as f32;
+            let decay = (1.0 - t) * (1.0 - t);
+            (tone * phase.sin() + noise * generator.next()) * decay
+        })
+        .collect();
+    Arc::new(Sample {
+        data,
+        sample_rate: DRUM_SAMPLE_RATE,
+        root_key: 60,
+        loop_range: None,
+    })
+}
+
+fn drum_kit() -> Instrument {
+    let kick = drum(150.0, 45.0, 1.0, 0.05, 0.35);
+    let snare = drum(220.0, 160.0, 0.4, 0.6, 0.25);
+    let tom = drum(200.0, 120.0, 0.9, 0.1, 0.4);
+    let closed_hat = drum(0.0, 0.0, 0.0, 0.4, 0.06);
+    let open_hat = drum(0.0, 0.0, 0.0, 0.4, 0.4);
+    let cymbal = drum(0.0, 0.0, 0.0, 0.35, 1.2);
+    let click = drum(1200.0, 800.0, 0.6, 0.2, 0.05);
+
+    let zones = (35u8..=81)
+        .map(|key| {
+            let (sample, scale_tuning, root_key, pan) = match key {
+                35 | 36 => (&kick, 0, key, 0.0),
+                38 | 40 => (&snare, 0, key, 0.0),
+                41 | 43 | 45 | 47 | 48 | 50 => (&tom, 50, 45, (key as f32 - 45.0) / 10.0),
+                42 | 44 => (&closed_hat, 0, key, 0.3),
+                46 => (&open_hat, 0, key, 0.3),
+                49 | 51 | 52 | 53 | 55 | 57 | 59 => (&cymbal, 0, key, -0.3),
+                _ => (&click, 100, 60, 0.0),
+            };
+            Zone {
+                keys: key..=key,
+                velocities: 0..=127,
+                sample: Arc::new(Sample {
+                    root_key,
+                    ..Sample::clone(sample)
+                }),
+                envelope: envelope(0.0, 0.0, 1.0, 0.5),
+                tune: 0,
+                scale_tuning,
+                volume: 1.0,
+                pan,
+            }
+        })
+        .collect();
+
+    Instrument {
+        name: "Standard Kit".to_string(),
+        zones,
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release { rate: f32 },
+    Finished,
+}
+
+#[derive(Debug)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    sample: Arc<Sample>,
+    /// Pitch in cents relative to the sample, before pitch bend.
+    cents: f32,
+    position: f64,
+    gain: f32,
+    pan: f32,
+    envelope: Envelope,
+    stage: Stage,
+    level: f32,
+    /// Released while the sustain pedal was down.
+    sustained: bool,
+}
+
+impl Voice {
+    fn release(&mut self, sample_rate: u32) {
+        if !matches!(self.stage, Stage::Release { .. } | Stage::Finished) {
+            let frames = (self.envelope.release * sample_rate as f32).max(1.0);
+            self.stage = Stage::Release {
+                rate: self.level / frames,
+            };
+        }
+    }
+
+    fn next_level(&mut self, sample_rate: u32) -> f32 {
+        let frames = |seconds: f32| (seconds * sample_rate as f32).max(1.0);
+        match self.stage {
+            Stage::Attack => {
+                self.level += 1.0 / frames(self.envelope.attack);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - self.envelope.sustain) / frames(self.envelope.decay);
+                if self.level <= self.envelope.sustain {
+                    self.level = self.envelope.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {
+                if self.level <= 0.0 {
+                    self.stage = Stage::Finished;
+                }
+            }
+            Stage::Release { rate } => {
+                self.level -= rate;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Finished;
+                }
+            }
+            Stage::Finished => {}
+        }
+        self.level
+    }
+
+    /// Mixes the voice into `output`, stopping early if it finishes.
+    fn render(&mut self, output: &mut [f32], sample_rate: u32, bend_cents: f32) {
+        let step = self.sample.sample_rate as f64 / sample_rate as f64
+            * 2f64.powf((self.cents + bend_cents) as f64 / 1200.0);
+        let angle = (self.pan.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+        let (left, right) = (angle.cos() * self.gain, angle.sin() * self.gain);
+        let sample = self.sample.clone();
+        let data = &sample.data;
+
+        for frame in output.chunks_exact_mut(2) {
+            let index = self.position as usize;
+            let current = match data.get(index) {
+                Some(&value) => value,
+                None => {
+                    self.stage = Stage::Finished;
+                    return;
+                }
+            };
+            let next = match &sample.loop_range {
+                Some(range) if index + 1 == range.end => data[range.start],
+                _ => data.get(index + 1).copied().unwrap_or(0.0),
+            };
+            let fraction = (self.position - index as f64) as f32;
+            let value = (current + (next - current) * fraction) * self.next_level(sample_rate);
+            if self.stage == Stage::Finished {
+                return;
+            }
+            frame[0] += value * left;
+            frame[1] += value * right;
+
+            self.position += step;
+            if let Some(range) = &sample.loop_range {
+                let (start, end) = (range.start as f64, range.end as f64);
+                if self.position >= end && end > start {
+                    self.position = start + (self.position - end) % (end - start);
+                }
+            }
+        }
+    }
+}
+
+/// Renders MIDI messages to stereo audio using the instruments of a `Bank`.
+pub struct Synth {
+    sample_rate: u32,
+    bank: Arc<Bank>,
+    channels: [Channel; 16],
+    voices: Vec<Voice>,
+    /// The most notes that can sound at once, beyond which the oldest are cut off.
+    pub max_voices: usize,
+    /// Overall volume, to leave room for many notes at once.
+    pub gain: f32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32, bank: Arc<Bank>) -> Self {
+        Self {
+            sample_rate,
+            bank,
+            channels: [Channel::default(); 16],
+            voices: Vec::new(),
+            max_voices: 64,
+            gain: 0.3,
+        }
+    }
+
+    /// The number of notes currently sounding.
+    pub fn active_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        self.note_off(channel, key);
+
+        let state = self.channels[channel as usize];
+        let zone = match self
+            .bank
+            .instrument(channel, state.program)
+            .and_then(|instrument| instrument.zone(key, velocity))
+        {
+            Some(zone) => zone,
+            None => return,
+        };
+
+        if self.voices.len() >= self.max_voices.max(1) {
+            self.voices.remove(0);
+        }
+
+        let velocity = velocity as f32 / 127.0;
+        let keys = key as f32 - zone.sample.root_key as f32;
+        self.voices.push(Voice {
+            channel,
+            key,
+            sample: zone.sample.clone(),
+            cents: keys * zone.scale_tuning as f32 + zone.tune as f32,
+            position: 0.0,
+            gain: velocity * velocity * zone.volume,
+            pan: zone.pan,
+            envelope: zone.envelope,
+            stage: Stage::Attack,
+            level: 0.0,
+            sustained: false,
+        });
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        // Drums play out their sample.
+        if channel == PERCUSSION_CHANNEL {
+            return;
+        }
+        let sustain = self.channels[channel as usize].sustain;
+        let sample_rate = self.sample_rate;
+        for voice in &mut self.voices {
+            if voice.channel == channel && voice.key == key && !voice.sustained {
+                if sustain {
+                    voice.sustained = true;
+                } else {
+                    voice.release(sample_rate);
+                }
+            }
+        }
+    }
+
+    fn controller(&mut self, channel: u8, controller: u8, value: u8) {
+        let sample_rate = self.sample_rate;
+        let state = &mut self.channels[channel as usize];
+        state.controller(controller, value);
+        match controller {
+            64 if !state.sustain => {
+                for voice in &mut self.voices {
+                    if voice.channel == channel && voice.sustained {
+                        voice.sustained = false;
+                        voice.release(sample_rate);
+                    }
+                }
+            }
+            120 => self.voices.retain(|voice| voice.channel != channel),
+            123 => {
+                for voice in &mut self.voices {
+                    if voice.channel == channel {
+                        voice.release(sample_rate);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MidiSink for Synth {
+    type Error = Infallible;
+
+    fn send(&mut self, channel: u8, message: midly::MidiMessage) -> Result<(), Infallible> {
+        let channel = channel & 0x0F;
+        match message {
+            midly::MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                self.note_on(channel, key.as_int(), vel.as_int())
+            }
+            midly::MidiMessage::NoteOn { key, .. } | midly::MidiMessage::NoteOff { key, .. } => {
+                self.note_off(channel, key.as_int())
+            }
+            midly::MidiMessage::Controller { controller, value } => {
+                self.controller(channel, controller.as_int(), value.as_int())
+            }
+            midly::MidiMessage::ProgramChange { program } => {
+                self.channels[channel as usize].program = program.as_int()
+            }
+            midly::MidiMessage::PitchBend { bend } => {
+                self.channels[channel as usize].bend = bend.as_int()
+            }
+            midly::MidiMessage::Aftertouch { .. }
+            | midly::MidiMessage::ChannelAftertouch { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl Renderer for Synth {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn render(&mut self, output: &mut [f32]) {
+        for sample in output.iter_mut() {
+            *sample = 0.0;
+        }
+        for voice in &mut self.voices {
+            let channel = &self.channels[voice.channel as usize];
+            let gain = voice.gain;
+            let pan = voice.pan;
+            voice.gain = gain * channel.gain() * self.gain;
+            voice.pan = pan + (channel.pan as f32 - 64.0) / 63.0;
+            voice.render(output, self.sample_rate, channel.bend_cents());
+            voice.gain = gain;
+            voice.pan = pan;
+        }
+        self.voices.retain(|voice| voice.stage != Stage::Finished);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::u7;
+
+    fn note(synth: &mut Synth, channel: u8, key: u8, vel: u8) {
+        synth
+            .send(
+                channel,
+                midly::MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            )
+            .unwrap();
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0f32, |peak, &s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn note_on_and_off() {
+        let mut synth = Synth::new(22_050, Arc::new(Bank::general_midi()));
+        synth
+            .send(
+                0,
+                midly::MidiMessage::ProgramChange {
+                    program: u7::new(80),
+                },
+            )
+            .unwrap();
+        let mut output = vec![0.0; 2 * 2205];
+
+        synth.render(&mut output);
+        assert_eq!(peak(&output), 0.0);
+
+        note(&mut synth, 0, 69, 127);
+        synth.render(&mut output);
+        assert!(peak(&output) > 0.05);
+        assert_eq!(synth.active_voices(), 1);
+
+        // Note on with velocity 0 is note off.
+        note(&mut synth, 0, 69, 0);
+        for _ in 0..3 {
+            synth.render(&mut output);
+        }
+        assert_eq!(peak(&output), 0.0);
+        assert_eq!(synth.active_voices(), 0);
+    }
+
+    #[test]
+    fn percussion_plays_out() {
+        let mut synth = Synth::new(22_050, Arc::new(Bank::general_midi()));
+        note(&mut synth, PERCUSSION_CHANNEL, 36, 100);
+        note(&mut synth, PERCUSSION_CHANNEL, 36, 0);
+        let mut output = vec![0.0; 2 * 2205];
+        synth.render(&mut output);
+        assert!(peak(&output) > 0.05);
+        for _ in 0..4 {
+            synth.render(&mut output);
+        }
+        assert_eq!(synth.active_voices(), 0);
+    }
+}
@@ -1,30 +1,10 @@
 fn main() {
-    windows::build! {
-        Windows::Foundation::*,
+    // Build scripts run on the host, so check the target rather than `cfg(windows)`.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+        windows::build! {
+            Windows::Foundation::*,
 
-        Windows::Devices::Midi::*,
-        Windows::Media::{
-            AudioBuffer,
-            AudioBufferAccessMode,
-            AudioFrame,
-        },
-        Windows::Media::Audio::{
-            AudioDeviceOutputNode,
-            AudioFrameCompletedEventArgs,
-            AudioFrameInputNode,
-            AudioGraph,
-            AudioGraphSettings,
-            CreateAudioDeviceOutputNodeResult,
-            CreateAudioGraphResult,
-        },
-        Windows::Media::MediaProperties::{
-            AudioEncodingProperties,
-        },
-        Windows::Media::Render::AudioRenderCategory,
-        Windows::Storage::Streams::Buffer,
-
-        Windows::Win32::System::WinRT::{
-            IMemoryBufferByteAccess,
-        },
+            Windows::Devices::Midi::*,
+        }
     }
 }
@@ -1,2 +1,4 @@
+#[cfg(windows)]
 pub use windows::*;
+#[cfg(windows)]
 windows::include_bindings!();
@@ -0,0 +1,48 @@
+/// Whether `name` matches `pattern`, where `*` matches any run of characters and `?` any one
+/// character. Case is ignored, as GOB and LFD names are upper case by convention only.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_uppercase().chars().collect::<Vec<char>>();
+    let name = name.to_ascii_uppercase().chars().collect::<Vec<char>>();
+
+    // Backtracks to just after the last `*`, letting it match one more character.
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(matches("*.BM", "WALL01.BM"));
+        assert!(matches("*.bm", "WALL01.BM"));
+        assert!(!matches("*.BM", "WALL01.BMP"));
+        assert!(matches("WALL??.*", "WALL01.BM"));
+        assert!(!matches("WALL?.*", "WALL01.BM"));
+        assert!(matches("*A*B*", "XAXXBX"));
+        assert!(matches("*", ""));
+        assert!(!matches("", "A"));
+    }
+}
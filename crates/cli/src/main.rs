@@ -0,0 +1,601 @@
+//! `df`: lists, extracts, describes, converts and packs Dark Forces data files.
+
+mod glob;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+use formats::archive::{self, Archive};
+use formats::asset::Asset;
+use formats::common::*;
+use formats::detect::{self, Detection};
+use formats::diff::{self, Details};
+use formats::edition::{self, Baseline, Change, Fingerprint, Identity};
+use formats::image::{self, PngFormat};
+use formats::install::Install;
+use formats::mint;
+use formats::resample;
+use formats::{gob, pal, schedule};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "\
+usage: df <command> [arguments]
+
+commands:
+  ls <archive>                    list the entries of a GOB or LFD
+  extract <archive> [<pattern>...] [-o <dir>]
+                                  extract the entries matching any glob pattern, or all
+  info <input>                    print decoded metadata
+  convert <input> [-o <output>] [--pal <input>]
+                                  convert BM, FME, WAX and PAL to PNG, VOC to 44.1kHz 16-bit
+                                  WAV, GMD to MID and LEV to JSON
+  pack <dir> <archive>            build a GOB from the files in a directory
+  diff <old> <new> [-o <dir>]     compare two archives, or an archive and a directory, with
+                                  changed levels, objects and INF items by field and images
+                                  by pixel, writing masks of changed pixels to <dir>
+  hash [<game dir>] [-o <output>] [--edition <id>]
+                                  hash the GOB and LFD files and entries, as a baseline of
+                                  the given edition if any
+  identify [<game dir>] [--baseline <file>...]
+                                  identify the release the data files are from, and list the
+                                  entries that differ from it
+
+An <input> is a file, or an archive entry as <archive>:<entry>, e.g. DARK.GOB:SECBASE.LEV.
+Images are converted with a grayscale palette unless --pal is given.
+Without a <game dir>, the game is searched for, see formats::install.
+Editions are cd-1.0, patched, digital and demo.
+";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = match args.next() {
+        Some(command) => command,
+        None => {
+            eprint!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(error) = Args::parse(args).and_then(|args| run(&command, args)) {
+        eprintln!("df: {}", error);
+        process::exit(1);
+    }
+}
+
+/// Positional arguments and options of a command.
+struct Args {
+    positional: Vec<String>,
+    output: Option<PathBuf>,
+    pal: Option<String>,
+    edition: Option<String>,
+    baselines: Vec<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut result = Self {
+            positional: Vec::new(),
+            output: None,
+            pal: None,
+            edition: None,
+            baselines: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => {
+                    result.output = Some(args.next().ok_or("-o needs a path")?.into());
+                }
+                "--pal" => {
+                    result.pal = Some(args.next().ok_or("--pal needs an input")?);
+                }
+                "--edition" => {
+                    result.edition = Some(args.next().ok_or("--edition needs an ID")?);
+                }
+                "--baseline" => {
+                    result
+                        .baselines
+                        .push(args.next().ok_or("--baseline needs a file")?);
+                }
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    return Err(format!("unknown option {}", arg).into());
+                }
+                _ => result.positional.push(arg),
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn run(command: &str, args: Args) -> Result<()> {
+    match (command, args.positional.as_slice()) {
+        ("ls", [archive]) => ls(Path::new(archive)),
+        ("extract", [archive, patterns @ ..]) => {
+            let dir = args.output.as_deref().unwrap_or_else(|| Path::new("."));
+            extract(Path::new(archive), patterns, dir)
+        }
+        ("info", [input]) => info(input),
+        ("convert", [input]) => convert(input, args.output.as_deref(), args.pal.as_deref()),
+        ("pack", [dir, archive]) => pack(Path::new(dir), Path::new(archive)),
+        ("diff", [old, new]) => diff(Path::new(old), Path::new(new), args.output.as_deref()),
+        ("hash", dir) if dir.len() <= 1 => {
+            let edition = args.edition.as_deref();
+            hash(dir.first(), args.output.as_deref(), edition)
+        }
+        ("identify", dir) if dir.len() <= 1 => identify(dir.first(), &args.baselines),
+        ("help", _) | ("-h", _) | ("--help", _) => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        ("ls", _)
+        | ("extract", _)
+        | ("info", _)
+        | ("convert", _)
+        | ("pack", _)
+        | ("hash", _)
+        | ("identify", _) => Err(format!("wrong arguments for {}, see df help", command).into()),
+        _ => Err(format!("unknown command {:?}, see df help", command).into()),
+    }
+}
+
+fn open_archive(path: &Path) -> Result<Archive> {
+    Ok(Archive::open(path).map_err(|error| format!("{}: {}", path.display(), error))?)
+}
+
+fn read_entry(archive: &Archive, entry: &CatalogEntry) -> Result<Vec<u8>> {
+    Ok(archive
+        .read(entry)
+        .map_err(|error| format!("{}: {}", entry.name, error))?)
+}
+
+/// Reads an input given as a file, or as `ARCHIVE:ENTRY`, returning its name and contents.
+fn read_input(input: &str) -> Result<(String, Vec<u8>)> {
+    let path = Path::new(input);
+    if !path.is_file() {
+        if let Some((archive, name)) = input.rsplit_once(':') {
+            if Path::new(archive).is_file() {
+                let archive_file = open_archive(Path::new(archive))?;
+                let entry = archive_file
+                    .find(name)
+                    .ok_or_else(|| format!("{} has no entry {}", archive, name))?;
+                return Ok((entry.name.clone(), read_entry(&archive_file, entry)?));
+            }
+        }
+    }
+    let data = fs::read(path).map_err(|error| format!("{}: {}", input, error))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    Ok((name, data))
+}
+
+fn read_asset(name: &str, data: &[u8]) -> Result<(Detection, Asset)> {
+    let read =
+        Asset::detect_and_read(name, data).map_err(|error| format!("{}: {}", name, error))?;
+    Ok(read.ok_or_else(|| format!("{}: unrecognized format", name))?)
+}
+
+fn ls(archive: &Path) -> Result<()> {
+    let archive = open_archive(archive)?;
+    for entry in &archive.catalog.entries {
+        let prefix = archive
+            .read_prefix(entry, detect::PREFIX_LEN)
+            .map_err(|error| format!("{}: {}", entry.name, error))?;
+        let format = match detect::detect_prefix(&entry.name, &prefix, entry.length as usize) {
+            Some(detection) => detection.format.to_string(),
+            None => "?".to_string(),
+        };
+        println!("{:<13} {:>9}  {}", entry.name, entry.length, format);
+    }
+    Ok(())
+}
+
+fn extract(path: &Path, patterns: &[String], dir: &Path) -> Result<()> {
+    let archive = open_archive(path)?;
+    fs::create_dir_all(dir)?;
+    let mut count = 0;
+    for entry in &archive.catalog.entries {
+        if !patterns.is_empty()
+            && !patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern, &entry.name))
+        {
+            continue;
+        }
+        if !archive::is_file_name(&entry.name) {
+            return Err(format!("{}: not a valid file name", entry.name).into());
+        }
+        let output = dir.join(&entry.name);
+        fs::write(&output, read_entry(&archive, entry)?)?;
+        println!("{}", output.display());
+        count += 1;
+    }
+    if count == 0 && !patterns.is_empty() {
+        return Err(format!("no entries of {} match", path.display()).into());
+    }
+    Ok(())
+}
+
+fn info(input: &str) -> Result<()> {
+    let (name, data) = read_input(input)?;
+    let (detection, asset) = read_asset(&name, &data)?;
+    println!(
+        "{}: {} ({:?}), {} bytes",
+        name,
+        detection.format,
+        detection.confidence,
+        data.len()
+    );
+    match asset {
+        Asset::Catalog(catalog) => {
+            println!("entries: {}", catalog.entries.len());
+        }
+        Asset::Bm(bm) => {
+            println!("size: {}x{}", bm.size.x, bm.size.y);
+            println!("idem size: {}x{}", bm.idem_size.x, bm.idem_size.y);
+            println!("compression: {:?}", bm.compression);
+            println!("flags: {:#x}", bm.flags);
+        }
+        Asset::Fme(fme) => {
+            println!("size: {}x{}", fme.cell.size.x, fme.cell.size.y);
+            println!("offset: {}, {}", fme.frame.offset.x, fme.frame.offset.y);
+            println!("flip: {}", fme.frame.flip);
+        }
+        Asset::Wax(wax) => {
+            println!("version: {:#x}", wax.version);
+            println!(
+                "states: {}, sequences: {}, frames: {}, cells: {}",
+                wax.states.len(),
+                wax.sequences.len(),
+                wax.frames.len(),
+                wax.cells.len()
+            );
+            for (index, state) in wax.states.iter().enumerate() {
+                println!(
+                    "state {}: world size {}x{}, {} frames per second",
+                    index, state.world_size.x, state.world_size.y, state.frame_rate
+                );
+            }
+        }
+        Asset::Pal(_) => {
+            println!("colours: 256");
+        }
+        Asset::Voc(voc) => {
+            println!("version: {}.{:02}", voc.version >> 8, voc.version & 0xFF);
+            println!("chunks: {}", voc.chunks.len());
+            let pcm = voc.decode(0)?;
+            println!(
+                "sound: {} Hz, {} channels, {} bits, {:.2} s",
+                pcm.sample_rate,
+                pcm.channels,
+                pcm.bits_per_sample,
+                pcm.duration().as_secs_f64()
+            );
+            println!(
+                "converts to: {} Hz, {} bits",
+                resample::EXPORT_RATE,
+                resample::EXPORT_FORMAT.bits_per_sample()
+            );
+        }
+        Asset::Gmd(gmd) => {
+            let chunks = gmd
+                .chunks
+                .iter()
+                .map(|chunk| String::from_utf8_lossy(&chunk.id).into_owned())
+                .collect::<Vec<String>>();
+            println!("chunks: {}", chunks.join(" "));
+            println!("tracks: {}", gmd.parse_smf()?.tracks.len());
+            println!("iMUSE events: {}", gmd.imuse_events()?.len());
+            let duration = schedule::Schedule::from_gmd(&gmd)?.duration();
+            println!("duration: {:.2} s", duration.as_secs_f64());
+        }
+        Asset::Lev(lev) => {
+            println!("palette: {}", lev.palette_name);
+            println!("textures: {}", lev.texture_names.len());
+            println!("sectors: {}", lev.sectors.len());
+            let walls = lev
+                .sectors
+                .iter()
+                .map(|sector| sector.walls.len())
+                .sum::<usize>();
+            println!("walls: {}", walls);
+            let layers = lev.sectors.iter().map(|sector| sector.layer);
+            if let (Some(min), Some(max)) = (layers.clone().min(), layers.max()) {
+                println!("layers: {} to {}", min, max);
+            }
+        }
+        Asset::Text(text) => {
+            println!("lines: {}", text.lines().count());
+        }
+    }
+    Ok(())
+}
+
+fn convert(input: &str, output: Option<&Path>, pal: Option<&str>) -> Result<()> {
+    let (name, data) = read_input(input)?;
+    let (detection, asset) = read_asset(&name, &data)?;
+    let stem = archive::stem(&name);
+    let output_path = |extension: &str| match output {
+        Some(output) => output.to_path_buf(),
+        None => PathBuf::from(format!("{}.{}", stem, extension)),
+    };
+
+    match asset {
+        Asset::Bm(bm) => {
+            let size = mint::Vector2 {
+                x: bm.size.x as u32,
+                y: bm.size.y as u32,
+            };
+            let pal = load_pal(pal)?;
+            save(&output_path("png"), |output| {
+                image::write_png(output, size, &bm.data, &pal, PngFormat::Indexed)
+            })
+        }
+        Asset::Fme(fme) => {
+            let pal = load_pal(pal)?;
+            save(&output_path("png"), |output| {
+                let cell = &fme.cell;
+                image::write_png(output, cell.size, &cell.data, &pal, PngFormat::Indexed)
+            })
+        }
+        Asset::Wax(wax) => {
+            // One PNG per cell, numbered like `TROOP-0.png`.
+            let pal = load_pal(pal)?;
+            let path = output_path("png");
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            for (index, cell) in wax.cells.iter().enumerate() {
+                let path = path.with_file_name(format!("{}-{}.png", stem, index));
+                save(&path, |output| {
+                    image::write_png(output, cell.size, &cell.data, &pal, PngFormat::Indexed)
+                })?;
+            }
+            Ok(())
+        }
+        Asset::Pal(pal) => save(&output_path("png"), |output| {
+            image::write_pal_png(output, &pal)
+        }),
+        Asset::Voc(voc) => {
+            let pcm = resample::convert_for_export(&voc.decode(0)?);
+            save(&output_path("wav"), |output| pcm.write_wav(output))
+        }
+        Asset::Gmd(gmd) => save(&output_path("mid"), |output| gmd.write_mid(output)),
+        Asset::Lev(lev) => save(&output_path("json"), |output| {
+            serde_json::to_writer_pretty(output, &lev)?;
+            Ok(())
+        }),
+        Asset::Catalog(_) | Asset::Text(_) => {
+            Err(format!("{}: cannot convert {} files", name, detection.format).into())
+        }
+    }
+}
+
+/// Loads a PAL, or a palette PNG, for converting images, defaulting to grayscale.
+fn load_pal(input: Option<&str>) -> Result<pal::Pal> {
+    let input = match input {
+        Some(input) => input,
+        None => return Ok(pal::Pal::grayscale()),
+    };
+    let (name, data) = read_input(input)?;
+    let pal = if name.to_ascii_uppercase().ends_with(".PNG") {
+        image::read_pal_png(data.as_slice()).map_err(|error| format!("{}: {}", name, error))?
+    } else {
+        pal::Pal::read(data.as_slice()).map_err(|error| format!("{}: {}", name, error))?
+    };
+    Ok(pal)
+}
+
+/// Writes a file with `write`, printing its path.
+fn save(path: &Path, write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> Result<()> {
+    let mut data = Vec::new();
+    write(&mut data)?;
+    fs::write(path, data).map_err(|error| format!("{}: {}", path.display(), error))?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn change_name(change: Change) -> &'static str {
+    match change {
+        Change::Added => "added",
+        Change::Removed => "removed",
+        Change::Changed => "changed",
+    }
+}
+
+fn diff(old: &Path, new: &Path, masks: Option<&Path>) -> Result<()> {
+    let read = |path: &Path| {
+        diff::read_entries(path).map_err(|error| format!("{}: {}", path.display(), error))
+    };
+    let differences = diff::compare(&read(old)?, &read(new)?);
+    if let Some(masks) = masks {
+        fs::create_dir_all(masks)?;
+    }
+    // Changed pixels are white.
+    let pal = load_pal(None)?;
+
+    for difference in &differences {
+        println!("{:<8} {}", change_name(difference.change), difference.name);
+        match &difference.details {
+            Details::None => {}
+            Details::Structure(items) => {
+                for item in items {
+                    if item.change != Change::Changed {
+                        println!("  {} {}", item.item, change_name(item.change));
+                        continue;
+                    }
+                    println!("  {}", item.item);
+                    for field in &item.fields {
+                        match (field.old.is_empty(), field.new.is_empty()) {
+                            (true, _) => println!("    + {}: {}", field.field, field.new),
+                            (_, true) => println!("    - {}: {}", field.field, field.old),
+                            _ => println!("    {}: {} -> {}", field.field, field.old, field.new),
+                        }
+                    }
+                }
+            }
+            Details::Pixels(pixels) => {
+                // WAX cells are numbered like `convert` numbers them.
+                let is_wax = difference.name.to_ascii_uppercase().ends_with(".WAX");
+                for pixel_diff in pixels {
+                    let cell = if is_wax {
+                        format!("cell {}: ", pixel_diff.cell)
+                    } else {
+                        String::new()
+                    };
+                    let size = |size: Option<mint::Vector2<u32>>| match size {
+                        Some(size) => format!("{}x{}", size.x, size.y),
+                        None => "none".to_string(),
+                    };
+                    match pixel_diff.bounds {
+                        _ if pixel_diff.old_size != pixel_diff.new_size => println!(
+                            "  {}size {} -> {}",
+                            cell,
+                            size(pixel_diff.old_size),
+                            size(pixel_diff.new_size)
+                        ),
+                        Some((min, max)) => println!(
+                            "  {}{} pixels changed in {},{} to {},{}",
+                            cell, pixel_diff.changed, min.x, min.y, max.x, max.y
+                        ),
+                        None => {}
+                    }
+
+                    if let (Some(masks), Some(mask), Some(size)) =
+                        (masks, &pixel_diff.mask, pixel_diff.new_size)
+                    {
+                        if !archive::is_file_name(&difference.name) {
+                            return Err(
+                                format!("{}: not a valid file name", difference.name).into()
+                            );
+                        }
+                        let name = if is_wax {
+                            format!("{}-{}.diff.png", difference.name, pixel_diff.cell)
+                        } else {
+                            format!("{}.diff.png", difference.name)
+                        };
+                        let data = mask
+                            .iter()
+                            .map(|&changed| if changed { 255 } else { 0 })
+                            .collect::<Vec<u8>>();
+                        save(&masks.join(name), |output| {
+                            image::write_png(output, size, &data, &pal, PngFormat::Indexed)
+                        })?;
+                    }
+                }
+            }
+        }
+    }
+    if differences.is_empty() {
+        println!("no differences");
+    }
+    Ok(())
+}
+
+fn pack(dir: &Path, archive: &Path) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|error| format!("{}: {}", dir.display(), error))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| format!("{:?} is not a valid entry name", name))?;
+        entries.push((name, fs::read(entry.path())?));
+    }
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut output = io::BufWriter::new(File::create(archive)?);
+    gob::write(&mut output, &entries)?;
+    output.flush()?;
+    println!(
+        "packed {} entries into {}",
+        entries.len(),
+        archive.display()
+    );
+    Ok(())
+}
+
+/// Finds the game directory, given or not, and reports which install it is.
+fn game_dir(dir: Option<&String>) -> Result<PathBuf> {
+    let install = Install::find(dir.map(Path::new))?;
+    eprintln!("{}", install);
+    Ok(install.dir)
+}
+
+/// Hashes the game directory, reporting archives that could not be read.
+fn fingerprint(dir: &Path) -> Result<Fingerprint> {
+    let fingerprint =
+        Fingerprint::of_dir(dir).map_err(|error| format!("{}: {}", dir.display(), error))?;
+    for unreadable in &fingerprint.unreadable {
+        eprintln!("df: {}: {}", unreadable.name, unreadable.error);
+    }
+    Ok(fingerprint)
+}
+
+fn hash(dir: Option<&String>, output: Option<&Path>, edition: Option<&str>) -> Result<()> {
+    let fingerprint = fingerprint(&game_dir(dir)?)?;
+    let mut text = Vec::new();
+    match edition {
+        Some(edition) => {
+            let edition = edition.parse()?;
+            if !fingerprint.unreadable.is_empty() {
+                return Err("not recording a baseline from unreadable archives".into());
+            }
+            Baseline {
+                edition,
+                fingerprint,
+            }
+            .write(&mut text)?;
+        }
+        None => fingerprint.write(&mut text)?,
+    }
+    match output {
+        Some(output) => save(output, |data| data.write_all(&text)),
+        None => Ok(io::stdout().write_all(&text)?),
+    }
+}
+
+fn identify(dir: Option<&String>, baseline_files: &[String]) -> Result<()> {
+    let fingerprint = fingerprint(&game_dir(dir)?)?;
+    let mut baselines = Baseline::known();
+    for file in baseline_files {
+        let text = fs::read_to_string(file).map_err(|error| format!("{}: {}", file, error))?;
+        let baseline = Baseline::read(&text).map_err(|error| format!("{}: {}", file, error))?;
+        baselines.push(baseline);
+    }
+
+    // The archive hashes identify the data in bug reports even without a matching baseline.
+    for hash in fingerprint
+        .hashes
+        .iter()
+        .filter(|hash| !hash.name.contains(':'))
+    {
+        println!("{:<24} {:>9}  {:08x}", hash.name, hash.length, hash.crc32);
+    }
+    match edition::identify(&fingerprint, &baselines) {
+        Identity::Known(edition) => println!("edition: {}", edition),
+        Identity::Modified {
+            edition,
+            differences,
+        } => {
+            println!("edition: modified {}", edition);
+            for difference in differences {
+                println!(
+                    "  {:<8} {}",
+                    change_name(difference.change),
+                    difference.name
+                );
+            }
+        }
+        Identity::Unknown if baselines.is_empty() => {
+            println!("edition: unknown, there are no baselines to compare with");
+            println!("pass one with --baseline <file>, recorded from an untouched install with");
+            println!("df hash --edition <id>");
+        }
+        Identity::Unknown => println!("edition: unknown or modded"),
+    }
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Runs the `df` binary on a small generated GOB and checks what it prints and writes.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use formats::archive::Archive;
+use formats::{bm, lev, mint, pal, pcm, voc};
+
+fn df(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_df"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "df {:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn path(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+/// A fresh directory for a test, holding a directory of files to pack.
+fn setup(test: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("df-cli-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let files = dir.join("files");
+    fs::create_dir_all(&files).unwrap();
+
+    let mut data = Vec::new();
+    bm::Bm {
+        size: mint::Vector2 { x: 3, y: 2 },
+        idem_size: mint::Vector2 { x: 3, y: 2 },
+        flags: 0,
+        log_size_y: false,
+        compression: bm::Compression::None,
+        data: vec![1, 2, 3, 4, 5, 6],
+    }
+    .write(&mut data)
+    .unwrap();
+    fs::write(files.join("WALL.BM"), data).unwrap();
+
+    let mut data = Vec::new();
+    pal::Pal {
+        entries: [pal::Entry { r: 63, g: 0, b: 0 }; 256],
+    }
+    .write(&mut data)
+    .unwrap();
+    fs::write(files.join("RED.PAL"), data).unwrap();
+
+    let lev = "LEV 2.1\nLEVELNAME TEST\nPALETTE RED.PAL\nMUSIC NULL.GMD\n\
+               PARALLAX 1024.0000 1024.0000\nTEXTURES 1\nTEXTURE: WALL.BM\nNUMSECTORS 0\n";
+    fs::write(files.join("TEST.LEV"), lev).unwrap();
+
+    (dir, files)
+}
+
+fn pack(dir: &Path, files: &Path) -> PathBuf {
+    let gob = dir.join("TEST.GOB");
+    let stdout = df(&["pack", path(files), path(&gob)]);
+    assert_eq!(stdout, format!("packed 3 entries into {}\n", gob.display()));
+    gob
+}
+
+#[test]
+fn pack_and_ls() {
+    let (dir, files) = setup("pack");
+    let gob = pack(&dir, &files);
+
+    let archive = Archive::open(&gob).unwrap();
+    let names = archive
+        .catalog
+        .entries
+        .iter()
+        .map(|entry| entry.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["RED.PAL", "TEST.LEV", "WALL.BM"]);
+    assert_eq!(
+        archive.read_named("WALL.BM").unwrap().unwrap(),
+        fs::read(files.join("WALL.BM")).unwrap()
+    );
+
+    let listing = df(&["ls", path(&gob)]);
+    let lines = listing
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            ["RED.PAL", "768", "PAL"],
+            ["TEST.LEV", "124", "LEV"],
+            ["WALL.BM", "38", "BM"]
+        ]
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn extract() {
+    let (dir, files) = setup("extract");
+    let gob = pack(&dir, &files);
+    let output = dir.join("extracted");
+
+    let stdout = df(&["extract", path(&gob), "*.bm", "*.LEV", "-o", path(&output)]);
+    assert_eq!(
+        stdout,
+        format!(
+            "{}\n{}\n",
+            output.join("TEST.LEV").display(),
+            output.join("WALL.BM").display()
+        )
+    );
+    for name in &["TEST.LEV", "WALL.BM"] {
+        assert_eq!(
+            fs::read(output.join(name)).unwrap(),
+            fs::read(files.join(name)).unwrap()
+        );
+    }
+    assert!(!output.join("RED.PAL").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn info() {
+    let (dir, files) = setup("info");
+    let gob = pack(&dir, &files);
+
+    let stdout = df(&["info", &format!("{}:wall.bm", path(&gob))]);
+    assert_eq!(
+        stdout,
+        "WALL.BM: BM (Signature), 38 bytes\n\
+         size: 3x2\nidem size: 3x2\ncompression: None\nflags: 0x0\n"
+    );
+
+    let stdout = df(&["info", path(&files.join("TEST.LEV"))]);
+    assert!(stdout.starts_with("TEST.LEV: LEV (Signature), 124 bytes\n"));
+    assert!(stdout.contains("palette: RED.PAL\ntextures: 1\nsectors: 0\n"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn convert() {
+    let (dir, files) = setup("convert");
+    let gob = pack(&dir, &files);
+
+    // An indexed PNG with the BM's size, using the given palette.
+    let png = dir.join("wall.png");
+    let stdout = df(&[
+        "convert",
+        &format!("{}:WALL.BM", path(&gob)),
+        "-o",
+        path(&png),
+        "--pal",
+        &format!("{}:RED.PAL", path(&gob)),
+    ]);
+    assert_eq!(stdout, format!("{}\n", png.display()));
+    let data = fs::read(&png).unwrap();
+    assert!(data.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"));
+    assert_eq!(data[16..24], [0, 0, 0, 3, 0, 0, 0, 2]);
+    // 8 bits per pixel, indexed colour.
+    assert_eq!(data[24..26], [8, 3]);
+    let plte = data.windows(4).position(|chunk| chunk == b"PLTE").unwrap() + 4;
+    assert_eq!(data[plte..plte + 6], [255, 0, 0, 255, 0, 0]);
+
+    let json = dir.join("test.json");
+    df(&["convert", path(&files.join("TEST.LEV")), "-o", path(&json)]);
+    let lev: lev::Lev = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+    assert_eq!(lev.palette_name, "RED.PAL");
+    assert_eq!(lev.texture_names, ["WALL.BM"]);
+
+    // Sounds are resampled to 44.1kHz 16-bit.
+    let mut data = Vec::new();
+    voc::Voc::from_pcm(&pcm::Pcm {
+        data: vec![0x80; 1000],
+        ..pcm::Pcm::new_u8_mono(11_025)
+    })
+    .write(&mut data)
+    .unwrap();
+    let sound = dir.join("SOUND.VOC");
+    fs::write(&sound, data).unwrap();
+    let wav = dir.join("sound.wav");
+    df(&["convert", path(&sound), "-o", path(&wav)]);
+    let data = fs::read(&wav).unwrap();
+    assert!(data.starts_with(b"RIFF") && data[8..12] == *b"WAVE");
+    assert_eq!(data[24..28], 44_100u32.to_le_bytes());
+    assert_eq!(data[34..36], 16u16.to_le_bytes());
+
+    fs::remove_dir_all(dir).unwrap();
+}
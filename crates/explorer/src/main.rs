@@ -1,14 +1,32 @@
 use std::fs::{read_dir, File};
 use std::io;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
+use audio::mixer::Sound;
+use audio::music::{Mood, Music, MusicSet, Notification};
+use audio::output::{AudioOutput, Playback};
+use audio::synth::{Bank, Synth};
 use eframe::egui;
 
 use formats::common::*;
 use formats::*;
 
 fn main() -> ReadResult<()> {
-    eframe::run_native(Box::new(App::new()?), eframe::NativeOptions::default())
+    let path = std::env::args_os().nth(1).map(std::path::PathBuf::from);
+    let install = match install::Install::find(path.as_deref()) {
+        Ok(install) => install,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    eprintln!("{}", install);
+    eframe::run_native(
+        Box::new(App::new(&install)?),
+        eframe::NativeOptions::default(),
+    )
 }
 
 struct App {
@@ -17,6 +35,7 @@ struct App {
     index: Option<(usize, usize)>,
     gob_palette: GobPalette,
     selected: Option<Selected>,
+    audio: AudioOutput,
 }
 
 struct DataFile {
@@ -85,37 +104,32 @@ impl GobPalette {
 }
 
 impl App {
-    fn new() -> ReadResult<Self> {
-        let steam_path = registry::Hive::CurrentUser
-            .open(r"Software\Valve\Steam", registry::Security::Read)
-            .expect("want a Steam installation")
-            .value("SteamPath")
-            .unwrap();
-        let steam_path = match steam_path {
-            registry::Data::String(steam_path) => steam_path.to_os_string(),
-            _ => panic!("unexpected type: {:?}", steam_path),
-        };
-
-        let game_path =
-            std::path::Path::new(&steam_path).join(r"steamapps\common\Dark Forces\Game");
-
-        let mut data_files = Self::files(game_path)?;
+    fn new(install: &install::Install) -> ReadResult<Self> {
+        let mut data_files = Self::files(install)?;
         let gob_palette = GobPalette::setup(&mut data_files);
+        let audio = AudioOutput::device().unwrap_or_else(|error| {
+            eprintln!("{}, sounds will not be heard", error);
+            AudioOutput::null(44_100)
+        });
         Ok(Self {
             data_files,
             search: String::new(),
             index: None,
             gob_palette,
             selected: None,
+            audio,
         })
     }
 
-    fn files(base_path: impl AsRef<Path>) -> ReadResult<Vec<DataFile>> {
-        let base_path = base_path.as_ref();
+    fn files(install: &install::Install) -> ReadResult<Vec<DataFile>> {
+        let is_extension = |path: &Path, extension: &str| {
+            let s = path.extension().unwrap_or_default();
+            s.eq_ignore_ascii_case(extension)
+        };
         let mut result = Vec::new();
-        for entry in read_dir(base_path)? {
+        for entry in read_dir(&install.dir)? {
             let path = entry?.path();
-            if path.extension().and_then(|s| s.to_str()) == Some("GOB") {
+            if is_extension(&path, "GOB") {
                 let name = path.file_name().unwrap().to_string_lossy().to_string();
                 let mut file = File::open(path)?;
                 let catalog = gob::read(&mut file)?;
@@ -126,9 +140,13 @@ impl App {
                 });
             }
         }
-        for entry in read_dir(base_path.join("LFD"))? {
+        let lfd_dir = match install.file("LFD") {
+            Some(lfd_dir) => lfd_dir,
+            None => return Ok(result),
+        };
+        for entry in read_dir(lfd_dir)? {
             let path = entry?.path();
-            if path.extension().and_then(|s| s.to_str()) == Some("LFD") {
+            if is_extension(&path, "LFD") {
                 let name = path.file_name().unwrap().to_string_lossy().to_string();
                 let mut file = File::open(path)?;
                 let catalog = lfd::read(&mut file)?;
@@ -235,16 +253,25 @@ impl eframe::epi::App for App {
                 }
 
                 if let Some((gob_index, entry_index)) = new_index {
-                    let data_file = &mut self.data_files[gob_index];
+                    let data_file = &self.data_files[gob_index];
                     let entry = &data_file.catalog.entries[entry_index];
 
                     let data = data_file.read(entry);
 
                     let pal = &self.gob_palette.items[self.gob_palette.selected].1;
 
-                    let decoded = match Decoded::read(frame, entry, &data, pal) {
+                    let detection = detect::detect(&entry.name, &data);
+                    let decoded = match Decoded::read(
+                        frame,
+                        entry,
+                        detection,
+                        &data,
+                        pal,
+                        &self.data_files,
+                        &self.audio,
+                    ) {
                         Err(error) => {
-                            println!("failed to read: {:?}", error);
+                            println!("failed to read {}: {}", entry.name, error);
                             Decoded::Unknown
                         }
                         Ok(decoded) => decoded,
@@ -260,6 +287,7 @@ impl eframe::epi::App for App {
                         name: entry.name.to_string(),
                         offset: entry.offset,
                         length: entry.length,
+                        detection,
                         text_data,
                         decoded,
                         reload: false,
@@ -273,15 +301,21 @@ impl eframe::epi::App for App {
                 ui.heading("No selected item");
             }
             Some(selected) => {
+                let format = match selected.detection {
+                    Some(detection) => {
+                        format!("{} ({:?})", detection.format, detection.confidence)
+                    }
+                    None => "unknown format".to_string(),
+                };
                 ui.heading(format!(
-                    "Selected: {:?} {:x} ({} bytes)",
-                    selected.name, selected.offset, selected.length,
+                    "Selected: {:?} {:x} ({} bytes, {})",
+                    selected.name, selected.offset, selected.length, format,
                 ));
             }
         });
 
         if let Some(selected) = &mut self.selected {
-            selected.show(ctx, &mut self.gob_palette);
+            selected.show(ctx, &mut self.gob_palette, &self.audio);
         }
     }
 }
@@ -290,20 +324,21 @@ struct Selected {
     name: String,
     offset: u32,
     length: u32,
+    detection: Option<detect::Detection>,
     text_data: String,
     decoded: Decoded,
     reload: bool,
 }
 
 impl Selected {
-    fn show(&mut self, ctx: &egui::CtxRef, palette: &mut GobPalette) {
+    fn show(&mut self, ctx: &egui::CtxRef, palette: &mut GobPalette, audio: &AudioOutput) {
         egui::CentralPanel::default().show(ctx, |ui| {
             match self.decoded {
                 Decoded::Unknown => {
                     self.show_raw_data(ui);
                 }
                 _ => {
-                    self.show_content(ui, palette);
+                    self.show_content(ui, palette, audio);
                 }
             }
 
@@ -321,14 +356,14 @@ impl Selected {
         });
     }
 
-    fn show_content(&mut self, ui: &mut egui::Ui, palette: &mut GobPalette) {
+    fn show_content(&mut self, ui: &mut egui::Ui, palette: &mut GobPalette, audio: &AudioOutput) {
         egui::ScrollArea::auto_sized().id_source(1).show(ui, |ui| {
             ui.horizontal(|ui| {
                 if self.decoded.want_pal() && palette.show(ui) {
                     self.reload = true;
                 }
 
-                self.decoded.show(ui);
+                self.decoded.show(ui, audio);
             });
         });
     }
@@ -390,14 +425,147 @@ impl DecodedImage {
     }
 }
 
+/// Renders a level's music with the software synthesizer, to hear its iMUSE transitions.
+struct MusicPreview {
+    set: MusicSet,
+    start: Mood,
+    then: Option<Mood>,
+    switch_after: f32,
+    playback: Option<Playback>,
+    notifications: Vec<(Duration, Notification)>,
+}
+
+impl MusicPreview {
+    const LENGTH: Duration = Duration::from_secs(30);
+
+    /// Loads the other moods of the level for a name like `STALK-01.GMD`, otherwise just plays
+    /// `gmd`.
+    fn new(name: &str, gmd: &gmd::Gmd, data_files: &[DataFile]) -> ReadResult<Self> {
+        let level = Mood::ALL.iter().find_map(|&mood| {
+            let level = name
+                .strip_prefix(&mood.name().to_uppercase())?
+                .strip_prefix('-')?
+                .strip_suffix(".GMD")?
+                .parse::<u32>()
+                .ok()?;
+            Some((mood, level))
+        });
+        let (set, start) = match level {
+            Some((mood, level)) => {
+                let set = MusicSet::load_level(level, |name| {
+                    data_files.iter().find_map(|file| {
+                        let entry = file.catalog.entries.iter().find(|e| e.name == name)?;
+                        Some(file.read(entry))
+                    })
+                })?;
+                (set, mood)
+            }
+            None => {
+                let mut set = MusicSet::new();
+                set.insert(Mood::Stalk, gmd.clone());
+                (set, Mood::Stalk)
+            }
+        };
+        Ok(Self {
+            set,
+            start,
+            then: None,
+            switch_after: 10.0,
+            playback: None,
+            notifications: Vec::new(),
+        })
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui, audio: &AudioOutput) {
+        let moods = Mood::ALL
+            .iter()
+            .copied()
+            .filter(|&mood| self.set.get(mood).is_some())
+            .collect::<Vec<_>>();
+
+        ui.heading("Music");
+        ui.horizontal(|ui| {
+            ui.label("start");
+            for &mood in &moods {
+                ui.radio_value(&mut self.start, mood, mood.name());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("then");
+            ui.radio_value(&mut self.then, None, "none");
+            for &mood in &moods {
+                ui.radio_value(&mut self.then, Some(mood), mood.name());
+            }
+        });
+        ui.add(egui::Slider::new(&mut self.switch_after, 1.0..=25.0).text("switch after seconds"));
+        ui.horizontal(|ui| {
+            if ui.button("Render and play").clicked() {
+                match self.render(audio.sample_rate()) {
+                    Ok(sound) => self.playback = Some(audio.play(Arc::new(sound), 1.0, false)),
+                    Err(error) => println!("failed to render music: {}", error),
+                }
+            }
+            if matches!(&self.playback, Some(playback) if playback.is_playing())
+                && ui.button("Stop").clicked()
+            {
+                self.playback = None;
+            }
+        });
+        egui::Grid::new("music").striped(true).show(ui, |ui| {
+            for (time, notification) in &self.notifications {
+                ui.code(format!("{:.3}s", time.as_secs_f64()));
+                ui.code(format!("{:?}", notification));
+                ui.end_row();
+            }
+        });
+    }
+
+    /// Plays the start mood, then requests the next mood after `switch_after`.
+    fn render(&mut self, sample_rate: u32) -> ReadResult<Sound> {
+        let mut music = Music::new(&self.set, self.start)?;
+        let mut synth = Synth::new(sample_rate, Arc::new(Bank::general_midi()));
+        let switch_after = Duration::from_secs_f32(self.switch_after);
+
+        fn infallible<T>(result: Result<T, std::convert::Infallible>) -> T {
+            match result {
+                Ok(value) => value,
+                Err(never) => match never {},
+            }
+        }
+
+        let mut pcm = infallible(audio::render_music(&mut music, &mut synth, switch_after));
+        if let Some(mood) = self.then {
+            music.request(mood);
+        }
+        let rest = infallible(audio::render_music(
+            &mut music,
+            &mut synth,
+            Self::LENGTH - switch_after,
+        ));
+        pcm.data.extend_from_slice(&rest.data);
+
+        self.notifications = music.take_notifications();
+        Ok(Sound::from_pcm(&pcm))
+    }
+}
+
 enum Decoded {
     Unknown,
     Lev(DecodedLev),
     Voc {
         voc: voc::Voc,
-        _player: voc::Player,
+        /// `None` if the chunks read but not the sound, e.g. with an unsupported codec.
+        sound: Option<Arc<Sound>>,
+        error: Option<String>,
+        playback: Option<Playback>,
+        volume: f32,
+        looping: bool,
     },
     Gmd {
+        gmd: gmd::Gmd,
+        imuse_events: Vec<imuse::TimedEvent>,
+        music: MusicPreview,
+        #[cfg(windows)]
         _playing: Box<dyn Drop>,
     },
     Bm {
@@ -447,33 +615,52 @@ impl Decoded {
     fn read(
         fw: &mut eframe::epi::Frame,
         entry: &CatalogEntry,
+        detection: Option<detect::Detection>,
         data: &[u8],
         pal: &pal::Pal,
+        data_files: &[DataFile],
+        audio: &AudioOutput,
     ) -> ReadResult<Self> {
-        Ok(match entry.name.split('.').last() {
+        let detection = match detection {
+            Some(detection) => detection,
+            None => return Ok(Self::Unknown),
+        };
+        Ok(match asset::Asset::read(detection.format, data)? {
             // Levels
-            Some("LEV") => {
-                let lev = lev::Lev::read(&mut io::Cursor::new(data))?;
-                Self::Lev(DecodedLev::new(lev))
-            }
+            asset::Asset::Lev(lev) => Self::Lev(DecodedLev::new(lev)),
 
             // Audio
-            Some("VOC") => {
-                let voc = voc::Voc::read(&mut io::Cursor::new(data))?;
-                let player = voc::play(&voc).unwrap();
+            asset::Asset::Voc(voc) => {
+                let (sound, error) = match Sound::from_voc(&voc) {
+                    Ok(sound) => (Some(Arc::new(sound)), None),
+                    Err(error) => (None, Some(error.to_string())),
+                };
+                let looping = matches!(&sound, Some(sound) if sound.loop_range.is_some());
                 Self::Voc {
+                    playback: sound
+                        .as_ref()
+                        .map(|sound| audio.play(sound.clone(), 1.0, looping)),
                     voc,
-                    _player: player,
+                    sound,
+                    error,
+                    volume: 1.0,
+                    looping,
                 }
             }
-            Some("GMD") => {
-                let playing = Box::new(gmd::play_in_thread(data.to_vec()));
-                Self::Gmd { _playing: playing }
+            asset::Asset::Gmd(gmd) => {
+                let imuse_events = gmd.imuse_events()?;
+                let music = MusicPreview::new(&entry.name, &gmd, data_files)?;
+                Self::Gmd {
+                    #[cfg(windows)]
+                    _playing: Box::new(gmd::play_in_thread(gmd.clone())?),
+                    music,
+                    gmd,
+                    imuse_events,
+                }
             }
 
             // Images
-            Some("BM") => {
-                let bm = bm::Bm::read(&mut io::Cursor::new(data))?;
+            asset::Asset::Bm(bm) => {
                 let size = mint::Vector2 {
                     x: bm.size.x as u32,
                     y: bm.size.y as u32,
@@ -481,8 +668,7 @@ impl Decoded {
                 let image = DecodedImage::load(fw, &bm.data, size, pal);
                 Self::Bm { bm, image }
             }
-            Some("PAL") => {
-                let pal = pal::Pal::read(&mut io::Cursor::new(data))?;
+            asset::Asset::Pal(pal) => {
                 let mut pixels = [egui::Color32::TRANSPARENT; 256];
                 for i in 1..256 {
                     let (r, g, b) = pal.entries[i].to_rgb();
@@ -493,14 +679,11 @@ impl Decoded {
                     .alloc_srgba_premultiplied((16, 16), &pixels);
                 Self::Pal { texture_id }
             }
-            Some("FME") => {
-                let fme = fme::Fme::read(&mut io::Cursor::new(data))?;
+            asset::Asset::Fme(fme) => {
                 let image = DecodedImage::load(fw, &fme.cell.data, fme.cell.size, pal);
                 Self::Fme { fme, image }
             }
-            Some("WAX") => {
-                let wax = wax::Wax::read(&mut io::Cursor::new(data))?;
-
+            asset::Asset::Wax(wax) => {
                 let images = wax
                     .cells
                     .iter()
@@ -514,11 +697,13 @@ impl Decoded {
                     selected_angle: 0,
                 }
             }
-            _ => Self::Unknown,
+
+            // Shown as raw data.
+            asset::Asset::Catalog(_) | asset::Asset::Text(_) => Self::Unknown,
         })
     }
 
-    fn show(&mut self, ui: &mut egui::Ui) {
+    fn show(&mut self, ui: &mut egui::Ui, audio: &AudioOutput) {
         fn row_code<T: ToString>(ui: &mut egui::Ui, label: &str, value: T) {
             ui.label(label);
             ui.code(value.to_string());
@@ -537,8 +722,43 @@ impl Decoded {
             Decoded::Lev(decoded) => {
                 decoded.show(ui);
             }
-            Decoded::Voc { voc, .. } => {
+            Decoded::Voc {
+                voc,
+                sound,
+                error,
+                playback,
+                volume,
+                looping,
+            } => {
                 ui.vertical(|ui| {
+                    if let Some(sound) = sound {
+                        ui.horizontal(|ui| {
+                            let playing =
+                                matches!(playback, Some(playback) if playback.is_playing());
+                            if playing {
+                                if ui.button("Stop").clicked() {
+                                    *playback = None;
+                                }
+                            } else if ui.button("Play").clicked() {
+                                *playback = Some(audio.play(sound.clone(), *volume, *looping));
+                            }
+                            if ui
+                                .add(egui::Slider::new(volume, 0.0..=1.0).text("volume"))
+                                .changed()
+                            {
+                                if let Some(playback) = playback {
+                                    playback.set_volume(*volume);
+                                }
+                            }
+                            if ui.checkbox(looping, "loop").changed() {
+                                if let Some(playback) = playback {
+                                    playback.set_looping(*looping);
+                                }
+                            }
+                        });
+                    } else if let Some(error) = error {
+                        ui.colored_label(egui::Color32::RED, format!("can't play: {}", error));
+                    }
                     egui::Grid::new(1).show(ui, |ui| {
                         row_code(ui, "version", {
                             let [major, minor] = voc.version.to_be_bytes();
@@ -570,6 +790,14 @@ impl Decoded {
                                     row_code(ui, "sample rate", sample_rate);
                                     row_code(ui, "sample count", sample_count);
                                 }
+                                voc::Chunk::Marker { id } => {
+                                    row_code(ui, "chunk", "marker");
+                                    row_code(ui, "id", id);
+                                }
+                                voc::Chunk::Text { text } => {
+                                    row_code(ui, "chunk", "text");
+                                    row_code(ui, "text", text);
+                                }
                                 voc::Chunk::Repeat { count } => {
                                     row_code(ui, "chunk", "repeat");
                                     match count {
@@ -580,16 +808,65 @@ impl Decoded {
                                 voc::Chunk::RepeatEnd => {
                                     row_code(ui, "chunk", "repeat end");
                                 }
-                                voc::Chunk::Unknown { ty, len } => {
+                                voc::Chunk::Extended {
+                                    time_constant,
+                                    codec,
+                                    stereo,
+                                } => {
+                                    row_code(ui, "chunk", "extended");
+                                    row_code(ui, "time constant", time_constant);
+                                    row_code(ui, "codec", codec);
+                                    row_code(ui, "stereo", stereo);
+                                }
+                                voc::Chunk::Sound {
+                                    sample_rate,
+                                    bits_per_sample,
+                                    channels,
+                                    codec,
+                                    data,
+                                } => {
+                                    row_code(ui, "chunk", "sound");
+                                    row_code(ui, "sample rate", format!("{}Hz", sample_rate));
+                                    row_code(ui, "bits per sample", bits_per_sample);
+                                    row_code(ui, "channels", channels);
+                                    row_code(ui, "codec", codec);
+                                    row_code(ui, "data len", data.len());
+                                }
+                                voc::Chunk::Unknown { ty, data } => {
                                     row_code(ui, "chunk", ty);
-                                    row_code(ui, "len", len);
+                                    row_code(ui, "len", data.len());
                                 }
                             });
                         });
                     }
                 });
             }
-            Decoded::Gmd { .. } => {}
+            Decoded::Gmd {
+                gmd,
+                imuse_events,
+                music,
+                ..
+            } => {
+                ui.vertical(|ui| {
+                    music.show(ui, audio);
+                    egui::Grid::new(1).striped(true).show(ui, |ui| {
+                        for chunk in &gmd.chunks {
+                            row_code(ui, "chunk", String::from_utf8_lossy(&chunk.id));
+                            row_code(ui, "len", chunk.data.len());
+                        }
+                        row_code(ui, "SMF len", gmd.smf.len());
+                    });
+                    ui.heading("iMUSE");
+                    egui::Grid::new(2).striped(true).show(ui, |ui| {
+                        for event in imuse_events {
+                            ui.code(event.track.to_string());
+                            ui.code(event.tick.to_string());
+                            ui.code(format!("{:?}", event.event));
+                            ui.end_row();
+                        }
+                    });
+                });
+            }
             Decoded::Bm { bm, image } => {
                 egui::Grid::new(1).striped(true).show(ui, |ui| {
                     row_vec2(ui, "size", bm.size);
@@ -0,0 +1,353 @@
+//! Builds a glTF 2.0 model of a level, with one primitive per texture.
+//!
+//! The geometry matches the level viewer: walls, steps to adjoining sectors, floors and
+//! ceilings. Map X and Z become glTF X and -Z, and altitudes, which grow downwards, become -Y.
+
+use std::collections::BTreeMap;
+
+use formats::lev;
+use formats::mint;
+use serde_json::json;
+
+pub struct Gltf {
+    pub json: serde_json::Value,
+    /// The binary buffer, to be written where `json` references it.
+    pub buffer: Vec<u8>,
+    /// Sectors whose floor and ceiling could not be built, and why.
+    pub warnings: Vec<String>,
+}
+
+/// Builds `lev` as a model, referencing its buffer as `buffer_uri` and each texture as
+/// `texture_uri(name)`. `texture_sizes` holds the size of each of `lev.texture_names`, or
+/// `None` when it could not be loaded, which leaves its surfaces untextured.
+pub fn level(
+    lev: &lev::Lev,
+    texture_sizes: &[Option<mint::Vector2<u32>>],
+    texture_uri: impl Fn(&str) -> String,
+    buffer_uri: &str,
+) -> Gltf {
+    let mut builder = Builder {
+        texture_sizes,
+        primitives: BTreeMap::new(),
+    };
+    let mut warnings = Vec::new();
+
+    for sector in &lev.sectors {
+        let floor = sector.floor_altitude;
+        let ceiling = sector.ceiling_altitude;
+
+        for wall in &sector.walls {
+            let (left, right) = match (
+                sector.vertices.get(wall.left_vertex),
+                sector.vertices.get(wall.right_vertex),
+            ) {
+                (Some(&left), Some(&right)) => (left, right),
+                _ => continue,
+            };
+
+            let adjoin = wall.adjoin_sector.and_then(|index| lev.sectors.get(index));
+            if let Some(adjoin) = adjoin {
+                if floor > adjoin.floor_altitude {
+                    let texture = &wall.bottom_texture;
+                    builder.wall(left, right, floor, adjoin.floor_altitude, texture);
+                }
+                if ceiling < adjoin.ceiling_altitude {
+                    let texture = &wall.top_texture;
+                    builder.wall(left, right, adjoin.ceiling_altitude, ceiling, texture);
+                }
+            }
+            if adjoin.is_none() || wall.flags.0 & 1 != 0 {
+                builder.wall(left, right, floor, ceiling, &wall.middle_texture);
+            }
+        }
+
+        match level_geometry::triangulate_sector(sector) {
+            Ok(triangles) => {
+                builder.flat(&triangles, floor, &sector.floor_texture, false);
+                builder.flat(&triangles, ceiling, &sector.ceiling_texture, true);
+            }
+            Err(error) => warnings.push(format!(
+                "sector {}: could not triangulate the floor and ceiling: {}",
+                sector.id, error
+            )),
+        }
+    }
+
+    let (json, buffer) = builder.build(lev, texture_uri, buffer_uri);
+    Gltf {
+        json,
+        buffer,
+        warnings,
+    }
+}
+
+/// Geometry using one texture.
+#[derive(Default)]
+struct Primitive {
+    positions: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+}
+
+impl Primitive {
+    fn add(&mut self, vertices: &[([f32; 3], [f32; 2])], indices: &[u32]) {
+        let base = self.positions.len() as u32;
+        for &(position, uv) in vertices {
+            self.positions.push(position);
+            self.uvs.push(uv);
+        }
+        self.indices
+            .extend(indices.iter().map(|index| base + index));
+    }
+
+    fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+        for position in &self.positions {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+        }
+        (min, max)
+    }
+}
+
+struct Builder<'a> {
+    texture_sizes: &'a [Option<mint::Vector2<u32>>],
+    primitives: BTreeMap<usize, Primitive>,
+}
+
+impl Builder<'_> {
+    /// Map units per repeat of `texture`, as a 64 texel wide texture spans 8 units.
+    fn repeat(&self, index: usize) -> [f32; 2] {
+        match self.texture_sizes.get(index).copied().flatten() {
+            Some(size) => [size.x as f32 / 8.0, size.y as f32 / 8.0],
+            None => [8.0, 8.0],
+        }
+    }
+
+    /// A wall from altitude `bottom` up to `top`, with the texture anchored at the bottom.
+    fn wall(
+        &mut self,
+        left: mint::Point2<f32>,
+        right: mint::Point2<f32>,
+        bottom: f32,
+        top: f32,
+        texture: &lev::Texture,
+    ) {
+        let index = match texture.index {
+            Some(index) => index,
+            None => return,
+        };
+        let repeat = self.repeat(index);
+        let width = ((right.x - left.x).powi(2) + (right.y - left.y).powi(2)).sqrt();
+        let height = bottom - top;
+        let u = |along: f32| (along + texture.offset.x) / repeat[0];
+        let v = |up: f32| -(up + texture.offset.y) / repeat[1];
+
+        self.primitives.entry(index).or_default().add(
+            &[
+                ([left.x, -bottom, -left.y], [u(0.0), v(0.0)]),
+                ([right.x, -bottom, -right.y], [u(width), v(0.0)]),
+                ([left.x, -top, -left.y], [u(0.0), v(height)]),
+                ([right.x, -top, -right.y], [u(width), v(height)]),
+            ],
+            &[0, 1, 2, 2, 1, 3],
+        );
+    }
+
+    /// A floor or ceiling at `altitude`.
+    fn flat(
+        &mut self,
+        triangles: &[[mint::Point2<f32>; 3]],
+        altitude: f32,
+        texture: &lev::Texture,
+        ceiling: bool,
+    ) {
+        let index = match texture.index {
+            Some(index) => index,
+            None => return,
+        };
+        let repeat = self.repeat(index);
+        let primitive = self.primitives.entry(index).or_default();
+        for triangle in triangles {
+            let vertices = triangle
+                .iter()
+                .map(|point| {
+                    let uv = [
+                        (point.x - texture.offset.x) / repeat[0],
+                        (point.y - texture.offset.y) / repeat[1],
+                    ];
+                    ([point.x, -altitude, -point.y], uv)
+                })
+                .collect::<Vec<_>>();
+            // Ceilings face down.
+            let indices: &[u32] = if ceiling { &[0, 2, 1] } else { &[0, 1, 2] };
+            primitive.add(&vertices, indices);
+        }
+    }
+
+    fn build(
+        self,
+        lev: &lev::Lev,
+        texture_uri: impl Fn(&str) -> String,
+        buffer_uri: &str,
+    ) -> (serde_json::Value, Vec<u8>) {
+        let mut buffer = Vec::new();
+        let mut views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut materials = Vec::new();
+        let mut textures = Vec::new();
+        let mut images = Vec::new();
+        let mut primitives = Vec::new();
+        // Position accessors need their bounds, added once `push` is done with `accessors`.
+        let mut bounds = Vec::new();
+
+        // Appends `bytes` to the buffer as a view and returns its accessor's index.
+        let mut push = |bytes: Vec<u8>, count: usize, ty: &str, component: u32, target: u32| {
+            views.push(json!({
+                "buffer": 0,
+                "byteOffset": buffer.len(),
+                "byteLength": bytes.len(),
+                "target": target,
+            }));
+            buffer.extend_from_slice(&bytes);
+            accessors.push(json!({
+                "bufferView": views.len() - 1,
+                "componentType": component,
+                "count": count,
+                "type": ty,
+            }));
+            accessors.len() - 1
+        };
+
+        for (&texture, primitive) in &self.primitives {
+            let count = primitive.positions.len();
+            let positions = primitive.positions.iter().flatten();
+            let positions = positions.flat_map(|value| value.to_le_bytes()).collect();
+            let uvs = primitive.uvs.iter().flatten();
+            let uvs = uvs.flat_map(|value| value.to_le_bytes()).collect();
+            let indices = primitive.indices.iter();
+            let indices = indices.flat_map(|index| index.to_le_bytes()).collect();
+
+            let position = push(positions, count, "VEC3", FLOAT, ARRAY_BUFFER);
+            let uv = push(uvs, count, "VEC2", FLOAT, ARRAY_BUFFER);
+            let index_count = primitive.indices.len();
+            let indices = push(indices, index_count, "SCALAR", UNSIGNED_INT, INDEX_BUFFER);
+            bounds.push((position, primitive.bounds()));
+
+            let name = lev.texture_names.get(texture).map_or("", String::as_str);
+            let mut material = json!({
+                "name": name,
+                "pbrMetallicRoughness": { "metallicFactor": 0.0 },
+                "alphaMode": "MASK",
+                "doubleSided": true,
+            });
+            if self.texture_sizes.get(texture).copied().flatten().is_some() {
+                material["pbrMetallicRoughness"]["baseColorTexture"] =
+                    json!({ "index": textures.len() });
+                textures.push(json!({ "source": images.len(), "sampler": 0 }));
+                images.push(json!({ "uri": texture_uri(name) }));
+            }
+            materials.push(material);
+
+            primitives.push(json!({
+                "attributes": { "POSITION": position, "TEXCOORD_0": uv },
+                "indices": indices,
+                "material": materials.len() - 1,
+            }));
+        }
+
+        for (accessor, (min, max)) in bounds {
+            accessors[accessor]["min"] = json!(min);
+            accessors[accessor]["max"] = json!(max);
+        }
+
+        let mut json = json!({
+            "asset": { "version": "2.0", "generator": "df-export" },
+            "scene": 0,
+            "scenes": [{}],
+            "samplers": [{ "magFilter": NEAREST, "minFilter": NEAREST }],
+            "materials": materials,
+            "textures": textures,
+            "images": images,
+            "accessors": accessors,
+            "bufferViews": views,
+            "buffers": [{ "uri": buffer_uri, "byteLength": buffer.len() }],
+        });
+        // glTF does not allow empty meshes.
+        if !primitives.is_empty() {
+            json["meshes"] = json!([{ "primitives": primitives }]);
+            json["nodes"] = json!([{ "mesh": 0 }]);
+            json["scenes"] = json!([{ "nodes": [0] }]);
+        }
+        (json, buffer)
+    }
+}
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const INDEX_BUFFER: u32 = 34963;
+const NEAREST: u32 = 9728;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn square_room() {
+        let lev = lev::Lev::read(
+            "LEV 2.1
+LEVELNAME TEST
+PALETTE TEST.PAL
+MUSIC NULL.GMD
+PARALLAX 1024.0000 1024.0000
+TEXTURES 1
+TEXTURE: WALL.BM
+NUMSECTORS 1
+SECTOR 0
+NAME
+AMBIENT 20
+FLOOR TEXTURE 0 0.00 0.00 0
+FLOOR ALTITUDE 0.00
+CEILING TEXTURE 0 0.00 0.00 0
+CEILING ALTITUDE -8.00
+SECOND ALTITUDE 0.00
+FLAGS 0 0 0
+LAYER 0
+VERTICES 4
+X: 0.00 Z: 0.00
+X: 0.00 Z: 8.00
+X: 8.00 Z: 8.00
+X: 8.00 Z: 0.00
+WALLS 4
+WALL LEFT: 0 RIGHT: 1 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0
+WALL LEFT: 1 RIGHT: 2 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0
+WALL LEFT: 2 RIGHT: 3 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0
+WALL LEFT: 3 RIGHT: 0 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let sizes = [Some(mint::Vector2 { x: 64, y: 64 })];
+        let gltf = level(
+            &lev,
+            &sizes,
+            |name| format!("textures/{}.png", name),
+            "TEST.bin",
+        );
+        assert!(gltf.warnings.is_empty());
+
+        // Four walls and two triangles each for the floor and ceiling, all in one primitive.
+        let primitives = &gltf.json["meshes"][0]["primitives"];
+        assert_eq!(primitives.as_array().unwrap().len(), 1);
+        let indices = &gltf.json["accessors"][primitives[0]["indices"].as_u64().unwrap() as usize];
+        assert_eq!(indices["count"], 4 * 6 + 2 * 2 * 3);
+        let position = &gltf.json["accessors"][0];
+        assert_eq!(position["min"], json!([0.0, 0.0, -8.0]));
+        assert_eq!(position["max"], json!([8.0, 8.0, 0.0]));
+        assert_eq!(gltf.json["images"][0]["uri"], "textures/WALL.BM.png");
+        assert_eq!(gltf.json["buffers"][0]["byteLength"], gltf.buffer.len());
+    }
+}
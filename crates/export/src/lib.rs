@@ -0,0 +1,500 @@
+//! Exports every GOB and LFD of a game installation to files ordinary tools can open: images as
+//! PNG, sounds as 44.1kHz 16-bit WAV, music as MIDI, levels as JSON and glTF, and anything else
+//! as-is.
+//!
+//! The output mirrors the installation, e.g. `DARK.GOB/SECBASE.LEV.json`, with each level also
+//! getting `levels/SECBASE/`, holding its model and the textures and sprites it uses in its own
+//! palette. `manifest.json` lists what each entry was exported to and what failed and why.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use formats::archive::{self, Archive};
+use formats::asset::Asset;
+use formats::common::*;
+use formats::image::{self, PngFormat};
+use formats::resample;
+use formats::{bm, detect, fme, lev, mint, pal, wax};
+use serde::Serialize;
+
+pub mod gltf;
+
+#[derive(Default, Serialize)]
+pub struct Manifest {
+    pub exported: Vec<Exported>,
+    pub failures: Vec<Failure>,
+}
+
+/// The files written for an entry, relative to the output directory.
+#[derive(Serialize)]
+pub struct Exported {
+    /// The entry, as `ARCHIVE:ENTRY`.
+    pub source: String,
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Failure {
+    /// The entry, as `ARCHIVE:ENTRY`, or the archive.
+    pub source: String,
+    pub error: String,
+}
+
+/// Exports the GOB and LFD files in `game_dir` and its subdirectories to `output_dir`, and
+/// writes the manifest there as `manifest.json`.
+///
+/// Entries that fail to decode or encode are listed in the manifest, only failing to write is an
+/// error.
+pub fn export(game_dir: &Path, output_dir: &Path) -> io::Result<Manifest> {
+    let mut exporter = Exporter {
+        output_dir,
+        manifest: Manifest::default(),
+    };
+
+    let mut archives = Vec::new();
+    for path in archive::find(game_dir)? {
+        let name = relative_name(game_dir, &path);
+        match Archive::open(&path) {
+            Ok(archive) => archives.push((name, archive)),
+            Err(error) => exporter.fail(name, error),
+        }
+    }
+    let library = Library::new(&archives);
+    let default_pal = library.default_pal();
+
+    let mut levels = Vec::new();
+    for (archive_name, archive) in &archives {
+        for entry in &archive.catalog.entries {
+            let source = format!("{}:{}", archive_name, entry.name);
+            let base = format!("{}/{}", archive_name, entry.name);
+            if !archive::is_file_name(&entry.name) {
+                exporter.fail(source, "not a valid file name");
+                continue;
+            }
+            let data = match archive.read(entry) {
+                Ok(data) => data,
+                Err(error) => {
+                    exporter.fail(source, error);
+                    continue;
+                }
+            };
+            let asset = match detect::detect(&entry.name, &data) {
+                Some(detection) => match Asset::read(detection.format, &data) {
+                    Ok(asset) => Some(asset),
+                    Err(error) => {
+                        exporter.fail(source, error);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let mut files = Vec::new();
+            let pal = &default_pal;
+            match asset {
+                Some(Asset::Bm(bm)) => exporter.bm(&source, &base, &bm, pal, &mut files)?,
+                Some(Asset::Fme(fme)) => exporter.fme(&source, &base, &fme, pal, &mut files)?,
+                Some(Asset::Wax(wax)) => exporter.wax(&source, &base, &wax, pal, &mut files)?,
+                Some(Asset::Pal(pal)) => {
+                    let file = format!("{}.png", base);
+                    exporter.write(&file, |output| image::write_pal_png(output, &pal))?;
+                    files.push(file);
+                }
+                Some(Asset::Voc(voc)) => match voc.decode(0) {
+                    Ok(pcm) => {
+                        let pcm = resample::convert_for_export(&pcm);
+                        let file = format!("{}.wav", base);
+                        exporter.write(&file, |output| pcm.write_wav(output))?;
+                        files.push(file);
+                    }
+                    Err(error) => {
+                        exporter.fail(source, error);
+                        continue;
+                    }
+                },
+                Some(Asset::Gmd(gmd)) => {
+                    let file = format!("{}.mid", base);
+                    exporter.write(&file, |output| gmd.write_mid(output))?;
+                    files.push(file);
+                }
+                Some(Asset::Lev(lev)) => {
+                    let file = format!("{}.json", base);
+                    exporter.write(&file, |output| {
+                        Ok(serde_json::to_writer_pretty(output, &lev)?)
+                    })?;
+                    files.push(file);
+                    levels.push((source.clone(), entry.name.clone(), lev));
+                }
+                // Text, nested archives and anything unrecognized.
+                Some(Asset::Text(_)) | Some(Asset::Catalog(_)) | None => {
+                    exporter.write(&base, |output| output.write_all(&data))?;
+                    files.push(base);
+                }
+            }
+            // Images that failed to encode are only listed as failures.
+            if !files.is_empty() {
+                exporter.manifest.exported.push(Exported { source, files });
+            }
+        }
+    }
+
+    for (source, name, lev) in &levels {
+        exporter.level(&library, source, name, lev, &default_pal)?;
+    }
+
+    let manifest = serde_json::to_vec_pretty(&exporter.manifest)?;
+    fs::write(output_dir.join("manifest.json"), manifest)?;
+    Ok(exporter.manifest)
+}
+
+/// `path` relative to `dir`, with `/` separators.
+fn relative_name(dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    let parts = relative.iter().map(|part| part.to_string_lossy());
+    parts.collect::<Vec<_>>().join("/")
+}
+
+/// Finds entries by name across all archives, the first archive to hold a name winning.
+struct Library<'a> {
+    entries: HashMap<String, (&'a Archive, &'a CatalogEntry)>,
+}
+
+impl<'a> Library<'a> {
+    fn new(archives: &'a [(String, Archive)]) -> Self {
+        let mut entries = HashMap::new();
+        for (_, archive) in archives {
+            for entry in &archive.catalog.entries {
+                entries
+                    .entry(entry.name.to_ascii_uppercase())
+                    .or_insert((archive, entry));
+            }
+        }
+        Self { entries }
+    }
+
+    fn read(&self, name: &str) -> ReadResult<Vec<u8>> {
+        match self.entries.get(&name.to_ascii_uppercase()) {
+            Some((archive, entry)) => archive.read(entry),
+            None => Err(ReadError::invalid_data(format!("{} not found", name))),
+        }
+    }
+
+    fn pal(&self, name: &str) -> ReadResult<pal::Pal> {
+        let data = self.read(name)?;
+        pal::Pal::read(data.as_slice())
+    }
+
+    /// The palette of the first level for images outside levels, or grayscale without one.
+    fn default_pal(&self) -> pal::Pal {
+        self.pal("SECBASE.PAL")
+            .unwrap_or_else(|_| pal::Pal::grayscale())
+    }
+}
+
+struct Exporter<'a> {
+    output_dir: &'a Path,
+    manifest: Manifest,
+}
+
+impl Exporter<'_> {
+    fn fail(&mut self, source: impl Into<String>, error: impl ToString) {
+        self.manifest.failures.push(Failure {
+            source: source.into(),
+            error: error.to_string(),
+        });
+    }
+
+    /// Writes `file`, relative to the output directory, creating its directory.
+    fn write(
+        &self,
+        file: &str,
+        write: impl FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let path = self.output_dir.join(file);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut output = io::BufWriter::new(fs::File::create(path)?);
+        write(&mut output)?;
+        output.flush()
+    }
+
+    /// Writes an indexed PNG, or lists `source` as failed if it can't be encoded, e.g. as it has
+    /// no pixels. Only failing to write the file is an error.
+    fn png(
+        &mut self,
+        source: &str,
+        file: String,
+        size: mint::Vector2<u32>,
+        data: &[u8],
+        pal: &pal::Pal,
+        files: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let mut png = Vec::new();
+        match image::write_png(&mut png, size, data, pal, PngFormat::Indexed) {
+            Ok(()) => {
+                self.write(&file, |output| output.write_all(&png))?;
+                files.push(file);
+            }
+            Err(error) => self.fail(source, error),
+        }
+        Ok(())
+    }
+
+    fn bm(
+        &mut self,
+        source: &str,
+        base: &str,
+        bm: &bm::Bm,
+        pal: &pal::Pal,
+        files: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let size = mint::Vector2 {
+            x: bm.size.x as u32,
+            y: bm.size.y as u32,
+        };
+        let file = format!("{}.png", base);
+        self.png(source, file, size, &bm.data, pal, files)
+    }
+
+    fn fme(
+        &mut self,
+        source: &str,
+        base: &str,
+        fme: &fme::Fme,
+        pal: &pal::Pal,
+        files: &mut Vec<String>,
+    ) -> io::Result<()> {
+        let file = format!("{}.png", base);
+        self.png(source, file, fme.cell.size, &fme.cell.data, pal, files)
+    }
+
+    /// Writes each cell, numbered like `TROOP.WAX-0.png`.
+    fn wax(
+        &mut self,
+        source: &str,
+        base: &str,
+        wax: &wax::Wax,
+        pal: &pal::Pal,
+        files: &mut Vec<String>,
+    ) -> io::Result<()> {
+        for (index, cell) in wax.cells.iter().enumerate() {
+            let file = format!("{}-{}.png", base, index);
+            let source = format!("{} cell {}", source, index);
+            self.png(&source, file, cell.size, &cell.data, pal, files)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the model of a level, with the textures and sprites it uses in its palette.
+    fn level(
+        &mut self,
+        library: &Library<'_>,
+        source: &str,
+        name: &str,
+        lev: &lev::Lev,
+        default_pal: &pal::Pal,
+    ) -> io::Result<()> {
+        let dir = format!("levels/{}", archive::stem(name));
+        let mut files = Vec::new();
+
+        let pal = match library.pal(&lev.palette_name) {
+            Ok(pal) => pal,
+            Err(error) => {
+                let source = format!("{} palette {}", source, lev.palette_name);
+                self.fail(source, error);
+                pal::Pal {
+                    entries: default_pal.entries,
+                }
+            }
+        };
+
+        let mut texture_sizes = Vec::with_capacity(lev.texture_names.len());
+        for texture in &lev.texture_names {
+            let bm = library
+                .read(texture)
+                .and_then(|data| bm::Bm::read(io::Cursor::new(data)));
+            match bm {
+                Ok(bm) if archive::is_file_name(texture) => {
+                    let base = format!("{}/textures/{}", dir, texture);
+                    let failure = format!("{} texture {}", source, texture);
+                    let written = files.len();
+                    self.bm(&failure, &base, &bm, &pal, &mut files)?;
+                    // Without its PNG, the texture is left out of the model.
+                    let size = mint::Vector2 {
+                        x: bm.size.x as u32,
+                        y: bm.size.y as u32,
+                    };
+                    texture_sizes.push(Some(size).filter(|_| files.len() > written));
+                }
+                Ok(_) => {
+                    self.fail(
+                        format!("{} texture {}", source, texture),
+                        "not a valid file name",
+                    );
+                    texture_sizes.push(None);
+                }
+                Err(error) => {
+                    self.fail(format!("{} texture {}", source, texture), error);
+                    texture_sizes.push(None);
+                }
+            }
+        }
+
+        // Sprites are listed in the objects file, e.g. `SPR: STORMFIN.WAX`.
+        if let Ok(objects) = library.read(&format!("{}.O", archive::stem(name))) {
+            let objects = String::from_utf8_lossy(&objects);
+            let mut sprites = Vec::new();
+            for line in objects.lines() {
+                let mut tokens = line.split_whitespace();
+                if let (Some("SPR:"), Some(sprite)) | (Some("FME:"), Some(sprite)) =
+                    (tokens.next(), tokens.next())
+                {
+                    if !sprites.contains(&sprite) {
+                        sprites.push(sprite);
+                    }
+                }
+            }
+            for sprite in sprites {
+                let failure = format!("{} sprite {}", source, sprite);
+                let data = match library.read(sprite) {
+                    Ok(data) if archive::is_file_name(sprite) => data,
+                    Ok(_) => {
+                        self.fail(failure, "not a valid file name");
+                        continue;
+                    }
+                    Err(error) => {
+                        self.fail(failure, error);
+                        continue;
+                    }
+                };
+                let base = format!("{}/sprites/{}", dir, sprite);
+                let detection = detect::detect(sprite, &data);
+                match detection.map(|detection| Asset::read(detection.format, &data)) {
+                    Some(Ok(Asset::Wax(wax))) => {
+                        self.wax(&failure, &base, &wax, &pal, &mut files)?
+                    }
+                    Some(Ok(Asset::Fme(fme))) => {
+                        self.fme(&failure, &base, &fme, &pal, &mut files)?
+                    }
+                    Some(Err(error)) => self.fail(failure, error),
+                    _ => self.fail(failure, "not a WAX or FME"),
+                }
+            }
+        }
+
+        let gltf_file = format!("{}/{}.gltf", dir, archive::stem(name));
+        let buffer_name = format!("{}.bin", archive::stem(name));
+        let texture_uri = |texture: &str| format!("textures/{}.png", texture);
+        let model = gltf::level(lev, &texture_sizes, texture_uri, &buffer_name);
+        for warning in &model.warnings {
+            self.fail(source, warning);
+        }
+        self.write(&gltf_file, |output| {
+            Ok(serde_json::to_writer_pretty(output, &model.json)?)
+        })?;
+        let buffer_file = format!("{}/{}", dir, buffer_name);
+        self.write(&buffer_file, |output| output.write_all(&model.buffer))?;
+        files.push(gltf_file);
+        files.push(buffer_file);
+
+        self.manifest.exported.push(Exported {
+            source: source.to_string(),
+            files,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use formats::{gob, pcm, voc};
+
+    #[test]
+    fn export_gob() {
+        let dir = std::env::temp_dir().join(format!("df-export-{}", std::process::id()));
+        let (game_dir, output_dir) = (dir.join("game"), dir.join("output"));
+        fs::create_dir_all(&game_dir).unwrap();
+        let lev = "LEV 2.1\nLEVELNAME TEST\nPALETTE TEST.PAL\nMUSIC NULL.GMD\n\
+                   PARALLAX 1024.0000 1024.0000\nTEXTURES 0\nNUMSECTORS 0\n";
+        let mut sound = Vec::new();
+        voc::Voc::from_pcm(&pcm::Pcm {
+            data: vec![0x80; 1000],
+            ..pcm::Pcm::new_u8_mono(11_025)
+        })
+        .write(&mut sound)
+        .unwrap();
+        // 1 pixel wide, so a multiple BM.
+        let mut multiple = Vec::new();
+        bm::Bm {
+            size: mint::Vector2 { x: 1, y: 5 },
+            idem_size: mint::Vector2 { x: 1, y: 5 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data: vec![0; 5],
+        }
+        .write(&mut multiple)
+        .unwrap();
+        // No pixels, so it reads but can't be encoded as a PNG.
+        let mut empty = Vec::new();
+        bm::Bm {
+            size: mint::Vector2 { x: 0, y: 5 },
+            idem_size: mint::Vector2 { x: 0, y: 5 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data: Vec::new(),
+        }
+        .write(&mut empty)
+        .unwrap();
+        let entries: &[(&str, &[u8])] = &[
+            ("TEST.LEV", lev.as_bytes()),
+            ("SOUND.VOC", &sound),
+            ("BROKEN.BM", b"BM \x1e"),
+            ("ANIM.BM", &multiple),
+            ("EMPTY.BM", &empty),
+            ("README.TXT", b"hello"),
+            ("../ESC.TXT", b"gotcha"),
+        ];
+        gob::write(
+            fs::File::create(game_dir.join("Test.gob")).unwrap(),
+            entries,
+        )
+        .unwrap();
+
+        let manifest = export(&game_dir, &output_dir).unwrap();
+        let failed = manifest
+            .failures
+            .iter()
+            .map(|failure| failure.source.as_str());
+        assert_eq!(
+            failed.collect::<Vec<_>>(),
+            [
+                "Test.gob:BROKEN.BM",
+                "Test.gob:ANIM.BM",
+                "Test.gob:EMPTY.BM",
+                "Test.gob:../ESC.TXT",
+                "Test.gob:TEST.LEV palette TEST.PAL",
+            ]
+        );
+        assert_eq!(
+            fs::read(output_dir.join("Test.gob/README.TXT")).unwrap(),
+            b"hello"
+        );
+        assert!(output_dir.join("Test.gob/TEST.LEV.json").is_file());
+        assert!(!output_dir.join("Test.gob/EMPTY.BM.png").exists());
+        // Sounds are resampled, so the WAV header has the export rate and format.
+        let wav = fs::read(output_dir.join("Test.gob/SOUND.VOC.wav")).unwrap();
+        assert_eq!(wav[24..28], resample::EXPORT_RATE.to_le_bytes());
+        assert_eq!(wav[34..36], 16u16.to_le_bytes());
+        assert!(output_dir.join("levels/TEST/TEST.gltf").is_file());
+        assert!(output_dir.join("manifest.json").is_file());
+        assert!(!dir.join("ESC.TXT").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+use std::path::Path;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (game_dir, output_dir) = match args.as_slice() {
+        [game_dir, output_dir] => (Path::new(game_dir), Path::new(output_dir)),
+        _ => {
+            eprintln!("usage: df-export <game dir> <output dir>");
+            std::process::exit(2);
+        }
+    };
+
+    match df_export::export(game_dir, output_dir) {
+        Ok(manifest) => {
+            println!(
+                "exported {} entries, {} failures, see {}",
+                manifest.exported.len(),
+                manifest.failures.len(),
+                output_dir.join("manifest.json").display(),
+            );
+        }
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
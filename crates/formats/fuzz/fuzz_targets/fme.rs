@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::fme;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = fme::Fme::read(Cursor::new(data));
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::gmd;
+use formats::schedule::Schedule;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(gmd) = gmd::Gmd::read(data) {
+        let _ = gmd.imuse_events();
+        if let Ok(schedule) = Schedule::from_gmd(&gmd) {
+            let _ = schedule.time_to_tick(schedule.duration());
+        }
+    }
+});
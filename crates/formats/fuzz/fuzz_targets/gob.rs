@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::gob;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = gob::read(Cursor::new(data));
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::{image, pal};
+
+fuzz_target!(|data: &[u8]| {
+    let pal = pal::Pal {
+        entries: [pal::Entry::BLACK; 256],
+    };
+    let _ = image::read_png(data, &pal);
+    let _ = image::read_pal_png(data);
+});
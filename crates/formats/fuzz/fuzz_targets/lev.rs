@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::lev;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = lev::Lev::read(data);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::lfd;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = lfd::read(Cursor::new(data));
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::pal;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = pal::Pal::read(Cursor::new(data));
+});
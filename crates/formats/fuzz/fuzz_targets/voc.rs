@@ -0,0 +1,13 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::voc;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(voc) = voc::Voc::read(Cursor::new(data)) {
+        let _ = voc.decode_with_loop(3);
+    }
+});
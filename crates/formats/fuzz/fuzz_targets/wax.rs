@@ -0,0 +1,11 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+
+use formats::wax;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = wax::Wax::read(Cursor::new(data));
+});
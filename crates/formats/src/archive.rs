@@ -0,0 +1,141 @@
+//! Reads the entries of a GOB or LFD, whichever the file turns out to be.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::common::*;
+use crate::detect::{self, Format};
+use crate::{gob, lfd};
+
+pub struct Archive<R = File> {
+    pub format: Format,
+    pub catalog: Catalog,
+    // Reads only need a shared reference, so entries can be read while iterating the catalog.
+    reader: RefCell<R>,
+}
+
+impl Archive {
+    pub fn open(path: impl AsRef<Path>) -> ReadResult<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<R: io::Read + io::Seek> Archive<R> {
+    pub fn new(mut reader: R) -> ReadResult<Self> {
+        let signature = read_buf(&mut reader, [0u8; 4]).field("signature")?;
+        reader.seek(io::SeekFrom::Start(0))?;
+        let (format, catalog) = match detect::sniff(&signature).map(|detection| detection.format) {
+            Some(Format::Gob) => (Format::Gob, gob::read(&mut reader)?),
+            Some(Format::Lfd) => (Format::Lfd, lfd::read(&mut reader)?),
+            _ => return Err(ReadError::signature("GOB or LFD")),
+        };
+        Ok(Self {
+            format,
+            catalog,
+            reader: RefCell::new(reader),
+        })
+    }
+
+    /// The entry called `name`, ignoring case.
+    pub fn find(&self, name: &str) -> Option<&CatalogEntry> {
+        self.catalog
+            .entries
+            .iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn read(&self, entry: &CatalogEntry) -> ReadResult<Vec<u8>> {
+        let mut reader = self.reader.borrow_mut();
+        reader.seek(io::SeekFrom::Start(entry.offset as u64))?;
+        let data = read_vec(&mut *reader, checked_len(entry.length as u64)?)?;
+        Ok(data)
+    }
+
+    /// Reads up to the first `len` bytes of an entry, e.g. to detect its format.
+    pub fn read_prefix(&self, entry: &CatalogEntry, len: usize) -> ReadResult<Vec<u8>> {
+        let mut reader = self.reader.borrow_mut();
+        reader.seek(io::SeekFrom::Start(entry.offset as u64))?;
+        let len = (entry.length as usize).min(len);
+        let data = read_vec(&mut *reader, len)?;
+        Ok(data)
+    }
+
+    /// Reads the entry called `name`, ignoring case, if there is one.
+    pub fn read_named(&self, name: &str) -> ReadResult<Option<Vec<u8>>> {
+        self.find(name).map(|entry| self.read(entry)).transpose()
+    }
+}
+
+/// The GOB and LFD files in `dir` and its subdirectories, sorted so they are always listed in the
+/// same order.
+pub fn find(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            archives.extend(find(&path)?);
+            continue;
+        }
+        let extension = path.extension().unwrap_or_default();
+        if extension.eq_ignore_ascii_case("GOB") || extension.eq_ignore_ascii_case("LFD") {
+            archives.push(path);
+        }
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+/// Whether an entry name can be used as a file name as it is. Names come from the archive, so
+/// anything that could escape the directory it is written to is rejected.
+pub fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(&['/', '\\', ':'][..])
+}
+
+/// An entry name without its extension, e.g. `SECBASE` for `SECBASE.LEV`.
+pub fn stem(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_entries() {
+        let mut bytes = Vec::new();
+        gob::write(&mut bytes, &[("A.TXT", b"first"), ("B.TXT", b"other")]).unwrap();
+        let archive = Archive::new(io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.format, Format::Gob);
+        for entry in &archive.catalog.entries {
+            assert_eq!(archive.read(entry).unwrap().len(), 5);
+        }
+        assert_eq!(archive.read_named("b.txt").unwrap().unwrap(), b"other");
+        let entry = &archive.catalog.entries[1];
+        assert_eq!(archive.read_prefix(entry, 3).unwrap(), b"oth");
+        assert_eq!(archive.read_prefix(entry, 100).unwrap(), b"other");
+        assert!(archive.read_named("C.TXT").unwrap().is_none());
+
+        // LFD entries are read from after their header.
+        let mut bytes = b"RMAPresource\x04\0\0\0DATA".to_vec();
+        bytes.extend_from_slice(b"DELTtest\0\0\0\0\x02\0\0\0\x01\x02");
+        let archive = Archive::new(io::Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.format, Format::Lfd);
+        assert_eq!(archive.read_named("TEST.DELT").unwrap().unwrap(), [1, 2]);
+
+        assert!(Archive::new(io::Cursor::new(b"BM \x1e")).is_err());
+    }
+
+    #[test]
+    fn file_names() {
+        assert!(is_file_name("SECBASE.LEV"));
+        for name in &["", ".", "..", "../X.BM", "A/B", "A\\B", "C:X"] {
+            assert!(!is_file_name(name), "{:?}", name);
+        }
+    }
+}
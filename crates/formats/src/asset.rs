@@ -0,0 +1,88 @@
+//! Decodes a file or catalog entry of any detected format, so every tool dispatches the same
+//! way.
+
+use std::io;
+
+use crate::common::*;
+use crate::detect::{self, Detection, Format};
+use crate::{bm, fme, gmd, gob, lev, lfd, pal, voc, wax};
+
+pub enum Asset {
+    /// A GOB or LFD archive.
+    Catalog(Catalog),
+    Bm(bm::Bm),
+    Fme(fme::Fme),
+    Wax(wax::Wax),
+    Pal(Box<pal::Pal>),
+    Voc(voc::Voc),
+    /// A GMD, or a standard MIDI file wrapped as one.
+    Gmd(gmd::Gmd),
+    Lev(lev::Lev),
+    /// A text format without a parser, e.g. `O` or `INF`.
+    Text(String),
+}
+
+impl Asset {
+    /// Decodes `data` as `format`.
+    pub fn read(format: Format, data: &[u8]) -> ReadResult<Self> {
+        let cursor = io::Cursor::new(data);
+        Ok(match format {
+            Format::Gob => Self::Catalog(gob::read(cursor)?),
+            Format::Lfd => Self::Catalog(lfd::read(cursor)?),
+            Format::Bm => Self::Bm(bm::Bm::read(cursor)?),
+            Format::Fme => Self::Fme(fme::Fme::read(cursor)?),
+            Format::Wax => Self::Wax(wax::Wax::read(cursor)?),
+            Format::Pal => Self::Pal(Box::new(pal::Pal::read(cursor)?)),
+            Format::Voc => Self::Voc(voc::Voc::read(cursor)?),
+            Format::Gmd => Self::Gmd(gmd::Gmd::read(cursor)?),
+            Format::Mid => Self::Gmd(gmd::Gmd::from_mid(data.to_vec())?),
+            Format::Lev => Self::Lev(lev::Lev::read(cursor)?),
+            Format::O | Format::Inf | Format::Gol | Format::Msg | Format::ThreeDo => {
+                Self::Text(String::from_utf8_lossy(data).into_owned())
+            }
+        })
+    }
+
+    /// Detects the format of `data` named `name` and decodes it, or `None` if the format is
+    /// not recognized.
+    pub fn detect_and_read(name: &str, data: &[u8]) -> ReadResult<Option<(Detection, Self)>> {
+        match detect::detect(name, data) {
+            Some(detection) => Ok(Some((detection, Self::read(detection.format, data)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect::Confidence;
+
+    #[test]
+    fn detect_and_read() {
+        let bm = bm::Bm {
+            size: mint::Vector2 { x: 2, y: 1 },
+            idem_size: mint::Vector2 { x: 2, y: 1 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data: vec![1, 2],
+        };
+        let mut data = Vec::new();
+        bm.write(&mut data).unwrap();
+
+        match Asset::detect_and_read("TEST.DELT", &data).unwrap() {
+            Some((detection, Asset::Bm(bm))) => {
+                assert_eq!(detection.confidence, Confidence::Signature);
+                assert_eq!(bm.data, [1, 2]);
+            }
+            _ => panic!("not detected as a BM"),
+        }
+
+        assert!(Asset::detect_and_read("TEST.TXT", b"text")
+            .unwrap()
+            .is_none());
+        // Named as a format, but not readable as one.
+        assert!(Asset::detect_and_read("TEST.VOC", b"text").is_err());
+    }
+}
@@ -2,12 +2,14 @@ use crate::common::*;
 use std::io;
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Compression {
     None,
     Rle1,
     Rle0,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bm {
     pub size: mint::Vector2<u16>,
     pub idem_size: mint::Vector2<u16>,
@@ -19,22 +21,32 @@ pub struct Bm {
 
 impl Bm {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
+        Self::read_bm(&mut file).map_err(|error| error.locate("BM", &mut file))
+    }
+
+    fn read_bm(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
         if &read_buf(&mut file, [0u8; 4])? != b"BM \x1e" {
-            return Err(ReadError::Signature);
+            return Err(ReadError::signature("BM"));
         }
 
-        let size = read_vec2_u16(&mut file)?;
-        let idem_size = read_vec2_u16(&mut file)?;
-        let flags = read_u8(&mut file)?;
-        let log_size_y = read_u8(&mut file)? != 0;
-        let compression = match read_u8(&mut file)? {
+        let size = read_vec2_u16(&mut file).field("size")?;
+        let idem_size = read_vec2_u16(&mut file).field("idem size")?;
+        let flags = read_u8(&mut file).field("flags")?;
+        let log_size_y = read_u8(&mut file).field("log size y")? != 0;
+        let compression = match read_u8(&mut file).field("compression")? {
             0 => Compression::None,
             1 => Compression::Rle1,
             2 => Compression::Rle0,
-            _ => return Err(ReadError::Decoding("invalid compression")),
+            value => {
+                return Err(
+                    ReadError::decoding("BM", format!("invalid compression {}", value))
+                        .at_offset(14)
+                        .with_field("compression"),
+                )
+            }
         };
         read_u8(&mut file)?; // padding
-        let data_size = read_u32(&mut file)?;
+        let data_size = read_u32(&mut file).field("data size")?;
         file.seek(io::SeekFrom::Current(12))?;
 
         if size.x != 1 || size.y == 1 {
@@ -44,8 +56,8 @@ impl Bm {
             };
             let columns = match compression {
                 Compression::None => {
-                    let mut columns = vec![0u8; size.x as usize * size.y as usize];
-                    file.read_exact(&mut columns)?;
+                    let mut columns = vec![0u8; image_len(size_u32).field("size")?];
+                    file.read_exact(&mut columns).field("pixels")?;
                     columns
                 }
                 Compression::Rle1 => {
@@ -58,7 +70,7 @@ impl Bm {
                 }
             };
 
-            let data = columns_to_rows(size_u32, columns);
+            let data = columns_to_rows(size_u32, columns)?;
 
             Ok(Bm {
                 size,
@@ -69,15 +81,33 @@ impl Bm {
                 data,
             })
         } else {
-            eprintln!("multiple unimplemented");
-            Ok(Bm {
-                size: mint::Vector2 { x: 1, y: 1 },
-                idem_size,
-                flags,
-                log_size_y,
-                compression,
-                data: vec![1],
-            })
+            // A 1 pixel wide BM holds several, e.g. an animated texture.
+            Err(ReadError::decoding("BM", "multiple BMs are not supported").with_field("size"))
         }
     }
+
+    /// Writes an uncompressed BM, regardless of the original `compression`.
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        let log_size_y = if self.log_size_y && self.size.y.is_power_of_two() {
+            self.size.y.trailing_zeros() as u8
+        } else {
+            0
+        };
+        let size_u32 = mint::Vector2 {
+            x: self.size.x as u32,
+            y: self.size.y as u32,
+        };
+        let columns = rows_to_columns(size_u32, &self.data)?;
+
+        output.write_all(b"BM \x1e")?;
+        output.write_all(&self.size.x.to_le_bytes())?;
+        output.write_all(&self.size.y.to_le_bytes())?;
+        output.write_all(&self.idem_size.x.to_le_bytes())?;
+        output.write_all(&self.idem_size.y.to_le_bytes())?;
+        output.write_all(&[self.flags, log_size_y, 0, 0])?;
+        output.write_all(&(columns.len() as u32).to_le_bytes())?;
+        output.write_all(&[0u8; 12])?;
+        output.write_all(&columns)?;
+        Ok(())
+    }
 }
@@ -37,6 +37,24 @@ pub fn read_vec2<R: io::Read, T>(
 
 pub type Vec2<T> = mint::Vector2<T>;
 
+/// The most a reader allocates for any one buffer. Far more than the game's data needs, so a
+/// hostile length fails to read rather than exhausting memory.
+pub const MAX_ALLOCATION: usize = 64 << 20;
+
+/// Checks a length read from a file against `MAX_ALLOCATION`.
+pub fn checked_len(len: u64) -> ReadResult<usize> {
+    if len > MAX_ALLOCATION as u64 {
+        let message = format!("length {} is over the allocation limit", len);
+        return Err(ReadError::invalid_data(message));
+    }
+    Ok(len as usize)
+}
+
+/// The length of an image of `size` with a byte per pixel, checked against `MAX_ALLOCATION`.
+pub fn image_len(size: mint::Vector2<u32>) -> ReadResult<usize> {
+    checked_len(size.x as u64 * size.y as u64)
+}
+
 macro_rules! vec2_subtype {
     ($p: ty, $read_vec: ident, $read_p: ident) => {
         pub fn $read_vec(input: impl io::Read) -> io::Result<mint::Vector2<$p>> {
@@ -49,12 +67,22 @@ vec2_subtype!(u16, read_vec2_u16, read_u16);
 vec2_subtype!(i32, read_vec2_i32, read_i32);
 vec2_subtype!(u32, read_vec2_u32, read_u32);
 
+/// A catalog entry name from its NUL padded bytes.
+pub fn entry_name(raw_name: &[u8]) -> ReadResult<String> {
+    let name = raw_name.split(|&c| c == 0).next().unwrap_or_default();
+    String::from_utf8(name.to_vec()).map_err(|_| ReadError::invalid_data("name is not text"))
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Catalog {
     pub entries: Vec<CatalogEntry>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CatalogEntry {
     pub name: String,
+    /// Where the entry's data starts in the archive. For LFD entries this is after the entry's
+    /// 16 byte header, as for GOB entries, so either can be read from here for `length` bytes.
     pub offset: u32,
     pub length: u32,
 }
@@ -64,22 +92,21 @@ pub fn rle0(
     offset: u32,
     size: mint::Vector2<u32>,
 ) -> ReadResult<Vec<u8>> {
-    let mut columns = Vec::with_capacity((size.x * size.y) as usize);
-    let mut column_offsets = Vec::new();
-
-    for _ in 0..size.x {
-        column_offsets.push(offset + read_u32(&mut file)?);
-    }
+    let len = image_len(size)?;
+    let column_offsets = read_column_offsets(&mut file, offset, size)?;
+    let mut columns = Vec::with_capacity(len);
 
     let mut buffer = [0u8; 128];
     for offset in column_offsets {
-        file.seek(io::SeekFrom::Start(offset as u64))?;
+        file.seek(io::SeekFrom::Start(offset))?;
         let mut unpacked_bytes = 0;
         while unpacked_bytes < size.y {
             let mut control_byte = 0u8;
-            file.read_exact(std::slice::from_mut(&mut control_byte))?;
+            file.read_exact(std::slice::from_mut(&mut control_byte))
+                .field("columns")?;
             if control_byte <= 128 {
-                let column = read_buf(&mut file, &mut buffer[0..control_byte as usize])?;
+                let column =
+                    read_buf(&mut file, &mut buffer[0..control_byte as usize]).field("columns")?;
                 columns.extend_from_slice(column);
             } else {
                 control_byte -= 128;
@@ -91,8 +118,9 @@ pub fn rle0(
         }
     }
 
-    if columns.len() != (size.x * size.y) as usize {
-        return Err(ReadError::Decoding("RLE0 decoded size did not match"));
+    if columns.len() != len {
+        let message = "RLE0 decoded size did not match";
+        return Err(ReadError::invalid_data(message).with_field("columns"));
     }
 
     Ok(columns)
@@ -103,25 +131,24 @@ pub fn rle1(
     offset: u32,
     size: mint::Vector2<u32>,
 ) -> ReadResult<Vec<u8>> {
-    let mut columns = Vec::with_capacity((size.x * size.y) as usize);
-    let mut column_offsets = Vec::new();
-
-    for _ in 0..size.x {
-        column_offsets.push(offset + read_u32(&mut file)?);
-    }
+    let len = image_len(size)?;
+    let column_offsets = read_column_offsets(&mut file, offset, size)?;
+    let mut columns = Vec::with_capacity(len);
 
     let mut buffer = [0u8; 128];
     for offset in column_offsets {
-        file.seek(io::SeekFrom::Start(offset as u64))?;
+        file.seek(io::SeekFrom::Start(offset))?;
         let mut unpacked_bytes = 0;
         while unpacked_bytes < size.y {
             let mut control_byte = 0u8;
-            file.read_exact(std::slice::from_mut(&mut control_byte))?;
+            file.read_exact(std::slice::from_mut(&mut control_byte))
+                .field("columns")?;
             if control_byte < 128 {
-                let column = read_buf(&mut file, &mut buffer[0..control_byte as usize])?;
+                let column =
+                    read_buf(&mut file, &mut buffer[0..control_byte as usize]).field("columns")?;
                 columns.extend_from_slice(column);
             } else {
-                let data_byte = read_u8(&mut file)?;
+                let data_byte = read_u8(&mut file).field("columns")?;
                 control_byte -= 128; // including 0 bytes
                 for _ in 0..control_byte {
                     columns.push(data_byte);
@@ -131,15 +158,33 @@ pub fn rle1(
         }
     }
 
-    if columns.len() != (size.x * size.y) as usize {
-        return Err(ReadError::Decoding("RLE1 decoded size did not match"));
+    if columns.len() != len {
+        let message = "RLE1 decoded size did not match";
+        return Err(ReadError::invalid_data(message).with_field("columns"));
     }
 
     Ok(columns)
 }
 
-pub fn columns_to_rows(size: mint::Vector2<u32>, columns: Vec<u8>) -> Vec<u8> {
-    assert_eq!((size.x * size.y) as usize, columns.len());
+fn read_column_offsets(
+    mut file: impl io::Read,
+    offset: u32,
+    size: mint::Vector2<u32>,
+) -> ReadResult<Vec<u64>> {
+    let mut column_offsets = Vec::new();
+    for _ in 0..size.x {
+        let column_offset = read_u32(&mut file).field("column offsets")?;
+        column_offsets.push(offset as u64 + column_offset as u64);
+    }
+    Ok(column_offsets)
+}
+
+pub fn columns_to_rows(size: mint::Vector2<u32>, columns: Vec<u8>) -> ReadResult<Vec<u8>> {
+    if image_len(size)? != columns.len() {
+        return Err(ReadError::invalid_data(
+            "image size does not match its data",
+        ));
+    }
     // data is in columns, bottom to top, not rows. Transpose it.
     let mut data = Vec::with_capacity(columns.len());
     for y in 0..size.y as usize {
@@ -147,5 +192,22 @@ pub fn columns_to_rows(size: mint::Vector2<u32>, columns: Vec<u8>) -> Vec<u8> {
             data.push(columns[x * size.y as usize + size.y as usize - y - 1]);
         }
     }
-    data
+    Ok(data)
+}
+
+pub fn rows_to_columns(size: mint::Vector2<u32>, rows: &[u8]) -> io::Result<Vec<u8>> {
+    if size.x as u64 * size.y as u64 != rows.len() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "image size does not match its data",
+        ));
+    }
+    // inverse of columns_to_rows(): columns, bottom to top.
+    let mut data = Vec::with_capacity(rows.len());
+    for x in 0..size.x as usize {
+        for y in (0..size.y as usize).rev() {
+            data.push(rows[y * size.x as usize + x]);
+        }
+    }
+    Ok(data)
 }
@@ -0,0 +1,371 @@
+//! Recognizes formats from content, so catalog entries with synthesized names such as
+//! `NAME.DELT`, misnamed files and loose mod files are read the same way as the originals.
+
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::common::MAX_ALLOCATION;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Gob,
+    Lfd,
+    Bm,
+    Fme,
+    Wax,
+    Pal,
+    Voc,
+    Gmd,
+    /// A standard MIDI file.
+    Mid,
+    Lev,
+    /// Object placement text.
+    O,
+    /// Level logic text.
+    Inf,
+    /// Mission goals text.
+    Gol,
+    /// Message text.
+    Msg,
+    /// 3D object text.
+    ThreeDo,
+}
+
+impl Format {
+    pub const ALL: [Format; 15] = [
+        Format::Gob,
+        Format::Lfd,
+        Format::Bm,
+        Format::Fme,
+        Format::Wax,
+        Format::Pal,
+        Format::Voc,
+        Format::Gmd,
+        Format::Mid,
+        Format::Lev,
+        Format::O,
+        Format::Inf,
+        Format::Gol,
+        Format::Msg,
+        Format::ThreeDo,
+    ];
+
+    /// The usual file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gob => "GOB",
+            Self::Lfd => "LFD",
+            Self::Bm => "BM",
+            Self::Fme => "FME",
+            Self::Wax => "WAX",
+            Self::Pal => "PAL",
+            Self::Voc => "VOC",
+            Self::Gmd => "GMD",
+            Self::Mid => "MID",
+            Self::Lev => "LEV",
+            Self::O => "O",
+            Self::Inf => "INF",
+            Self::Gol => "GOL",
+            Self::Msg => "MSG",
+            Self::ThreeDo => "3DO",
+        }
+    }
+
+    /// The format with `extension`, ignoring case. LFD entry types are included, e.g. `VOIC`.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let extension = extension.to_ascii_uppercase();
+        match extension.as_str() {
+            "VOIC" => return Some(Self::Voc),
+            "GMID" => return Some(Self::Gmd),
+            "MIDI" => return Some(Self::Mid),
+            _ => {}
+        }
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|format| format.extension() == extension)
+    }
+
+    /// The format suggested by the extension of `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+        Self::from_extension(extension)
+    }
+
+    /// Whether the format is line based text.
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            Self::Lev | Self::O | Self::Inf | Self::Gol | Self::Msg | Self::ThreeDo
+        )
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// How sure a detection is, from least to most.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    /// Only the name suggests the format, the content is not recognizable on its own.
+    Name,
+    /// The content is consistent with a format that has no signature.
+    Structure,
+    /// The content starts with the format's signature.
+    Signature,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Detection {
+    pub format: Format,
+    pub confidence: Confidence,
+}
+
+/// Detects the format of `data` from its content, falling back to the extension of `name`.
+///
+/// The content wins when the two disagree, so a BM named `.FME` is a BM.
+pub fn detect(name: &str, data: &[u8]) -> Option<Detection> {
+    detect_prefix(name, data, data.len())
+}
+
+/// How much of a file `detect_prefix()` needs to recognize the usual layouts of every format.
+pub const PREFIX_LEN: usize = 4096;
+
+/// Detects the format of a file `len` bytes long from the start of its content, `prefix`, so
+/// large catalog entries can be listed without reading them in full.
+///
+/// Checks that need more than the prefix fail, falling back to the name.
+pub fn detect_prefix(name: &str, prefix: &[u8], len: usize) -> Option<Detection> {
+    let prefix = &prefix[..prefix.len().min(len)];
+    let by_name = Format::from_name(name);
+    if let Some(format) = signature(prefix) {
+        return Some(Detection {
+            format,
+            confidence: Confidence::Signature,
+        });
+    }
+    // The structural checks can mistake one signature-less format for another, so try the
+    // named one first.
+    let structural = by_name
+        .filter(|&format| structure(format, prefix, len))
+        .or_else(|| {
+            STRUCTURAL
+                .iter()
+                .copied()
+                .find(|&format| structure(format, prefix, len))
+        });
+    if let Some(format) = structural {
+        return Some(Detection {
+            format,
+            confidence: Confidence::Structure,
+        });
+    }
+    by_name.map(|format| Detection {
+        format,
+        confidence: Confidence::Name,
+    })
+}
+
+/// Detects the format of `data` from its content alone.
+pub fn sniff(data: &[u8]) -> Option<Detection> {
+    detect("", data)
+}
+
+/// Formats without a signature, most distinctive first.
+const STRUCTURAL: [Format; 3] = [Format::Pal, Format::Wax, Format::Fme];
+
+fn signature(data: &[u8]) -> Option<Format> {
+    const SIGNATURES: [(&[u8], Format); 6] = [
+        (b"GOB\n", Format::Gob),
+        (b"BM \x1e", Format::Bm),
+        (b"Creative Voice File\x1a", Format::Voc),
+        (b"MIDI", Format::Gmd),
+        (b"MThd\0\0\0\x06", Format::Mid),
+        // LFD files start with a resource map entry.
+        (b"RMAP", Format::Lfd),
+    ];
+    if let Some(&(_, format)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| data.starts_with(signature))
+    {
+        return Some(format);
+    }
+    text_header(data)
+}
+
+/// The format named by the first token of a text file, e.g. `LEV 2.1`, after any comments.
+fn text_header(data: &[u8]) -> Option<Format> {
+    let text = &data[..data.len().min(1024)];
+    let line = text
+        .split(|&c| c == b'\n')
+        .map(trim_start)
+        .find(|line| !line.is_empty() && !line.starts_with(b"#"))?;
+    let mut tokens = line
+        .split(|c| c.is_ascii_whitespace())
+        .filter(|token| !token.is_empty());
+    let format = match tokens.next()? {
+        b"LEV" => Format::Lev,
+        b"O" => Format::O,
+        b"INF" => Format::Inf,
+        b"GOL" => Format::Gol,
+        b"MSG" => Format::Msg,
+        b"3DO" => Format::ThreeDo,
+        _ => return None,
+    };
+    // A version number, e.g. `1.1`.
+    let version = tokens.next()?;
+    if version.iter().all(|&c| c.is_ascii_digit() || c == b'.') && version[0].is_ascii_digit() {
+        Some(format)
+    } else {
+        None
+    }
+}
+
+fn trim_start(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|c| !c.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    &line[start..]
+}
+
+/// Whether the start of a file `len` bytes long, `data`, is consistent with `format`.
+fn structure(format: Format, data: &[u8], len: usize) -> bool {
+    match format {
+        // 256 VGA DAC entries, 6 bits per channel.
+        Format::Pal => len == 256 * 3 && data.len() == len && data.iter().all(|&value| value < 64),
+        Format::Fme => fme_structure(data, len),
+        Format::Wax => wax_structure(data, len),
+        _ => false,
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// A frame header whose cell header fits in the file and describes a plausible cell.
+fn fme_structure(data: &[u8], len: usize) -> bool {
+    let check = || {
+        let flip = u32_at(data, 8)?;
+        let cell_offset = u32_at(data, 12)? as usize;
+        Some(flip <= 1 && cell_offset >= 32 && cell_fits(data, cell_offset, len)?)
+    };
+    check().unwrap_or(false)
+}
+
+fn cell_fits(data: &[u8], offset: usize, len: usize) -> Option<bool> {
+    let width = u32_at(data, offset)? as usize;
+    let height = u32_at(data, offset + 4)? as usize;
+    let compressed = u32_at(data, offset + 8)?;
+    let data_offset = u32_at(data, offset + 16)?;
+    if width == 0 || height == 0 || compressed > 1 || data_offset != 0 {
+        return Some(false);
+    }
+    let pixels = width.checked_mul(height)?;
+    let cell_len = if compressed == 0 {
+        pixels
+    } else {
+        // At least the column offsets.
+        width.checked_mul(4)?
+    };
+    Some(pixels <= MAX_ALLOCATION && offset.checked_add(24 + cell_len)? <= len)
+}
+
+/// Plausible counts and state offsets within the file, the first of which points at a state
+/// with sequence offsets within the file.
+fn wax_structure(data: &[u8], len: usize) -> bool {
+    let check = || {
+        let counts = [u32_at(data, 4)?, u32_at(data, 8)?, u32_at(data, 12)?];
+        if counts
+            .iter()
+            .any(|&count| count == 0 || count > 32 * 32 * 32)
+        {
+            return Some(false);
+        }
+        let first_state = u32_at(data, 32)? as usize;
+        if first_state < 32 + 32 * 4 {
+            return Some(false);
+        }
+        for angle in 0..32 {
+            let sequence = u32_at(data, first_state + 28 + angle * 4)? as usize;
+            if sequence == 0 || sequence >= len {
+                return Some(false);
+            }
+        }
+        Some(true)
+    };
+    check().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_formats() {
+        let detection = detect("NAME.DELT", b"BM \x1e\x01\0");
+        assert_eq!(
+            detection,
+            Some(Detection {
+                format: Format::Bm,
+                confidence: Confidence::Signature,
+            })
+        );
+
+        let lev = b"# a mod level\r\n\r\nLEV 2.1\r\nLEVELNAME TEST\r\n";
+        assert_eq!(sniff(lev).unwrap().format, Format::Lev);
+        assert_eq!(sniff(b"O 1.1\nLEVELNAME TEST").unwrap().format, Format::O);
+        assert_eq!(sniff(b"INF 1.0\n").unwrap().format, Format::Inf);
+        assert_eq!(sniff(b"Once upon a time"), None);
+
+        let pal = [32u8; 768];
+        assert_eq!(
+            detect("COLORS.DAT", &pal),
+            Some(Detection {
+                format: Format::Pal,
+                confidence: Confidence::Structure,
+            })
+        );
+        assert_eq!(sniff(&[255u8; 768]), None);
+
+        let mut fme = Vec::new();
+        crate::fme::Fme {
+            frame: crate::fme::Frame {
+                offset: mint::Vector2 { x: 0, y: 0 },
+                flip: false,
+            },
+            cell: crate::fme::Cell {
+                size: mint::Vector2 { x: 2, y: 3 },
+                data: vec![1; 6],
+            },
+        }
+        .write(&mut fme)
+        .unwrap();
+        assert_eq!(sniff(&fme).unwrap().format, Format::Fme);
+        // Too short for the cell.
+        assert_eq!(sniff(&fme[..fme.len() - 1]), None);
+        // Only the headers are needed with the full length.
+        let detection = detect_prefix("", &fme[..32 + 24], fme.len()).unwrap();
+        assert_eq!(detection.format, Format::Fme);
+        assert_eq!(
+            detect_prefix("COLORS.PAL", &pal[..64], 768)
+                .unwrap()
+                .confidence,
+            Confidence::Name
+        );
+
+        assert_eq!(
+            detect("music.gmid", b"garbage"),
+            Some(Detection {
+                format: Format::Gmd,
+                confidence: Confidence::Name,
+            })
+        );
+        assert_eq!(detect("README", b"garbage"), None);
+    }
+}
@@ -0,0 +1,743 @@
+//! Compares two archives, or an archive and a directory, entry by entry.
+//!
+//! Changed levels are compared field by field, objects and INF items record by record, and
+//! images pixel by pixel, so a reviewer can see what an update touched without opening it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::archive::Archive;
+use crate::asset::Asset;
+use crate::common::*;
+use crate::detect::{self, Format};
+use crate::{lev, pal};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug)]
+pub struct Difference {
+    pub name: String,
+    pub change: Change,
+    pub details: Details,
+}
+
+#[derive(Debug)]
+pub enum Details {
+    /// Added, removed, or not a format compared in detail.
+    None,
+    /// Sectors and walls, objects or INF items, or palette colours.
+    Structure(Vec<ItemChange>),
+    /// One per image of a BM or FME, or per cell of a WAX.
+    Pixels(Vec<PixelDiff>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ItemChange {
+    /// e.g. `sector 3 wall 2`, `object 14 (SPRITE)` or `ITEM: SECTOR NAME: elev1`.
+    pub item: String,
+    pub change: Change,
+    /// The fields that changed, for changed items.
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: String,
+    /// Empty when the field was added.
+    pub old: String,
+    /// Empty when the field was removed.
+    pub new: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PixelDiff {
+    pub cell: usize,
+    pub old_size: Option<mint::Vector2<u32>>,
+    pub new_size: Option<mint::Vector2<u32>>,
+    /// Pixels that differ, or every pixel of the new image if the sizes differ.
+    pub changed: usize,
+    /// The smallest and largest corner of the changed pixels, inclusive.
+    pub bounds: Option<(mint::Point2<u32>, mint::Point2<u32>)>,
+    /// Whether each pixel of the new image changed, in rows, if the sizes match.
+    pub mask: Option<Vec<bool>>,
+}
+
+/// Reads the entries of an archive, or the files in a directory, which can be compared with an
+/// archive before it is packed.
+pub fn read_entries(path: &Path) -> ReadResult<Vec<(String, Vec<u8>)>> {
+    let mut entries = Vec::new();
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                entries.push((name, fs::read(entry.path())?));
+            }
+        }
+    } else {
+        let archive = Archive::open(path)?;
+        for entry in &archive.catalog.entries {
+            entries.push((entry.name.clone(), archive.read(entry)?));
+        }
+    }
+    Ok(entries)
+}
+
+/// How `new` differs from `old`, matching entries by name ignoring case, sorted by name.
+pub fn compare(old: &[(String, Vec<u8>)], new: &[(String, Vec<u8>)]) -> Vec<Difference> {
+    fn by_name(entries: &[(String, Vec<u8>)]) -> BTreeMap<String, &(String, Vec<u8>)> {
+        let entries = entries.iter();
+        entries
+            .map(|entry| (entry.0.to_ascii_uppercase(), entry))
+            .collect()
+    }
+    let (old, new) = (by_name(old), by_name(new));
+
+    let mut differences = Vec::new();
+    for (key, (name, new_data)) in &new {
+        let (change, details) = match old.get(key) {
+            None => (Change::Added, Details::None),
+            Some((_, old_data)) if old_data != new_data => {
+                (Change::Changed, compare_data(name, old_data, new_data))
+            }
+            Some(_) => continue,
+        };
+        differences.push(Difference {
+            name: name.to_string(),
+            change,
+            details,
+        });
+    }
+    for (key, (name, _)) in &old {
+        if !new.contains_key(key) {
+            differences.push(Difference {
+                name: name.to_string(),
+                change: Change::Removed,
+                details: Details::None,
+            });
+        }
+    }
+    differences.sort_by(|a, b| {
+        a.name
+            .to_ascii_uppercase()
+            .cmp(&b.name.to_ascii_uppercase())
+    });
+    differences
+}
+
+/// Compares two versions of the entry `name` in detail, if both decode as the same format.
+pub fn compare_data(name: &str, old: &[u8], new: &[u8]) -> Details {
+    let format = match (detect::detect(name, old), detect::detect(name, new)) {
+        (Some(old), Some(new)) if old.format == new.format => old.format,
+        _ => return Details::None,
+    };
+    match format {
+        Format::O => return Details::Structure(compare_records(old, new, "CLASS:", "object")),
+        Format::Inf => return Details::Structure(compare_records(old, new, "ITEM:", "")),
+        _ => {}
+    }
+    match (Asset::read(format, old), Asset::read(format, new)) {
+        (Ok(Asset::Lev(old)), Ok(Asset::Lev(new))) => {
+            Details::Structure(compare_levels(&old, &new))
+        }
+        (Ok(Asset::Pal(old)), Ok(Asset::Pal(new))) => {
+            Details::Structure(compare_palettes(&old, &new))
+        }
+        (Ok(old), Ok(new)) => match (images(old), images(new)) {
+            (Some(old), Some(new)) => Details::Pixels(compare_images(&old, &new)),
+            _ => Details::None,
+        },
+        _ => Details::None,
+    }
+}
+
+/// Collects changed fields of one item.
+struct Fields(Vec<FieldChange>);
+
+impl Fields {
+    fn compare<T: PartialEq>(&mut self, field: &str, old: T, new: T, show: impl Fn(T) -> String) {
+        if old != new {
+            self.0.push(FieldChange {
+                field: field.to_string(),
+                old: show(old),
+                new: show(new),
+            });
+        }
+    }
+
+    /// Adds the item to `changes` if any of its fields changed.
+    fn finish(self, item: String, changes: &mut Vec<ItemChange>) {
+        if !self.0.is_empty() {
+            changes.push(ItemChange {
+                item,
+                change: Change::Changed,
+                fields: self.0,
+            });
+        }
+    }
+}
+
+fn show_index(index: Option<usize>) -> String {
+    index.map_or_else(|| "none".to_string(), |index| index.to_string())
+}
+
+fn show_texture((index, x, y): (Option<usize>, f32, f32)) -> String {
+    format!("{} at {}, {}", show_index(index), x, y)
+}
+
+fn texture(texture: &lev::Texture) -> (Option<usize>, f32, f32) {
+    (texture.index, texture.offset.x, texture.offset.y)
+}
+
+fn show_flags((a, b, c): (u32, u32, u32)) -> String {
+    format!("{} {} {}", a, b, c)
+}
+
+/// Calls `compare` for each index in both lists and reports the rest as added or removed.
+fn compare_lists<T>(
+    old: &[T],
+    new: &[T],
+    item: impl Fn(usize) -> String,
+    changes: &mut Vec<ItemChange>,
+    mut compare: impl FnMut(usize, &T, &T, &mut Vec<ItemChange>),
+) {
+    for index in 0..old.len().max(new.len()) {
+        let change = match (old.get(index), new.get(index)) {
+            (Some(old), Some(new)) => {
+                compare(index, old, new, changes);
+                continue;
+            }
+            (None, _) => Change::Added,
+            (_, None) => Change::Removed,
+        };
+        changes.push(ItemChange {
+            item: item(index),
+            change,
+            fields: Vec::new(),
+        });
+    }
+}
+
+fn compare_levels(old: &lev::Lev, new: &lev::Lev) -> Vec<ItemChange> {
+    let mut changes = Vec::new();
+
+    let mut fields = Fields(Vec::new());
+    fields.compare(
+        "palette",
+        &old.palette_name,
+        &new.palette_name,
+        String::clone,
+    );
+    let parallax = |lev: &lev::Lev| (lev.parallax.x, lev.parallax.y);
+    let show_pair = |(x, y): (f32, f32)| format!("{}, {}", x, y);
+    fields.compare("parallax", parallax(old), parallax(new), show_pair);
+    for index in 0..old.texture_names.len().max(new.texture_names.len()) {
+        let name = |lev: &lev::Lev| lev.texture_names.get(index).cloned().unwrap_or_default();
+        fields.compare(
+            &format!("texture {}", index),
+            name(old),
+            name(new),
+            |name| name,
+        );
+    }
+    fields.finish("level".to_string(), &mut changes);
+
+    let sector_item = |index| format!("sector {}", index);
+    compare_lists(
+        &old.sectors,
+        &new.sectors,
+        sector_item,
+        &mut changes,
+        |index, old, new, changes| {
+            let mut fields = Fields(Vec::new());
+            fields.compare("name", &old.name, &new.name, |name| {
+                name.clone().unwrap_or_default()
+            });
+            fields.compare("ambient", old.ambient, new.ambient, |value| {
+                value.to_string()
+            });
+            let floor = (texture(&old.floor_texture), texture(&new.floor_texture));
+            fields.compare("floor texture", floor.0, floor.1, show_texture);
+            let (old_floor, new_floor) = (old.floor_altitude, new.floor_altitude);
+            fields.compare("floor altitude", old_floor, new_floor, |value| {
+                value.to_string()
+            });
+            let ceiling = (texture(&old.ceiling_texture), texture(&new.ceiling_texture));
+            fields.compare("ceiling texture", ceiling.0, ceiling.1, show_texture);
+            let (old_ceiling, new_ceiling) = (old.ceiling_altitude, new.ceiling_altitude);
+            fields.compare("ceiling altitude", old_ceiling, new_ceiling, |value| {
+                value.to_string()
+            });
+            let (old_second, new_second) = (old.second_altitude, new.second_altitude);
+            fields.compare("second altitude", old_second, new_second, |value| {
+                value.to_string()
+            });
+            fields.compare("flags", old.flags, new.flags, show_flags);
+            fields.compare("layer", old.layer, new.layer, |value| value.to_string());
+            for vertex in 0..old.vertices.len().max(new.vertices.len()) {
+                let point = |sector: &lev::Sector| {
+                    let point = sector.vertices.get(vertex)?;
+                    Some((point.x, point.y))
+                };
+                let show = |point: Option<(f32, f32)>| point.map(show_pair).unwrap_or_default();
+                fields.compare(&format!("vertex {}", vertex), point(old), point(new), show);
+            }
+            fields.finish(sector_item(index), changes);
+
+            let wall_item = |wall| format!("sector {} wall {}", index, wall);
+            compare_lists(
+                &old.walls,
+                &new.walls,
+                wall_item,
+                changes,
+                |wall, old, new, changes| {
+                    let mut fields = Fields(Vec::new());
+                    let show = |value: usize| value.to_string();
+                    fields.compare("left vertex", old.left_vertex, new.left_vertex, show);
+                    fields.compare("right vertex", old.right_vertex, new.right_vertex, show);
+                    let textures = [
+                        ("middle texture", &old.middle_texture, &new.middle_texture),
+                        ("top texture", &old.top_texture, &new.top_texture),
+                        ("bottom texture", &old.bottom_texture, &new.bottom_texture),
+                        ("sign texture", &old.sign_texture, &new.sign_texture),
+                    ];
+                    for (field, old, new) in textures.iter() {
+                        fields.compare(field, texture(old), texture(new), show_texture);
+                    }
+                    fields.compare("adjoin", old.adjoin_sector, new.adjoin_sector, show_index);
+                    fields.compare("mirror", old.mirror_wall, new.mirror_wall, show_index);
+                    fields.compare("walk", old.walk_sector, new.walk_sector, show_index);
+                    fields.compare("flags", old.flags, new.flags, show_flags);
+                    fields.compare("light", old.light, new.light, |value| value.to_string());
+                    fields.finish(wall_item(wall), changes);
+                },
+            );
+        },
+    );
+    changes
+}
+
+fn compare_palettes(old: &pal::Pal, new: &pal::Pal) -> Vec<ItemChange> {
+    let mut fields = Fields(Vec::new());
+    for (index, (old, new)) in old.entries.iter().zip(new.entries.iter()).enumerate() {
+        let rgb = |entry: &pal::Entry| entry.to_rgb();
+        let show = |(r, g, b): (u8, u8, u8)| format!("#{:02x}{:02x}{:02x}", r, g, b);
+        fields.compare(&format!("colour {}", index), rgb(old), rgb(new), show);
+    }
+    let mut changes = Vec::new();
+    fields.finish("palette".to_string(), &mut changes);
+    changes
+}
+
+/// The text of an `O` or `INF` file split into records, each starting with a line beginning
+/// with `marker`, after the header. Comments, blank lines and extra spaces are dropped.
+fn records(data: &[u8], marker: &str) -> (Vec<String>, Vec<Vec<String>>) {
+    let text = String::from_utf8_lossy(data);
+    let mut header = Vec::new();
+    let mut records: Vec<Vec<String>> = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            continue;
+        }
+        let start = line.get(..marker.len()).unwrap_or_default();
+        let starts_record = start.eq_ignore_ascii_case(marker);
+        match records.last_mut() {
+            _ if starts_record => records.push(vec![line]),
+            Some(record) => record.push(line),
+            None => header.push(line),
+        }
+    }
+    (header, records)
+}
+
+/// The `KEY: value` pairs of a record's first line, e.g. `CLASS: SPRITE DATA: 0 X: 1.5`.
+fn pairs(line: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for token in line.split(' ') {
+        match pairs.last_mut() {
+            _ if token.ends_with(':') => pairs.push((token.to_ascii_uppercase(), String::new())),
+            Some((_, value)) => {
+                if !value.is_empty() {
+                    value.push(' ');
+                }
+                value.push_str(token);
+            }
+            None => pairs.push((String::new(), token.to_string())),
+        }
+    }
+    pairs
+}
+
+/// Compares the fields of the first lines, and the rest of the records line by line.
+fn compare_record(old: &[String], new: &[String]) -> Vec<FieldChange> {
+    let mut fields = Fields(Vec::new());
+    let (old_pairs, new_pairs) = (pairs(&old[0]), pairs(&new[0]));
+    let mut keys = old_pairs.iter().map(|(key, _)| key).collect::<Vec<_>>();
+    for (key, _) in &new_pairs {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    for key in keys {
+        let value = |pairs: &[(String, String)]| {
+            let pair = pairs.iter().find(|(other, _)| other == key);
+            pair.map(|(_, value)| value.clone()).unwrap_or_default()
+        };
+        let field = key.trim_end_matches(':');
+        fields.compare(field, value(&old_pairs), value(&new_pairs), |value| value);
+    }
+    let removed = old[1..].iter().filter(|line| !new[1..].contains(line));
+    for line in removed {
+        fields.compare("line", line.as_str(), "", str::to_string);
+    }
+    let added = new[1..].iter().filter(|line| !old[1..].contains(line));
+    for line in added {
+        fields.compare("line", "", line.as_str(), str::to_string);
+    }
+    fields.0
+}
+
+/// Compares records, matching them by position if `item` names them, e.g. `object`, or else
+/// by their first line, e.g. `ITEM: SECTOR NAME: elev1`.
+fn compare_records(old: &[u8], new: &[u8], marker: &str, item: &str) -> Vec<ItemChange> {
+    let (old_header, old_records) = records(old, marker);
+    let (new_header, new_records) = records(new, marker);
+    let mut changes = Vec::new();
+
+    let header = compare_record(
+        &[vec![String::new()], old_header].concat(),
+        &[vec![String::new()], new_header].concat(),
+    );
+    if !header.is_empty() {
+        changes.push(ItemChange {
+            item: "header".to_string(),
+            change: Change::Changed,
+            fields: header,
+        });
+    }
+
+    if !item.is_empty() {
+        let class = |records: &[Vec<String>], index: usize| {
+            let pairs = pairs(&records.get(index)?[0]);
+            let class = pairs.into_iter().find(|(key, _)| key == "CLASS:")?;
+            Some(class.1)
+        };
+        let name = |index| match class(&new_records, index).or_else(|| class(&old_records, index)) {
+            Some(class) => format!("{} {} ({})", item, index, class),
+            None => format!("{} {}", item, index),
+        };
+        compare_lists(
+            &old_records,
+            &new_records,
+            name,
+            &mut changes,
+            |index, old, new, changes| {
+                let fields = compare_record(old, new);
+                Fields(fields).finish(name(index), changes);
+            },
+        );
+        return changes;
+    }
+
+    // Items can share a first line, so match the nth occurrence of each.
+    let keyed = |records: &[Vec<String>]| {
+        let mut keyed = BTreeMap::new();
+        let mut order = Vec::new();
+        for record in records {
+            let mut key = (record[0].to_ascii_uppercase(), 0);
+            while keyed.contains_key(&key) {
+                key.1 += 1;
+            }
+            order.push(key.clone());
+            keyed.insert(key, record.clone());
+        }
+        (keyed, order)
+    };
+    let (old_keyed, old_order) = keyed(&old_records);
+    let (new_keyed, new_order) = keyed(&new_records);
+    for key in &new_order {
+        let record = &new_keyed[key];
+        let change = match old_keyed.get(key) {
+            Some(old) => {
+                Fields(compare_record(old, record)).finish(record[0].clone(), &mut changes);
+                continue;
+            }
+            None => Change::Added,
+        };
+        changes.push(ItemChange {
+            item: record[0].clone(),
+            change,
+            fields: Vec::new(),
+        });
+    }
+    for key in old_order.iter().filter(|key| !new_keyed.contains_key(key)) {
+        changes.push(ItemChange {
+            item: old_keyed[key][0].clone(),
+            change: Change::Removed,
+            fields: Vec::new(),
+        });
+    }
+    changes
+}
+
+/// The images of a BM, FME or WAX, as size and rows.
+fn images(asset: Asset) -> Option<Vec<(mint::Vector2<u32>, Vec<u8>)>> {
+    match asset {
+        Asset::Bm(bm) => {
+            let size = mint::Vector2 {
+                x: bm.size.x as u32,
+                y: bm.size.y as u32,
+            };
+            Some(vec![(size, bm.data)])
+        }
+        Asset::Fme(fme) => Some(vec![(fme.cell.size, fme.cell.data)]),
+        Asset::Wax(wax) => Some(
+            wax.cells
+                .into_iter()
+                .map(|cell| (cell.size, cell.data))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Lists the images that differ.
+fn compare_images(
+    old: &[(mint::Vector2<u32>, Vec<u8>)],
+    new: &[(mint::Vector2<u32>, Vec<u8>)],
+) -> Vec<PixelDiff> {
+    let mut diffs = Vec::new();
+    for cell in 0..old.len().max(new.len()) {
+        let (old, new) = (old.get(cell), new.get(cell));
+        let diff = match (old, new) {
+            (Some((old_size, old_data)), Some((new_size, new_data))) if old_size == new_size => {
+                if old_data == new_data {
+                    continue;
+                }
+                let mask = old_data
+                    .iter()
+                    .zip(new_data)
+                    .map(|(old, new)| old != new)
+                    .collect::<Vec<bool>>();
+                let mut bounds: Option<(mint::Point2<u32>, mint::Point2<u32>)> = None;
+                let width = new_size.x.max(1) as usize;
+                for (index, _) in mask.iter().enumerate().filter(|(_, &changed)| changed) {
+                    let (x, y) = ((index % width) as u32, (index / width) as u32);
+                    bounds = Some(match bounds {
+                        Some((min, max)) => (
+                            mint::Point2 {
+                                x: min.x.min(x),
+                                y: min.y.min(y),
+                            },
+                            mint::Point2 {
+                                x: max.x.max(x),
+                                y: max.y.max(y),
+                            },
+                        ),
+                        None => (mint::Point2 { x, y }, mint::Point2 { x, y }),
+                    });
+                }
+                PixelDiff {
+                    cell,
+                    old_size: Some(*old_size),
+                    new_size: Some(*new_size),
+                    changed: mask.iter().filter(|&&changed| changed).count(),
+                    bounds,
+                    mask: Some(mask),
+                }
+            }
+            _ => {
+                let new_size = new.map(|(size, _)| *size);
+                let changed = new.map_or(0, |(_, data)| data.len());
+                let bounds = new_size
+                    .filter(|size| size.x > 0 && size.y > 0)
+                    .map(|size| {
+                        let max = mint::Point2 {
+                            x: size.x - 1,
+                            y: size.y - 1,
+                        };
+                        (mint::Point2 { x: 0, y: 0 }, max)
+                    });
+                PixelDiff {
+                    cell,
+                    old_size: old.map(|(size, _)| *size),
+                    new_size,
+                    changed,
+                    bounds,
+                    mask: None,
+                }
+            }
+        };
+        diffs.push(diff);
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bm;
+
+    fn bm(data: Vec<u8>) -> Vec<u8> {
+        let bm = bm::Bm {
+            size: mint::Vector2 { x: 3, y: 2 },
+            idem_size: mint::Vector2 { x: 3, y: 2 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data,
+        };
+        let mut bytes = Vec::new();
+        bm.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn entries_and_pixels() {
+        let entry = |name: &str, data: Vec<u8>| (name.to_string(), data);
+        let old = [
+            entry("SAME.TXT", b"same".to_vec()),
+            entry("GONE.TXT", b"gone".to_vec()),
+            entry("WALL.BM", bm(vec![1, 2, 3, 4, 5, 6])),
+        ];
+        let new = [
+            entry("same.txt", b"same".to_vec()),
+            entry("NEW.TXT", b"new".to_vec()),
+            entry("WALL.BM", bm(vec![1, 2, 3, 4, 9, 9])),
+        ];
+        let differences = compare(&old, &new);
+        let summary = differences
+            .iter()
+            .map(|difference| (difference.name.as_str(), difference.change));
+        assert_eq!(
+            summary.collect::<Vec<_>>(),
+            [
+                ("GONE.TXT", Change::Removed),
+                ("NEW.TXT", Change::Added),
+                ("WALL.BM", Change::Changed)
+            ]
+        );
+        match &differences[2].details {
+            Details::Pixels(pixels) => {
+                assert_eq!(pixels.len(), 1);
+                assert_eq!(pixels[0].changed, 2);
+                let bounds = pixels[0].bounds.unwrap();
+                assert_eq!(
+                    (bounds.0.x, bounds.0.y, bounds.1.x, bounds.1.y),
+                    (1, 1, 2, 1)
+                );
+            }
+            details => panic!("expected a pixel diff, got {:?}", details),
+        }
+    }
+
+    #[test]
+    fn objects_and_inf() {
+        let old = b"O 1.1\nLEVELNAME TEST\nOBJECTS 2\n\
+            CLASS: SPRITE DATA: 0 X: 1.00 Y: 0.00 Z: 2.00 PCH: 0 YAW: 0 ROL: 0 DIFF: 1\n\
+            CLASS: SAFE DATA: 0 X: 0.00 Y: 0.00 Z: 0.00 PCH: 0 YAW: 0 ROL: 0 DIFF: 1\n";
+        let new = b"O 1.1\nLEVELNAME TEST\nOBJECTS 2\n\
+            CLASS: SPRITE DATA: 0 X: 4.00 Y: 0.00 Z: 2.00 PCH: 0 YAW: 0 ROL: 0 DIFF: 1\n\
+             SEQ\n  LOGIC: TROOP\n SEQEND\n\
+            CLASS: SAFE DATA: 0 X: 0.00 Y: 0.00 Z: 0.00 PCH: 0 YAW: 0 ROL: 0 DIFF: 1 # same\n";
+        let field = |field: &str, old: &str, new: &str| FieldChange {
+            field: field.to_string(),
+            old: old.to_string(),
+            new: new.to_string(),
+        };
+        assert_eq!(
+            compare_records(old, new, "CLASS:", "object"),
+            [ItemChange {
+                item: "object 0 (SPRITE)".to_string(),
+                change: Change::Changed,
+                fields: vec![
+                    field("X", "1.00", "4.00"),
+                    field("line", "", "SEQ"),
+                    field("line", "", "LOGIC: TROOP"),
+                    field("line", "", "SEQEND"),
+                ],
+            }]
+        );
+
+        let old = b"INF 1.0\nITEMS 2\nITEM: SECTOR NAME: door\n SEQ\n  CLASS: DOOR\n SEQEND\n\
+            ITEM: SECTOR NAME: lift\n SEQ\n  CLASS: ELEVATOR MOVE_FLOOR\n  STOP: 0 5\n SEQEND\n";
+        let new =
+            b"INF 1.0\nITEMS 2\nITEM: SECTOR NAME: lift\n SEQ\n  CLASS: ELEVATOR MOVE_FLOOR\n\
+            \x20 STOP: 0 10\n SEQEND\nITEM: LINE NAME: switch NUM: 1\n";
+        let changes = compare_records(old, new, "ITEM:", "");
+        let summary = changes
+            .iter()
+            .map(|change| (change.item.as_str(), change.change));
+        assert_eq!(
+            summary.collect::<Vec<_>>(),
+            [
+                ("ITEM: SECTOR NAME: lift", Change::Changed),
+                ("ITEM: LINE NAME: switch NUM: 1", Change::Added),
+                ("ITEM: SECTOR NAME: door", Change::Removed),
+            ]
+        );
+        assert_eq!(
+            changes[0].fields,
+            [
+                field("line", "STOP: 0 5", ""),
+                field("line", "", "STOP: 0 10")
+            ]
+        );
+    }
+
+    #[test]
+    fn levels() {
+        let wall =
+            "WALL LEFT: 0 RIGHT: 1 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 \
+                    SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0\n";
+        let lev = |altitude: &str, walls: usize| {
+            format!(
+                "LEV 2.1\nLEVELNAME TEST\nPALETTE TEST.PAL\nMUSIC NULL.GMD\n\
+                 PARALLAX 1024.0000 1024.0000\nTEXTURES 1\nTEXTURE: DEFAULT.BM\nNUMSECTORS 1\n\
+                 SECTOR 0\nNAME\nAMBIENT 20\nFLOOR TEXTURE 0 0.00 0.00 0\n\
+                 FLOOR ALTITUDE {}\nCEILING TEXTURE 0 0.00 0.00 0\nCEILING ALTITUDE -8.00\n\
+                 SECOND ALTITUDE 0.00\nFLAGS 0 0 0\nLAYER 0\nVERTICES 2\nX: 0.00 Z: 0.00\n\
+                 X: 1.00 Z: 0.00\nWALLS {}\n{}",
+                altitude,
+                walls,
+                wall.repeat(walls)
+            )
+        };
+        let details = compare_data(
+            "TEST.LEV",
+            lev("0.00", 1).as_bytes(),
+            lev("2.00", 2).as_bytes(),
+        );
+        let changes = match details {
+            Details::Structure(changes) => changes,
+            details => panic!("expected a structural diff, got {:?}", details),
+        };
+        assert_eq!(
+            changes,
+            [
+                ItemChange {
+                    item: "sector 0".to_string(),
+                    change: Change::Changed,
+                    fields: vec![FieldChange {
+                        field: "floor altitude".to_string(),
+                        old: "0".to_string(),
+                        new: "2".to_string(),
+                    }],
+                },
+                ItemChange {
+                    item: "sector 0 wall 1".to_string(),
+                    change: Change::Added,
+                    fields: Vec::new(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,482 @@
+//! Identifies which release a set of data files is from, by hashing the GOB and LFD files and
+//! their entries and comparing them with baselines recorded from known releases.
+//!
+//! A fingerprint is written one hash per line as `NAME LENGTH CRC32`, where `NAME` is the archive,
+//! relative to the game directory, or `ARCHIVE:ENTRY`, and a baseline is a fingerprint following
+//! an `edition ID` line.
+//!
+//! Bundled baselines live in `baselines/`, one file per edition, recorded with
+//! `df hash --edition ID -o baselines/ID.txt` from an untouched install and listed in
+//! `BASELINES`. Only ever record them from genuine copies, as a wrong one would misreport every
+//! matching install; other baselines can be given at run time instead.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::archive::{self, Archive};
+use crate::common::*;
+pub use crate::diff::Change;
+
+/// Baselines of known releases, in the format read by `Baseline::read`, e.g.
+/// `include_str!("../baselines/cd-1.0.txt")`. None have been recorded yet, see
+/// `baselines/README.md`, so identifying an install needs a baseline given at run time.
+const BASELINES: &[&str] = &[];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edition {
+    /// The original CD release, version 1.0.
+    Cd10,
+    /// The CD release with an official 1.x patch applied.
+    Patched,
+    /// The Steam and GOG releases.
+    Digital,
+    Demo,
+}
+
+impl Edition {
+    /// The ID used by baselines, e.g. `cd-1.0`.
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Cd10 => "cd-1.0",
+            Self::Patched => "patched",
+            Self::Digital => "digital",
+            Self::Demo => "demo",
+        }
+    }
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cd10 => "CD 1.0",
+            Self::Patched => "patched 1.x",
+            Self::Digital => "Steam/GOG",
+            Self::Demo => "demo",
+        })
+    }
+}
+
+impl FromStr for Edition {
+    type Err = ReadError;
+
+    fn from_str(id: &str) -> ReadResult<Self> {
+        let editions = [Self::Cd10, Self::Patched, Self::Digital, Self::Demo];
+        let edition = editions.iter().find(|edition| edition.id() == id);
+        edition
+            .copied()
+            .ok_or_else(|| ReadError::invalid_data(format!("unknown edition {:?}", id)))
+    }
+}
+
+/// The length and CRC-32 of an archive or entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hash {
+    /// The archive, e.g. `LFD/JEDISFX.LFD`, or `ARCHIVE:ENTRY`, in upper case.
+    pub name: String,
+    pub length: u64,
+    pub crc32: u32,
+}
+
+impl Hash {
+    fn is_entry(&self) -> bool {
+        self.name.contains(':')
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Sorted by name.
+    pub hashes: Vec<Hash>,
+    /// Archives that could not be read in full, whose entries are missing from `hashes`.
+    pub unreadable: Vec<Unreadable>,
+}
+
+/// An archive `Fingerprint::of_dir` could not read, e.g. a truncated or corrupt download.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unreadable {
+    pub name: String,
+    pub error: String,
+}
+
+impl Fingerprint {
+    /// Hashes the GOB and LFD files in `dir` and its subdirectories, and their entries.
+    ///
+    /// An archive that cannot be read is recorded in `unreadable` rather than failing, with its
+    /// file hash if there is one, so it shows up as modified when identifying the release.
+    pub fn of_dir(dir: &Path) -> ReadResult<Self> {
+        let mut fingerprint = Self::default();
+        for path in archive::find(dir)? {
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let parts = relative.iter().map(|part| part.to_string_lossy());
+            let name = parts.collect::<Vec<_>>().join("/").to_ascii_uppercase();
+
+            if let Err(error) = fingerprint.add_archive(&path, &name) {
+                fingerprint.unreadable.push(Unreadable {
+                    name,
+                    error: error.to_string(),
+                });
+            }
+        }
+        fingerprint.hashes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(fingerprint)
+    }
+
+    /// Hashes an archive, then its entries, keeping none of them if any cannot be read.
+    fn add_archive(&mut self, path: &Path, name: &str) -> ReadResult<()> {
+        let data = fs::read(path)?;
+        self.hashes.push(Hash {
+            name: name.to_string(),
+            length: data.len() as u64,
+            crc32: crc32(&data),
+        });
+        let archive = Archive::new(io::Cursor::new(data))?;
+        let mut entries = Vec::with_capacity(archive.catalog.entries.len());
+        for entry in &archive.catalog.entries {
+            let data = archive.read(entry)?;
+            entries.push(Hash {
+                name: format!("{}:{}", name, entry.name.to_ascii_uppercase()),
+                length: data.len() as u64,
+                crc32: crc32(&data),
+            });
+        }
+        self.hashes.extend(entries);
+        Ok(())
+    }
+
+    pub fn read(text: &str) -> ReadResult<Self> {
+        Self::read_lines(text.lines().enumerate())
+    }
+
+    /// Reads lines numbered from 0.
+    fn read_lines<'a>(lines: impl Iterator<Item = (usize, &'a str)>) -> ReadResult<Self> {
+        let mut hashes = Vec::new();
+        for (index, line) in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let hash = Self::read_line(line).map_err(|error| error.at_line(index + 1))?;
+            hashes.push(hash);
+        }
+        hashes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self {
+            hashes,
+            unreadable: Vec::new(),
+        })
+    }
+
+    fn read_line(line: &str) -> ReadResult<Hash> {
+        // Names may contain spaces, so split from the end.
+        let mut fields = line.rsplitn(3, ' ');
+        let (crc32, length, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(crc32), Some(length), Some(name)) => (crc32, length, name),
+            _ => return Err(ReadError::invalid_data("expected NAME LENGTH CRC32")),
+        };
+        Ok(Hash {
+            name: name.trim().to_ascii_uppercase(),
+            length: length
+                .parse()
+                .map_err(|_| ReadError::invalid_data(format!("bad length {:?}", length)))?,
+            crc32: u32::from_str_radix(crc32, 16)
+                .map_err(|_| ReadError::invalid_data(format!("bad CRC-32 {:?}", crc32)))?,
+        })
+    }
+
+    /// Writes the hashes, and any unreadable archives as comments.
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        for unreadable in &self.unreadable {
+            writeln!(
+                output,
+                "# unreadable {}: {}",
+                unreadable.name, unreadable.error
+            )?;
+        }
+        for hash in &self.hashes {
+            writeln!(output, "{} {} {:08x}", hash.name, hash.length, hash.crc32)?;
+        }
+        Ok(())
+    }
+
+    /// How the entries of `self` differ from `baseline`, and which archives were added or
+    /// removed. Archives that only differ in their entries are not listed themselves.
+    pub fn differences(&self, baseline: &Fingerprint) -> Vec<Difference> {
+        let ours = self.by_name();
+        let theirs = baseline.by_name();
+        let mut differences = Vec::new();
+        for (name, hash) in &ours {
+            let change = match theirs.get(name) {
+                None => Change::Added,
+                Some(base) if hash.is_entry() && base != hash => Change::Changed,
+                Some(_) => continue,
+            };
+            differences.push(Difference {
+                name: name.to_string(),
+                change,
+            });
+        }
+        for name in theirs.keys().filter(|name| !ours.contains_key(*name)) {
+            differences.push(Difference {
+                name: name.to_string(),
+                change: Change::Removed,
+            });
+        }
+        differences.sort_by(|a, b| a.name.cmp(&b.name));
+        differences
+    }
+
+    fn by_name(&self) -> BTreeMap<&str, &Hash> {
+        let hashes = self.hashes.iter();
+        hashes.map(|hash| (hash.name.as_str(), hash)).collect()
+    }
+
+    /// How many entries of `baseline` are unchanged in `self`.
+    fn matching_entries(&self, baseline: &Fingerprint) -> usize {
+        let ours = self.by_name();
+        let entries = baseline.hashes.iter().filter(|hash| hash.is_entry());
+        entries
+            .filter(|hash| ours.get(hash.name.as_str()) == Some(hash))
+            .count()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub name: String,
+    pub change: Change,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Baseline {
+    pub edition: Edition,
+    pub fingerprint: Fingerprint,
+}
+
+impl Baseline {
+    /// Reads an `edition ID` line followed by a fingerprint.
+    pub fn read(text: &str) -> ReadResult<Self> {
+        let mut lines = text.lines().enumerate().filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        });
+        let (index, line) = lines
+            .next()
+            .ok_or_else(|| ReadError::invalid_data("empty baseline"))?;
+        let edition = match line.trim().strip_prefix("edition ") {
+            Some(id) => id.trim().parse::<Edition>(),
+            None => Err(ReadError::invalid_data("expected edition ID")),
+        };
+        Ok(Self {
+            edition: edition.map_err(|error| error.at_line(index + 1))?,
+            fingerprint: Fingerprint::read_lines(lines)?,
+        })
+    }
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        writeln!(output, "edition {}", self.edition.id())?;
+        self.fingerprint.write(output)
+    }
+
+    /// The baselines bundled with this crate, currently none.
+    pub fn known() -> Vec<Self> {
+        let baselines = BASELINES.iter().map(|text| Self::read(text));
+        baselines.filter_map(Result::ok).collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Identity {
+    /// Every entry matches the baseline of a release.
+    Known(Edition),
+    /// Most entries match the baseline of a release, listing those that do not.
+    Modified {
+        edition: Edition,
+        differences: Vec<Difference>,
+    },
+    /// Does not match any baseline well enough, e.g. a total conversion or a release there is
+    /// no baseline for.
+    Unknown,
+}
+
+/// Identifies `fingerprint` as the release whose baseline shares the most entries with it.
+pub fn identify(fingerprint: &Fingerprint, baselines: &[Baseline]) -> Identity {
+    let best = baselines
+        .iter()
+        .max_by_key(|baseline| fingerprint.matching_entries(&baseline.fingerprint));
+    let baseline = match best {
+        Some(baseline) => baseline,
+        None => return Identity::Unknown,
+    };
+    let differences = fingerprint.differences(&baseline.fingerprint);
+    let entries = baseline.fingerprint.hashes.iter();
+    let entry_count = entries.filter(|hash| hash.is_entry()).count();
+    if differences.is_empty() {
+        Identity::Known(baseline.edition)
+    } else if fingerprint.matching_entries(&baseline.fingerprint) * 2 >= entry_count.max(1) {
+        Identity::Modified {
+            edition: baseline.edition,
+            differences,
+        }
+    } else {
+        Identity::Unknown
+    }
+}
+
+/// The CRC-32 used by zip and PNG.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gob;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn identify_modified() {
+        let dir = std::env::temp_dir().join(format!("df-edition-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write_gob = |entries: &[(&str, &[u8])]| {
+            let file = fs::File::create(dir.join("dark.gob")).unwrap();
+            gob::write(file, entries).unwrap();
+        };
+
+        write_gob(&[
+            ("A.TXT", b"one"),
+            ("B.TXT", b"two"),
+            ("C.TXT", b"three"),
+            ("D.TXT", b"four"),
+        ]);
+        let baseline = Baseline {
+            edition: Edition::Demo,
+            fingerprint: Fingerprint::of_dir(&dir).unwrap(),
+        };
+        let mut text = Vec::new();
+        baseline.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("edition demo\nDARK.GOB 111 "));
+        assert!(text.contains("\nDARK.GOB:A.TXT 3 7a6c86f1\n"));
+        let baselines = [Baseline::read(&text).unwrap()];
+        assert_eq!(baselines[0], baseline);
+
+        let fingerprint = Fingerprint::of_dir(&dir).unwrap();
+        assert_eq!(
+            identify(&fingerprint, &baselines),
+            Identity::Known(Edition::Demo)
+        );
+
+        write_gob(&[
+            ("A.TXT", b"one"),
+            ("B.TXT", b"two"),
+            ("C.TXT", b"3"),
+            ("E.TXT", b"five"),
+        ]);
+        let fingerprint = Fingerprint::of_dir(&dir).unwrap();
+        let difference = |name: &str, change| Difference {
+            name: name.to_string(),
+            change,
+        };
+        assert_eq!(
+            identify(&fingerprint, &baselines),
+            Identity::Modified {
+                edition: Edition::Demo,
+                differences: vec![
+                    difference("DARK.GOB:C.TXT", Change::Changed),
+                    difference("DARK.GOB:D.TXT", Change::Removed),
+                    difference("DARK.GOB:E.TXT", Change::Added),
+                ],
+            }
+        );
+
+        write_gob(&[("A.TXT", b"one"), ("F.TXT", b"six")]);
+        let fingerprint = Fingerprint::of_dir(&dir).unwrap();
+        assert_eq!(identify(&fingerprint, &baselines), Identity::Unknown);
+        assert_eq!(identify(&fingerprint, &[]), Identity::Unknown);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_archives() {
+        let dir = std::env::temp_dir().join(format!("df-edition-bad-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let entries: &[(&str, &[u8])] = &[("A.TXT", b"one"), ("B.TXT", b"two")];
+        gob::write(fs::File::create(dir.join("DARK.GOB")).unwrap(), entries).unwrap();
+        gob::write(fs::File::create(dir.join("SOUNDS.GOB")).unwrap(), entries).unwrap();
+        let baselines = [Baseline {
+            edition: Edition::Cd10,
+            fingerprint: Fingerprint::of_dir(&dir).unwrap(),
+        }];
+
+        // Truncated, so the catalog points past the end.
+        let data = fs::read(dir.join("SOUNDS.GOB")).unwrap();
+        fs::write(dir.join("SOUNDS.GOB"), &data[..data.len() - 8]).unwrap();
+        let fingerprint = Fingerprint::of_dir(&dir).unwrap();
+        assert_eq!(fingerprint.unreadable.len(), 1);
+        assert_eq!(fingerprint.unreadable[0].name, "SOUNDS.GOB");
+        let names = fingerprint.hashes.iter().map(|hash| hash.name.as_str());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            ["DARK.GOB", "DARK.GOB:A.TXT", "DARK.GOB:B.TXT", "SOUNDS.GOB"]
+        );
+        let mut text = Vec::new();
+        fingerprint.write(&mut text).unwrap();
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .starts_with("# unreadable SOUNDS.GOB: "));
+
+        let removed = |name: &str| Difference {
+            name: name.to_string(),
+            change: Change::Removed,
+        };
+        assert_eq!(
+            identify(&fingerprint, &baselines),
+            Identity::Modified {
+                edition: Edition::Cd10,
+                differences: vec![removed("SOUNDS.GOB:A.TXT"), removed("SOUNDS.GOB:B.TXT")],
+            }
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_errors() {
+        let error = Baseline::read("edition demo\nDARK.GOB 12 xyz\n").unwrap_err();
+        assert_eq!(error.location, Some(Location::Line(2)));
+        assert!(Baseline::read("edition retail\n").is_err());
+        assert!(Baseline::read("").is_err());
+    }
+}
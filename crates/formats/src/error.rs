@@ -1,16 +1,182 @@
-use std::io;
+use std::borrow::Cow;
+use std::{error, fmt, io};
 
 pub type ReadResult<T> = Result<T, ReadError>;
 
+/// Why a file could not be read, and as far as is known, where.
 #[derive(Debug)]
-pub enum ReadError {
+pub struct ReadError {
+    pub kind: ReadErrorKind,
+    /// The format being read, e.g. `"GOB"`.
+    pub format: Option<&'static str>,
+    pub location: Option<Location>,
+    /// The field being read, e.g. `"compression"`.
+    pub field: Option<&'static str>,
+}
+
+#[derive(Debug)]
+pub enum ReadErrorKind {
     IO(io::Error),
+    /// The file does not start with the format's magic bytes.
     Signature,
-    Decoding(&'static str),
+    Decoding(Cow<'static, str>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// Bytes from the start of the file.
+    Offset(u64),
+    /// 1-based line of a text file.
+    Line(usize),
+}
+
+impl ReadError {
+    pub fn new(kind: ReadErrorKind) -> Self {
+        Self {
+            kind,
+            format: None,
+            location: None,
+            field: None,
+        }
+    }
+
+    /// A file of `format` that does not start with its magic bytes.
+    pub fn signature(format: &'static str) -> Self {
+        Self::new(ReadErrorKind::Signature)
+            .with_format(format)
+            .at(Location::Offset(0))
+    }
+
+    pub fn decoding(format: &'static str, message: impl Into<Cow<'static, str>>) -> Self {
+        Self::invalid_data(message).with_format(format)
+    }
+
+    /// A decoding error in code shared between formats, which fill in their own name.
+    pub fn invalid_data(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(ReadErrorKind::Decoding(message.into()))
+    }
+
+    pub fn with_format(mut self, format: &'static str) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn with_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    pub fn at(mut self, location: Location) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn at_offset(self, offset: u64) -> Self {
+        self.at(Location::Offset(offset))
+    }
+
+    pub fn at_line(self, line: usize) -> Self {
+        self.at(Location::Line(line))
+    }
+
+    /// Fills in `format` where it is not already known, when an error leaves a reader.
+    /// Nested readers have filled in their own by then.
+    pub fn or_format(mut self, format: &'static str) -> Self {
+        self.format.get_or_insert(format);
+        self
+    }
+
+    /// Like `or_format()`, also filling in the position of `file` as the location.
+    pub fn locate(self, format: &'static str, mut file: impl io::Seek) -> Self {
+        let mut error = self.or_format(format);
+        if error.location.is_none() {
+            error.location = file.stream_position().ok().map(Location::Offset);
+        }
+        error
+    }
+
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match &self.kind {
+            ReadErrorKind::IO(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for ReadError {
     fn from(value: io::Error) -> Self {
-        Self::IO(value)
+        Self::new(ReadErrorKind::IO(value))
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.format.unwrap_or("file"))?;
+        match self.location {
+            Some(Location::Offset(offset)) => write!(f, " at offset {:#x}", offset)?,
+            Some(Location::Line(line)) => write!(f, " at line {}", line)?,
+            None => {}
+        }
+        if let Some(field) = self.field {
+            write!(f, " in {}", field)?;
+        }
+        f.write_str(": ")?;
+        match &self.kind {
+            ReadErrorKind::IO(error) => write!(f, "{}", error),
+            ReadErrorKind::Signature => f.write_str("invalid signature"),
+            ReadErrorKind::Decoding(message) => f.write_str(message),
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.kind {
+            ReadErrorKind::IO(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Names the field being read when a read fails, e.g. `read_u32(&mut file).field("length")?`.
+pub trait ReadContext<T> {
+    fn field(self, field: &'static str) -> ReadResult<T>;
+}
+
+impl<T, E: Into<ReadError>> ReadContext<T> for Result<T, E> {
+    fn field(self, field: &'static str) -> ReadResult<T> {
+        self.map_err(|error| {
+            let mut error = error.into();
+            error.field.get_or_insert(field);
+            error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let error = ReadError::decoding("BM", "invalid compression")
+            .at_offset(0x0E)
+            .with_field("compression");
+        assert_eq!(
+            error.to_string(),
+            "BM at offset 0xe in compression: invalid compression"
+        );
+
+        let eof: ReadResult<()> = Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        let error = eof
+            .field("catalog offset")
+            .unwrap_err()
+            .locate("GOB", io::Cursor::new([0u8; 6]));
+        assert_eq!(error.location, Some(Location::Offset(0)));
+        assert!(error.io_error().is_some());
+        assert!(error::Error::source(&error).is_some());
+        assert!(error
+            .to_string()
+            .starts_with("GOB at offset 0x0 in catalog offset: "));
     }
 }
@@ -2,6 +2,7 @@ use std::io;
 
 use crate::common::*;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Fme {
     pub frame: Frame,
     pub cell: Cell,
@@ -9,14 +10,27 @@ pub struct Fme {
 
 impl Fme {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
+        Self::read_fme(&mut file).map_err(|error| error.locate("FME", &mut file))
+    }
+
+    fn read_fme(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
         let frame = Frame::read(&mut file)?;
-        let cell_offset = read_u32(&mut file)?;
+        let cell_offset = read_u32(&mut file).field("cell offset")?;
         let cell = Cell::read(&mut file, cell_offset)?;
         Ok(Self { frame, cell })
     }
+
+    /// Writes the frame followed by an uncompressed cell.
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        self.frame.write(&mut output)?;
+        output.write_all(&32u32.to_le_bytes())?; // cell offset
+        output.write_all(&[0u8; 16])?; // unit size and padding
+        self.cell.write(&mut output)
+    }
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Frame {
     pub offset: mint::Vector2<i32>,
     pub flip: bool,
@@ -24,12 +38,20 @@ pub struct Frame {
 
 impl Frame {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
-        let offset = read_vec2_i32(&mut file)?;
-        let flip = read_u32(&mut file)? != 0;
+        let offset = read_vec2_i32(&mut file).field("frame offset")?;
+        let flip = read_u32(&mut file).field("flip")? != 0;
         Ok(Self { offset, flip })
     }
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        output.write_all(&self.offset.x.to_le_bytes())?;
+        output.write_all(&self.offset.y.to_le_bytes())?;
+        output.write_all(&(self.flip as u32).to_le_bytes())?;
+        Ok(())
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cell {
     pub size: mint::Vector2<u32>,
     pub data: Vec<u8>,
@@ -39,24 +61,39 @@ impl Cell {
     pub fn read(mut file: impl io::Read + io::Seek, offset: u32) -> ReadResult<Self> {
         file.seek(io::SeekFrom::Start(offset as u64))?;
 
-        let size = read_vec2_u32(&mut file)?;
-        let compressed = read_u32(&mut file)? != 0;
+        let size = read_vec2_u32(&mut file).field("cell size")?;
+        let compressed = read_u32(&mut file).field("compressed")? != 0;
         /*let data_size = */
         read_u32(&mut file)?;
-        let data_offset = read_i32(&mut file)?;
+        let data_offset = read_i32(&mut file).field("data offset")?;
         read_u32(&mut file)?; // padding
 
-        assert_eq!(data_offset, 0);
-        // file.seek(io::SeekFrom::Current(data_offset as i64))?;
+        if data_offset != 0 {
+            let message = format!("unsupported data offset {}", data_offset);
+            return Err(ReadError::invalid_data(message).with_field("data offset"));
+        }
 
         let columns = if !compressed {
-            read_vec(&mut file, (size.x * size.y) as usize)?
+            read_vec(&mut file, image_len(size).field("cell size")?).field("pixels")?
         } else {
             rle0(&mut file, offset, size)?
         };
 
-        let data = columns_to_rows(size, columns);
+        let data = columns_to_rows(size, columns)?;
 
         Ok(Self { size, data })
     }
+
+    /// Writes an uncompressed cell header and data.
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        let columns = rows_to_columns(self.size, &self.data)?;
+        output.write_all(&self.size.x.to_le_bytes())?;
+        output.write_all(&self.size.y.to_le_bytes())?;
+        output.write_all(&0u32.to_le_bytes())?; // compressed
+        output.write_all(&(columns.len() as u32).to_le_bytes())?;
+        output.write_all(&0u32.to_le_bytes())?; // data offset
+        output.write_all(&0u32.to_le_bytes())?; // padding
+        output.write_all(&columns)?;
+        Ok(())
+    }
 }
@@ -1,224 +1,325 @@
-use std::{thread, time};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{io, thread, time};
 
 use crate::common::*;
+use crate::imuse;
+use crate::schedule::{Cursor, Scheduled};
 
-pub fn play_in_thread(data: Vec<u8>) -> impl Drop {
-    use std::sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    };
-    struct Stop(Arc<AtomicBool>);
-    impl Drop for Stop {
-        fn drop(&mut self) {
-            self.0.store(true, Ordering::SeqCst);
-        }
+/// A GMD file: a `MIDI` wrapper holding iMUSE chunks (e.g. `MDpg`), followed by a standard
+/// MIDI file starting with `MThd`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gmd {
+    pub chunks: Vec<Chunk>,
+    pub smf: Vec<u8>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Chunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Gmd {
+    pub fn read(mut file: impl io::Read) -> ReadResult<Self> {
+        Self::read_gmd(&mut file).map_err(|error| error.or_format("GMD"))
     }
 
-    let stop = Stop(Arc::new(AtomicBool::new(false)));
+    fn read_gmd(mut file: impl io::Read) -> ReadResult<Self> {
+        if &read_buf(&mut file, [0u8; 4])? != b"MIDI" {
+            return Err(ReadError::signature("GMD"));
+        }
+        let len = read_buf(&mut file, [0u8; 4])
+            .field("length")
+            .map_err(|error| error.at_offset(4))?;
+        let len = u32::from_be_bytes(len);
+        // Every chunk is within the length, so this limits them all.
+        checked_len(len as u64)
+            .field("length")
+            .map_err(|error| error.at_offset(4))?;
 
-    thread::Builder::new()
-        .name("midi_playback".to_string())
-        .stack_size(0x1000)
-        .spawn({
-            let stop = stop.0.clone();
-            move || {
-                midi(data, &stop);
+        let mut chunks = Vec::new();
+        let mut remaining = len as usize;
+        loop {
+            let offset = 8 + (len as usize - remaining) as u64;
+            let locate = |error: ReadError| error.at_offset(offset);
+            if remaining < 8 {
+                return Err(ReadError::decoding("GMD", "no MThd chunk").at_offset(offset));
+            }
+            let id = read_buf(&mut file, [0u8; 4])
+                .field("chunk id")
+                .map_err(locate)?;
+            let chunk_len = read_buf(&mut file, [0u8; 4])
+                .field("chunk length")
+                .map_err(locate)?;
+            if &id == b"MThd" {
+                // The rest is the SMF, including this header.
+                let mut smf = Vec::with_capacity(remaining);
+                smf.extend_from_slice(&id);
+                smf.extend_from_slice(&chunk_len);
+                smf.resize(remaining, 0);
+                file.read_exact(&mut smf[8..])
+                    .field("SMF")
+                    .map_err(locate)?;
+                return Ok(Self { chunks, smf });
+            }
+            let chunk_len = u32::from_be_bytes(chunk_len) as usize;
+            if chunk_len > remaining - 8 {
+                let message = format!("{} chunk overruns file", String::from_utf8_lossy(&id));
+                return Err(ReadError::decoding("GMD", message)
+                    .at_offset(offset)
+                    .with_field("chunk length"));
             }
-        })
-        .unwrap();
+            let data = read_vec(&mut file, chunk_len)
+                .field("chunk")
+                .map_err(locate)?;
+            remaining -= 8 + chunk_len;
+            chunks.push(Chunk { id, data });
+        }
+    }
 
-    stop
-}
+    /// Wraps a standard MIDI file, with no iMUSE chunks.
+    pub fn from_mid(smf: Vec<u8>) -> ReadResult<Self> {
+        let gmd = Self {
+            chunks: Vec::new(),
+            smf,
+        };
+        gmd.parse_smf()?;
+        Ok(gmd)
+    }
 
-pub fn midi(data: Vec<u8>, stop: &std::sync::atomic::AtomicBool) {
-    if !data.starts_with(b"MIDI") {
-        println!("no GMD 'MIDI' signature");
-        return;
+    pub fn parse_smf(&self) -> ReadResult<midly::Smf<'_>> {
+        if !self.smf.starts_with(b"MThd") {
+            return Err(ReadError::signature("SMF"));
+        }
+        // midly negates this SMPTE frame rate, which overflows in debug builds.
+        if self.smf.get(12) == Some(&0x80) {
+            let error = ReadError::decoding("SMF", "invalid SMPTE frame rate");
+            return Err(error.at_offset(12).with_field("division"));
+        }
+        midly::Smf::parse(&self.smf)
+            .map_err(|error| ReadError::decoding("SMF", format!("invalid SMF: {}", error)))
     }
-    let mut data = &data[8..];
-    while !data.starts_with(b"MThd") {
-        read_buf(&mut data, [0u8; 4]).unwrap();
-        let len = u32::from_be_bytes(read_buf(&mut data, [0u8; 4]).unwrap());
-        data = &data[len as usize..];
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        let len = self
+            .chunks
+            .iter()
+            .map(|chunk| 8 + chunk.data.len())
+            .sum::<usize>()
+            + self.smf.len();
+        output.write_all(b"MIDI")?;
+        output.write_all(&(len as u32).to_be_bytes())?;
+        for chunk in &self.chunks {
+            output.write_all(&chunk.id)?;
+            output.write_all(&(chunk.data.len() as u32).to_be_bytes())?;
+            output.write_all(&chunk.data)?;
+        }
+        output.write_all(&self.smf)
     }
 
-    use bindings::{
-        Result, RuntimeType,
-        Windows::{Devices::Midi, Foundation::IAsyncOperation},
-    };
+    pub fn imuse_events(&self) -> ReadResult<Vec<imuse::TimedEvent>> {
+        Ok(imuse::events(&self.parse_smf()?))
+    }
 
-    fn get<T: RuntimeType>(result: Result<IAsyncOperation<T>>) -> T {
-        result.unwrap().get().unwrap()
+    /// Writes the embedded standard MIDI file as-is, i.e. a `.mid` file.
+    pub fn write_mid(&self, mut output: impl io::Write) -> io::Result<()> {
+        output.write_all(&self.smf)
     }
+}
 
-    let midi = get(Midi::MidiSynthesizer::CreateAsync());
+/// Something that plays MIDI channel messages, e.g. a hardware port or a software synthesizer.
+pub trait MidiSink {
+    type Error;
 
-    let smf = midly::Smf::parse(&data).unwrap();
+    fn send(&mut self, channel: u8, message: midly::MidiMessage) -> Result<(), Self::Error>;
 
-    let mut events = match smf.header.format {
-        midly::Format::SingleTrack | midly::Format::Parallel => smf
-            .tracks
-            .iter()
-            .flat_map(|msgs| {
-                msgs.iter().scan(0, |ticks, event| {
-                    *ticks += event.delta.as_int();
-                    Some((*ticks, event.kind))
-                })
-            })
-            .collect::<Vec<_>>(),
-        midly::Format::Sequential => smf
-            .tracks
-            .iter()
-            .flatten()
-            .scan(0, |ticks, event| {
-                *ticks += event.delta.as_int();
-                Some((*ticks, event.kind))
-            })
-            .collect::<Vec<_>>(),
-    };
+    /// Receives the contents of SysEx messages that are not iMUSE control messages. Ignored by
+    /// default.
+    fn sysex(&mut self, _data: &[u8]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
 
-    events.sort_by_key(|(ts, _)| *ts);
+/// Sends a song event to `sink`. Meta events are skipped, as tempo is already applied by the
+/// `Schedule`, as are iMUSE messages, which control the player rather than the synthesizer.
+pub fn send_event<S: MidiSink + ?Sized>(
+    sink: &mut S,
+    kind: midly::TrackEventKind<'_>,
+) -> Result<(), S::Error> {
+    match kind {
+        midly::TrackEventKind::Midi { channel, message } => sink.send(channel.as_int(), message),
+        midly::TrackEventKind::SysEx(bytes) if imuse::decode(bytes).is_none() => sink.sysex(bytes),
+        _ => Ok(()),
+    }
+}
 
-    let mut elapsed_ticks = 0;
-    let mut last_time = time::Instant::now();
-    let mut beat = time::Duration::from_secs(1);
+/// Plays the events from `cursor` into `sink` in real time, until the end of the song or `stop`
+/// is set. Returns whether the song was played to the end.
+pub fn play<S: MidiSink + ?Sized>(
+    cursor: Cursor<'_, '_>,
+    sink: &mut S,
+    stop: &AtomicBool,
+) -> Result<bool, S::Error> {
+    let start_time = time::Instant::now();
 
-    try_set_thread_priority_real_time();
+    for Scheduled {
+        time: offset,
+        event,
+    } in cursor
+    {
+        let event_time = start_time + offset;
 
-    for (ts_ticks, event) in events {
-        if ts_ticks > elapsed_ticks {
-            let ticks = ts_ticks - elapsed_ticks;
-            let duration = match smf.header.timing {
-                midly::Timing::Metrical(ticks_per_beat) => {
-                    beat * ticks / ticks_per_beat.as_int() as u32
-                }
-                midly::Timing::Timecode(fps, subframe) => {
-                    time::Duration::from_secs_f32(ticks as f32 / fps.as_f32() / subframe as f32)
-                }
-            };
-            last_time += duration;
-            elapsed_ticks = ts_ticks;
-
-            // Semi-accurately wait. sleep() alone gives silly results!
-            const SLEEP_ACCURACY_ESTIMATE: time::Duration = time::Duration::from_millis(1);
-            while let Some(remaining) = last_time.checked_duration_since(time::Instant::now()) {
-                if let Some(sleep_duration) = remaining.checked_sub(SLEEP_ACCURACY_ESTIMATE) {
-                    thread::sleep(sleep_duration);
-                } else {
-                    thread::yield_now();
-                }
+        // Semi-accurately wait. sleep() alone gives silly results!
+        const SLEEP_ACCURACY_ESTIMATE: time::Duration = time::Duration::from_millis(1);
+        while let Some(remaining) = event_time.checked_duration_since(time::Instant::now()) {
+            if let Some(sleep_duration) = remaining.checked_sub(SLEEP_ACCURACY_ESTIMATE) {
+                thread::sleep(sleep_duration);
+            } else {
+                thread::yield_now();
             }
         }
 
-        if stop.load(std::sync::atomic::Ordering::SeqCst) {
-            println!("MIDI: Cancelled");
-            return;
+        if stop.load(Ordering::SeqCst) {
+            return Ok(false);
         }
 
-        match event {
-            midly::TrackEventKind::Meta(event) => match event {
-                midly::MetaMessage::Tempo(us_per_beat) => {
-                    beat = time::Duration::from_micros(us_per_beat.as_int().into());
-                }
-                _ => {}
-            },
-            midly::TrackEventKind::Midi { channel, message } => match message {
-                midly::MidiMessage::NoteOff { key, vel } => {
-                    midi.SendMessage(
-                        Midi::MidiNoteOffMessage::CreateMidiNoteOffMessage(
-                            channel.into(),
-                            key.into(),
-                            vel.into(),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::NoteOn { key, vel } => {
-                    midi.SendMessage(
-                        Midi::MidiNoteOnMessage::CreateMidiNoteOnMessage(
-                            channel.into(),
-                            key.into(),
-                            vel.into(),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::Aftertouch { vel, key } => {
-                    Midi::MidiPolyphonicKeyPressureMessage::CreateMidiPolyphonicKeyPressureMessage(
-                        channel.into(),
-                        key.into(),
-                        vel.into(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::Controller { controller, value } => {
-                    midi.SendMessage(
-                        Midi::MidiControlChangeMessage::CreateMidiControlChangeMessage(
-                            channel.into(),
-                            controller.into(),
-                            value.into(),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::ProgramChange { program } => {
-                    midi.SendMessage(
-                        Midi::MidiProgramChangeMessage::CreateMidiProgramChangeMessage(
-                            channel.into(),
-                            program.into(),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::ChannelAftertouch { vel } => {
-                    Midi::MidiChannelPressureMessage::CreateMidiChannelPressureMessage(
-                        channel.into(),
-                        vel.into(),
-                    )
-                    .unwrap();
-                }
-                midly::MidiMessage::PitchBend { bend } => {
-                    midi.SendMessage(
-                        Midi::MidiPitchBendChangeMessage::CreateMidiPitchBendChangeMessage(
-                            channel.into(),
-                            bend.0.as_int(),
-                        )
-                        .unwrap(),
-                    )
-                    .unwrap();
-                }
-            },
-            midly::TrackEventKind::SysEx(bytes) => {
-                if let Some(bytes) = bytes.strip_prefix(&[125]) {
-                    let (kind, content) =
-                        bytes.strip_suffix(&[0xF7]).unwrap().split_first().unwrap();
-                    match kind {
-                        3 => {
-                            let content = content.strip_suffix(&[0]).unwrap();
-                            println!("iMUSE: label: {:?}", std::str::from_utf8(content));
-                        }
-                        _ => {
-                            println!("iMUSE: {}: {:?}", kind, content);
-                        }
+        send_event(sink, event.kind)?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(feature = "windows-playback")]
+pub fn play_in_thread(gmd: Gmd) -> ReadResult<impl Drop> {
+    // report parse errors to the caller, rather than the playback thread.
+    gmd.parse_smf()?;
+
+    use std::sync::Arc;
+    struct Stop(Arc<AtomicBool>);
+    impl Drop for Stop {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let stop = Stop(Arc::new(AtomicBool::new(false)));
+
+    thread::Builder::new()
+        .name("midi_playback".to_string())
+        .stack_size(0x1000)
+        .spawn({
+            let stop = stop.0.clone();
+            move || match gmd.parse_smf() {
+                Ok(smf) => {
+                    if let Err(error) = midi(&smf, &stop) {
+                        eprintln!("MIDI: playback failed: {:?}", error);
                     }
-                } else {
-                    println!("MIDI: SysEx: {:?}", bytes);
                 }
+                Err(error) => {
+                    eprintln!("MIDI: {:?}", error);
+                }
+            }
+        })?;
+
+    Ok(stop)
+}
+
+/// The Windows built-in General MIDI synthesizer.
+#[cfg(feature = "windows-playback")]
+pub struct WindowsSynth(bindings::Windows::Devices::Midi::MidiSynthesizer);
+
+#[cfg(feature = "windows-playback")]
+impl WindowsSynth {
+    pub fn new() -> bindings::Result<Self> {
+        use bindings::Windows::Devices::Midi;
+        Ok(Self(Midi::MidiSynthesizer::CreateAsync()?.get()?))
+    }
+}
+
+#[cfg(feature = "windows-playback")]
+impl MidiSink for WindowsSynth {
+    type Error = bindings::Error;
+
+    fn send(&mut self, channel: u8, message: midly::MidiMessage) -> bindings::Result<()> {
+        use bindings::Windows::Devices::Midi;
+
+        let midi = &self.0;
+        match message {
+            midly::MidiMessage::NoteOff { key, vel } => {
+                midi.SendMessage(Midi::MidiNoteOffMessage::CreateMidiNoteOffMessage(
+                    channel,
+                    key.into(),
+                    vel.into(),
+                )?)?;
             }
-            midly::TrackEventKind::Escape(bytes) => {
-                println!("MIDI: Esc: {:?}", bytes);
-                // midi.SendBuffer(create_buffer(bytes).unwrap()).unwrap();
+            midly::MidiMessage::NoteOn { key, vel } => {
+                midi.SendMessage(Midi::MidiNoteOnMessage::CreateMidiNoteOnMessage(
+                    channel,
+                    key.into(),
+                    vel.into(),
+                )?)?;
+            }
+            midly::MidiMessage::Aftertouch { vel, key } => {
+                Midi::MidiPolyphonicKeyPressureMessage::CreateMidiPolyphonicKeyPressureMessage(
+                    channel,
+                    key.into(),
+                    vel.into(),
+                )?;
+            }
+            midly::MidiMessage::Controller { controller, value } => {
+                midi.SendMessage(
+                    Midi::MidiControlChangeMessage::CreateMidiControlChangeMessage(
+                        channel,
+                        controller.into(),
+                        value.into(),
+                    )?,
+                )?;
+            }
+            midly::MidiMessage::ProgramChange { program } => {
+                midi.SendMessage(
+                    Midi::MidiProgramChangeMessage::CreateMidiProgramChangeMessage(
+                        channel,
+                        program.into(),
+                    )?,
+                )?;
+            }
+            midly::MidiMessage::ChannelAftertouch { vel } => {
+                Midi::MidiChannelPressureMessage::CreateMidiChannelPressureMessage(
+                    channel,
+                    vel.into(),
+                )?;
+            }
+            midly::MidiMessage::PitchBend { bend } => {
+                midi.SendMessage(
+                    Midi::MidiPitchBendChangeMessage::CreateMidiPitchBendChangeMessage(
+                        channel,
+                        bend.0.as_int(),
+                    )?,
+                )?;
             }
         }
+        Ok(())
+    }
+
+    fn sysex(&mut self, _data: &[u8]) -> bindings::Result<()> {
+        Ok(())
     }
+}
+
+#[cfg(feature = "windows-playback")]
+pub fn midi(smf: &midly::Smf<'_>, stop: &AtomicBool) -> bindings::Result<()> {
+    let mut synth = WindowsSynth::new()?;
+    let schedule = crate::schedule::Schedule::new(smf);
 
-    println!("MIDI: done");
+    try_set_thread_priority_real_time();
+
+    play(schedule.cursor(), &mut synth, stop)?;
+    Ok(())
 }
 
+#[cfg(feature = "windows-playback")]
 fn try_set_thread_priority_real_time() {
     #[cfg(windows)]
     {
@@ -231,10 +332,37 @@ fn try_set_thread_priority_real_time() {
         let ok =
             unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL) != 0 };
         if !ok {
-            println!(
+            eprintln!(
                 "SetThreadPriority() failed: {}",
                 std::io::Error::last_os_error()
             );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMF: &[u8] = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x04\0\xFF\x2F\0";
+
+    #[test]
+    fn round_trip() {
+        let mut gmd = Gmd::from_mid(SMF.to_vec()).unwrap();
+        gmd.chunks.push(Chunk {
+            id: *b"MDpg",
+            data: vec![1, 2, 3],
+        });
+
+        let mut bytes = Vec::new();
+        gmd.write(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"MIDI\0\0\0\x25MDpg\0\0\0\x03"));
+
+        let read = Gmd::read(&bytes[..]).unwrap();
+        assert_eq!(read.chunks.len(), 1);
+        assert_eq!(&read.chunks[0].id, b"MDpg");
+        assert_eq!(read.chunks[0].data, [1, 2, 3]);
+        assert_eq!(read.smf, SMF);
+        assert_eq!(read.parse_smf().unwrap().tracks.len(), 1);
+    }
+}
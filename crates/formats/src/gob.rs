@@ -1,26 +1,28 @@
+use std::convert::TryFrom;
 use std::io;
 
 use crate::common::*;
 
 pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Catalog> {
+    read_catalog(&mut file).map_err(|error| error.locate("GOB", &mut file))
+}
+
+fn read_catalog(mut file: impl io::Read + io::Seek) -> ReadResult<Catalog> {
     if read_buf(&mut file, &mut [0u8; 4])? != b"GOB\n" {
-        return Err(ReadError::Signature);
+        return Err(ReadError::signature("GOB"));
     }
 
-    let catalog_offset = read_u32(&mut file)?;
+    let catalog_offset = read_u32(&mut file).field("catalog offset")?;
     file.seek(io::SeekFrom::Start(catalog_offset as u64))?;
-    let num_entries = read_u32(&mut file)?;
+    let num_entries = read_u32(&mut file).field("entry count")?;
 
     let mut entries = Vec::new();
 
     for _ in 0..num_entries {
-        let offset = read_u32(&mut file)?;
-        let length = read_u32(&mut file)?;
-        let raw_name = read_buf(&mut file, [0u8; 13])?;
-        let mut name = String::from_utf8(raw_name.to_vec()).expect("name to be ascii");
-        if let Some(index) = name.find('\0') {
-            name.truncate(index);
-        }
+        let offset = read_u32(&mut file).field("entry offset")?;
+        let length = read_u32(&mut file).field("entry length")?;
+        let raw_name = read_buf(&mut file, [0u8; 13]).field("entry name")?;
+        let name = entry_name(&raw_name).field("entry name")?;
 
         entries.push(CatalogEntry {
             name,
@@ -31,3 +33,66 @@ pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Catalog> {
 
     Ok(Catalog { entries })
 }
+
+/// Writes a GOB holding `entries` as `(name, data)`, in order. Names are at most 12 bytes.
+pub fn write<N: AsRef<str>, D: AsRef<[u8]>>(
+    mut output: impl io::Write,
+    entries: &[(N, D)],
+) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut catalog = Vec::with_capacity(4 + entries.len() * 21);
+    catalog.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    let mut offset = 8u32;
+    for (name, data) in entries {
+        let name = name.as_ref();
+        let length = data.as_ref().len();
+        if name.is_empty() || name.len() > 12 || name.contains('\0') {
+            return Err(invalid(format!(
+                "GOB entry name {:?} is not 1-12 bytes",
+                name
+            )));
+        }
+        let length = u32::try_from(length)
+            .ok()
+            .filter(|&length| offset.checked_add(length).is_some())
+            .ok_or_else(|| invalid(format!("GOB entry {:?} is past the 4GB limit", name)))?;
+
+        let mut raw_name = [0u8; 13];
+        raw_name[..name.len()].copy_from_slice(name.as_bytes());
+        catalog.extend_from_slice(&offset.to_le_bytes());
+        catalog.extend_from_slice(&length.to_le_bytes());
+        catalog.extend_from_slice(&raw_name);
+        offset += length;
+    }
+
+    output.write_all(b"GOB\n")?;
+    output.write_all(&offset.to_le_bytes())?; // catalog offset
+    for (_, data) in entries {
+        output.write_all(data.as_ref())?;
+    }
+    output.write_all(&catalog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let entries = [("TEST.BM", vec![1u8, 2, 3]), ("EMPTY.TXT", vec![])];
+        let mut bytes = Vec::new();
+        write(&mut bytes, &entries).unwrap();
+
+        let catalog = read(io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(catalog.entries.len(), 2);
+        let entry = &catalog.entries[0];
+        assert_eq!(entry.name, "TEST.BM");
+        let start = entry.offset as usize;
+        assert_eq!(&bytes[start..start + entry.length as usize], [1, 2, 3]);
+        assert_eq!(catalog.entries[1].length, 0);
+
+        let long = [("THIRTEEN.CHAR", [0u8])];
+        assert!(write(Vec::new(), &long).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::common::*;
+use crate::pal::{self, Pal};
+
+/// How to store indexed image data in a PNG.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PngFormat {
+    /// 8-bit palette image, with index 0 marked as transparent.
+    Indexed,
+    /// 32-bit RGBA image, with index 0 fully transparent.
+    Rgba,
+}
+
+/// Indexed image data in rows, top to bottom, as decoded by the `bm`, `fme` and `wax` readers.
+pub struct Image {
+    pub size: mint::Vector2<u32>,
+    pub data: Vec<u8>,
+}
+
+pub fn write_png(
+    output: impl io::Write,
+    size: mint::Vector2<u32>,
+    data: &[u8],
+    pal: &Pal,
+    format: PngFormat,
+) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(output, size.x, size.y);
+    encoder.set_depth(png::BitDepth::Eight);
+    match format {
+        PngFormat::Indexed => {
+            encoder.set_color(png::ColorType::Indexed);
+            let mut palette = Vec::with_capacity(256 * 3);
+            for entry in &pal.entries {
+                let (r, g, b) = entry.to_rgb();
+                palette.extend_from_slice(&[r, g, b]);
+            }
+            encoder.set_palette(palette);
+            // Only the first entry is needed, the rest default to opaque.
+            encoder.set_trns(vec![0u8]);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(data)?;
+        }
+        PngFormat::Rgba => {
+            encoder.set_color(png::ColorType::RGBA);
+            let mut pixels = Vec::with_capacity(data.len() * 4);
+            for &index in data {
+                if index == 0 {
+                    pixels.extend_from_slice(&[0, 0, 0, 0]);
+                } else {
+                    let (r, g, b) = pal.entries[index as usize].to_rgb();
+                    pixels.extend_from_slice(&[r, g, b, 0xFF]);
+                }
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&pixels)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a PNG as indexed data for `pal`.
+///
+/// Indexed PNGs using the same colours as `pal` keep their indices as-is, anything else is
+/// mapped to the nearest colour in `pal`, with mostly transparent pixels mapped to index 0.
+pub fn read_png(mut input: impl io::Read, pal: &Pal) -> ReadResult<Image> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+
+    if let Some(image) = read_png_matching_indices(&bytes, pal)? {
+        return Ok(image);
+    }
+
+    let (size, pixels) = read_png_rgba(&bytes)?;
+
+    let mut cache = HashMap::new();
+    let data = pixels
+        .chunks_exact(4)
+        .map(|pixel| {
+            if pixel[3] < 0x80 {
+                0
+            } else {
+                let rgb = (pixel[0], pixel[1], pixel[2]);
+                *cache.entry(rgb).or_insert_with(|| pal.nearest(rgb))
+            }
+        })
+        .collect();
+
+    Ok(Image { size, data })
+}
+
+/// Writes the palette as a 16x16 swatch, one pixel per entry, left to right, top to bottom.
+pub fn write_pal_png(output: impl io::Write, pal: &Pal) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(output, 16, 16);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_color(png::ColorType::RGB);
+    let mut pixels = Vec::with_capacity(256 * 3);
+    for entry in &pal.entries {
+        let (r, g, b) = entry.to_rgb();
+        pixels.extend_from_slice(&[r, g, b]);
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    Ok(())
+}
+
+/// Reads a palette from any 256 pixel PNG, such as one written by `write_pal_png()`.
+pub fn read_pal_png(mut input: impl io::Read) -> ReadResult<Pal> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    let (size, pixels) = read_png_rgba(&bytes)?;
+    if size.x * size.y != 256 {
+        return Err(ReadError::decoding(
+            "PNG",
+            "palette PNG must have 256 pixels",
+        ));
+    }
+    let mut entries = [pal::Entry::BLACK; 256];
+    for (entry, pixel) in entries.iter_mut().zip(pixels.chunks_exact(4)) {
+        *entry = pal::Entry::from_rgb((pixel[0], pixel[1], pixel[2]));
+    }
+    Ok(Pal { entries })
+}
+
+fn read_png_matching_indices(bytes: &[u8], pal: &Pal) -> ReadResult<Option<Image>> {
+    let decoder = png_decoder(bytes);
+    let (info, mut reader) = decoder.read_info().map_err(png_error)?;
+    if info.color_type != png::ColorType::Indexed || info.bit_depth != png::BitDepth::Eight {
+        return Ok(None);
+    }
+
+    let palette = match &reader.info().palette {
+        Some(palette) => palette,
+        None => return Ok(None),
+    };
+    let matches = palette
+        .chunks_exact(3)
+        .zip(pal.entries.iter())
+        .skip(1) // index 0 is transparent, its colour doesn't matter.
+        .all(|(png, entry)| (png[0], png[1], png[2]) == entry.to_rgb());
+    if !matches {
+        return Ok(None);
+    }
+
+    let len = checked_len(info.buffer_size() as u64).map_err(|error| error.with_format("PNG"))?;
+    let mut data = vec![0u8; len];
+    reader.next_frame(&mut data).map_err(png_error)?;
+    let size = mint::Vector2 {
+        x: info.width,
+        y: info.height,
+    };
+    Ok(Some(Image { size, data }))
+}
+
+fn read_png_rgba(bytes: &[u8]) -> ReadResult<(mint::Vector2<u32>, Vec<u8>)> {
+    let mut decoder = png_decoder(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(png_error)?;
+    // As RGBA, which is larger than the decoded buffer for the other colour types.
+    checked_len(info.width as u64 * info.height as u64 * 4)
+        .map_err(|error| error.with_format("PNG"))?;
+    let mut buffer = vec![0u8; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(png_error)?;
+
+    let size = mint::Vector2 {
+        x: info.width,
+        y: info.height,
+    };
+
+    let channels = match info.color_type {
+        png::ColorType::RGBA => return Ok((size, buffer)),
+        png::ColorType::RGB => 3,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Grayscale => 1,
+        png::ColorType::Indexed => {
+            return Err(ReadError::decoding("PNG", "palette was not expanded"));
+        }
+    };
+
+    let mut pixels = Vec::with_capacity(buffer.len() / channels * 4);
+    for p in buffer.chunks_exact(channels) {
+        pixels.extend_from_slice(&match *p {
+            [r, g, b] => [r, g, b, 0xFF],
+            [l, a] => [l, l, l, a],
+            [l] => [l, l, l, 0xFF],
+            _ => unreachable!(),
+        });
+    }
+    Ok((size, pixels))
+}
+
+fn png_decoder(bytes: &[u8]) -> png::Decoder<&[u8]> {
+    let limits = png::Limits {
+        bytes: MAX_ALLOCATION,
+    };
+    png::Decoder::new_with_limits(bytes, limits)
+}
+
+fn png_error(error: png::DecodingError) -> ReadError {
+    match error {
+        png::DecodingError::IoError(error) => ReadError::from(error).with_format("PNG"),
+        error => ReadError::decoding("PNG", error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pal() -> Pal {
+        let mut entries = [pal::Entry::BLACK; 256];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            let value = (index & 63) as u8;
+            *entry = pal::Entry {
+                r: value,
+                g: 63 - value,
+                b: (index >> 2) as u8,
+            };
+        }
+        Pal { entries }
+    }
+
+    #[test]
+    fn round_trip() {
+        let pal = test_pal();
+        let size = mint::Vector2 { x: 16, y: 16 };
+        let data = (0..=255).collect::<Vec<u8>>();
+
+        for &format in &[PngFormat::Indexed, PngFormat::Rgba] {
+            let mut png = Vec::new();
+            write_png(&mut png, size, &data, &pal, format).unwrap();
+            let image = read_png(&png[..], &pal).unwrap();
+            assert_eq!(image.size, size);
+            assert_eq!(image.data, data);
+        }
+    }
+}
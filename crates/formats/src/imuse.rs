@@ -0,0 +1,294 @@
+//! iMUSE control messages, embedded in GMD tracks as SysEx messages for manufacturer 0x7D.
+//!
+//! Message kinds and layouts follow the iMUSE v1 messages as documented by ScummVM, along with
+//! the null-terminated label (kind 3) found in Dark Forces music. Most values after the kind
+//! byte are sent as pairs of nibbles, high first, to keep them within 7 bits.
+
+pub const MANUFACTURER: u8 = 0x7D;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    AllocatePart(Part),
+    ShutdownPart {
+        channel: u8,
+    },
+    StartSong,
+    Label(String),
+    /// Markers the game can wait on, e.g. to start a transition.
+    Marker {
+        ids: Vec<u8>,
+    },
+    /// If hook `hook` is set (or is 0), continue playing from `position` in `track`.
+    Jump {
+        hook: u8,
+        track: u16,
+        position: Position,
+    },
+    Transpose {
+        hook: u8,
+        relative: bool,
+        transpose: i8,
+    },
+    PartEnable {
+        hook: u8,
+        channel: u8,
+        enabled: bool,
+    },
+    PartVolume {
+        hook: u8,
+        channel: u8,
+        volume: u8,
+    },
+    PartProgram {
+        hook: u8,
+        channel: u8,
+        program: u8,
+    },
+    PartTranspose {
+        hook: u8,
+        channel: u8,
+        relative: bool,
+        transpose: i8,
+    },
+    /// Repeat from `end` back to `start`, `count` times.
+    SetLoop {
+        count: u16,
+        start: Position,
+        end: Position,
+    },
+    ClearLoop,
+    /// A kind that is not decoded, or is too short for its kind.
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Parameters of a MIDI channel ("part") allocated by the song.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub channel: u8,
+    pub enabled: bool,
+    pub reverb: bool,
+    pub priority: u8,
+    pub volume: u8,
+    pub pan: i8,
+    pub percussion: bool,
+    pub transpose: i8,
+    pub detune: i8,
+    pub pitch_bend_range: u8,
+    pub program: u8,
+}
+
+/// A musical position: a 1-based beat and tick within that beat.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub beat: u16,
+    pub tick: u16,
+}
+
+impl Position {
+    pub fn from_ticks(ticks: u64, ticks_per_beat: u16) -> Self {
+        let ticks_per_beat = ticks_per_beat.max(1) as u64;
+        Self {
+            beat: (ticks / ticks_per_beat + 1) as u16,
+            tick: (ticks % ticks_per_beat) as u16,
+        }
+    }
+
+    pub fn to_ticks(self, ticks_per_beat: u16) -> u64 {
+        (self.beat.max(1) as u64 - 1) * ticks_per_beat as u64 + self.tick as u64
+    }
+}
+
+/// An iMUSE event at an absolute tick within its track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimedEvent {
+    pub track: usize,
+    pub tick: u64,
+    pub event: Event,
+}
+
+/// Decodes the contents of a SysEx message, as provided by `midly`, returning `None` if it is
+/// not an iMUSE message.
+pub fn decode(sysex: &[u8]) -> Option<Event> {
+    let message = sysex.strip_prefix(&[MANUFACTURER])?;
+    let message = message.strip_suffix(&[0xF7]).unwrap_or(message);
+    let (&kind, data) = message.split_first()?;
+    Some(decode_kind(kind, data).unwrap_or_else(|| Event::Unknown {
+        kind,
+        data: data.to_vec(),
+    }))
+}
+
+/// All iMUSE events in the file, in track then tick order.
+pub fn events(smf: &midly::Smf<'_>) -> Vec<TimedEvent> {
+    let mut result = Vec::new();
+    for (track, events) in smf.tracks.iter().enumerate() {
+        let mut tick = 0u64;
+        for event in events {
+            tick += event.delta.as_int() as u64;
+            if let midly::TrackEventKind::SysEx(bytes) = event.kind {
+                if let Some(event) = decode(bytes) {
+                    result.push(TimedEvent { track, tick, event });
+                }
+            }
+        }
+    }
+    result
+}
+
+fn decode_kind(kind: u8, data: &[u8]) -> Option<Event> {
+    Some(match kind {
+        0x00 => {
+            let (&channel, rest) = data.split_first()?;
+            let bytes = nibbles(rest);
+            if bytes.len() < 8 {
+                return None;
+            }
+            Event::AllocatePart(Part {
+                channel: channel & 0x0F,
+                enabled: bytes[0] & 0x01 != 0,
+                reverb: bytes[0] & 0x02 != 0,
+                priority: bytes[1],
+                volume: bytes[2],
+                pan: bytes[3] as i8,
+                percussion: bytes[4] & 0x80 != 0,
+                transpose: bytes[4] as i8,
+                detune: bytes[5] as i8,
+                pitch_bend_range: bytes[6],
+                program: bytes[7],
+            })
+        }
+        0x01 => Event::ShutdownPart {
+            channel: *data.first()? & 0x0F,
+        },
+        0x02 => Event::StartSong,
+        0x03 => {
+            let text = data.split(|&c| c == 0).next().unwrap_or_default();
+            Event::Label(String::from_utf8_lossy(text).into_owned())
+        }
+        0x30 => {
+            let bytes = nibbles(data.get(1..)?);
+            let bytes = bytes.get(..7)?;
+            Event::Jump {
+                hook: bytes[0],
+                track: u16::from_be_bytes([bytes[1], bytes[2]]),
+                position: Position {
+                    beat: u16::from_be_bytes([bytes[3], bytes[4]]),
+                    tick: u16::from_be_bytes([bytes[5], bytes[6]]),
+                },
+            }
+        }
+        0x31 => {
+            let bytes = nibbles(data.get(1..)?);
+            let bytes = bytes.get(..3)?;
+            Event::Transpose {
+                hook: bytes[0],
+                relative: bytes[1] != 0,
+                transpose: bytes[2] as i8,
+            }
+        }
+        0x32..=0x35 => {
+            let (&channel, rest) = data.split_first()?;
+            let channel = channel & 0x0F;
+            let bytes = nibbles(rest);
+            let hook = *bytes.first()?;
+            let value = *bytes.get(1)?;
+            match kind {
+                0x32 => Event::PartEnable {
+                    hook,
+                    channel,
+                    enabled: value != 0,
+                },
+                0x33 => Event::PartVolume {
+                    hook,
+                    channel,
+                    volume: value,
+                },
+                0x34 => Event::PartProgram {
+                    hook,
+                    channel,
+                    program: value,
+                },
+                _ => Event::PartTranspose {
+                    hook,
+                    channel,
+                    relative: value != 0,
+                    transpose: *bytes.get(2)? as i8,
+                },
+            }
+        }
+        0x40 => Event::Marker {
+            ids: data.get(1..)?.to_vec(),
+        },
+        0x50 => {
+            let bytes = nibbles(data.get(1..)?);
+            let bytes = bytes.get(..10)?;
+            let word = |index: usize| u16::from_be_bytes([bytes[index], bytes[index + 1]]);
+            Event::SetLoop {
+                count: word(0),
+                start: Position {
+                    beat: word(2),
+                    tick: word(4),
+                },
+                end: Position {
+                    beat: word(6),
+                    tick: word(8),
+                },
+            }
+        }
+        0x51 => Event::ClearLoop,
+        _ => return None,
+    })
+}
+
+fn nibbles(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2)
+        .map(|pair| (pair[0] << 4) | (pair[1] & 0x0F))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_messages() {
+        assert_eq!(decode(&[0x41, 0x10, 0xF7]), None);
+        assert_eq!(
+            decode(b"\x7D\x03stalk\0\xF7"),
+            Some(Event::Label("stalk".to_string()))
+        );
+        assert_eq!(
+            decode(&[0x7D, 0x40, 0x00, 0x02, 0xF7]),
+            Some(Event::Marker { ids: vec![2] })
+        );
+        // hook 1, track 2, beat 3, tick 0x40
+        assert_eq!(
+            decode(&[0x7D, 0x30, 0x00, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 4, 0, 0xF7]),
+            Some(Event::Jump {
+                hook: 1,
+                track: 2,
+                position: Position {
+                    beat: 3,
+                    tick: 0x40
+                },
+            })
+        );
+        assert_eq!(
+            decode(&[0x7D, 0x30, 0x00, 0, 1, 0xF7]),
+            Some(Event::Unknown {
+                kind: 0x30,
+                data: vec![0x00, 0, 1],
+            })
+        );
+    }
+
+    #[test]
+    fn position_ticks() {
+        let position = Position::from_ticks(250, 120);
+        assert_eq!(position, Position { beat: 3, tick: 10 });
+        assert_eq!(position.to_ticks(120), 250);
+    }
+}
@@ -0,0 +1,459 @@
+//! Finds the game's data files, i.e. the directory holding `DARK.GOB`.
+//!
+//! Sources are tried in order: a path given by the user, `DF_GAME_DIR`, the `game_dir` line of
+//! the config file, Steam libraries, then folders GOG and DOSBox installs are usually kept in.
+//! Names are matched ignoring case, as installs copied from CD or DOS are often lower case.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The environment variable naming the game directory.
+pub const ENV_VAR: &str = "DF_GAME_DIR";
+
+/// Subdirectories of an install the data files can be in: Steam uses `Game`, and DOSBox setups
+/// usually keep the original `C:\DARK`.
+const GAME_DIRS: &[&str] = &["", "Game", "DARK"];
+
+#[derive(Debug)]
+pub struct Install {
+    /// The directory holding `DARK.GOB`.
+    pub dir: PathBuf,
+    pub layout: Layout,
+    pub source: Source,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Under `steamapps/common`, with the data files in `Game`.
+    Steam,
+    /// A GOG install, recognized by its `goggame-*.info` file.
+    Gog,
+    /// A directory mounted by DOSBox, with the data files in `DARK` or a `dosbox*.conf` nearby.
+    DosBox,
+    /// Just the data files, e.g. copied from the CD.
+    Plain,
+}
+
+/// Where the install was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Argument,
+    Environment,
+    Config(PathBuf),
+    SteamLibrary(PathBuf),
+    /// One of the folders GOG and DOSBox installs are usually kept in.
+    Search(PathBuf),
+}
+
+/// No install was found, listing where was looked.
+#[derive(Debug)]
+pub struct NotFound {
+    pub searched: Vec<PathBuf>,
+}
+
+impl Install {
+    /// Finds the install at `path` if given, and otherwise searches for it.
+    ///
+    /// A path from the argument, environment or config file must hold the game, the search
+    /// only continues if none was given.
+    pub fn find(path: Option<&Path>) -> Result<Self, NotFound> {
+        let mut searched = Vec::new();
+
+        let config = config_path().and_then(|config| {
+            let dir = read_config(&config)?;
+            Some((dir, config))
+        });
+        let given = match (path, env::var_os(ENV_VAR), config) {
+            (Some(path), _, _) => Some((path.to_path_buf(), Source::Argument)),
+            (None, Some(dir), _) if !dir.is_empty() => Some((dir.into(), Source::Environment)),
+            (None, _, Some((dir, config))) => Some((dir, Source::Config(config))),
+            _ => None,
+        };
+        if let Some((path, source)) = given {
+            // Allow naming `DARK.GOB` itself.
+            let name = path.file_name().unwrap_or_default();
+            let is_gob = name.eq_ignore_ascii_case("DARK.GOB");
+            let dir = match path.parent() {
+                Some(parent) if is_gob => parent,
+                _ => &path,
+            };
+            return Self::in_dir(dir, source, &mut searched).ok_or(NotFound { searched });
+        }
+
+        for library in steam_libraries() {
+            let common = library.join("steamapps").join("common");
+            for dir in child_dirs(&common) {
+                let name = dir.file_name().unwrap_or_default().to_string_lossy();
+                if name.to_ascii_lowercase().contains("dark forces") {
+                    let source = Source::SteamLibrary(library.clone());
+                    if let Some(install) = Self::in_dir(&dir, source, &mut searched) {
+                        return Ok(install);
+                    }
+                }
+            }
+        }
+
+        for folder in search_folders() {
+            for dir in child_dirs(&folder) {
+                let source = Source::Search(folder.clone());
+                if let Some(install) = Self::in_dir(&dir, source, &mut searched) {
+                    return Ok(install);
+                }
+            }
+        }
+
+        Err(NotFound { searched })
+    }
+
+    /// The install in `dir` or one of its `GAME_DIRS`.
+    fn in_dir(dir: &Path, source: Source, searched: &mut Vec<PathBuf>) -> Option<Self> {
+        searched.push(dir.to_path_buf());
+        for &game_dir in GAME_DIRS {
+            let game_dir = match game_dir {
+                "" => dir.to_path_buf(),
+                name => match find_file(dir, name) {
+                    Some(game_dir) => game_dir,
+                    None => continue,
+                },
+            };
+            if find_file(&game_dir, "DARK.GOB").is_some() {
+                let layout = layout(&game_dir);
+                return Some(Self {
+                    dir: game_dir,
+                    layout,
+                    source,
+                });
+            }
+        }
+        None
+    }
+
+    /// The path of `name` in the game directory, ignoring case, e.g. `LFD/JEDISFX.LFD`.
+    pub fn file(&self, name: &str) -> Option<PathBuf> {
+        name.split(&['/', '\\'][..])
+            .try_fold(self.dir.clone(), |dir, name| find_file(&dir, name))
+    }
+}
+
+impl fmt::Display for Install {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} install in {}, found ",
+            self.layout,
+            self.dir.display()
+        )?;
+        match &self.source {
+            Source::Argument => write!(f, "from the command line"),
+            Source::Environment => write!(f, "from {}", ENV_VAR),
+            Source::Config(config) => write!(f, "from {}", config.display()),
+            Source::SteamLibrary(library) => write!(f, "in Steam library {}", library.display()),
+            Source::Search(folder) => write!(f, "in {}", folder.display()),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Steam => "Steam",
+            Self::Gog => "GOG",
+            Self::DosBox => "DOSBox",
+            Self::Plain => "plain",
+        })
+    }
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not find DARK.GOB, pass the game directory or set {}",
+            ENV_VAR
+        )?;
+        if !self.searched.is_empty() {
+            write!(f, ", searched:")?;
+            for dir in &self.searched {
+                write!(f, "\n  {}", dir.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for NotFound {}
+
+/// The entry of `dir` called `name`, ignoring case.
+fn find_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    let exact = dir.join(name);
+    if exact.exists() {
+        return Some(exact);
+    }
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(name)
+        })
+        .map(|entry| entry.path())
+}
+
+fn child_dirs(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut dirs = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs
+}
+
+/// Whether `dir` has a file whose name, ignoring case, starts with `prefix` and ends with `suffix`.
+fn has_file(dir: &Path, prefix: &str, suffix: &str) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        let name = entry.file_name().to_string_lossy().to_ascii_lowercase();
+        name.starts_with(prefix) && name.ends_with(suffix)
+    })
+}
+
+fn layout(game_dir: &Path) -> Layout {
+    let ancestors = game_dir.ancestors().take(4).collect::<Vec<_>>();
+    let is_named = |dir: &Path, name: &str| {
+        dir.file_name()
+            .unwrap_or_default()
+            .eq_ignore_ascii_case(name)
+    };
+    if ancestors.iter().any(|dir| is_named(dir, "steamapps")) {
+        Layout::Steam
+    } else if ancestors
+        .iter()
+        .any(|dir| has_file(dir, "goggame-", ".info"))
+    {
+        Layout::Gog
+    } else if is_named(game_dir, "DARK")
+        || ancestors.iter().any(|dir| has_file(dir, "dosbox", ".conf"))
+    {
+        Layout::DosBox
+    } else {
+        Layout::Plain
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let home = env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?;
+    Some(PathBuf::from(home)).filter(|home| !home.as_os_str().is_empty())
+}
+
+/// `df/config` in the platform's config directory.
+fn config_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(home_dir()?.join(".config")))
+    };
+    Some(config_dir?.join("df").join("config"))
+}
+
+/// Reads `game_dir = PATH` from the config file, which may have other `key = value` lines and
+/// `#` comments.
+fn read_config(config: &Path) -> Option<PathBuf> {
+    let text = fs::read_to_string(config).ok()?;
+    text.lines().find_map(|line| {
+        let line = line.split('#').next()?;
+        let (key, value) = line.split_once('=')?;
+        if key.trim() == "game_dir" && !value.trim().is_empty() {
+            Some(PathBuf::from(value.trim()))
+        } else {
+            None
+        }
+    })
+}
+
+/// Steam installs, each a library, and the other libraries they list.
+fn steam_libraries() -> Vec<PathBuf> {
+    let mut steam_dirs = Vec::new();
+    #[cfg(windows)]
+    steam_dirs.extend(steam_registry_path());
+    if let Some(home) = home_dir() {
+        steam_dirs.push(home.join(".steam").join("steam"));
+        steam_dirs.push(home.join(".local").join("share").join("Steam"));
+        // Flatpak
+        let flatpak = home
+            .join(".var")
+            .join("app")
+            .join("com.valvesoftware.Steam");
+        steam_dirs.push(flatpak.join(".local").join("share").join("Steam"));
+        steam_dirs.push(
+            home.join("Library")
+                .join("Application Support")
+                .join("Steam"),
+        );
+    }
+    if cfg!(windows) {
+        steam_dirs.push(PathBuf::from(r"C:\Program Files (x86)\Steam"));
+    }
+
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for steam_dir in steam_dirs {
+        let folders = steam_dir.join("steamapps").join("libraryfolders.vdf");
+        let folders = fs::read_to_string(folders).unwrap_or_default();
+        let found = std::iter::once(steam_dir).chain(library_folders(&folders));
+        for library in found {
+            // `~/.steam/steam` is usually a link to another of the Steam directories.
+            let library = library.canonicalize().unwrap_or(library);
+            if library.is_dir() && !libraries.contains(&library) {
+                libraries.push(library);
+            }
+        }
+    }
+    libraries
+}
+
+#[cfg(windows)]
+fn steam_registry_path() -> Option<PathBuf> {
+    let key = registry::Hive::CurrentUser
+        .open(r"Software\Valve\Steam", registry::Security::Read)
+        .ok()?;
+    match key.value("SteamPath").ok()? {
+        registry::Data::String(path) => Some(PathBuf::from(path.to_os_string())),
+        _ => None,
+    }
+}
+
+/// The library paths listed in a `libraryfolders.vdf`, which has been both
+/// `"1" "PATH"` and `"1" { "path" "PATH" ... }`.
+fn library_folders(vdf: &str) -> Vec<PathBuf> {
+    // Quoted strings, with `\\` and `\"` escapes.
+    let mut tokens = Vec::new();
+    let mut chars = vdf.chars();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => token.extend(chars.next()),
+                c => token.push(c),
+            }
+        }
+        tokens.push(token);
+    }
+
+    tokens
+        .windows(2)
+        .filter(|pair| {
+            let key = &pair[0];
+            let is_index = !key.is_empty() && key.bytes().all(|b| b.is_ascii_digit());
+            key.eq_ignore_ascii_case("path") || (is_index && pair[1].contains(&['/', '\\'][..]))
+        })
+        .map(|pair| PathBuf::from(&pair[1]))
+        .collect()
+}
+
+/// Folders GOG and DOSBox installs are usually kept in, whose subdirectories are searched.
+fn search_folders() -> Vec<PathBuf> {
+    let mut folders = Vec::new();
+    if let Some(home) = home_dir() {
+        for name in &["GOG Games", "Games", "dosbox", "DOSBox", "DOS"] {
+            folders.push(home.join(name));
+        }
+    }
+    if cfg!(windows) {
+        for folder in &[
+            r"C:\GOG Games",
+            r"C:\Program Files (x86)\GOG Galaxy\Games",
+            r"C:\DOSBox",
+        ] {
+            folders.push(PathBuf::from(folder));
+        }
+    }
+    folders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn library_folders_vdf() {
+        let vdf = r#"
+"libraryfolders"
+{
+	"contentstatsid"		"123"
+	"0"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"apps" { "32400" "123" }
+	}
+	"1"
+	{
+		"path"		"D:\\SteamLibrary"
+	}
+}
+"#;
+        assert_eq!(
+            library_folders(vdf),
+            [
+                PathBuf::from("/home/user/.local/share/Steam"),
+                PathBuf::from(r"D:\SteamLibrary")
+            ]
+        );
+        let old = "\"LibraryFolders\"\n{\n\t\"TimeNextStatsReport\"\t\"1\"\n\t\"1\"\t\"E:\\\\Games\\\\Steam\"\n}";
+        assert_eq!(library_folders(old), [PathBuf::from(r"E:\Games\Steam")]);
+    }
+
+    #[test]
+    fn find_layouts() {
+        let dir = env::temp_dir().join(format!("df-install-{}", std::process::id()));
+        let steam = dir.join("steamapps/common/Dark Forces/Game");
+        let dosbox = dir.join("dos/dark");
+        let plain = dir.join("plain");
+        for (game_dir, gob) in &[
+            (&steam, "DARK.GOB"),
+            (&dosbox, "dark.gob"),
+            (&plain, "Dark.Gob"),
+        ] {
+            fs::create_dir_all(game_dir.join("lfd")).unwrap();
+            fs::write(game_dir.join(gob), b"GOB\n").unwrap();
+        }
+
+        let install = Install::find(Some(&dir.join("steamapps/common/Dark Forces"))).unwrap();
+        assert_eq!(
+            (install.layout, install.source),
+            (Layout::Steam, Source::Argument)
+        );
+        assert_eq!(install.dir, steam);
+
+        let install = Install::find(Some(&dir.join("dos"))).unwrap();
+        assert_eq!(install.layout, Layout::DosBox);
+        assert_eq!(install.file("LFD"), Some(dosbox.join("lfd")));
+        assert_eq!(install.file("LFD/JEDISFX.LFD"), None);
+
+        let install = Install::find(Some(&plain.join("DARK.GOB"))).unwrap();
+        assert_eq!(install.layout, Layout::Plain);
+        assert_eq!(install.file("dark.gob"), Some(plain.join("Dark.Gob")));
+
+        let error = Install::find(Some(&dir)).unwrap_err();
+        assert_eq!(error.searched, [dir.as_path()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ use std::io;
 use nom::bytes::complete::{is_not, tag};
 use nom::character::complete::{char, digit1, line_ending, not_line_ending, space0, space1};
 use nom::combinator::{complete, cut, map, map_res, opt, recognize, value};
+use nom::error::{context, ContextError, ErrorKind, ParseError, VerboseErrorKind};
 use nom::multi::{many0, many1, many_m_n};
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom::Parser;
@@ -10,6 +11,8 @@ use nom::Parser;
 use nom::branch::alt;
 use std::str::FromStr;
 
+use crate::common::*;
+
 type NomError<'a> = nom::error::VerboseError<&'a str>;
 type NomResult<'a, O> = nom::IResult<&'a str, O, NomError<'a>>;
 
@@ -76,16 +79,19 @@ fn entry<'a, V>(
     tag_name: &'static str,
     value: impl Parser<&'a str, V, NomError<'a>>,
 ) -> impl FnMut(&'a str) -> NomResult<'a, V> {
-    terminated(entry_inline(tag_name, value), eol)
+    context(tag_name, terminated(entry_inline(tag_name, value), eol))
 }
 
 fn entry_opt<'a, V>(
     tag_name: &'static str,
     value: impl Parser<&'a str, V, NomError<'a>>,
 ) -> impl FnMut(&'a str) -> NomResult<'a, Option<V>> {
-    terminated(
-        preceded(tag(tag_name), opt(delimited(space1, cut(value), space0))),
-        eol,
+    context(
+        tag_name,
+        terminated(
+            preceded(tag(tag_name), opt(delimited(space1, cut(value), space0))),
+            eol,
+        ),
     )
 }
 
@@ -94,9 +100,71 @@ fn entry_inline<'a, V>(
     tag_name: &'static str,
     value: impl Parser<&'a str, V, NomError<'a>>,
 ) -> impl FnMut(&'a str) -> NomResult<'a, V> {
-    preceded(pair(tag(tag_name), space1), terminated(cut(value), space0))
+    context(
+        tag_name,
+        preceded(pair(tag(tag_name), space1), terminated(cut(value), space0)),
+    )
+}
+
+// handle the count of the following lines, which cannot be more than there are characters
+// left, so a hostile count does not allocate a huge `many_m_n` vector up front
+fn line_count<'a>(tag_name: &'static str) -> impl FnMut(&'a str) -> NomResult<'a, usize> {
+    move |input| {
+        let (rest, count) = entry(tag_name, uint)(input)?;
+        if count > rest.len() {
+            let error = ParseError::from_error_kind(input, ErrorKind::TooLarge);
+            return Err(nom::Err::Failure(ContextError::add_context(
+                input, tag_name, error,
+            )));
+        }
+        Ok((rest, count))
+    }
+}
+
+/// Describes where and why parsing failed, from the innermost error.
+fn parse_error(input: &str, error: NomError) -> ReadError {
+    let (remaining, kind) = match error.errors.first() {
+        Some(first) => first,
+        None => return ReadError::decoding("LEV", "invalid LEV"),
+    };
+    let offset = input.len() - remaining.len();
+    let line = input[..offset].matches('\n').count() + 1;
+    let field = error.errors.iter().find_map(|(_, kind)| match kind {
+        VerboseErrorKind::Context(name) => Some(name.trim_end_matches(':')),
+        _ => None,
+    });
+
+    let expected = match kind {
+        VerboseErrorKind::Char(c) => format!("expected '{}'", c),
+        VerboseErrorKind::Context(name) => format!("invalid {}", name),
+        VerboseErrorKind::Nom(ErrorKind::Tag) => match field {
+            Some(field) => format!("expected {}", field),
+            None => "unexpected text".to_string(),
+        },
+        VerboseErrorKind::Nom(ErrorKind::Digit) => "expected a number".to_string(),
+        VerboseErrorKind::Nom(ErrorKind::MapRes) => "number out of range".to_string(),
+        VerboseErrorKind::Nom(ErrorKind::CrLf) => "expected end of line".to_string(),
+        VerboseErrorKind::Nom(ErrorKind::IsNot) => "expected a value".to_string(),
+        VerboseErrorKind::Nom(ErrorKind::Space) => "expected a space".to_string(),
+        VerboseErrorKind::Nom(ErrorKind::TooLarge) => "more than the file holds".to_string(),
+        VerboseErrorKind::Nom(kind) => format!("{} failed", kind.description()),
+    };
+    let found = remaining.lines().next().unwrap_or_default().trim_end();
+    let message = if found.is_empty() {
+        format!("{}, found end of line", expected)
+    } else {
+        let found: String = found.chars().take(24).collect();
+        format!("{}, found \"{}\"", expected, found)
+    };
+
+    let error = ReadError::decoding("LEV", message).at_line(line);
+    match field {
+        Some(field) => error.with_field(field),
+        None => error,
+    }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Lev {
     pub palette_name: String,
     pub parallax: mint::Vector2<f32>,
@@ -105,19 +173,25 @@ pub struct Lev {
 }
 
 impl Lev {
-    pub fn read(mut file: impl io::Read) -> io::Result<Self> {
+    pub fn read(mut file: impl io::Read) -> ReadResult<Self> {
         let mut str = String::new();
-        file.read_to_string(&mut str)?;
+        file.read_to_string(&mut str)
+            .map_err(|error| ReadError::from(error).with_format("LEV"))?;
         let input: &str = &str;
-        let (_, result) =
+        let (rest, result) =
             complete(Self::parse)(input).map_err(|err: nom::Err<NomError>| match err {
                 nom::Err::Incomplete(_) => unreachable!(),
-                nom::Err::Error(error) | nom::Err::Failure(error) => {
-                    let message = nom::error::convert_error(input, error);
-                    eprintln!("{}", message);
-                    io::Error::new(io::ErrorKind::InvalidData, message)
-                }
+                nom::Err::Error(error) | nom::Err::Failure(error) => parse_error(input, error),
             })?;
+        // Anything but padding after the last sector is a sector that failed to start.
+        if !rest
+            .trim_matches(|c: char| c.is_whitespace() || c == '\x1a')
+            .is_empty()
+        {
+            let error: NomError = ParseError::from_error_kind(rest, ErrorKind::Tag);
+            let error = ContextError::add_context(rest, "SECTOR", error);
+            return Err(parse_error(input, error));
+        }
         Ok(result)
     }
 
@@ -147,6 +221,7 @@ impl Lev {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sector {
     pub id: u32,
     pub name: Option<String>,
@@ -165,6 +240,12 @@ pub struct Sector {
 impl Sector {
     fn parse(input: &str) -> NomResult<Self> {
         let (input, id) = entry("SECTOR", uint)(input)?;
+        // Once a sector has started, anything missing is an error rather than the end of the
+        // sectors.
+        cut(move |input| Self::parse_fields(input, id))(input)
+    }
+
+    fn parse_fields(input: &str, id: u32) -> NomResult<'_, Self> {
         let (input, name) = entry_opt("NAME", word)(input)?;
         let (input, ambient) = entry("AMBIENT", uint)(input)?;
         let (input, floor_texture) = entry("FLOOR TEXTURE", Texture::parse)(input)?;
@@ -175,7 +256,7 @@ impl Sector {
         let (input, flags) = entry("FLAGS", tuple((uint, uint, uint)))(input)?;
         let (input, layer) = entry("LAYER", sint)(input)?;
 
-        let (input, vertex_count) = entry("VERTICES", uint)(input)?;
+        let (input, vertex_count) = line_count("VERTICES")(input)?;
         let (input, vertices) = many_m_n(
             vertex_count,
             vertex_count,
@@ -188,7 +269,7 @@ impl Sector {
             ),
         )(input)?;
 
-        let (input, wall_count) = entry("WALLS", uint)(input)?;
+        let (input, wall_count) = line_count("WALLS")(input)?;
         let (input, walls) =
             many_m_n(wall_count, wall_count, entry_inline("WALL", Wall::parse))(input)?;
 
@@ -211,6 +292,7 @@ impl Sector {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Wall {
     pub left_vertex: usize,
     pub right_vertex: usize,
@@ -260,6 +342,7 @@ impl Wall {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Texture {
     pub index: Option<usize>,
     pub offset: mint::Vector2<f32>,
@@ -278,3 +361,81 @@ impl Texture {
         Ok((input, Self { index, offset }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEV: &str = "LEV 2.1
+LEVELNAME TEST
+PALETTE TEST.PAL
+MUSIC NULL.GMD
+PARALLAX 1024.0000 1024.0000
+TEXTURES 1
+TEXTURE: DEFAULT.BM # comment
+NUMSECTORS 1
+
+SECTOR 0
+NAME
+AMBIENT 20
+FLOOR TEXTURE 0 0.00 0.00 0
+FLOOR ALTITUDE 0.00
+CEILING TEXTURE 0 0.00 0.00 0
+CEILING ALTITUDE -8.00
+SECOND ALTITUDE 0.00
+FLAGS 0 0 0
+LAYER 0
+VERTICES 2
+X: 0.00 Z: 0.00
+X: 1.00 Z: 0.00
+WALLS 1
+WALL LEFT: 0 RIGHT: 1 MID: 0 0.00 0.00 0 TOP: 0 0.00 0.00 0 BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: -1 MIRROR: -1 WALK: -1 FLAGS: 0 0 0 LIGHT: 0
+";
+
+    #[test]
+    fn read() {
+        let lev = Lev::read(LEV.as_bytes()).unwrap();
+        assert_eq!(lev.texture_names, ["DEFAULT.BM"]);
+        assert_eq!(lev.sectors.len(), 1);
+        assert_eq!(lev.sectors[0].walls[0].right_vertex, 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let lev = Lev::read(LEV.as_bytes()).unwrap();
+        let json = serde_json::to_string(&lev).unwrap();
+        // `mint` vectors serialize as arrays.
+        assert!(json.contains(r#""parallax":[1024.0,1024.0]"#));
+        let lev: Lev = serde_json::from_str(&json).unwrap();
+        assert_eq!(lev.sectors[0].vertices[1].x, 1.0);
+        assert_eq!(lev.sectors[0].walls[0].adjoin_sector, None);
+    }
+
+    #[test]
+    fn error_location() {
+        let broken = LEV.replace("FLOOR ALTITUDE 0.00", "FLOOR ALTITUDE low");
+        let error = Lev::read(broken.as_bytes()).err().unwrap();
+        assert_eq!(error.location, Some(Location::Line(14)));
+        assert_eq!(error.field, Some("FLOOR ALTITUDE"));
+        assert_eq!(
+            error.to_string(),
+            "LEV at line 14 in FLOOR ALTITUDE: expected a number, found \"low\""
+        );
+
+        let broken = LEV.replace("AMBIENT", "AMBIANCE");
+        let error = Lev::read(broken.as_bytes()).err().unwrap();
+        assert_eq!(error.location, Some(Location::Line(12)));
+        assert_eq!(error.field, Some("AMBIENT"));
+
+        let broken = LEV.replace("LIGHT: 0", "LIGHT: 0 bright");
+        let error = Lev::read(broken.as_bytes()).err().unwrap();
+        assert_eq!(error.location, Some(Location::Line(24)));
+
+        // Not allocated up front.
+        let broken = LEV.replace("VERTICES 2", "VERTICES 4000000000");
+        let error = Lev::read(broken.as_bytes()).err().unwrap();
+        assert_eq!(error.location, Some(Location::Line(20)));
+        assert_eq!(error.field, Some("VERTICES"));
+    }
+}
@@ -1,43 +1,79 @@
 use std::io;
 
 use crate::common::*;
-use crate::error::ReadResult;
 
 pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Catalog> {
+    read_catalog(&mut file).map_err(|error| error.locate("LFD", &mut file))
+}
+
+fn read_catalog(mut file: impl io::Read + io::Seek) -> ReadResult<Catalog> {
     let mut offset = 0u32;
 
     let mut entries = Vec::new();
 
     loop {
         let mut ty = [0u8; 4];
-        match file.read(&mut ty)? {
+        match file.read(&mut ty).field("entry type")? {
             0 => break,
             4 => {}
-            _ => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            _ => {
+                return Err(
+                    ReadError::from(io::Error::from(io::ErrorKind::UnexpectedEof))
+                        .with_field("entry type"),
+                )
+            }
         }
 
-        let raw_name = read_buf(&mut file, [0u8; 8])?;
+        let raw_name = read_buf(&mut file, [0u8; 8]).field("entry name")?;
 
-        let length = read_u32(&mut file)?;
+        let length = read_u32(&mut file).field("entry length")?;
 
-        let ty = String::from_utf8(ty.to_vec()).unwrap();
+        let ty = entry_name(&ty).field("entry type")?;
 
-        let mut name =
-            String::from_utf8(raw_name.split(|&c| c == 0u8).next().unwrap().to_vec()).unwrap();
+        let mut name = entry_name(&raw_name).field("entry name")?;
 
         name.push('.');
         name.push_str(&ty);
 
+        // Entries point at their data, after the header, as in a GOB.
+        let (data_offset, end) = match offset
+            .checked_add(4 + 8 + 4)
+            .and_then(|data_offset| Some((data_offset, data_offset.checked_add(length)?)))
+        {
+            Some(offsets) => offsets,
+            None => {
+                let message = "entry is past the 4GB limit";
+                return Err(ReadError::invalid_data(message).with_field("entry length"));
+            }
+        };
+
         entries.push(CatalogEntry {
             name,
             length,
-            offset,
+            offset: data_offset,
         });
 
         file.seek(io::SeekFrom::Current(length as i64))?;
 
-        offset += 4 + 8 + 4 + length;
+        offset = end;
     }
 
     Ok(Catalog { entries })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_offsets() {
+        let mut bytes = b"RMAPresource\x04\0\0\0DATA".to_vec();
+        bytes.extend_from_slice(b"DELTtest\0\0\0\0\x02\0\0\0\x01\x02");
+
+        let catalog = read(io::Cursor::new(&bytes)).unwrap();
+        let entry = &catalog.entries[1];
+        assert_eq!(entry.name, "test.DELT");
+        assert_eq!((entry.offset, entry.length), (36, 2));
+        assert_eq!(&bytes[36..], [1, 2]);
+    }
+}
@@ -3,12 +3,240 @@ pub use mint;
 pub mod common;
 mod error;
 
+pub mod archive;
+pub mod asset;
 pub mod bm;
+pub mod detect;
+pub mod diff;
+pub mod edition;
 pub mod fme;
 pub mod gmd;
 pub mod gob;
+pub mod image;
+pub mod imuse;
+pub mod install;
 pub mod lev;
 pub mod lfd;
 pub mod pal;
+pub mod pcm;
+pub mod resample;
+pub mod schedule;
 pub mod voc;
 pub mod wax;
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Reads `data` with every reader, which must return rather than panic.
+    fn read_all(data: &[u8]) {
+        let _ = asset::Asset::detect_and_read("", data);
+        let _ = bm::Bm::read(Cursor::new(data));
+        let _ = fme::Fme::read(Cursor::new(data));
+        if let Ok(gmd) = gmd::Gmd::read(data) {
+            let _ = gmd.imuse_events();
+            let _ = schedule::Schedule::from_gmd(&gmd).map(|schedule| schedule.duration());
+        }
+        let _ = gob::read(Cursor::new(data));
+        let _ = lev::Lev::read(data);
+        let _ = lfd::read(Cursor::new(data));
+        if let Ok(voc) = voc::Voc::read(Cursor::new(data)) {
+            let _ = voc.decode_with_loop(3);
+        }
+        let _ = wax::Wax::read(Cursor::new(data));
+    }
+
+    #[test]
+    fn malformed_data() {
+        let mut samples = Vec::new();
+
+        let mut bytes = Vec::new();
+        let bm = bm::Bm {
+            size: mint::Vector2 { x: 3, y: 2 },
+            idem_size: mint::Vector2 { x: 3, y: 2 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data: vec![1, 2, 3, 4, 5, 6],
+        };
+        bm.write(&mut bytes).unwrap();
+        samples.push(bytes);
+
+        let mut bytes = Vec::new();
+        let fme = fme::Fme {
+            frame: fme::Frame {
+                offset: mint::Vector2 { x: -1, y: 2 },
+                flip: false,
+            },
+            cell: fme::Cell {
+                size: mint::Vector2 { x: 2, y: 2 },
+                data: vec![1, 2, 3, 4],
+            },
+        };
+        fme.write(&mut bytes).unwrap();
+        samples.push(bytes);
+
+        let mut bytes = Vec::new();
+        let smf =
+            b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x0C\0\x90\x3C\x40\x60\x80\x3C\0\0\xFF\x2F\0";
+        let mut gmd = gmd::Gmd::from_mid(smf.to_vec()).unwrap();
+        gmd.chunks.push(gmd::Chunk {
+            id: *b"MDpg",
+            data: vec![1, 2, 3],
+        });
+        gmd.write(&mut bytes).unwrap();
+        samples.push(bytes);
+
+        let mut bytes = b"GOB\n\x0C\0\0\0DATA\x01\0\0\0\x08\0\0\0\x04\0\0\0".to_vec();
+        bytes.extend_from_slice(b"TEST.BM\0\0\0\0\0\0");
+        samples.push(bytes);
+
+        let mut bytes = b"RMAPresource\x04\0\0\0DATA".to_vec();
+        bytes.extend_from_slice(b"DELTtest\0\0\0\0\x02\0\0\0\x01\x02");
+        samples.push(bytes);
+
+        let mut bytes = Vec::new();
+        let pcm = pcm::Pcm {
+            data: vec![0x80, 0x90, 0xA0, 0x90],
+            ..pcm::Pcm::new_u8_mono(11_025)
+        };
+        voc::Voc::from_pcm(&pcm).write(&mut bytes).unwrap();
+        samples.push(bytes);
+
+        for sample in &samples {
+            read_all(sample);
+            for len in 0..sample.len() {
+                read_all(&sample[..len]);
+            }
+            for index in 0..sample.len() {
+                for &flip in &[0x01, 0x80, 0xFF] {
+                    let mut corrupted = sample.clone();
+                    corrupted[index] ^= flip;
+                    read_all(&corrupted);
+                }
+            }
+        }
+
+        // Mostly printable noise, so the text parser gets some way in.
+        let mut state = 0x1234_5678u32;
+        for len in &[4, 16, 64, 256, 1024] {
+            let noise = (0..*len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect::<Vec<u8>>();
+            read_all(&noise);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+        let json = serde_json::to_string(value).unwrap();
+        let value: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+        value
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        // Where there is a writer, the round-tripped value must also write the same bytes.
+        fn written(write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            write(&mut bytes).unwrap();
+            bytes
+        }
+
+        let bm = bm::Bm {
+            size: mint::Vector2 { x: 3, y: 2 },
+            idem_size: mint::Vector2 { x: 3, y: 2 },
+            flags: 0,
+            log_size_y: false,
+            compression: bm::Compression::None,
+            data: vec![1, 2, 3, 4, 5, 6],
+        };
+        assert_eq!(
+            written(|bytes| round_trip(&bm).write(bytes)),
+            written(|bytes| bm.write(bytes))
+        );
+
+        let cell = fme::Cell {
+            size: mint::Vector2 { x: 2, y: 2 },
+            data: vec![1, 2, 3, 4],
+        };
+        let fme = fme::Fme {
+            frame: fme::Frame {
+                offset: mint::Vector2 { x: -1, y: 2 },
+                flip: true,
+            },
+            cell,
+        };
+        assert_eq!(
+            written(|bytes| round_trip(&fme).write(bytes)),
+            written(|bytes| fme.write(bytes))
+        );
+
+        let wax = round_trip(&wax::Wax {
+            version: 0x0001_1000,
+            num_sequences: 1,
+            num_frames: 1,
+            num_cells: 1,
+            states: vec![wax::WaxState {
+                offset: 32,
+                world_size: mint::Vector2 { x: 65536, y: 65536 },
+                frame_rate: 10,
+                angle_sequence_indices: [0; 32],
+            }],
+            sequences: vec![wax::WaxSequence {
+                offset: 160,
+                frame_indices: vec![0],
+            }],
+            frames: vec![wax::WaxFrame {
+                offset: 288,
+                frame: fme.frame,
+                cell_index: 0,
+            }],
+            cells: vec![fme::Cell {
+                size: fme.cell.size,
+                data: fme.cell.data.clone(),
+            }],
+        });
+        assert_eq!(wax.states[0].world_size.x, 65536);
+        assert_eq!(wax.sequences[0].frame_indices, [0]);
+        assert!(wax.frames[0].frame.flip);
+        assert_eq!(wax.cells[0].data, [1, 2, 3, 4]);
+
+        let voc = voc::Voc::from_pcm(&pcm::Pcm {
+            data: vec![0x80, 0x90, 0xA0, 0x90],
+            ..pcm::Pcm::new_u8_mono(11_025)
+        });
+        assert_eq!(
+            written(|bytes| round_trip(&voc).write(bytes)),
+            written(|bytes| voc.write(bytes))
+        );
+
+        let mut pal = pal::Pal {
+            entries: [pal::Entry::BLACK; 256],
+        };
+        pal.entries[255] = pal::Entry { r: 63, g: 32, b: 1 };
+        assert_eq!(
+            written(|bytes| round_trip(&pal).write(bytes)),
+            written(|bytes| pal.write(bytes))
+        );
+        assert!(serde_json::from_str::<pal::Pal>(r#"{"entries":[{"r":0,"g":0,"b":0}]}"#).is_err());
+
+        let smf = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk\0\0\0\x04\0\xFF\x2F\0";
+        let mut gmd = gmd::Gmd::from_mid(smf.to_vec()).unwrap();
+        gmd.chunks.push(gmd::Chunk {
+            id: *b"MDpg",
+            data: vec![1, 2, 3],
+        });
+        assert_eq!(
+            written(|bytes| round_trip(&gmd).write(bytes)),
+            written(|bytes| gmd.write(bytes))
+        );
+    }
+}
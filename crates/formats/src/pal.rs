@@ -1,23 +1,91 @@
-use crate::common::read_buf;
+use crate::common::{read_buf, ReadError, ReadResult};
 use std::io;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pal {
+    #[cfg_attr(feature = "serde", serde(with = "entries"))]
     pub entries: [Entry; 256],
 }
 
+/// Serde only implements arrays of up to 32 elements, so the entries go through a sequence.
+#[cfg(feature = "serde")]
+mod entries {
+    use super::Entry;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::convert::TryInto;
+
+    pub fn serialize<S: Serializer>(
+        entries: &[Entry; 256],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(entries.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[Entry; 256], D::Error> {
+        let entries = Vec::<Entry>::deserialize(deserializer)?;
+        let len = entries.len();
+        entries
+            .try_into()
+            .map_err(|_| de::Error::invalid_length(len, &"256 palette entries"))
+    }
+}
+
 impl Pal {
-    pub fn read(file: impl io::Read) -> io::Result<Self> {
-        let bytes = read_buf(file, [0u8; 256 * 3])?;
+    pub fn read(file: impl io::Read) -> ReadResult<Self> {
+        let bytes = read_buf(file, [0u8; 256 * 3])
+            .map_err(|error| ReadError::from(error).with_format("PAL"))?;
         Ok(Self {
             // Safety: [u8; 3] has the same layout as Entry,
             //         so [u8; 256 * 3] has the same layout as [Entry; 256]
             entries: unsafe { std::mem::transmute(bytes) },
         })
     }
+
+    /// Shades from black to white, for images without a palette of their own.
+    pub fn grayscale() -> Self {
+        let mut entries = [Entry::BLACK; 256];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            let value = index as u8;
+            *entry = Entry::from_rgb((value, value, value));
+        }
+        Self { entries }
+    }
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        for entry in &self.entries {
+            output.write_all(&[entry.r, entry.g, entry.b])?;
+        }
+        Ok(())
+    }
+
+    /// Index of the closest colour to an 8-bit RGB value, never returning 0 as that is used
+    /// for transparency.
+    pub fn nearest(&self, (r, g, b): (u8, u8, u8)) -> u8 {
+        let mut best_index = 1;
+        let mut best_distance = u32::MAX;
+        for index in 1..256 {
+            let (er, eg, eb) = self.entries[index].to_rgb();
+            let dr = er as i32 - r as i32;
+            let dg = eg as i32 - g as i32;
+            let db = eb as i32 - b as i32;
+            let distance = (dr * dr + dg * dg + db * db) as u32;
+            if distance < best_distance {
+                best_index = index;
+                best_distance = distance;
+                if distance == 0 {
+                    break;
+                }
+            }
+        }
+        best_index as u8
+    }
 }
 
 #[repr(C)]
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entry {
     pub r: u8,
     pub g: u8,
@@ -34,6 +102,14 @@ impl Entry {
             channel_6_to_8_bit(self.b),
         )
     }
+
+    pub fn from_rgb((r, g, b): (u8, u8, u8)) -> Self {
+        Self {
+            r: r >> 2,
+            g: g >> 2,
+            b: b >> 2,
+        }
+    }
 }
 
 fn channel_6_to_8_bit(value: u8) -> u8 {
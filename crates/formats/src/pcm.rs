@@ -0,0 +1,163 @@
+use std::{io, time};
+
+/// Decoded audio, as interleaved little-endian samples in the same layout as a WAV `data`
+/// chunk: 8-bit samples are unsigned, 16-bit samples are signed, 32-bit samples are float.
+#[derive(Clone, Debug)]
+pub struct Pcm {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    pub data: Vec<u8>,
+}
+
+/// How each sample of a `Pcm` is stored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    U8,
+    I16,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            Self::U8 => 8,
+            Self::I16 => 16,
+            Self::F32 => 32,
+        }
+    }
+}
+
+impl Pcm {
+    pub fn new_u8_mono(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            channels: 1,
+            bits_per_sample: 8,
+            data: Vec::new(),
+        }
+    }
+
+    /// Converts interleaved samples in `-1.0..=1.0` to 16-bit, clipping anything out of range.
+    pub fn from_f32(sample_rate: u32, channels: u16, samples: &[f32]) -> Self {
+        Self::encode(sample_rate, channels, SampleFormat::I16, samples)
+    }
+
+    /// Converts interleaved samples in `-1.0..=1.0` to `format`, clipping anything out of range
+    /// for the integer formats.
+    pub fn encode(sample_rate: u32, channels: u16, format: SampleFormat, samples: &[f32]) -> Self {
+        let mut data = Vec::with_capacity(samples.len() * format.bits_per_sample() as usize / 8);
+        for &sample in samples {
+            match format {
+                SampleFormat::U8 => {
+                    data.push((sample * 128.0 + 128.0).round().clamp(0.0, 255.0) as u8);
+                }
+                SampleFormat::I16 => {
+                    let value = (sample * 32767.0).round().clamp(-32768.0, 32767.0) as i16;
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                SampleFormat::F32 => {
+                    data.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+        }
+        Self {
+            sample_rate,
+            channels,
+            bits_per_sample: format.bits_per_sample(),
+            data,
+        }
+    }
+
+    pub fn sample_format(&self) -> SampleFormat {
+        match self.bits_per_sample {
+            8 => SampleFormat::U8,
+            32 => SampleFormat::F32,
+            _ => SampleFormat::I16,
+        }
+    }
+
+    /// Converts the samples to `-1.0..1.0`, still interleaved.
+    pub fn to_f32(&self) -> Vec<f32> {
+        match self.sample_format() {
+            SampleFormat::U8 => self
+                .data
+                .iter()
+                .map(|&sample| (sample as f32 - 128.0) / 128.0)
+                .collect(),
+            SampleFormat::I16 => self
+                .data
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0)
+                .collect(),
+            SampleFormat::F32 => self
+                .data
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+                .collect(),
+        }
+    }
+
+    /// The same audio with samples stored as `format`.
+    pub fn to_format(&self, format: SampleFormat) -> Self {
+        if format == self.sample_format() {
+            return self.clone();
+        }
+        Self::encode(self.sample_rate, self.channels, format, &self.to_f32())
+    }
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.channels as usize * (self.bits_per_sample as usize / 8)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.data.len() / self.bytes_per_frame()
+    }
+
+    pub fn duration(&self) -> time::Duration {
+        time::Duration::from_secs_f64(self.frame_count() as f64 / self.sample_rate as f64)
+    }
+
+    pub fn write_wav(&self, mut output: impl io::Write) -> io::Result<()> {
+        let block_align = self.bytes_per_frame() as u16;
+        let byte_rate = self.sample_rate * block_align as u32;
+        let data_len = self.data.len() as u32;
+        let pad = data_len & 1;
+        // Float samples need the extended format chunk, and a fact chunk with the frame count.
+        let float = self.sample_format() == SampleFormat::F32;
+        let header_len = if float {
+            4 + 8 + 18 + 8 + 4 + 8
+        } else {
+            4 + 8 + 16 + 8
+        };
+
+        output.write_all(b"RIFF")?;
+        output.write_all(&(header_len + data_len + pad).to_le_bytes())?;
+        output.write_all(b"WAVE")?;
+
+        output.write_all(b"fmt ")?;
+        output.write_all(&(if float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        let format_tag: u16 = if float { 3 } else { 1 }; // IEEE float or PCM
+        output.write_all(&format_tag.to_le_bytes())?;
+        output.write_all(&self.channels.to_le_bytes())?;
+        output.write_all(&self.sample_rate.to_le_bytes())?;
+        output.write_all(&byte_rate.to_le_bytes())?;
+        output.write_all(&block_align.to_le_bytes())?;
+        output.write_all(&self.bits_per_sample.to_le_bytes())?;
+        if float {
+            output.write_all(&0u16.to_le_bytes())?;
+
+            output.write_all(b"fact")?;
+            output.write_all(&4u32.to_le_bytes())?;
+            output.write_all(&(self.frame_count() as u32).to_le_bytes())?;
+        }
+
+        output.write_all(b"data")?;
+        output.write_all(&data_len.to_le_bytes())?;
+        output.write_all(&self.data)?;
+        if pad != 0 {
+            output.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! Band-limited sample rate conversion, so the ~11kHz sounds can be played and exported at
+//! modern output rates without aliasing or drifting in pitch.
+
+use std::ops::Range;
+
+use crate::pcm::{Pcm, SampleFormat};
+
+/// The usual output rates.
+pub const OUTPUT_RATES: [u32; 3] = [22_050, 44_100, 48_000];
+
+/// The rate sounds are exported at, which every player supports, rather than the odd rates VOC
+/// files encode.
+pub const EXPORT_RATE: u32 = 44_100;
+/// The sample format sounds are exported in.
+pub const EXPORT_FORMAT: SampleFormat = SampleFormat::I16;
+
+/// Zero crossings of the filter kernel on each side.
+const ZERO_CROSSINGS: usize = 16;
+/// Kernel table entries per zero crossing, linearly interpolated between.
+const TABLE_RESOLUTION: usize = 256;
+/// Kaiser window shape, trading transition width for stop band attenuation.
+const KAISER_BETA: f64 = 8.0;
+/// The pass band as a fraction of the lower Nyquist frequency, leaving room for the transition
+/// band below it.
+const ROLLOFF: f64 = 0.94;
+
+/// A windowed sinc filter converting from one sample rate to another.
+///
+/// The input rate should be the rate the sound was actually recorded at, e.g. the ~10_989Hz
+/// that VOC files encode rather than the nominal 11_025Hz, otherwise the pitch is off by the
+/// difference.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    from_rate: f64,
+    to_rate: u32,
+    /// The filter cutoff as a fraction of the input Nyquist frequency.
+    cutoff: f64,
+    /// The windowed sinc from zero to `ZERO_CROSSINGS`.
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: f64, to_rate: u32) -> Self {
+        let cutoff = (to_rate as f64 / from_rate).min(1.0) * ROLLOFF;
+        let scale = 1.0 / bessel_i0(KAISER_BETA);
+        let table = (0..=ZERO_CROSSINGS * TABLE_RESOLUTION)
+            .map(|index| {
+                let x = index as f64 / TABLE_RESOLUTION as f64;
+                let window = x / ZERO_CROSSINGS as f64;
+                let kaiser = bessel_i0(KAISER_BETA * (1.0 - window * window).sqrt()) * scale;
+                (sinc(x) * kaiser) as f32
+            })
+            .collect();
+        Self {
+            from_rate,
+            to_rate,
+            cutoff,
+            table,
+        }
+    }
+
+    pub fn from_rate(&self) -> f64 {
+        self.from_rate
+    }
+
+    pub fn to_rate(&self) -> u32 {
+        self.to_rate
+    }
+
+    /// Output frames per input frame.
+    pub fn ratio(&self) -> f64 {
+        self.to_rate as f64 / self.from_rate
+    }
+
+    /// The number of output frames for `frames` input frames.
+    pub fn output_len(&self, frames: usize) -> usize {
+        (frames as f64 * self.ratio()).round() as usize
+    }
+
+    /// Resamples interleaved `input`. Silence is assumed before and after it.
+    pub fn process(&self, input: &[f32], channels: usize) -> Vec<f32> {
+        let frames = input.len() / channels;
+        let ratio = self.ratio();
+        let mut output = Vec::with_capacity(self.output_len(frames) * channels);
+        self.render(
+            input,
+            channels,
+            &mut output,
+            0..self.output_len(frames),
+            |frame| frame as f64 / ratio,
+            None,
+        );
+        output
+    }
+
+    /// Resamples interleaved `input` where the frames in `loop_range` can repeat forever,
+    /// returning the output and its loop range.
+    ///
+    /// The loop is filtered as if it repeats, so it joins without a click, and is stretched to
+    /// a whole number of output frames, so repeating it does not drift.
+    pub fn process_looped(
+        &self,
+        input: &[f32],
+        channels: usize,
+        loop_range: Range<usize>,
+    ) -> (Vec<f32>, Range<usize>) {
+        let frames = input.len() / channels;
+        if loop_range.start >= loop_range.end || loop_range.end > frames {
+            return (self.process(input, channels), 0..0);
+        }
+        let ratio = self.ratio();
+        let output_loop = self.output_len(loop_range.start)..self.output_len(loop_range.end);
+        let output_frames = output_loop.end + self.output_len(frames - loop_range.end);
+        let loop_scale = loop_range.len() as f64 / output_loop.len().max(1) as f64;
+
+        let mut output = Vec::with_capacity(output_frames * channels);
+        self.render(
+            input,
+            channels,
+            &mut output,
+            0..output_loop.start,
+            |frame| frame as f64 / ratio,
+            None,
+        );
+        self.render(
+            input,
+            channels,
+            &mut output,
+            output_loop.clone(),
+            |frame| loop_range.start as f64 + (frame - output_loop.start) as f64 * loop_scale,
+            Some(&loop_range),
+        );
+        self.render(
+            input,
+            channels,
+            &mut output,
+            output_loop.end..output_frames,
+            |frame| loop_range.end as f64 + (frame - output_loop.end) as f64 / ratio,
+            None,
+        );
+        (output, output_loop)
+    }
+
+    /// Appends the output `frames`, each filtered around the input position `position(frame)`.
+    /// With `wrap`, input frames outside it are read from inside it, as if it repeats.
+    fn render(
+        &self,
+        input: &[f32],
+        channels: usize,
+        output: &mut Vec<f32>,
+        frames: Range<usize>,
+        position: impl Fn(usize) -> f64,
+        wrap: Option<&Range<usize>>,
+    ) {
+        let frame_count = (input.len() / channels) as isize;
+        let reach = ZERO_CROSSINGS as f64 / self.cutoff;
+        let mut sum = vec![0.0f32; channels];
+
+        for frame in frames {
+            let position = position(frame);
+            if self.from_rate == self.to_rate as f64 && wrap.is_none() {
+                // Nothing to filter.
+                let index = position as usize * channels;
+                output.extend_from_slice(&input[index..index + channels]);
+                continue;
+            }
+
+            for value in sum.iter_mut() {
+                *value = 0.0;
+            }
+            let first = (position - reach).ceil() as isize;
+            let last = (position + reach).floor() as isize;
+            for index in first..=last {
+                let weight = self.kernel(position - index as f64);
+                let index = match wrap {
+                    Some(range) if index < range.start as isize || index >= range.end as isize => {
+                        let start = range.start as isize;
+                        start + (index - start).rem_euclid(range.len() as isize)
+                    }
+                    _ => index,
+                };
+                if index < 0 || index >= frame_count {
+                    continue;
+                }
+                let start = index as usize * channels;
+                for (value, &sample) in sum.iter_mut().zip(&input[start..start + channels]) {
+                    *value += sample * weight;
+                }
+            }
+            output.extend_from_slice(&sum);
+        }
+    }
+
+    /// The filter weight of an input frame `distance` input frames from the output position.
+    fn kernel(&self, distance: f64) -> f32 {
+        let x = distance.abs() * self.cutoff * TABLE_RESOLUTION as f64;
+        let index = x as usize;
+        if index + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let fraction = (x - index as f64) as f32;
+        let value = self.table[index] + (self.table[index + 1] - self.table[index]) * fraction;
+        value * self.cutoff as f32
+    }
+}
+
+/// Converts `pcm` to `sample_rate` and `format`, e.g. to export a decoded VOC as a WAV.
+pub fn convert(pcm: &Pcm, sample_rate: u32, format: SampleFormat) -> Pcm {
+    let channels = pcm.channels.max(1);
+    let samples = pcm.to_f32();
+    let samples = if pcm.sample_rate == sample_rate {
+        samples
+    } else {
+        Resampler::new(pcm.sample_rate as f64, sample_rate).process(&samples, channels as usize)
+    };
+    Pcm::encode(sample_rate, channels, format, &samples)
+}
+
+/// Converts `pcm` to `EXPORT_RATE` and `EXPORT_FORMAT`.
+pub fn convert_for_export(pcm: &Pcm) -> Pcm {
+    convert(pcm, EXPORT_RATE, EXPORT_FORMAT)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * std::f64::consts::PI;
+        x.sin() / x
+    }
+}
+
+/// The zeroth order modified Bessel function of the first kind, for the Kaiser window.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        let squared = term * term;
+        sum += squared;
+        if squared < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, sample_rate: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| {
+                (frame as f64 * frequency * 2.0 * std::f64::consts::PI / sample_rate).sin() as f32
+                    * 0.5
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn keeps_pitch() {
+        // One second of the actual rate for an "11_025Hz" VOC.
+        let from_rate = 1_000_000.0 / 91.0;
+        let input = sine(1_000.0, from_rate, 10_989);
+        for &to_rate in &OUTPUT_RATES {
+            let output = Resampler::new(from_rate, to_rate).process(&input, 1);
+            assert_eq!(output.len(), to_rate as usize);
+
+            // Count rising zero crossings away from the edges.
+            let middle = &output[to_rate as usize / 10..to_rate as usize * 9 / 10];
+            let crossings = middle
+                .windows(2)
+                .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+                .count();
+            assert!((crossings as i32 - 800).abs() <= 1, "{}", crossings);
+            assert!((rms(middle) - rms(&input)).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn removes_aliases() {
+        let input = sine(20_000.0, 48_000.0, 48_000);
+        let output = Resampler::new(48_000.0, 22_050).process(&input, 1);
+        assert!(rms(&output[1_000..21_000]) < 0.002);
+
+        let input = sine(5_000.0, 48_000.0, 48_000);
+        let output = Resampler::new(48_000.0, 22_050).process(&input, 1);
+        assert!(rms(&output[1_000..21_000]) > 0.35);
+    }
+
+    #[test]
+    fn smooth_loop() {
+        let input = vec![0.5; 1_000];
+        let resampler = Resampler::new(11_000.0, 48_000);
+        let (output, loop_range) = resampler.process_looped(&input, 1, 200..1_000);
+        assert_eq!(loop_range, 873..4_364);
+        assert_eq!(output.len(), 4_364);
+        // A constant loop stays constant across the join.
+        for &sample in output[loop_range.start..].iter() {
+            assert!((sample - 0.5).abs() < 0.01, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn convert_format() {
+        let pcm = Pcm {
+            data: vec![0x80, 0xC0, 0x80, 0x40],
+            ..Pcm::new_u8_mono(11_025)
+        };
+        let same = convert(&pcm, 11_025, SampleFormat::F32);
+        assert_eq!(same.bits_per_sample, 32);
+        assert_eq!(same.to_f32(), [0.0, 0.5, 0.0, -0.5]);
+
+        let converted = convert(&pcm, 44_100, SampleFormat::I16);
+        assert_eq!(converted.sample_rate, 44_100);
+        assert_eq!(converted.bits_per_sample, 16);
+        assert_eq!(converted.frame_count(), 16);
+
+        let mut wav = Vec::new();
+        same.write_wav(&mut wav).unwrap();
+        assert_eq!(wav.len(), 58 + 16);
+        assert_eq!(&wav[4..8], &(50u32 + 16).to_le_bytes());
+    }
+}
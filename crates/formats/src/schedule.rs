@@ -0,0 +1,470 @@
+//! Converts the tracks of a standard MIDI file into a single list of events at absolute times,
+//! independent of any playback clock, so it can be seeked, looped and tested.
+
+use std::time::Duration;
+
+use crate::common::*;
+use crate::gmd::Gmd;
+
+/// The tempo until the first tempo change: 120 beats per minute.
+pub const DEFAULT_US_PER_BEAT: u32 = 500_000;
+
+/// A MIDI event at an absolute position in the song.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Event<'a> {
+    pub track: usize,
+    pub tick: u64,
+    pub time: Duration,
+    pub kind: midly::TrackEventKind<'a>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct TempoChange {
+    tick: u64,
+    time: Duration,
+    us_per_beat: u32,
+}
+
+/// All events of a song, in playback order.
+///
+/// For `Metrical` timing, tempo meta events in any track change the tick length from that tick
+/// on. For `Timecode` timing ticks have a fixed length and tempo events do not affect timing.
+#[derive(Clone, Debug)]
+pub struct Schedule<'a> {
+    timing: midly::Timing,
+    tempo_changes: Vec<TempoChange>,
+    track_starts: Vec<u64>,
+    events: Vec<Event<'a>>,
+}
+
+impl<'a> Schedule<'a> {
+    pub fn new(smf: &midly::Smf<'a>) -> Self {
+        let mut events = Vec::new();
+        let mut track_starts = Vec::with_capacity(smf.tracks.len());
+        let mut track_start = 0u64;
+        for (track, track_events) in smf.tracks.iter().enumerate() {
+            track_starts.push(track_start);
+            let mut tick = track_start;
+            for event in track_events {
+                tick += event.delta.as_int() as u64;
+                events.push(Event {
+                    track,
+                    tick,
+                    time: Duration::ZERO,
+                    kind: event.kind,
+                });
+            }
+            // Sequential tracks play one after another, the others all start together.
+            if smf.header.format == midly::Format::Sequential {
+                track_start = tick;
+            }
+        }
+        // Stable, so events at the same tick stay in track order.
+        events.sort_by_key(|event| event.tick);
+
+        let mut schedule = Self {
+            timing: smf.header.timing,
+            tempo_changes: vec![TempoChange {
+                tick: 0,
+                time: Duration::ZERO,
+                us_per_beat: DEFAULT_US_PER_BEAT,
+            }],
+            track_starts,
+            events,
+        };
+
+        for index in 0..schedule.events.len() {
+            let event = schedule.events[index];
+            let time = schedule.tick_to_time(event.tick);
+            schedule.events[index].time = time;
+            if let midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(us_per_beat)) = event.kind
+            {
+                let change = TempoChange {
+                    tick: event.tick,
+                    time,
+                    us_per_beat: us_per_beat.as_int(),
+                };
+                let last = schedule.tempo_changes.last_mut().unwrap();
+                if last.tick == change.tick {
+                    *last = change;
+                } else {
+                    schedule.tempo_changes.push(change);
+                }
+            }
+        }
+
+        schedule
+    }
+
+    /// Schedules the standard MIDI file in `gmd`, ignoring iMUSE control.
+    pub fn from_gmd(gmd: &'a Gmd) -> ReadResult<Self> {
+        Ok(Self::new(&gmd.parse_smf()?))
+    }
+
+    pub fn events(&self) -> &[Event<'a>] {
+        &self.events
+    }
+
+    pub fn timing(&self) -> midly::Timing {
+        self.timing
+    }
+
+    /// Ticks per beat, for `Metrical` timing.
+    pub fn ticks_per_beat(&self) -> Option<u16> {
+        match self.timing {
+            midly::Timing::Metrical(ticks_per_beat) => Some(ticks_per_beat.as_int()),
+            midly::Timing::Timecode(..) => None,
+        }
+    }
+
+    /// The tick `track` starts at: after the previous track for `Sequential` files, otherwise 0.
+    pub fn track_start(&self, track: usize) -> Option<u64> {
+        self.track_starts.get(track).copied()
+    }
+
+    /// The tick of the last event, usually the last end of track.
+    pub fn end_tick(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.tick)
+    }
+
+    /// The total playing time, without looping.
+    pub fn duration(&self) -> Duration {
+        self.events
+            .last()
+            .map_or(Duration::ZERO, |event| event.time)
+    }
+
+    /// The time at `tick`, which may be past the last event.
+    pub fn tick_to_time(&self, tick: u64) -> Duration {
+        match self.timing {
+            midly::Timing::Metrical(ticks_per_beat) => {
+                let change = self.tempo_change_at(|change| change.tick <= tick);
+                let ticks = (tick - change.tick) as u128;
+                let nanos = ticks * change.us_per_beat as u128 * 1000
+                    / ticks_per_beat.as_int().max(1) as u128;
+                change.time + duration_from_nanos(nanos)
+            }
+            midly::Timing::Timecode(fps, subframes) => {
+                let ticks_per_second = fps.as_f32() as f64 * subframes.max(1) as f64;
+                Duration::from_secs_f64(tick as f64 / ticks_per_second)
+            }
+        }
+    }
+
+    /// The tick playing at `time`, rounded down.
+    pub fn time_to_tick(&self, time: Duration) -> u64 {
+        match self.timing {
+            midly::Timing::Metrical(ticks_per_beat) => {
+                let change = self.tempo_change_at(|change| change.time <= time);
+                let nanos = (time - change.time).as_nanos();
+                let ticks = nanos * ticks_per_beat.as_int() as u128
+                    / (change.us_per_beat.max(1) as u128 * 1000);
+                change.tick + ticks as u64
+            }
+            midly::Timing::Timecode(fps, subframes) => {
+                let ticks_per_second = fps.as_f32() as f64 * subframes.max(1) as f64;
+                (time.as_secs_f64() * ticks_per_second) as u64
+            }
+        }
+    }
+
+    /// The index of the first event at or after `time`.
+    pub fn index_at_time(&self, time: Duration) -> usize {
+        self.events.partition_point(|event| event.time < time)
+    }
+
+    /// The index of the first event at or after `tick`.
+    pub fn index_at_tick(&self, tick: u64) -> usize {
+        self.events.partition_point(|event| event.tick < tick)
+    }
+
+    /// The channel state events before `index`: program, controller and pitch bend changes and
+    /// SysEx messages, which should be sent before starting playback from `index` so the song
+    /// sounds the same as if it had played from the start.
+    pub fn chase(&self, index: usize) -> impl Iterator<Item = &Event<'a>> {
+        self.events[..index.min(self.events.len())]
+            .iter()
+            .filter(|event| match event.kind {
+                midly::TrackEventKind::Midi { message, .. } => matches!(
+                    message,
+                    midly::MidiMessage::ProgramChange { .. }
+                        | midly::MidiMessage::Controller { .. }
+                        | midly::MidiMessage::PitchBend { .. }
+                ),
+                midly::TrackEventKind::SysEx(_) => true,
+                _ => false,
+            })
+    }
+
+    /// Plays the events from the start, with no loop.
+    pub fn cursor(&self) -> Cursor<'_, 'a> {
+        Cursor {
+            schedule: self,
+            index: 0,
+            tick: 0,
+            origin: Duration::ZERO,
+            looped: Duration::ZERO,
+            loop_region: None,
+        }
+    }
+
+    fn tempo_change_at(&self, is_before: impl Fn(&TempoChange) -> bool) -> TempoChange {
+        let index = self.tempo_changes.partition_point(is_before);
+        self.tempo_changes[index.max(1) - 1]
+    }
+}
+
+fn duration_from_nanos(nanos: u128) -> Duration {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Playback repeats the ticks from `start` up to (but not including) `end`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LoopRegion {
+    pub start: u64,
+    pub end: u64,
+    /// How many more times to jump back to `start`, or `None` to loop forever.
+    pub count: Option<u32>,
+}
+
+/// An event and the time it should play, measured from when playback started at the cursor's
+/// first event, including any time spent looping.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Scheduled<'s, 'a> {
+    pub time: Duration,
+    pub event: &'s Event<'a>,
+}
+
+/// Iterates a `Schedule` in playback order, following any loop region.
+#[derive(Clone, Debug)]
+pub struct Cursor<'s, 'a> {
+    schedule: &'s Schedule<'a>,
+    index: usize,
+    /// The tick playback has reached.
+    tick: u64,
+    /// The song time when playback started.
+    origin: Duration,
+    /// The song time skipped back by looping.
+    looped: Duration,
+    loop_region: Option<LoopRegion>,
+}
+
+impl<'s, 'a> Cursor<'s, 'a> {
+    /// Sets the loop region, or clears it with `None`.
+    ///
+    /// Regions containing no events are ignored, as playback would never advance.
+    pub fn set_loop(&mut self, loop_region: Option<LoopRegion>) {
+        let events = &self.schedule.events;
+        self.loop_region = loop_region.filter(|region| {
+            let index = self.schedule.index_at_tick(region.start);
+            matches!(events.get(index), Some(event) if event.tick < region.end)
+        });
+    }
+
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        self.loop_region
+    }
+
+    /// Moves to song time `time`, so the next event is the first at or after it.
+    ///
+    /// Following playback times are relative to `time`, so the first event after seeking to a
+    /// time between events is not played immediately. Use `Schedule::chase()` to restore the
+    /// channel state at the new position.
+    pub fn seek(&mut self, time: Duration) {
+        self.index = self.schedule.index_at_time(time);
+        self.tick = self.schedule.time_to_tick(time);
+        self.origin = time;
+        self.looped = Duration::ZERO;
+    }
+
+    /// The index of the next event in `Schedule::events()`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The tick playback has reached.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+}
+
+impl<'s, 'a> Iterator for Cursor<'s, 'a> {
+    type Item = Scheduled<'s, 'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let schedule = self.schedule;
+        let mut next = schedule.events.get(self.index);
+
+        if let Some(region) = &mut self.loop_region {
+            let reached_end = !matches!(next, Some(event) if event.tick < region.end);
+            if self.tick < region.end && reached_end && region.count != Some(0) {
+                self.looped +=
+                    schedule.tick_to_time(region.end) - schedule.tick_to_time(region.start);
+                self.tick = region.start;
+                self.index = schedule.index_at_tick(region.start);
+                if let Some(count) = &mut region.count {
+                    *count -= 1;
+                }
+                next = schedule.events.get(self.index);
+            }
+        }
+
+        let event = next?;
+        self.index += 1;
+        self.tick = event.tick;
+        Some(Scheduled {
+            time: event.time + self.looped - self.origin,
+            event,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u15, u24, u28, u4, u7};
+
+    fn event(delta: u32, kind: midly::TrackEventKind<'static>) -> midly::TrackEvent<'static> {
+        midly::TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        }
+    }
+
+    fn note_on(key: u8) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Midi {
+            channel: u4::new(0),
+            message: midly::MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(100),
+            },
+        }
+    }
+
+    fn tempo(us_per_beat: u32) -> midly::TrackEventKind<'static> {
+        midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(u24::new(us_per_beat)))
+    }
+
+    const END: midly::TrackEventKind<'static> =
+        midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack);
+
+    /// Two tracks at 100 ticks per beat: a note every beat, and the tempo doubling after 2 beats.
+    fn smf() -> midly::Smf<'static> {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::Parallel,
+            midly::Timing::Metrical(u15::new(100)),
+        ));
+        smf.tracks
+            .push(vec![event(200, tempo(250_000)), event(200, END)]);
+        smf.tracks.push(vec![
+            event(0, note_on(60)),
+            event(100, note_on(61)),
+            event(100, note_on(62)),
+            event(100, note_on(63)),
+            event(100, END),
+        ]);
+        smf
+    }
+
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let smf = smf();
+        let schedule = Schedule::new(&smf);
+        let times = schedule
+            .events()
+            .iter()
+            .filter(|event| event.track == 1)
+            .map(|event| event.time)
+            .collect::<Vec<_>>();
+        assert_eq!(times, [0, 500, 1000, 1250, 1500].map(millis));
+        assert_eq!(schedule.duration(), millis(1500));
+        assert_eq!(schedule.end_tick(), 400);
+        assert_eq!(schedule.tick_to_time(250), millis(1125));
+        assert_eq!(schedule.time_to_tick(millis(1125)), 250);
+        assert_eq!(schedule.time_to_tick(millis(750)), 150);
+    }
+
+    #[test]
+    fn timecode() {
+        let mut smf = smf();
+        smf.header.timing = midly::Timing::Timecode(midly::Fps::Fps25, 4);
+        let schedule = Schedule::new(&smf);
+        // 100 ticks per second, ignoring tempo.
+        assert_eq!(schedule.duration(), millis(4000));
+        assert_eq!(schedule.tick_to_time(250), millis(2500));
+        assert_eq!(schedule.time_to_tick(millis(2500)), 250);
+    }
+
+    #[test]
+    fn sequential() {
+        let mut smf = smf();
+        smf.header.format = midly::Format::Sequential;
+        let schedule = Schedule::new(&smf);
+        assert_eq!(schedule.end_tick(), 800);
+        assert_eq!(schedule.events()[2].tick, 400);
+        assert_eq!(schedule.events()[2].kind, note_on(60));
+        assert_eq!(schedule.track_start(1), Some(400));
+        assert_eq!(schedule.track_start(2), None);
+    }
+
+    #[test]
+    fn seek() {
+        let smf = smf();
+        let schedule = Schedule::new(&smf);
+        let mut cursor = schedule.cursor();
+        cursor.seek(millis(1100));
+        assert_eq!(cursor.tick(), 240);
+        let next = cursor.next().unwrap();
+        assert_eq!(next.event.kind, note_on(63));
+        assert_eq!(next.time, millis(150));
+
+        let index = schedule.index_at_time(millis(1100));
+        assert_eq!(schedule.chase(index).count(), 0);
+    }
+
+    #[test]
+    fn loops() {
+        let smf = smf();
+        let schedule = Schedule::new(&smf);
+        let mut cursor = schedule.cursor();
+        cursor.set_loop(Some(LoopRegion {
+            start: 100,
+            end: 200,
+            count: Some(2),
+        }));
+        let played = cursor
+            .filter(|scheduled| scheduled.event.track == 1)
+            .map(|scheduled| (scheduled.event.kind, scheduled.time))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            played,
+            [
+                (note_on(60), millis(0)),
+                (note_on(61), millis(500)),
+                (note_on(61), millis(1000)),
+                (note_on(61), millis(1500)),
+                (note_on(62), millis(2000)),
+                (note_on(63), millis(2250)),
+                (END, millis(2500)),
+            ]
+        );
+
+        // No events in the region.
+        let mut cursor = schedule.cursor();
+        cursor.set_loop(Some(LoopRegion {
+            start: 450,
+            end: 500,
+            count: None,
+        }));
+        assert_eq!(cursor.loop_region(), None);
+        assert_eq!(cursor.count(), schedule.events().len());
+    }
+}
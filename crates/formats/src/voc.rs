@@ -1,27 +1,41 @@
+use std::ops::Range;
 use std::{fmt, io};
 
 use crate::common::*;
+use crate::pcm::{Pcm, SampleFormat};
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Voc {
     pub version: u16,
     pub chunks: Vec<Chunk>,
 }
 
+const SIGNATURE: &[u8; 0x16] = b"Creative Voice File\x1a\x1a\0";
+
+/// Chunk contents are limited to a 24-bit length.
+const MAX_CHUNK_LEN: usize = 0xFF_FFFF;
+
 impl Voc {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
-        if &read_buf(&mut file, [0u8; 0x16])? != b"Creative Voice File\x1a\x1a\0" {
-            return Err(ReadError::Signature);
+        Self::read_voc(&mut file).map_err(|error| error.locate("VOC", &mut file))
+    }
+
+    fn read_voc(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
+        if &read_buf(&mut file, [0u8; 0x16])? != SIGNATURE {
+            return Err(ReadError::signature("VOC"));
         }
 
-        let version = read_u16(&mut file)?;
-        let version_check = read_u16(&mut file)?;
-        let expected_version_check = (!version).wrapping_add(0x1234);
+        let version = read_u16(&mut file).field("version")?;
+        let version_check = read_u16(&mut file).field("version check")?;
+        let expected_version_check = version_check_for(version);
         if expected_version_check != version_check {
-            eprintln!(
-                "VOC version check: {:04x} (expected {:04x})",
+            let message = format!(
+                "version check {:04x} does not match {:04x}",
                 version_check, expected_version_check
             );
-            return Err(ReadError::Decoding("version check failed"));
+            return Err(ReadError::decoding("VOC", message)
+                .at_offset(0x18)
+                .with_field("version check"));
         }
 
         let mut chunks = Vec::new();
@@ -32,12 +46,209 @@ impl Voc {
 
         Ok(Self { version, chunks })
     }
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        output.write_all(SIGNATURE)?;
+        output.write_all(&self.version.to_le_bytes())?;
+        output.write_all(&version_check_for(self.version).to_le_bytes())?;
+        for chunk in &self.chunks {
+            chunk.write(&mut output)?;
+        }
+        output.write_all(&[0])?; // terminator
+        Ok(())
+    }
+
+    /// Encodes PCM as a VOC, using the original 8-bit sound chunks where the format allows,
+    /// otherwise the version 1.20 sound chunk.
+    pub fn from_pcm(pcm: &Pcm) -> Self {
+        if pcm.sample_format() == SampleFormat::F32 {
+            return Self::from_pcm(&pcm.to_format(SampleFormat::I16));
+        }
+        let legacy_sample_rate = if pcm.channels == 1 && pcm.bits_per_sample == 8 {
+            SampleRate::from_hz(pcm.sample_rate)
+        } else {
+            None
+        };
+
+        match legacy_sample_rate {
+            Some(sample_rate) => {
+                let mut pieces = pcm.data.chunks(MAX_CHUNK_LEN - 2);
+                let data = pieces.next().unwrap_or_default().to_vec();
+                let mut chunks = vec![Chunk::SoundStart {
+                    sample_rate,
+                    codec: Codec::Pcm8,
+                    data,
+                }];
+                chunks.extend(pieces.map(|data| Chunk::SoundContinue {
+                    data: data.to_vec(),
+                }));
+                Self {
+                    version: 0x010a,
+                    chunks,
+                }
+            }
+            None => {
+                let codec = if pcm.bits_per_sample == 8 {
+                    Codec::Pcm8
+                } else {
+                    Codec::Pcm16
+                };
+                // keep whole frames in each chunk
+                let piece_len =
+                    (MAX_CHUNK_LEN - 12) / pcm.bytes_per_frame() * pcm.bytes_per_frame();
+                let chunks = pcm
+                    .data
+                    .chunks(piece_len)
+                    .map(|data| Chunk::Sound {
+                        sample_rate: pcm.sample_rate,
+                        bits_per_sample: pcm.bits_per_sample as u8,
+                        channels: pcm.channels as u8,
+                        codec,
+                        data: data.to_vec(),
+                    })
+                    .collect();
+                Self {
+                    version: 0x0114,
+                    chunks,
+                }
+            }
+        }
+    }
+
+    /// Decodes the chunks to a single PCM stream.
+    ///
+    /// Silence is expanded at the sound's sample rate, and repeated sections are included up
+    /// to `max_repeats` extra times, which also applies to endlessly repeating sections.
+    /// 8-bit and ADPCM sounds decode to 8-bit samples, 16-bit and A-law/μ-law sounds decode
+    /// to 16-bit samples.
+    pub fn decode(&self, max_repeats: u16) -> ReadResult<Pcm> {
+        Ok(self.decode_with_loop(max_repeats)?.0)
+    }
+
+    /// Decodes like `decode()`, also returning the frames covered by the first pass of an
+    /// endlessly repeating section, so a player can loop them.
+    pub fn decode_with_loop(&self, max_repeats: u16) -> ReadResult<(Pcm, Option<Range<usize>>)> {
+        self.decode_chunks(max_repeats)
+            .map_err(|error| error.or_format("VOC"))
+    }
+
+    fn decode_chunks(&self, max_repeats: u16) -> ReadResult<(Pcm, Option<Range<usize>>)> {
+        let mut decoder = Decoder::default();
+
+        // (index of the first repeated chunk, remaining repeats)
+        let mut repeat: Option<(usize, u16)> = None;
+        let mut endless_start = None;
+        let mut endless = None;
+        let frame_count = |decoder: &Decoder| decoder.pcm.as_ref().map_or(0, Pcm::frame_count);
+
+        let mut index = 0;
+        while let Some(chunk) = self.chunks.get(index) {
+            index += 1;
+            match chunk {
+                Chunk::SoundStart {
+                    sample_rate,
+                    codec,
+                    data,
+                } => {
+                    let format = match decoder.extended.take() {
+                        Some(format) => format,
+                        None => Format {
+                            sample_rate: sample_rate.to_hz(),
+                            channels: 1,
+                            codec: *codec,
+                        },
+                    };
+                    decoder.start(format)?;
+                    decoder.decode(data)?;
+                }
+                Chunk::SoundContinue { data } => {
+                    decoder.decode(data)?;
+                }
+                Chunk::Silence {
+                    sample_count,
+                    sample_rate,
+                } => {
+                    decoder.silence(*sample_count as u64 + 1, sample_rate.to_hz())?;
+                }
+                Chunk::Repeat { count } => {
+                    if repeat.is_some() {
+                        return Err(ReadError::decoding("VOC", "nested repeat"));
+                    }
+                    if count.is_none() && endless.is_none() {
+                        endless_start = Some(frame_count(&decoder));
+                    }
+                    let count = count.unwrap_or(max_repeats).min(max_repeats);
+                    repeat = Some((index, count));
+                }
+                Chunk::RepeatEnd => {
+                    if let Some(start) = endless_start.take() {
+                        endless = Some(start..frame_count(&decoder));
+                    }
+                    match repeat {
+                        Some((start, remaining)) if remaining != 0 => {
+                            repeat = Some((start, remaining - 1));
+                            index = start;
+                        }
+                        _ => {
+                            repeat = None;
+                        }
+                    }
+                }
+                Chunk::Extended {
+                    time_constant,
+                    codec,
+                    stereo,
+                } => {
+                    let channels = if *stereo { 2 } else { 1 };
+                    let divisor = channels as u32 * (65536 - *time_constant as u32);
+                    decoder.extended = Some(Format {
+                        sample_rate: (256_000_000 + divisor / 2) / divisor,
+                        channels,
+                        codec: *codec,
+                    });
+                }
+                Chunk::Sound {
+                    sample_rate,
+                    bits_per_sample,
+                    channels,
+                    codec,
+                    data,
+                } => {
+                    if *codec == Codec::Pcm8 && *bits_per_sample != 8
+                        || *codec == Codec::Pcm16 && *bits_per_sample != 16
+                    {
+                        return Err(
+                            ReadError::decoding("VOC", "sample size does not match codec")
+                                .with_field("bits per sample"),
+                        );
+                    }
+                    decoder.start(Format {
+                        sample_rate: *sample_rate,
+                        channels: *channels as u16,
+                        codec: *codec,
+                    })?;
+                    decoder.decode(data)?;
+                }
+                Chunk::Marker { .. } | Chunk::Text { .. } | Chunk::Unknown { .. } => {}
+            }
+        }
+
+        let pcm = decoder
+            .pcm
+            .ok_or_else(|| ReadError::decoding("VOC", "no sound"))?;
+        Ok((pcm, endless))
+    }
 }
 
+fn version_check_for(version: u16) -> u16 {
+    (!version).wrapping_add(0x1234)
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Chunk {
     SoundStart {
         sample_rate: SampleRate,
-        codec: u8,
+        codec: Codec,
         data: Vec<u8>,
     },
     SoundContinue {
@@ -47,36 +258,56 @@ pub enum Chunk {
         sample_count: u16,
         sample_rate: SampleRate,
     },
+    Marker {
+        id: u16,
+    },
+    Text {
+        text: String,
+    },
     Repeat {
         count: Option<u16>,
     },
     RepeatEnd,
+    /// Overrides the sample rate and format of the following `SoundStart`.
+    Extended {
+        time_constant: u16,
+        codec: Codec,
+        stereo: bool,
+    },
+    /// Version 1.20 sound data, with an explicit format.
+    Sound {
+        sample_rate: u32,
+        bits_per_sample: u8,
+        channels: u8,
+        codec: Codec,
+        data: Vec<u8>,
+    },
     Unknown {
         ty: u8,
-        len: u32,
+        data: Vec<u8>,
     },
 }
 
 impl Chunk {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Option<Self>> {
-        let ty = read_u8(&mut file)?;
+        let ty = read_u8(&mut file).field("chunk type")?;
         if ty == 0 {
             return Ok(None);
         }
 
         let len = u32::from_le_bytes([
-            read_u8(&mut file)?,
-            read_u8(&mut file)?,
-            read_u8(&mut file)?,
+            read_u8(&mut file).field("chunk length")?,
+            read_u8(&mut file).field("chunk length")?,
+            read_u8(&mut file).field("chunk length")?,
             0,
         ]);
 
-        let mut content = io::Cursor::new(read_vec(file, len as usize)?);
+        let mut content = io::Cursor::new(read_vec(file, len as usize).field("chunk")?);
 
         Ok(Some(match ty {
             1 => {
                 let sample_rate = SampleRate(read_u8(&mut content)?);
-                let codec = read_u8(&mut content)?;
+                let codec = Codec::from(read_u8(&mut content)? as u16);
                 let data = read_vec(&mut content, len as usize - 2)?;
                 Self::SoundStart {
                     sample_rate,
@@ -96,6 +327,16 @@ impl Chunk {
                     sample_rate,
                 }
             }
+            4 => {
+                let id = read_u16(&mut content)?;
+                Self::Marker { id }
+            }
+            5 => {
+                let bytes = content.into_inner();
+                let text = bytes.split(|&c| c == 0).next().unwrap_or_default();
+                let text = String::from_utf8_lossy(text).into_owned();
+                Self::Text { text }
+            }
             6 => {
                 let count = match read_u16(&mut content)? {
                     0xFFFF => None,
@@ -104,9 +345,200 @@ impl Chunk {
                 Self::Repeat { count }
             }
             7 => Self::RepeatEnd,
-            _ => Self::Unknown { ty, len },
+            8 => {
+                let time_constant = read_u16(&mut content)?;
+                let codec = Codec::from(read_u8(&mut content)? as u16);
+                let stereo = read_u8(&mut content)? != 0;
+                Self::Extended {
+                    time_constant,
+                    codec,
+                    stereo,
+                }
+            }
+            9 => {
+                let sample_rate = read_u32(&mut content)?;
+                let bits_per_sample = read_u8(&mut content)?;
+                let channels = read_u8(&mut content)?;
+                let codec = Codec::from(read_u16(&mut content)?);
+                read_u32(&mut content)?; // reserved
+                let data = read_vec(&mut content, len as usize - 12)?;
+                Self::Sound {
+                    sample_rate,
+                    bits_per_sample,
+                    channels,
+                    codec,
+                    data,
+                }
+            }
+            _ => Self::Unknown {
+                ty,
+                data: content.into_inner(),
+            },
         }))
     }
+
+    pub fn write(&self, mut output: impl io::Write) -> io::Result<()> {
+        let mut content = Vec::new();
+        let ty = match self {
+            Self::SoundStart {
+                sample_rate,
+                codec,
+                data,
+            } => {
+                content.push(sample_rate.0);
+                content.push(codec.to_legacy()?);
+                content.extend_from_slice(data);
+                1
+            }
+            Self::SoundContinue { data } => {
+                content.extend_from_slice(data);
+                2
+            }
+            Self::Silence {
+                sample_count,
+                sample_rate,
+            } => {
+                content.extend_from_slice(&sample_count.to_le_bytes());
+                content.push(sample_rate.0);
+                3
+            }
+            Self::Marker { id } => {
+                content.extend_from_slice(&id.to_le_bytes());
+                4
+            }
+            Self::Text { text } => {
+                content.extend_from_slice(text.as_bytes());
+                content.push(0);
+                5
+            }
+            Self::Repeat { count } => {
+                content.extend_from_slice(&count.unwrap_or(0xFFFF).to_le_bytes());
+                6
+            }
+            Self::RepeatEnd => 7,
+            Self::Extended {
+                time_constant,
+                codec,
+                stereo,
+            } => {
+                content.extend_from_slice(&time_constant.to_le_bytes());
+                content.push(codec.to_legacy()?);
+                content.push(*stereo as u8);
+                8
+            }
+            Self::Sound {
+                sample_rate,
+                bits_per_sample,
+                channels,
+                codec,
+                data,
+            } => {
+                content.extend_from_slice(&sample_rate.to_le_bytes());
+                content.push(*bits_per_sample);
+                content.push(*channels);
+                content.extend_from_slice(&u16::from(*codec).to_le_bytes());
+                content.extend_from_slice(&[0u8; 4]);
+                content.extend_from_slice(data);
+                9
+            }
+            Self::Unknown { ty, data } => {
+                content.extend_from_slice(data);
+                *ty
+            }
+        };
+
+        if content.len() > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "VOC chunk too large",
+            ));
+        }
+        let len = (content.len() as u32).to_le_bytes();
+        output.write_all(&[ty, len[0], len[1], len[2]])?;
+        output.write_all(&content)?;
+        Ok(())
+    }
+}
+
+/// Sample encoding, called "pack" in older chunks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Codec {
+    /// 8-bit unsigned
+    Pcm8,
+    /// Creative 4-bit ADPCM
+    Adpcm4,
+    /// Creative 2.6-bit ADPCM, three samples per byte
+    Adpcm3,
+    /// Creative 2-bit ADPCM
+    Adpcm2,
+    /// 16-bit signed
+    Pcm16,
+    ALaw,
+    MuLaw,
+    /// Creative 16-bit to 4-bit ADPCM
+    Adpcm4Ct,
+    Unknown(u16),
+}
+
+impl From<u16> for Codec {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Pcm8,
+            1 => Self::Adpcm4,
+            2 => Self::Adpcm3,
+            3 => Self::Adpcm2,
+            4 => Self::Pcm16,
+            6 => Self::ALaw,
+            7 => Self::MuLaw,
+            0x200 => Self::Adpcm4Ct,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+impl From<Codec> for u16 {
+    fn from(value: Codec) -> Self {
+        match value {
+            Codec::Pcm8 => 0,
+            Codec::Adpcm4 => 1,
+            Codec::Adpcm3 => 2,
+            Codec::Adpcm2 => 3,
+            Codec::Pcm16 => 4,
+            Codec::ALaw => 6,
+            Codec::MuLaw => 7,
+            Codec::Adpcm4Ct => 0x200,
+            Codec::Unknown(value) => value,
+        }
+    }
+}
+
+impl Codec {
+    fn to_legacy(self) -> io::Result<u8> {
+        match u16::from(self) {
+            value @ 0..=0xFF => Ok(value as u8),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "codec can only be used in a version 1.20 sound chunk",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pcm8 => f.write_str("8-bit PCM"),
+            Self::Adpcm4 => f.write_str("4-bit ADPCM"),
+            Self::Adpcm3 => f.write_str("2.6-bit ADPCM"),
+            Self::Adpcm2 => f.write_str("2-bit ADPCM"),
+            Self::Pcm16 => f.write_str("16-bit PCM"),
+            Self::ALaw => f.write_str("A-law"),
+            Self::MuLaw => f.write_str("μ-law"),
+            Self::Adpcm4Ct => f.write_str("CT 4-bit ADPCM"),
+            Self::Unknown(value) => write!(f, "unknown ({:#x})", value),
+        }
+    }
 }
 
 //    encoded_sample_rate = 256 - (1_000_000 / true_sample_rate)
@@ -118,125 +550,361 @@ impl Chunk {
 //   = 1_000_000 / (256 - 165)
 //   = 10_989 (off by ~36)
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SampleRate(u8);
 
-// impl SampleRate {
-//     pub fn sample_count_duration(&self, count: usize) -> std::time::Duration {
-//         std::time::Duration::from_nanos((256 - self.0 as u64) * count as u64)
-//     }
-// }
-//
-impl fmt::Display for SampleRate {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "~{}Hz", 1_000_000 / (256 - self.0 as u32))
+impl SampleRate {
+    /// The closest encodable rate, if any: from ~3_922Hz to 1MHz.
+    pub fn from_hz(hz: u32) -> Option<Self> {
+        if hz == 0 {
+            return None;
+        }
+        let divisor = (1_000_000 + hz / 2) / hz;
+        if (1..=256).contains(&divisor) {
+            Some(Self((256 - divisor) as u8))
+        } else {
+            None
+        }
     }
-}
 
-pub struct Player {
-    graph: bindings::Windows::Media::Audio::AudioGraph,
-}
-impl Drop for Player {
-    fn drop(&mut self) {
-        if let Err(error) = self.graph.Close() {
-            eprintln!("AudioGraph::Close failed: {}", error);
-        }
+    /// The rate actually encoded, e.g. 10_989Hz rather than 11_025Hz, rounded to the nearest Hz.
+    pub fn to_hz(self) -> u32 {
+        let divisor = 256 - self.0 as u32;
+        (1_000_000 + divisor / 2) / divisor
     }
-}
 
-pub fn play(
-    voc: &Voc,
-    // commands: std::sync::mpsc::Receiver<MediaCommand>,
-) -> bindings::Result<Player> {
-    use bindings::{
-        Interface,
-        Windows::Foundation::TypedEventHandler,
-        Windows::Media::{Audio::*, MediaProperties::*, Render::*, *},
-        Windows::Win32::System::WinRT::IMemoryBufferByteAccess,
-    };
+    pub fn to_hz_f64(self) -> f64 {
+        1_000_000.0 / (256 - self.0 as u32) as f64
+    }
 
-    let settings = AudioGraphSettings::Create(AudioRenderCategory::GameEffects)?;
-    let graph = AudioGraph::CreateAsync(settings)?.get()?.Graph()?;
+    pub fn sample_count_duration(self, count: usize) -> std::time::Duration {
+        std::time::Duration::from_micros((256 - self.0 as u64) * count as u64)
+    }
+}
 
-    let input_node =
-        graph.CreateFrameInputNodeWithFormat(AudioEncodingProperties::CreatePcm(11_025, 1, 8)?)?;
+impl fmt::Display for SampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "~{}Hz", 1_000_000 / (256 - self.0 as u32))
+    }
+}
 
-    let output_node = graph
-        .CreateDeviceOutputNodeAsync()?
-        .get()?
-        .DeviceOutputNode()?;
-    input_node.AddOutgoingConnection(output_node)?;
+#[derive(Copy, Clone)]
+struct Format {
+    sample_rate: u32,
+    channels: u16,
+    codec: Codec,
+}
 
-    fn create_frame(data: &[u8]) -> bindings::Result<AudioFrame> {
-        let frame = AudioFrame::Create(data.len() as u32)?;
-        let buffer = frame.LockBuffer(AudioBufferAccessMode::Write)?;
-        let reference = buffer.CreateReference()?;
-        write_buffer(reference.cast()?, data)?;
-        reference.Close()?;
-        buffer.Close()?;
-        Ok(frame)
-    }
+#[derive(Default)]
+struct Decoder {
+    pcm: Option<Pcm>,
+    codec: Option<Codec>,
+    extended: Option<Format>,
+    adpcm: Option<Adpcm>,
+}
 
-    fn write_buffer(access: IMemoryBufferByteAccess, data: &[u8]) -> bindings::Result<()> {
-        unsafe {
-            let mut bytes = std::ptr::null_mut();
-            let mut len = 0;
-            access.GetBuffer(&mut bytes, &mut len).ok()?;
-            bytes.copy_from(data.as_ptr(), data.len().min(len as usize));
+impl Decoder {
+    fn start(&mut self, format: Format) -> ReadResult<()> {
+        let bits_per_sample = match format.codec {
+            Codec::Pcm8 | Codec::Adpcm4 | Codec::Adpcm3 | Codec::Adpcm2 => 8,
+            Codec::Pcm16 | Codec::ALaw | Codec::MuLaw => 16,
+            Codec::Adpcm4Ct | Codec::Unknown(_) => {
+                let message = format!("unsupported codec {}", format.codec);
+                return Err(ReadError::decoding("VOC", message).with_field("codec"));
+            }
+        };
+        if format.sample_rate == 0 {
+            return Err(ReadError::decoding("VOC", "sample rate is 0").with_field("sample rate"));
+        }
+        if format.channels == 0 {
+            return Err(ReadError::decoding("VOC", "sound has no channels").with_field("channels"));
         }
+        let pcm = self.pcm.get_or_insert_with(|| Pcm {
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            bits_per_sample,
+            data: Vec::new(),
+        });
+        if pcm.sample_rate != format.sample_rate
+            || pcm.channels != format.channels
+            || pcm.bits_per_sample != bits_per_sample
+        {
+            return Err(ReadError::decoding("VOC", "sound format changed"));
+        }
+        self.codec = Some(format.codec);
+        // the first byte of each sound is a reference sample
+        self.adpcm = None;
         Ok(())
     }
 
-    let mut repeat = false;
-    let mut repeat_frames = Vec::new();
+    fn decode(&mut self, data: &[u8]) -> ReadResult<()> {
+        let (pcm, codec) = match (&mut self.pcm, self.codec) {
+            (Some(pcm), Some(codec)) => (pcm, codec),
+            _ => return Err(ReadError::decoding("VOC", "sound continued before start")),
+        };
 
-    for chunk in &voc.chunks {
-        match chunk {
-            Chunk::SoundStart { data, .. } | Chunk::SoundContinue { data } => {
-                let frame = create_frame(data)?;
-                if repeat {
-                    repeat_frames.push(frame.clone());
-                    // Avoids small gaps by ensuring there's always a frame being played. E.g.
-                    // A(B#), where B should repeat, should end up with a queue like:
-                    // ABB
-                    //  BB
-                    //   B
-                    //   BB
-                    //    B
-                    //    BB
-                    // ...
-                    // Probably a smarter way to do this?
-                    // This is assuming only one repeated chunk, of course.
-                    input_node.AddFrame(frame.clone())?;
+        match codec {
+            Codec::Pcm8 | Codec::Pcm16 => pcm.data.extend_from_slice(data),
+            Codec::ALaw => {
+                for &value in data {
+                    pcm.data
+                        .extend_from_slice(&alaw_to_linear(value).to_le_bytes());
                 }
-                input_node.AddFrame(frame)?;
             }
-            Chunk::Repeat { .. } => {
-                repeat = true;
+            Codec::MuLaw => {
+                for &value in data {
+                    pcm.data
+                        .extend_from_slice(&mulaw_to_linear(value).to_le_bytes());
+                }
             }
-            Chunk::RepeatEnd => {
-                repeat = false;
+            Codec::Adpcm4 | Codec::Adpcm3 | Codec::Adpcm2 => {
+                if pcm.channels != 1 {
+                    return Err(ReadError::decoding("VOC", "unsupported stereo ADPCM")
+                        .with_field("channels"));
+                }
+                let mut data = data;
+                if self.adpcm.is_none() {
+                    match data.split_first() {
+                        Some((&reference, rest)) => {
+                            pcm.data.push(reference);
+                            data = rest;
+                            self.adpcm = Some(Adpcm::new(reference));
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                let adpcm = self.adpcm.as_mut().unwrap();
+                for &byte in data {
+                    match codec {
+                        Codec::Adpcm4 => {
+                            pcm.data.push(adpcm.expand(byte >> 4, 4, 0));
+                            pcm.data.push(adpcm.expand(byte & 0xF, 4, 0));
+                        }
+                        Codec::Adpcm3 => {
+                            pcm.data.push(adpcm.expand(byte >> 5, 3, 0));
+                            pcm.data.push(adpcm.expand(byte >> 2 & 0x7, 3, 0));
+                            pcm.data.push(adpcm.expand(byte & 0x3, 2, 0));
+                        }
+                        _ => {
+                            pcm.data.push(adpcm.expand(byte >> 6, 2, 2));
+                            pcm.data.push(adpcm.expand(byte >> 4 & 0x3, 2, 2));
+                            pcm.data.push(adpcm.expand(byte >> 2 & 0x3, 2, 2));
+                            pcm.data.push(adpcm.expand(byte & 0x3, 2, 2));
+                        }
+                    }
+                }
             }
-            _ => {}
+            Codec::Adpcm4Ct | Codec::Unknown(_) => unreachable!("rejected by start()"),
         }
+        // Repeats can expand a small file a lot.
+        checked_len(pcm.data.len() as u64).field("sound")?;
+        Ok(())
     }
 
-    graph.Start()?;
+    fn silence(&mut self, sample_count: u64, sample_rate: u32) -> ReadResult<()> {
+        let pcm = self
+            .pcm
+            .get_or_insert_with(|| Pcm::new_u8_mono(sample_rate));
+        let count = sample_count * pcm.sample_rate as u64 / sample_rate as u64;
+        let len = count * pcm.bytes_per_frame() as u64;
+        let len = checked_len(pcm.data.len() as u64 + len).field("sound")?;
+        let value = if pcm.bits_per_sample == 8 { 0x80 } else { 0 };
+        pcm.data.resize(len, value);
+        Ok(())
+    }
+}
 
-    input_node.AudioFrameCompleted(TypedEventHandler::<
-        AudioFrameInputNode,
-        AudioFrameCompletedEventArgs,
-    >::new({
-        let input_node = input_node.clone();
-        move |_node, args| {
-            if let Some(args) = args {
-                let frame = args.Frame()?;
-                if repeat_frames.contains(&frame) {
-                    input_node.AddFrame(frame)?;
-                }
-            }
-            Ok(())
+/// Creative Sound Blaster ADPCM state, working on 8-bit samples scaled up by 128.
+struct Adpcm {
+    predictor: i32,
+    step: u32,
+}
+
+impl Adpcm {
+    fn new(reference: u8) -> Self {
+        Self {
+            predictor: (reference as i32 - 0x80) << 7,
+            step: 0,
         }
-    }))?;
+    }
 
-    Ok(Player { graph })
+    fn expand(&mut self, code: u8, size: u32, shift: u32) -> u8 {
+        let sign = code & 1 << (size - 1) != 0;
+        let delta = (code & ((1 << (size - 1)) - 1)) as i32;
+        let diff = delta << (7 + self.step + shift);
+        let predictor = if sign {
+            self.predictor - diff
+        } else {
+            self.predictor + diff
+        };
+        self.predictor = predictor.clamp(-0x4000, 0x3F80);
+
+        if delta >= 2 * size as i32 - 3 && self.step < 3 {
+            self.step += 1;
+        } else if delta == 0 && self.step > 0 {
+            self.step -= 1;
+        }
+
+        ((self.predictor >> 7) + 0x80) as u8
+    }
+}
+
+fn alaw_to_linear(value: u8) -> i16 {
+    let value = value ^ 0x55;
+    let mut linear = ((value & 0x0F) as i16) << 4;
+    let segment = (value & 0x70) >> 4;
+    match segment {
+        0 => linear += 8,
+        1 => linear += 0x108,
+        _ => linear = (linear + 0x108) << (segment - 1),
+    }
+    if value & 0x80 != 0 {
+        linear
+    } else {
+        -linear
+    }
+}
+
+fn mulaw_to_linear(value: u8) -> i16 {
+    let value = !value;
+    let linear = ((((value & 0x0F) as i16) << 3) + 0x84) << ((value & 0x70) >> 4);
+    if value & 0x80 != 0 {
+        0x84 - linear
+    } else {
+        linear - 0x84
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_expands_silence_and_repeats() {
+        let voc = Voc {
+            version: 0x010a,
+            chunks: vec![
+                Chunk::SoundStart {
+                    sample_rate: SampleRate(156),
+                    codec: Codec::Pcm8,
+                    data: vec![1, 2],
+                },
+                Chunk::Silence {
+                    sample_count: 2,
+                    sample_rate: SampleRate(156),
+                },
+                Chunk::Repeat { count: Some(2) },
+                Chunk::SoundContinue { data: vec![3] },
+                Chunk::RepeatEnd,
+                Chunk::Repeat { count: None },
+                Chunk::SoundContinue { data: vec![4] },
+                Chunk::RepeatEnd,
+            ],
+        };
+
+        let pcm = voc.decode(1).unwrap();
+        assert_eq!(pcm.sample_rate, 10_000);
+        assert_eq!(pcm.data, [1, 2, 0x80, 0x80, 0x80, 3, 3, 4, 4]);
+
+        let pcm = voc.decode(3).unwrap();
+        assert_eq!(pcm.data, [1, 2, 0x80, 0x80, 0x80, 3, 3, 3, 4, 4, 4, 4]);
+
+        let (pcm, endless) = voc.decode_with_loop(0).unwrap();
+        assert_eq!(pcm.data, [1, 2, 0x80, 0x80, 0x80, 3, 4]);
+        assert_eq!(endless, Some(6..7));
+    }
+
+    #[test]
+    fn decode_adpcm() {
+        let voc = Voc {
+            version: 0x010a,
+            chunks: vec![Chunk::SoundStart {
+                sample_rate: SampleRate(156),
+                codec: Codec::Adpcm4,
+                data: vec![0x80, 0x17, 0x90],
+            }],
+        };
+
+        let pcm = voc.decode(0).unwrap();
+        assert_eq!(pcm.data, [0x80, 0x81, 0x88, 0x86, 0x86]);
+    }
+
+    #[test]
+    fn decode_extended_and_new_format() {
+        let voc = Voc {
+            version: 0x0114,
+            chunks: vec![
+                Chunk::Extended {
+                    time_constant: (65_536u32 - 6_400) as u16,
+                    codec: Codec::Pcm8,
+                    stereo: true,
+                },
+                Chunk::SoundStart {
+                    sample_rate: SampleRate(0),
+                    codec: Codec::Pcm8,
+                    data: vec![1, 2],
+                },
+                Chunk::Marker { id: 1 },
+            ],
+        };
+        let pcm = voc.decode(0).unwrap();
+        assert_eq!((pcm.sample_rate, pcm.channels), (20_000, 2));
+
+        let voc = Voc {
+            version: 0x0114,
+            chunks: vec![Chunk::Sound {
+                sample_rate: 8_000,
+                bits_per_sample: 8,
+                channels: 1,
+                codec: Codec::MuLaw,
+                data: vec![0xFF, 0x7F, 0x00],
+            }],
+        };
+        let pcm = voc.decode(0).unwrap();
+        assert_eq!(pcm.bits_per_sample, 16);
+        assert_eq!(pcm.data, [0, 0, 0, 0, 0x84, 0x82]);
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let pcm = Pcm {
+            sample_rate: 44_100,
+            channels: 2,
+            bits_per_sample: 16,
+            data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let mut voc = Voc::from_pcm(&pcm);
+        voc.chunks.insert(
+            0,
+            Chunk::Text {
+                text: "hello".to_string(),
+            },
+        );
+
+        let mut bytes = Vec::new();
+        voc.write(&mut bytes).unwrap();
+        let read = Voc::read(io::Cursor::new(bytes)).unwrap();
+        assert_eq!(read.version, 0x0114);
+        assert!(matches!(&read.chunks[0], Chunk::Text { text } if text == "hello"));
+
+        let decoded = read.decode(0).unwrap();
+        assert_eq!(decoded.sample_rate, pcm.sample_rate);
+        assert_eq!(decoded.channels, pcm.channels);
+        assert_eq!(decoded.data, pcm.data);
+
+        let pcm = Pcm {
+            data: vec![0x80; 10],
+            ..Pcm::new_u8_mono(11_025)
+        };
+        let voc = Voc::from_pcm(&pcm);
+        assert!(matches!(voc.chunks[0], Chunk::SoundStart { .. }));
+    }
+
+    #[test]
+    fn sample_rate() {
+        assert_eq!(SampleRate(165).to_hz(), 10_989);
+        assert_eq!(SampleRate(156).to_hz(), 10_000);
+        assert_eq!(SampleRate::from_hz(11_025).map(|r| r.0), Some(165));
+        assert!(SampleRate::from_hz(1_000).is_none());
+    }
 }
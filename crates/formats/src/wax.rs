@@ -4,6 +4,7 @@ use crate::common::*;
 
 use crate::fme;
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Wax {
     pub version: u32,
     pub num_sequences: u32,
@@ -15,6 +16,7 @@ pub struct Wax {
     pub cells: Vec<fme::Cell>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WaxState {
     pub offset: u32,
     pub world_size: mint::Vector2<u32>,
@@ -22,11 +24,13 @@ pub struct WaxState {
     pub angle_sequence_indices: [usize; 32],
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WaxSequence {
     pub offset: u32,
     pub frame_indices: Vec<usize>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WaxFrame {
     pub offset: u32,
     pub frame: fme::Frame,
@@ -35,10 +39,14 @@ pub struct WaxFrame {
 
 impl Wax {
     pub fn read(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
-        let version = read_u32(&mut file)?;
-        let num_sequences = read_u32(&mut file)?;
-        let num_frames = read_u32(&mut file)?;
-        let num_cells = read_u32(&mut file)?;
+        Self::read_wax(&mut file).map_err(|error| error.locate("WAX", &mut file))
+    }
+
+    fn read_wax(mut file: impl io::Read + io::Seek) -> ReadResult<Self> {
+        let version = read_u32(&mut file).field("version")?;
+        let num_sequences = read_u32(&mut file).field("sequence count")?;
+        let num_frames = read_u32(&mut file).field("frame count")?;
+        let num_cells = read_u32(&mut file).field("cell count")?;
         read_u32(&mut file)?; // scale x, according to dftools (always 0 in Dork Forces)
         read_u32(&mut file)?; // scale y, "
         read_u32(&mut file)?; // extra light, "
@@ -46,28 +54,35 @@ impl Wax {
 
         let mut state_offsets = Vec::with_capacity(32);
         for _ in 0..32 {
-            let offset = read_u32(&mut file)?;
+            let offset = read_u32(&mut file).field("state offsets")?;
             if offset == 0 {
                 break;
             }
             state_offsets.push(offset);
         }
 
-        let mut sequence_offsets = IndexMap::with_capacity(num_sequences as usize);
-        let mut sequences = Vec::with_capacity(num_sequences as usize);
+        // The counts are only hints: each state has at most 32 sequences of at most 32 frames.
+        let max_sequences = state_offsets.len() * 32;
+        let max_frames = max_sequences * 32;
+        let num_sequences_hint = (num_sequences as usize).min(max_sequences);
+        let num_frames_hint = (num_frames as usize).min(max_frames);
+        let num_cells_hint = (num_cells as usize).min(max_frames);
+
+        let mut sequence_offsets = IndexMap::with_capacity(num_sequences_hint);
+        let mut sequences = Vec::with_capacity(num_sequences_hint);
 
-        let mut frame_offsets = IndexMap::with_capacity(num_frames as usize);
-        let mut frames = Vec::with_capacity(num_frames as usize);
+        let mut frame_offsets = IndexMap::with_capacity(num_frames_hint);
+        let mut frames = Vec::with_capacity(num_frames_hint);
 
-        let mut cell_offsets = IndexMap::with_capacity(num_cells as usize);
-        let mut cells = Vec::with_capacity(num_cells as usize);
+        let mut cell_offsets = IndexMap::with_capacity(num_cells_hint);
+        let mut cells = Vec::with_capacity(num_cells_hint);
 
         let mut states = Vec::new();
 
         for offset in state_offsets {
             file.seek(io::SeekFrom::Start(offset as u64))?;
-            let world_size = read_vec2_u32(&mut file)?;
-            let frame_rate = read_u32(&mut file)?;
+            let world_size = read_vec2_u32(&mut file).field("world size")?;
+            let frame_rate = read_u32(&mut file).field("frame rate")?;
             read_u32(&mut file)?; // num_frames, according to dftools (always 0 in Dark Forces)
             read_u32(&mut file)?; // padding...
             read_u32(&mut file)?;
@@ -75,7 +90,7 @@ impl Wax {
 
             let mut angle_sequence_indices = [0usize; 32];
             for angle in 0..32 {
-                let sequence_offset = read_u32(&mut file)?;
+                let sequence_offset = read_u32(&mut file).field("sequence offsets")?;
                 angle_sequence_indices[angle] = sequence_offsets.add_index(sequence_offset);
             }
 
@@ -91,7 +106,7 @@ impl Wax {
             file.seek(io::SeekFrom::Start(offset as u64 + 16))?;
             let mut frame_indices = Vec::with_capacity(32);
             for _ in 0..32 {
-                let frame_offset = read_u32(&mut file)?;
+                let frame_offset = read_u32(&mut file).field("frame offsets")?;
                 if frame_offset == 0 {
                     break;
                 }
@@ -106,7 +121,7 @@ impl Wax {
         for offset in frame_offsets.keys {
             file.seek(io::SeekFrom::Start(offset as u64))?;
             let frame = fme::Frame::read(&mut file)?;
-            let cell_offset = read_u32(&mut file)?;
+            let cell_offset = read_u32(&mut file).field("cell offset")?;
 
             let cell_index = cell_offsets.add_index(cell_offset);
             frames.push(WaxFrame {
@@ -116,8 +131,12 @@ impl Wax {
             });
         }
 
+        // Cells can overlap, so limit them all together as well as each one.
+        let mut total_len = 0;
         for cell_offset in cell_offsets.keys {
-            cells.push(fme::Cell::read(&mut file, cell_offset)?);
+            let cell = fme::Cell::read(&mut file, cell_offset)?;
+            total_len = checked_len((total_len + cell.data.len()) as u64).field("cells")?;
+            cells.push(cell);
         }
 
         Ok(Self {
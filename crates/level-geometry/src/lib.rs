@@ -1,153 +1,556 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
 use formats::lev;
 
-pub fn walls_to_polygons(walls: impl IntoIterator<Item = (usize, usize)>) -> Vec<Vec<usize>> {
-    // open segment of a Polygon
-    struct Contour {
-        indices: std::collections::VecDeque<usize>,
+mod validate;
+
+pub use validate::{validate, Issue, Report};
+
+/// The closed polygons formed by the walls of a sector, and the problems found on the way.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Polygons {
+    pub polygons: Vec<Polygon>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Polygon {
+    /// Indices of the vertices in the direction of the walls, starting with the lowest.
+    /// Vertices at the same position as an earlier one are replaced with it.
+    pub vertices: Vec<usize>,
+    /// The polygon this one is a hole in. Polygons without one are separate islands,
+    /// including those inside a hole.
+    pub hole_in: Option<usize>,
+}
+
+/// Something wrong with the walls of a sector, and what was done about it. Walls and
+/// vertices are identified by their index in the sector.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// The wall uses a vertex that doesn't exist, and was ignored.
+    VertexOutOfRange { wall: usize, vertex: usize },
+    /// The wall uses a vertex whose position is infinite or NaN, and was ignored.
+    NotFinite { wall: usize, vertex: usize },
+    /// The ends of the wall are at the same position, and it was ignored.
+    ZeroLength { wall: usize },
+    /// The wall runs between the same vertices as an earlier one, and was ignored.
+    Duplicate { wall: usize, of: usize },
+    /// The walls run between the same vertices in opposite directions, enclosing nothing,
+    /// and both were ignored.
+    Opposite { wall: usize, of: usize },
+    /// The vertex is at the same position as an earlier one, and was replaced with it.
+    Coincident { vertex: usize, of: usize },
+    /// More than one wall leaves the vertex, so polygons touch there. They were split
+    /// at the vertex.
+    SharedVertex { vertex: usize },
+    /// The walls run the wrong way, and were reversed to continue a chain.
+    Reversed { walls: Vec<usize> },
+    /// No wall continues a chain ending at `from`, so it was joined to `to`.
+    Gap { from: usize, to: usize },
+    /// The walls form a chain too short to close, and were ignored.
+    OpenChain { walls: Vec<usize> },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::VertexOutOfRange { wall, vertex } => {
+                write!(
+                    f,
+                    "wall {} uses vertex {}, which doesn't exist",
+                    wall, vertex
+                )
+            }
+            Diagnostic::NotFinite { wall, vertex } => {
+                write!(
+                    f,
+                    "wall {} uses vertex {}, which isn't finite",
+                    wall, vertex
+                )
+            }
+            Diagnostic::ZeroLength { wall } => write!(f, "wall {} has no length", wall),
+            Diagnostic::Duplicate { wall, of } => write!(f, "wall {} duplicates wall {}", wall, of),
+            Diagnostic::Opposite { wall, of } => {
+                write!(f, "wall {} runs back along wall {}", wall, of)
+            }
+            Diagnostic::Coincident { vertex, of } => {
+                write!(
+                    f,
+                    "vertex {} is at the same position as vertex {}",
+                    vertex, of
+                )
+            }
+            Diagnostic::SharedVertex { vertex } => {
+                write!(f, "more than one wall leaves vertex {}", vertex)
+            }
+            Diagnostic::Reversed { walls } => write!(f, "walls {:?} run the wrong way", walls),
+            Diagnostic::Gap { from, to } => {
+                write!(f, "no wall joins vertex {} to vertex {}", from, to)
+            }
+            Diagnostic::OpenChain { walls } => {
+                write!(f, "walls {:?} form a chain too short to close", walls)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Edge {
+    wall: usize,
+    from: usize,
+    to: usize,
+}
+
+/// Vertices joined by walls, with one more vertex than walls until it's closed.
+struct Chain {
+    vertices: Vec<usize>,
+    walls: Vec<usize>,
+}
+
+impl Chain {
+    fn start(&self) -> usize {
+        self.vertices[0]
     }
 
-    impl Contour {
-        fn new(left: usize, right: usize) -> Self {
-            let mut indices = std::collections::VecDeque::with_capacity(8);
-            indices.push_back(left);
-            indices.push_back(right);
-            Self { indices }
+    fn end(&self) -> usize {
+        *self.vertices.last().unwrap()
+    }
+
+    fn append(&mut self, other: Chain) {
+        self.vertices.extend_from_slice(&other.vertices[1..]);
+        self.walls.extend(other.walls);
+    }
+
+    fn reverse(&mut self) {
+        self.vertices.reverse();
+        self.walls.reverse();
+    }
+}
+
+/// Builds the closed polygons formed by walls going from their left to their right vertex,
+/// recovering what it can from walls that don't close up.
+///
+/// Polygons touching at a vertex are split there, choosing the walls that keep the area to
+/// the right, as in a sector whose walls wind clockwise. Chains of walls are joined where
+/// they meet, reversing walls that run the wrong way, and then to the nearest chain or
+/// closed across the gap.
+pub fn walls_to_polygons(
+    vertices: &[mint::Point2<f32>],
+    walls: impl IntoIterator<Item = (usize, usize)>,
+) -> Polygons {
+    let mut diagnostics = Vec::new();
+
+    let mut first_at = BTreeMap::new();
+    let canonical = vertices
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            *first_at
+                .entry(((v.x + 0.0).to_bits(), (v.y + 0.0).to_bits()))
+                .or_insert(i)
+        })
+        .collect::<Vec<_>>();
+    let mut coincident = BTreeSet::new();
+
+    // Walls by their ends, dropping those that can't be part of a polygon.
+    let mut ends = BTreeMap::new();
+    let mut order = Vec::new();
+    for (wall, (left, right)) in walls.into_iter().enumerate() {
+        if let Some(&vertex) = [left, right].iter().find(|&&v| v >= vertices.len()) {
+            diagnostics.push(Diagnostic::VertexOutOfRange { wall, vertex });
+            continue;
+        }
+        // Infinite positions, such as from overlong numbers, would make the angles and
+        // distances below NaN.
+        let finite = |v: usize| vertices[v].x.is_finite() && vertices[v].y.is_finite();
+        if let Some(&vertex) = [left, right].iter().find(|&&v| !finite(v)) {
+            diagnostics.push(Diagnostic::NotFinite { wall, vertex });
+            continue;
         }
+        for &vertex in &[left, right] {
+            if canonical[vertex] != vertex && coincident.insert(vertex) {
+                let of = canonical[vertex];
+                diagnostics.push(Diagnostic::Coincident { vertex, of });
+            }
+        }
+
+        let (from, to) = (canonical[left], canonical[right]);
+        if from == to {
+            diagnostics.push(Diagnostic::ZeroLength { wall });
+        } else if let Some(&of) = ends.get(&(from, to)) {
+            diagnostics.push(Diagnostic::Duplicate { wall, of });
+        } else if let Some(of) = ends.remove(&(to, from)) {
+            diagnostics.push(Diagnostic::Opposite { wall, of });
+        } else {
+            ends.insert((from, to), wall);
+            order.push((from, to));
+        }
+    }
+    let edges = order
+        .into_iter()
+        .filter_map(|(from, to)| ends.remove(&(from, to)).map(|wall| Edge { wall, from, to }))
+        .collect::<Vec<_>>();
+
+    let mut outgoing = BTreeMap::<usize, Vec<usize>>::new();
+    let mut incoming = BTreeMap::<usize, usize>::new();
+    for (i, edge) in edges.iter().enumerate() {
+        outgoing.entry(edge.from).or_default().push(i);
+        *incoming.entry(edge.to).or_default() += 1;
+    }
+    for (&vertex, out) in &outgoing {
+        if out.len() > 1 {
+            diagnostics.push(Diagnostic::SharedVertex { vertex });
+        }
+    }
 
-        fn left_end(&self) -> usize {
-            *self.indices.front().unwrap()
+    let point = |i: usize| (vertices[i].x as f64, vertices[i].y as f64);
+    // How far the area to the right of `edge` turns anticlockwise to reach `next`.
+    let turn = |edge: Edge, next: usize| {
+        let (x, y) = point(edge.to);
+        let back = (point(edge.from).0 - x, point(edge.from).1 - y);
+        let out = (point(edges[next].to).0 - x, point(edges[next].to).1 - y);
+        let angle = (back.0 * out.1 - back.1 * out.0).atan2(back.0 * out.0 + back.1 * out.1);
+        if angle <= 0.0 {
+            angle + 2.0 * std::f64::consts::PI
+        } else {
+            angle
         }
+    };
 
-        fn right_end(&self) -> usize {
-            *self.indices.back().unwrap()
+    // Trace chains from where more walls leave a vertex than arrive, then the loops left.
+    let mut used = vec![false; edges.len()];
+    let mut chains = Vec::new();
+    let mut loops = Vec::new();
+    let starts = (0..edges.len()).filter(|&i| {
+        let from = edges[i].from;
+        outgoing[&from].len() > incoming.get(&from).copied().unwrap_or_default()
+    });
+    for start in starts.chain(0..edges.len()) {
+        if used[start] {
+            continue;
+        }
+        let mut chain = Chain {
+            vertices: vec![edges[start].from],
+            walls: Vec::new(),
+        };
+        let mut edge = start;
+        loop {
+            used[edge] = true;
+            let Edge { wall, to, .. } = edges[edge];
+            chain.vertices.push(to);
+            chain.walls.push(wall);
+            if to == chain.start() {
+                break;
+            }
+            let next = outgoing.get(&to).into_iter().flatten().copied();
+            let next = next.filter(|&i| !used[i]).min_by(|&a, &b| {
+                turn(edges[edge], a)
+                    .partial_cmp(&turn(edges[edge], b))
+                    .unwrap()
+            });
+            match next {
+                Some(next) => edge = next,
+                None => break,
+            }
         }
+        if chain.start() == chain.end() {
+            chain.vertices.pop();
+            loops.push(chain);
+        } else {
+            chains.push(chain);
+        }
+    }
 
-        fn add(&mut self, left: usize, right: usize, dup: bool) -> Add {
-            if left == self.right_end() {
-                if right == self.left_end() {
-                    Add::Closed
-                } else if !dup {
-                    self.indices.push_back(right);
-                    Add::Extended
+    // Join chains that meet, reversing one of them if they meet end to end or start to start.
+    'join: loop {
+        for i in 0..chains.len() {
+            for j in 0..chains.len() {
+                let (a, b) = (&chains[i], &chains[j]);
+                let reverse = if i == j {
+                    continue;
+                } else if a.end() == b.start() {
+                    false
+                } else if a.end() == b.end() || a.start() == b.start() {
+                    true
                 } else {
-                    Add::Unmatched
+                    continue;
+                };
+
+                let mut b = chains.remove(j);
+                let i = if j < i { i - 1 } else { i };
+                if reverse {
+                    b.reverse();
+                    let walls = b.walls.clone();
+                    diagnostics.push(Diagnostic::Reversed { walls });
                 }
-            } else if right == self.left_end() {
-                if left == self.right_end() {
-                    Add::Closed
-                } else if !dup {
-                    self.indices.push_front(left);
-                    Add::Extended
+                let mut chain = chains.remove(i);
+                if chain.end() == b.start() {
+                    chain.append(b);
                 } else {
-                    Add::Unmatched
+                    b.append(chain);
+                    chain = b;
                 }
-            } else {
-                Add::Unmatched
+                if chain.start() == chain.end() {
+                    chain.vertices.pop();
+                    loops.push(chain);
+                } else {
+                    chains.push(chain);
+                }
+                continue 'join;
             }
         }
+        break;
     }
 
-    enum Add {
-        Unmatched,
-        Closed,
-        Extended,
-    }
-
-    let mut polygons: Vec<Vec<usize>> = Vec::new();
-    let mut contours: Vec<Contour> = Vec::new();
-    let mut added = std::collections::BTreeSet::new();
-
-    'wall: for (left, right) in walls {
-        // detect if this is a dup wall, and discard if so.
-        // still need the last wall to close an inner polygon though.
-        let dup = !added.insert(left) & !added.insert(right);
-
-        for i in 0..contours.len() {
-            let add = contours[i].add(left, right, dup);
-            match add {
-                Add::Unmatched => {}
-                Add::Extended => continue 'wall,
-                Add::Closed => {
-                    let mut polygon = contours.remove(i).indices;
-                    let min_index = polygon.iter().enumerate().min_by_key(|x| x.1).unwrap().0;
-                    polygon.rotate_left(min_index);
-                    polygons.push(polygon.into_iter().collect());
-                    continue 'wall;
-                }
+    // Bridge the gaps left to the nearest chain, or across to the start of the chain.
+    let distance = |a: usize, b: usize| {
+        let (a, b) = (point(a), point(b));
+        (a.0 - b.0).hypot(a.1 - b.1)
+    };
+    while !chains.is_empty() {
+        let mut chain = chains.remove(0);
+        loop {
+            let end = chain.end();
+            let nearest = (0..chains.len())
+                .map(|j| (j, distance(end, chains[j].start())))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .filter(|&(_, d)| d < distance(end, chain.start()));
+            if let Some((j, _)) = nearest {
+                let next = chains.remove(j);
+                diagnostics.push(Diagnostic::Gap {
+                    from: end,
+                    to: next.start(),
+                });
+                chain.vertices.extend(next.vertices);
+                chain.walls.extend(next.walls);
+            } else if chain.vertices.len() >= 3 {
+                diagnostics.push(Diagnostic::Gap {
+                    from: end,
+                    to: chain.start(),
+                });
+                loops.push(chain);
+                break;
+            } else {
+                diagnostics.push(Diagnostic::OpenChain { walls: chain.walls });
+                break;
             }
         }
+    }
 
-        if !dup {
-            contours.push(Contour::new(left, right));
+    let mut polygons = loops
+        .into_iter()
+        .map(|chain| {
+            let mut vertices = chain.vertices;
+            let lowest = (0..vertices.len()).min_by_key(|&i| vertices[i]).unwrap();
+            vertices.rotate_left(lowest);
+            vertices
+        })
+        .collect::<Vec<_>>();
+    polygons.sort_unstable_by_key(|p| p[0]);
+
+    // A polygon is a hole in the innermost polygon containing it, which is the one
+    // contained by the most others.
+    let inside = |polygon: &[usize], other: &[usize]| {
+        let outline = other.iter().map(|&i| point(i)).collect::<Vec<_>>();
+        polygon
+            .iter()
+            .find(|i| !other.contains(i))
+            .map(|&i| contains(&outline, point(i)))
+            .unwrap_or_default()
+    };
+    let containers = polygons
+        .iter()
+        .enumerate()
+        .map(|(i, polygon)| {
+            (0..polygons.len())
+                .filter(|&j| j != i && inside(polygon, &polygons[j]))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let polygons = polygons
+        .into_iter()
+        .zip(&containers)
+        .map(|(vertices, own)| {
+            let parent = own.iter().copied().max_by_key(|&j| containers[j].len());
+            Polygon {
+                vertices,
+                hole_in: parent.filter(|_| own.len() % 2 == 1),
+            }
+        })
+        .collect();
+
+    Polygons {
+        polygons,
+        diagnostics,
+    }
+}
+
+fn contains(polygon: &[(f64, f64)], (x, y): (f64, f64)) -> bool {
+    let mut inside = false;
+    let mut previous = polygon[polygon.len() - 1];
+    for &vertex in polygon {
+        if (vertex.1 > y) != (previous.1 > y)
+            && x < (previous.0 - vertex.0) * (y - vertex.1) / (previous.1 - vertex.1) + vertex.0
+        {
+            inside = !inside;
         }
+        previous = vertex;
     }
+    inside
+}
 
-    polygons.sort_unstable_by_key(|p| p[0]);
+/// Triangulates the floor and ceiling of a sector, each island separately with its holes.
+///
+/// Vertices in the error are numbered as in the sector, and contours as the polygons
+/// returned by [`walls_to_polygons`].
+pub fn triangulate_sector(
+    sector: &lev::Sector,
+) -> Result<Vec<[mint::Point2<f32>; 3]>, seidel::Error> {
+    let walls = sector.walls.iter().map(|w| (w.left_vertex, w.right_vertex));
+    let polygons = walls_to_polygons(&sector.vertices, walls).polygons;
+    if polygons.is_empty() {
+        return Err(seidel::Error::NoContours);
+    }
+
+    let mut triangles = Vec::new();
+    for outer in (0..polygons.len()).filter(|&i| polygons[i].hole_in.is_none()) {
+        let holes = (0..polygons.len()).filter(|&i| polygons[i].hole_in == Some(outer));
+        let component = std::iter::once(outer).chain(holes).collect::<Vec<_>>();
+        let contours = component
+            .iter()
+            .map(|&i| polygons[i].vertices.len())
+            .collect::<Vec<_>>();
+        let indices = component
+            .iter()
+            .flat_map(|&i| polygons[i].vertices.iter().copied())
+            .collect::<Vec<_>>();
+        let vertices = indices
+            .iter()
+            .map(|&i| seidel::Vertex {
+                x: sector.vertices[i].x as f64,
+                y: sector.vertices[i].y as f64,
+            })
+            .collect::<Vec<_>>();
 
-    polygons
+        let result = seidel::triangulate(&contours, &vertices)
+            .map_err(|error| error.renumber(|v| indices[v], |c| component[c]))?;
+        let v = |i: usize| sector.vertices[indices[i]];
+        triangles.extend(result.into_iter().map(|[a, b, c]| [v(a), v(b), v(c)]));
+    }
+    Ok(triangles)
 }
 
-pub fn triangulate_sector(sector: &lev::Sector) -> Vec<[mint::Point2<f32>; 3]> {
-    // reverse the polygon order by flipping the wall ends as Seidel requires it.
-    let polygons = walls_to_polygons(sector.walls.iter().map(|w| (w.right_vertex, w.left_vertex)));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let contours = polygons.iter().map(|p| p.len() as i32).collect::<Vec<_>>();
-    let vertices = polygons
-        .iter()
-        .flatten()
-        .map(|&i| {
-            let vertex = sector.vertices[i];
-            seidel::Vertex {
-                x: vertex.x as f64,
-                y: vertex.y as f64,
-            }
-        })
-        .collect::<Vec<_>>();
+    fn points(points: &[(f32, f32)]) -> Vec<mint::Point2<f32>> {
+        points.iter().map(|&(x, y)| mint::Point2 { x, y }).collect()
+    }
 
-    let triangles = seidel::triangulate(&contours, &vertices);
-    fn v(vertex: seidel::Vertex) -> mint::Point2<f32> {
-        mint::Point2 {
-            x: vertex.x as f32,
-            y: vertex.y as f32,
+    fn polygon(vertices: &[usize], hole_in: Option<usize>) -> Polygon {
+        Polygon {
+            vertices: vertices.to_vec(),
+            hole_in,
         }
     }
-    triangles
-        .into_iter()
-        .map(|t| {
-            [
-                v(vertices[t.0[0] as usize - 1]),
-                v(vertices[t.0[1] as usize - 1]),
-                v(vertices[t.0[2] as usize - 1]),
+
+    #[test]
+    fn holes_and_islands() {
+        // Clockwise squares, anticlockwise around the hole, one inside the other.
+        #[rustfmt::skip]
+        let vertices = points(&[
+            (0.0, 0.0), (0.0, 9.0), (9.0, 9.0), (9.0, 0.0),
+            (1.0, 1.0), (8.0, 1.0), (8.0, 8.0), (1.0, 8.0),
+            (2.0, 2.0), (2.0, 7.0), (7.0, 7.0), (7.0, 2.0),
+        ]);
+        let walls = (0..3)
+            .flat_map(|square| (0..4).map(move |i| (square * 4 + i, square * 4 + (i + 1) % 4)));
+        let polygons = walls_to_polygons(&vertices, walls);
+        assert_eq!(polygons.diagnostics, vec![]);
+        assert_eq!(
+            polygons.polygons,
+            vec![
+                polygon(&[0, 1, 2, 3], None),
+                polygon(&[4, 5, 6, 7], Some(0)),
+                polygon(&[8, 9, 10, 11], None),
             ]
-        })
-        .collect()
+        );
+    }
+
+    #[test]
+    fn recovery() {
+        #[rustfmt::skip]
+        let vertices = points(&[(0.0, 0.0), (0.0, 8.0), (8.0, 8.0), (8.0, 0.0), (8.0, 8.0)]);
+        // The wall from 3 to 0 is missing, and the one from 2 to 3 is reversed.
+        let walls = vec![(0, 1), (1, 4), (3, 2), (0, 1), (2, 2), (9, 0)];
+        let polygons = walls_to_polygons(&vertices, walls);
+        assert_eq!(polygons.polygons, vec![polygon(&[0, 1, 2, 3], None)]);
+        assert_eq!(
+            polygons.diagnostics,
+            vec![
+                Diagnostic::Coincident { vertex: 4, of: 2 },
+                Diagnostic::Duplicate { wall: 3, of: 0 },
+                Diagnostic::ZeroLength { wall: 4 },
+                Diagnostic::VertexOutOfRange { wall: 5, vertex: 9 },
+                Diagnostic::Reversed { walls: vec![2] },
+                Diagnostic::Gap { from: 3, to: 0 },
+            ]
+        );
+
+        let mut infinite = vertices.clone();
+        infinite[3].x = f32::INFINITY;
+        infinite[4].y = f32::NAN;
+        let walls = vec![(0, 1), (1, 2), (2, 3), (3, 0), (2, 4)];
+        let polygons = walls_to_polygons(&infinite, walls);
+        assert_eq!(polygons.polygons, vec![polygon(&[0, 1, 2], None)]);
+        assert_eq!(
+            polygons.diagnostics,
+            vec![
+                Diagnostic::NotFinite { wall: 2, vertex: 3 },
+                Diagnostic::NotFinite { wall: 3, vertex: 3 },
+                Diagnostic::NotFinite { wall: 4, vertex: 4 },
+                Diagnostic::Gap { from: 2, to: 0 },
+            ]
+        );
+
+        let polygons = walls_to_polygons(&vertices, vec![(0, 1), (1, 2), (2, 1)]);
+        assert_eq!(polygons.polygons, vec![]);
+        assert_eq!(
+            polygons.diagnostics,
+            vec![
+                Diagnostic::Opposite { wall: 2, of: 1 },
+                Diagnostic::OpenChain { walls: vec![0] },
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_vertex() {
+        // Two squares touching at a corner, outlined as one.
+        #[rustfmt::skip]
+        let vertices = points(&[
+            (0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (1.0, 2.0), (2.0, 2.0), (2.0, 1.0),
+        ]);
+        let walls = vec![
+            (0, 1),
+            (1, 2),
+            (2, 4),
+            (4, 5),
+            (5, 6),
+            (6, 2),
+            (2, 3),
+            (3, 0),
+        ];
+        let polygons = walls_to_polygons(&vertices, walls);
+        assert_eq!(
+            polygons.diagnostics,
+            vec![Diagnostic::SharedVertex { vertex: 2 }]
+        );
+        assert_eq!(
+            polygons.polygons,
+            vec![polygon(&[0, 1, 2, 3], None), polygon(&[2, 4, 5, 6], None)]
+        );
+    }
 }
-//
-// fn validate_lev(lev: &lev::Lev) {
-//     for sector in &lev.sectors {
-//         // In order to generate a triangulated floor / ceil, we want every vertex to be touched
-//         // by the left and right of one wall each - but unfortunately
-//         let mut left = (0..sector.vertices.len()).collect::<BTreeSet<_>>();
-//         let mut right = (0..sector.vertices.len()).collect::<BTreeSet<_>>();
-//         for wall in &sector.walls {
-//             if !left.remove(&wall.left_vertex) {
-//                 eprintln!(
-//                     "sector {} vertex {} already used on left",
-//                     sector.id, wall.left_vertex
-//                 );
-//             }
-//             if !right.remove(&wall.right_vertex) {
-//                 eprintln!(
-//                     "sector {} vertex {} already used on right",
-//                     sector.id, wall.right_vertex
-//                 );
-//             }
-//         }
-//         if !left.is_empty() && !right.is_empty() {
-//             eprintln!(
-//                 "sector {} has untouched left vertices: {:?}, right vertices: {:?}",
-//                 sector.id, left, right,
-//             );
-//         }
-//     }
-// }
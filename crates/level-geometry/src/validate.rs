@@ -0,0 +1,578 @@
+//! Checks a level for the mistakes that make the game misbehave or crash, such as adjoins
+//! that don't point back, sectors that don't close and sectors overlapping each other.
+
+use std::fmt;
+
+use formats::lev;
+
+use crate::{triangulate_sector, walls_to_polygons, Diagnostic};
+
+/// Everything [`validate`] found wrong with a level, in order of sector.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// A problem with a level. Sectors, walls and vertices are identified by their index.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// A wall uses a vertex that the sector doesn't have.
+    VertexOutOfRange {
+        sector: usize,
+        wall: usize,
+        vertex: usize,
+    },
+    /// A texture index is past the end of the level's texture list. The wall is `None`
+    /// for the floor and ceiling.
+    TextureOutOfRange {
+        sector: usize,
+        wall: Option<usize>,
+        texture: usize,
+    },
+    /// The ceiling is below the floor. Altitudes grow downwards.
+    CeilingBelowFloor { sector: usize },
+    /// A wall's adjoin, mirror or walk refers to a sector or wall that doesn't exist, or
+    /// only one of the adjoin and mirror is set.
+    BadAdjoin { sector: usize, wall: usize },
+    /// The mirror of a wall doesn't adjoin and mirror it in return.
+    NotMirrored {
+        sector: usize,
+        wall: usize,
+        adjoin: usize,
+        mirror: usize,
+    },
+    /// A wall and its mirror don't join the same two points in opposite directions.
+    MirrorEndpoints { sector: usize, wall: usize },
+    /// The walls of a sector don't close, as shown by a gap or an open chain.
+    Unclosed {
+        sector: usize,
+        diagnostic: Diagnostic,
+    },
+    /// Any other problem [`walls_to_polygons`] had with the walls of a sector.
+    Walls {
+        sector: usize,
+        diagnostic: Diagnostic,
+    },
+    /// A polygon of a sector winds the wrong way: outlines should be clockwise, and holes
+    /// anticlockwise.
+    WrongWinding { sector: usize, vertices: Vec<usize> },
+    /// The floor and ceiling of a sector can't be triangulated.
+    Triangulation { sector: usize, error: seidel::Error },
+    /// Two sectors on the same layer cover some of the same area.
+    Overlap { first: usize, second: usize },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::VertexOutOfRange {
+                sector,
+                wall,
+                vertex,
+            } => write!(
+                f,
+                "sector {} wall {}: vertex {} doesn't exist",
+                sector, wall, vertex
+            ),
+            Issue::TextureOutOfRange {
+                sector,
+                wall: Some(wall),
+                texture,
+            } => write!(
+                f,
+                "sector {} wall {}: texture {} doesn't exist",
+                sector, wall, texture
+            ),
+            Issue::TextureOutOfRange {
+                sector,
+                wall: None,
+                texture,
+            } => write!(f, "sector {}: texture {} doesn't exist", sector, texture),
+            Issue::CeilingBelowFloor { sector } => {
+                write!(f, "sector {}: the ceiling is below the floor", sector)
+            }
+            Issue::BadAdjoin { sector, wall } => write!(
+                f,
+                "sector {} wall {}: the adjoin is incomplete or doesn't exist",
+                sector, wall
+            ),
+            Issue::NotMirrored {
+                sector,
+                wall,
+                adjoin,
+                mirror,
+            } => write!(
+                f,
+                "sector {} wall {}: sector {} wall {} doesn't adjoin it in return",
+                sector, wall, adjoin, mirror
+            ),
+            Issue::MirrorEndpoints { sector, wall } => write!(
+                f,
+                "sector {} wall {}: the mirror has different ends",
+                sector, wall
+            ),
+            Issue::Unclosed { sector, diagnostic } => {
+                write!(f, "sector {} isn't closed: {}", sector, diagnostic)
+            }
+            Issue::Walls { sector, diagnostic } => write!(f, "sector {}: {}", sector, diagnostic),
+            Issue::WrongWinding { sector, vertices } => write!(
+                f,
+                "sector {}: the polygon of vertices {:?} winds the wrong way",
+                sector, vertices
+            ),
+            Issue::Triangulation { sector, error } => write!(f, "sector {}: {}", sector, error),
+            Issue::Overlap { first, second } => {
+                write!(f, "sectors {} and {} overlap", first, second)
+            }
+        }
+    }
+}
+
+/// Checks every sector of a level, and sectors on the same layer against each other.
+pub fn validate(lev: &lev::Lev) -> Report {
+    let mut issues = Vec::new();
+    let mut floors = Vec::with_capacity(lev.sectors.len());
+
+    for (index, sector) in lev.sectors.iter().enumerate() {
+        if sector.ceiling_altitude > sector.floor_altitude {
+            issues.push(Issue::CeilingBelowFloor { sector: index });
+        }
+        for texture in &[&sector.floor_texture, &sector.ceiling_texture] {
+            check_texture(lev, index, None, texture, &mut issues);
+        }
+
+        for (w, wall) in sector.walls.iter().enumerate() {
+            for &vertex in &[wall.left_vertex, wall.right_vertex] {
+                if vertex >= sector.vertices.len() {
+                    issues.push(Issue::VertexOutOfRange {
+                        sector: index,
+                        wall: w,
+                        vertex,
+                    });
+                }
+            }
+            let textures = [
+                &wall.middle_texture,
+                &wall.top_texture,
+                &wall.bottom_texture,
+                &wall.sign_texture,
+            ];
+            for texture in &textures {
+                check_texture(lev, index, Some(w), texture, &mut issues);
+            }
+            check_adjoin(lev, index, w, &mut issues);
+        }
+
+        let walls = sector.walls.iter().map(|w| (w.left_vertex, w.right_vertex));
+        let polygons = walls_to_polygons(&sector.vertices, walls);
+        for diagnostic in polygons.diagnostics {
+            issues.push(match diagnostic {
+                Diagnostic::VertexOutOfRange { .. } => continue,
+                Diagnostic::Gap { .. } | Diagnostic::OpenChain { .. } => Issue::Unclosed {
+                    sector: index,
+                    diagnostic,
+                },
+                _ => Issue::Walls {
+                    sector: index,
+                    diagnostic,
+                },
+            });
+        }
+        for polygon in polygons.polygons {
+            let area = area(polygon.vertices.iter().map(|&i| sector.vertices[i]));
+            let clockwise = polygon.hole_in.is_none();
+            if area != 0.0 && (area < 0.0) != clockwise {
+                issues.push(Issue::WrongWinding {
+                    sector: index,
+                    vertices: polygon.vertices,
+                });
+            }
+        }
+
+        match triangulate_sector(sector) {
+            Ok(triangles) => floors.push(triangles),
+            Err(error) => {
+                issues.push(Issue::Triangulation {
+                    sector: index,
+                    error,
+                });
+                floors.push(Vec::new());
+            }
+        }
+    }
+
+    let bounds = floors
+        .iter()
+        .map(|triangles| bounds(triangles))
+        .collect::<Vec<_>>();
+    for first in 0..lev.sectors.len() {
+        for second in first + 1..lev.sectors.len() {
+            if lev.sectors[first].layer != lev.sectors[second].layer {
+                continue;
+            }
+            let (a, b) = (bounds[first], bounds[second]);
+            if a[0] >= b[2] || b[0] >= a[2] || a[1] >= b[3] || b[1] >= a[3] {
+                continue;
+            }
+            let overlap = floors[first]
+                .iter()
+                .any(|a| floors[second].iter().any(|b| triangles_overlap(a, b)));
+            if overlap {
+                issues.push(Issue::Overlap { first, second });
+            }
+        }
+    }
+
+    Report { issues }
+}
+
+fn check_texture(
+    lev: &lev::Lev,
+    sector: usize,
+    wall: Option<usize>,
+    texture: &lev::Texture,
+    issues: &mut Vec<Issue>,
+) {
+    if let Some(texture) = texture.index {
+        if texture >= lev.texture_names.len() {
+            issues.push(Issue::TextureOutOfRange {
+                sector,
+                wall,
+                texture,
+            });
+        }
+    }
+}
+
+fn check_adjoin(lev: &lev::Lev, sector: usize, wall: usize, issues: &mut Vec<Issue>) {
+    let this = &lev.sectors[sector];
+    let w = &this.walls[wall];
+    let bad = Issue::BadAdjoin { sector, wall };
+    if w.walk_sector
+        .filter(|&walk| walk >= lev.sectors.len())
+        .is_some()
+    {
+        issues.push(bad);
+        return;
+    }
+    let (adjoin, mirror) = match (w.adjoin_sector, w.mirror_wall) {
+        (None, None) => return,
+        (Some(adjoin), Some(mirror)) => (adjoin, mirror),
+        _ => {
+            issues.push(bad);
+            return;
+        }
+    };
+    let other = match lev.sectors.get(adjoin).and_then(|s| s.walls.get(mirror)) {
+        Some(other) => other,
+        None => {
+            issues.push(bad);
+            return;
+        }
+    };
+
+    if other.adjoin_sector == Some(sector) && other.mirror_wall == Some(wall) {
+        // The pair is checked once, from its first wall.
+        if (adjoin, mirror) < (sector, wall) {
+            return;
+        }
+    } else {
+        issues.push(Issue::NotMirrored {
+            sector,
+            wall,
+            adjoin,
+            mirror,
+        });
+    }
+
+    let ends = |s: &lev::Sector, w: &lev::Wall| {
+        let left = s.vertices.get(w.left_vertex)?;
+        let right = s.vertices.get(w.right_vertex)?;
+        Some((*left, *right))
+    };
+    if let (Some((left, right)), Some((other_left, other_right))) =
+        (ends(this, w), ends(&lev.sectors[adjoin], other))
+    {
+        if left != other_right || right != other_left {
+            issues.push(Issue::MirrorEndpoints { sector, wall });
+        }
+    }
+}
+
+/// Twice the signed area of a polygon, negative when it winds clockwise.
+fn area(polygon: impl Iterator<Item = mint::Point2<f32>> + Clone) -> f64 {
+    let previous = polygon.clone().last();
+    let pairs = previous.into_iter().chain(polygon.clone()).zip(polygon);
+    pairs
+        .map(|(a, b)| a.x as f64 * b.y as f64 - b.x as f64 * a.y as f64)
+        .sum()
+}
+
+/// The smallest and largest X and Y of some triangles.
+fn bounds(triangles: &[[mint::Point2<f32>; 3]]) -> [f32; 4] {
+    let mut bounds = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    for point in triangles.iter().flatten() {
+        bounds[0] = bounds[0].min(point.x);
+        bounds[1] = bounds[1].min(point.y);
+        bounds[2] = bounds[2].max(point.x);
+        bounds[3] = bounds[3].max(point.y);
+    }
+    bounds
+}
+
+/// Whether the insides of two triangles overlap by more than rounding errors, so
+/// triangles sharing an edge or a corner don't.
+fn triangles_overlap(a: &[mint::Point2<f32>; 3], b: &[mint::Point2<f32>; 3]) -> bool {
+    const TOLERANCE: f32 = 1e-3;
+    for triangle in &[a, b] {
+        for i in 0..3 {
+            let (start, end) = (triangle[i], triangle[(i + 1) % 3]);
+            let length = (end.x - start.x).hypot(end.y - start.y);
+            if length == 0.0 {
+                continue;
+            }
+            let normal = ((start.y - end.y) / length, (end.x - start.x) / length);
+            let project = |triangle: &[mint::Point2<f32>; 3]| {
+                let distances = triangle.iter().map(|p| p.x * normal.0 + p.y * normal.1);
+                distances.fold((f32::MAX, f32::MIN), |(min, max), d| {
+                    (min.min(d), max.max(d))
+                })
+            };
+            let ((a_min, a_max), (b_min, b_max)) = (project(a), project(b));
+            if a_max <= b_min + TOLERANCE || b_max <= a_min + TOLERANCE {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sector<'a> {
+        floor: f32,
+        ceiling: f32,
+        layer: i32,
+        vertices: &'a [(f32, f32)],
+        /// Left and right vertex, adjoin, mirror and middle texture.
+        walls: &'a [(usize, usize, i32, i32, usize)],
+    }
+
+    fn level(sectors: &[Sector<'_>]) -> lev::Lev {
+        let mut text = String::from(
+            "LEV 2.1
+LEVELNAME TEST
+PALETTE TEST.PAL
+MUSIC NULL.GMD
+PARALLAX 1024.0000 1024.0000
+TEXTURES 1
+TEXTURE: WALL.BM
+",
+        );
+        text += &format!("NUMSECTORS {}\n", sectors.len());
+        for (i, sector) in sectors.iter().enumerate() {
+            text += &format!(
+                "SECTOR {}
+NAME
+AMBIENT 20
+FLOOR TEXTURE 0 0.00 0.00 0
+FLOOR ALTITUDE {:.2}
+CEILING TEXTURE 0 0.00 0.00 0
+CEILING ALTITUDE {:.2}
+SECOND ALTITUDE 0.00
+FLAGS 0 0 0
+LAYER {}
+VERTICES {}
+",
+                i,
+                sector.floor,
+                sector.ceiling,
+                sector.layer,
+                sector.vertices.len()
+            );
+            for (x, z) in sector.vertices {
+                text += &format!("X: {:.2} Z: {:.2}\n", x, z);
+            }
+            text += &format!("WALLS {}\n", sector.walls.len());
+            for &(left, right, adjoin, mirror, texture) in sector.walls {
+                text += &format!(
+                    "WALL LEFT: {} RIGHT: {} MID: {} 0.00 0.00 0 TOP: 0 0.00 0.00 0 \
+                     BOT: 0 0.00 0.00 0 SIGN: -1 0.00 0.00 ADJOIN: {} MIRROR: {} WALK: {} \
+                     FLAGS: 0 0 0 LIGHT: 0\n",
+                    left, right, texture, adjoin, mirror, adjoin
+                );
+            }
+        }
+        lev::Lev::read(text.as_bytes()).unwrap()
+    }
+
+    const LEFT: &[(f32, f32)] = &[(0.0, 0.0), (0.0, 8.0), (8.0, 8.0), (8.0, 0.0)];
+    const RIGHT: &[(f32, f32)] = &[(8.0, 0.0), (8.0, 8.0), (16.0, 8.0), (16.0, 0.0)];
+
+    #[test]
+    fn valid() {
+        let lev = level(&[
+            Sector {
+                floor: 0.0,
+                ceiling: -8.0,
+                layer: 0,
+                vertices: LEFT,
+                walls: &[
+                    (0, 1, -1, -1, 0),
+                    (1, 2, -1, -1, 0),
+                    (2, 3, 1, 0, 0),
+                    (3, 0, -1, -1, 0),
+                ],
+            },
+            Sector {
+                floor: 0.0,
+                ceiling: -8.0,
+                layer: 0,
+                vertices: RIGHT,
+                walls: &[
+                    (0, 1, 0, 2, 0),
+                    (1, 2, -1, -1, 0),
+                    (2, 3, -1, -1, 0),
+                    (3, 0, -1, -1, 0),
+                ],
+            },
+            // Overlapping, but on another layer.
+            Sector {
+                floor: 0.0,
+                ceiling: -8.0,
+                layer: 1,
+                vertices: &[(4.0, 4.0), (4.0, 12.0), (12.0, 12.0), (12.0, 4.0)],
+                walls: &[
+                    (0, 1, -1, -1, 0),
+                    (1, 2, -1, -1, 0),
+                    (2, 3, -1, -1, 0),
+                    (3, 0, -1, -1, 0),
+                ],
+            },
+        ]);
+        assert_eq!(validate(&lev), Report::default());
+    }
+
+    #[test]
+    fn not_finite() {
+        let mut lev = level(&[Sector {
+            floor: 0.0,
+            ceiling: -8.0,
+            layer: 0,
+            vertices: LEFT,
+            walls: &[
+                (0, 1, -1, -1, 0),
+                (1, 2, -1, -1, 0),
+                (2, 3, -1, -1, 0),
+                (3, 0, -1, -1, 0),
+            ],
+        }]);
+        // As the parser reads overlong numbers.
+        lev.sectors[0].vertices[3].x = f32::INFINITY;
+        let report = validate(&lev);
+        assert_eq!(
+            report.issues[0],
+            Issue::Walls {
+                sector: 0,
+                diagnostic: Diagnostic::NotFinite { wall: 2, vertex: 3 }
+            }
+        );
+    }
+
+    #[test]
+    fn issues() {
+        let lev = level(&[
+            Sector {
+                floor: 0.0,
+                ceiling: -8.0,
+                layer: 0,
+                vertices: LEFT,
+                walls: &[
+                    (0, 1, -1, -1, 0),
+                    (1, 2, -1, -1, 0),
+                    (2, 3, 1, 0, 0),
+                    (3, 0, -1, -1, 5),
+                ],
+            },
+            // The wall from 3 to 0 is missing.
+            Sector {
+                floor: 0.0,
+                ceiling: 4.0,
+                layer: 0,
+                vertices: RIGHT,
+                walls: &[
+                    (0, 1, 0, 2, 0),
+                    (1, 2, -1, -1, 0),
+                    (2, 3, -1, -1, 0),
+                    (2, 7, -1, -1, 0),
+                ],
+            },
+            // Anticlockwise, and overlapping both.
+            Sector {
+                floor: 0.0,
+                ceiling: -8.0,
+                layer: 0,
+                vertices: &[(4.0, 4.0), (12.0, 4.0), (12.0, 12.0), (4.0, 12.0)],
+                walls: &[
+                    (0, 1, 0, 0, 0),
+                    (1, 2, 9, 0, 0),
+                    (2, 3, -1, -1, 0),
+                    (3, 0, -1, -1, 0),
+                ],
+            },
+        ]);
+        let report = validate(&lev);
+        assert_eq!(
+            report.issues,
+            vec![
+                Issue::TextureOutOfRange {
+                    sector: 0,
+                    wall: Some(3),
+                    texture: 5
+                },
+                Issue::CeilingBelowFloor { sector: 1 },
+                Issue::VertexOutOfRange {
+                    sector: 1,
+                    wall: 3,
+                    vertex: 7
+                },
+                Issue::Unclosed {
+                    sector: 1,
+                    diagnostic: Diagnostic::Gap { from: 3, to: 0 }
+                },
+                Issue::NotMirrored {
+                    sector: 2,
+                    wall: 0,
+                    adjoin: 0,
+                    mirror: 0
+                },
+                Issue::MirrorEndpoints { sector: 2, wall: 0 },
+                Issue::BadAdjoin { sector: 2, wall: 1 },
+                Issue::WrongWinding {
+                    sector: 2,
+                    vertices: vec![0, 1, 2, 3]
+                },
+                Issue::Overlap {
+                    first: 0,
+                    second: 2
+                },
+                Issue::Overlap {
+                    first: 1,
+                    second: 2
+                },
+            ]
+        );
+        assert_eq!(
+            report.issues[3].to_string(),
+            "sector 1 isn't closed: no wall joins vertex 3 to vertex 0"
+        );
+    }
+}
@@ -57,7 +57,10 @@ impl Level {
                 }
             }
 
-            let triangulation = triangulate_sector(&sector);
+            let triangulation = triangulate_sector(&sector).unwrap_or_else(|error| {
+                eprintln!("{}: sector {}: {}", name, sector.id, error);
+                Vec::new()
+            });
             builder.add_floor(
                 floor,
                 &triangulation,
@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io;
 use std::path::Path;
 
+use formats::install::Install;
 use wgpu::util::DeviceExt;
 
 pub use level::Level;
@@ -37,11 +38,15 @@ pub struct Loader {
 }
 
 impl Loader {
-    pub fn open(base_path: impl AsRef<Path>) -> LoaderResult<Self> {
-        let base_path = base_path.as_ref();
+    pub fn open(install: &Install) -> LoaderResult<Self> {
+        let path = |name: &str| {
+            install
+                .file(name)
+                .ok_or_else(|| LoaderError::NotFound(name.to_string()))
+        };
         Ok(Self {
-            dark: Gob::open(base_path.join("DARK.GOB"))?,
-            textures: Gob::open(base_path.join("TEXTURES.GOB"))?,
+            dark: Gob::open(path("DARK.GOB")?)?,
+            textures: Gob::open(path("TEXTURES.GOB")?)?,
         })
     }
 
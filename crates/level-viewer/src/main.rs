@@ -17,18 +17,17 @@ mod renderer;
 fn main() {
     wgpu_subscriber::initialize_default_subscriber(None);
 
-    let steam_path = registry::Hive::CurrentUser.open(r"Software\Valve\Steam", registry::Security::Read)
-        .expect("want a Steam installation")
-        .value("SteamPath")
-        .unwrap();
-    let steam_path = match steam_path {
-        registry::Data::String(steam_path) => steam_path.to_os_string(),
-        _ => panic!("unexpected type: {:?}", steam_path),
+    let path = std::env::args_os().nth(1).map(std::path::PathBuf::from);
+    let install = match formats::install::Install::find(path.as_deref()) {
+        Ok(install) => install,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
     };
+    eprintln!("{}", install);
 
-    let game_path = std::path::Path::new(&steam_path).join(r"steamapps\common\Dark Forces\Game");
-
-    let mut loader = loader::Loader::open(game_path).unwrap();
+    let mut loader = loader::Loader::open(&install).unwrap();
 
     let level_names = loader.level_names();
     dbg!(&level_names);
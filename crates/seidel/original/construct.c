@@ -2,12 +2,13 @@
 #include <math.h>
 
 
-node_t qs[QSIZE];		/* Query structure */
-trap_t tr[TRSIZE];		/* Trapezoid structure */
-segment_t seg[SEGSIZE];		/* Segment table */
+THREAD_LOCAL node_t qs[QSIZE];		/* Query structure */
+THREAD_LOCAL trap_t tr[TRSIZE];		/* Trapezoid structure */
+THREAD_LOCAL segment_t seg[SEGSIZE];	/* Segment table */
+THREAD_LOCAL int failed;
 
-static int q_idx;
-static int tr_idx;
+static THREAD_LOCAL int q_idx;
+static THREAD_LOCAL int tr_idx;
 
 /* Return a new node to be added into the query tree */
 static int newnode()
@@ -16,8 +17,10 @@ static int newnode()
     return q_idx++;
   else
     {
-      fprintf(stderr, "newnode: Query-table overflow\n");
-      return -1;
+      /* Query-table overflow: hand out the unused node 0 so nothing */
+      /* is written out of bounds, and give up on the result */
+      failed = TRUE;
+      return 0;
     }
 }
 
@@ -33,8 +36,9 @@ static int newtrap()
     }
   else
     {
-      fprintf(stderr, "newtrap: Trapezoid-table overflow\n");
-      return -1;
+      /* Trapezoid-table overflow, as above */
+      failed = TRUE;
+      return 0;
     }
 }
 
@@ -343,8 +347,9 @@ int locate_endpoint(v, vo, r)
 	return locate_endpoint(v, vo, rptr->right); /* right */	
 
     default:
-      fprintf(stderr, "Haggu !!!!!\n");
-      break;
+      /* A corrupt query structure: report it instead of returning garbage */
+      failed = TRUE;
+      return 0;
     }
 }
 
@@ -8,22 +8,29 @@ extern double log2(double);
 extern double log2();
 #endif
 
-static int choose_idx;
-static int permute[SEGSIZE];
+static THREAD_LOCAL int choose_idx;
+static THREAD_LOCAL int permute[SEGSIZE];
+static THREAD_LOCAL unsigned long seed;
+
+
+/* A private linear congruential generator: lrand48() shares its */
+/* state between threads, and a fixed seed makes the output repeatable */
+static long next_random()
+{
+  seed = (seed * 1103515245UL + 12345UL) & 0x7fffffffUL;
+  return (long) (seed >> 8);
+}
 
 
 /* Generate a random permutation of the segments 1..n */
 int generate_random_ordering(n)
      int n;
 {
-  struct timeval tval;
-  struct timezone tzone;
   register int i;
   int m, st[SEGSIZE], *p;
   
   choose_idx = 1;
-  gettimeofday(&tval, &tzone);
-  srand48(tval.tv_sec);
+  seed = 1;
 
   for (i = 0; i <= n; i++)
     st[i] = i;
@@ -31,7 +38,7 @@ int generate_random_ordering(n)
   p = st;
   for (i = 1; i <= n; i++, p++)
     {
-      m = lrand48() % (n + 1 - i) + 1;
+      m = next_random() % (n + 1 - i) + 1;
       permute[i] = p[m];
       if (m != 1)
 	p[m] = p[1];
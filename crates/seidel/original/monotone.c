@@ -4,21 +4,21 @@
 #define CROSS_SINE(v0, v1) ((v0).x * (v1).y - (v1).x * (v0).y)
 #define LENGTH(v0) (sqrt((v0).x * (v0).x + (v0).y * (v0).y))
 
-static monchain_t mchain[TRSIZE]; /* Table to hold all the monotone */
+static THREAD_LOCAL monchain_t mchain[TRSIZE]; /* Table to hold all the monotone */
 				  /* polygons . Each monotone polygon */
 				  /* is a circularly linked list */
 
-static vertexchain_t vert[SEGSIZE]; /* chain init. information. This */
+static THREAD_LOCAL vertexchain_t vert[SEGSIZE]; /* chain init. information. This */
 				    /* is used to decide which */
 				    /* monotone polygon to split if */
 				    /* there are several other */
 				    /* polygons touching at the same */
 				    /* vertex  */
 
-static int mon[SEGSIZE];	/* contains position of any vertex in */
+static THREAD_LOCAL int mon[SEGSIZE];	/* contains position of any vertex in */
 				/* the monotone chain for the polygon */
-static int visited[TRSIZE];
-static int chain_idx, op_idx, mon_idx;
+static THREAD_LOCAL int visited[TRSIZE];
+static THREAD_LOCAL int chain_idx, op_idx, op_size, mon_idx;
 
 
 static int triangulate_single_polygon(int, int, int, int (*)[3]);
@@ -47,14 +47,40 @@ static int inside_polygon(t)
 /* return a new mon structure from the table */
 static int newmon()
 {
-  return ++mon_idx;
+  if (mon_idx + 1 < SEGSIZE)
+    return ++mon_idx;
+  failed = TRUE;
+  return 0;
 }
 
 
 /* return a new chain element from the table */
 static int new_chain_element()
 {
-  return ++chain_idx;
+  if (chain_idx + 1 < TRSIZE)
+    return ++chain_idx;
+  failed = TRUE;
+  return 0;
+}
+
+
+/* Add a triangle to the output, unless it is already full */
+static int add_triangle(op, v0, v1, v2)
+     int op[][3];
+     int v0;
+     int v1;
+     int v2;
+{
+  if (op_idx >= op_size)
+    {
+      failed = TRUE;
+      return 0;
+    }
+  op[op_idx][0] = v0;
+  op[op_idx][1] = v1;
+  op[op_idx][2] = v2;
+  op_idx++;
+  return 0;
 }
 
 
@@ -89,7 +115,7 @@ static int get_vertex_positions(v0, v1, ip, iq)
   vertexchain_t *vp0, *vp1;
   register int i;
   double angle, temp;
-  int tp, tq;
+  int tp = 0, tq = 0;
 
   vp0 = &vert[v0];
   vp1 = &vert[v1];
@@ -177,6 +203,11 @@ static int make_new_monotone_poly(mcur, v0, v1)
 
   nf0 = vp0->nextfree;
   nf1 = vp1->nextfree;
+  if ((nf0 >= 4) || (nf1 >= 4))
+    {
+      failed = TRUE;
+      return mnew;
+    }
 
   vp0->vnext[ip] = v1;
 
@@ -219,6 +250,11 @@ int monotonate_trapezoids(n)
   for (i = 0; i < TRSIZE; i++)
     if (inside_polygon(&tr[i]))
       break;
+  if (i == TRSIZE)
+    {
+      failed = TRUE;
+      return 0;
+    }
   tr_start = i;
   
   /* Initialise the mon data-structure and start spanning all the */
@@ -563,9 +599,10 @@ static int traverse_polygon(mcur, trnum, from, dir)
 /* triangulation. */
 /* Take care not to triangulate duplicate monotone polygons */
 
-int triangulate_monotone_polygons(nvert, nmonpoly, op)
+int triangulate_monotone_polygons(nvert, nmonpoly, ntriangles, op)
      int nvert;
      int nmonpoly;
+     int ntriangles;
      int op[][3];
 {
   register int i;
@@ -590,6 +627,7 @@ int triangulate_monotone_polygons(nvert, nmonpoly, op)
 #endif
 
   op_idx = 0;
+  op_size = ntriangles;
   for (i = 0; i < nmonpoly; i++)
     {
       vcount = 1;
@@ -628,10 +666,8 @@ int triangulate_monotone_polygons(nvert, nmonpoly, op)
       
       if (vcount == 3)		/* already a triangle */
 	{
-	  op[op_idx][0] = mchain[p].vnum;
-	  op[op_idx][1] = mchain[mchain[p].next].vnum;
-	  op[op_idx][2] = mchain[mchain[p].prev].vnum;
-	  op_idx++;
+	  add_triangle(op, mchain[p].vnum, mchain[mchain[p].next].vnum,
+		       mchain[mchain[p].prev].vnum);
 	}
       else			/* triangulate the polygon */
 	{
@@ -702,14 +738,16 @@ static int triangulate_single_polygon(nvert, posmax, side, op)
 	  if (CROSS(vert[v].pt, vert[rc[ri - 1]].pt, 
 		    vert[rc[ri]].pt) > 0)
 	    {			/* convex corner: cut if off */
-	      op[op_idx][0] = rc[ri - 1];
-	      op[op_idx][1] = rc[ri];
-	      op[op_idx][2] = v;
-	      op_idx++;	     
+	      add_triangle(op, rc[ri - 1], rc[ri], v);
 	      ri--;
 	    }
 	  else		/* non-convex */
 	    {		/* add v to the chain */
+	      if (ri + 1 >= SEGSIZE)
+		{
+		  failed = TRUE;
+		  return 0;
+		}
 	      ri++;
 	      rc[ri] = v;
 	      vpos = mchain[vpos].next;
@@ -718,6 +756,11 @@ static int triangulate_single_polygon(nvert, posmax, side, op)
 	}
       else			/* reflex-chain empty: add v to the */
 	{			/* reflex chain and advance it  */
+	  if (ri + 1 >= SEGSIZE)
+	    {
+	      failed = TRUE;
+	      return 0;
+	    }
 	  rc[++ri] = v;
 	  vpos = mchain[vpos].next;
 	  v = mchain[vpos].vnum;
@@ -725,10 +768,12 @@ static int triangulate_single_polygon(nvert, posmax, side, op)
     } /* end-while */
   
   /* reached the bottom vertex. Add in the triangle formed */
-  op[op_idx][0] = rc[ri - 1];
-  op[op_idx][1] = rc[ri];
-  op[op_idx][2] = v;
-  op_idx++;	     
+  if (ri < 1)
+    {
+      failed = TRUE;
+      return 0;
+    }
+  add_triangle(op, rc[ri - 1], rc[ri], v);
   ri--;
   
   return 0;
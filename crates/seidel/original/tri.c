@@ -33,7 +33,7 @@ int main(argc, argv)
   initialise(n);
   construct_trapezoids(n);
   nmonpoly = monotonate_trapezoids(n);
-  ntriangles = triangulate_monotone_polygons(n, nmonpoly, op);
+  ntriangles = triangulate_monotone_polygons(n, nmonpoly, SEGSIZE, op);
 
   for (i = 0; i < ntriangles; i++)
     printf("triangle #%d: %d %d %d\n", i, 
@@ -62,24 +62,37 @@ int main(argc, argv)
  *           vertices[0] must NOT be used (i.e. i/p starts from
  *           vertices[1] instead. The output triangles are
  *	     specified  w.r.t. the indices of these vertices.
+ * ntriangles: Size of the output array.
  * triangles: Output array to hold triangles.
  *  
- * Enough space must be allocated for all the arrays before calling
- * this routine
+ * Returns the number of triangles written, or -1 if there are more
+ * than SEGSIZE - 1 points, the output array is too small, or the
+ * input was not a valid set of contours.
  */
 
 
-int triangulate_polygon(ncontours, cntr, vertices, triangles)
+int triangulate_polygon(ncontours, cntr, vertices, ntriangles, triangles)
      int ncontours;
      int cntr[];
      double (*vertices)[2];
+     int ntriangles;
      int (*triangles)[3];
 {
   register int i;
   int nmonpoly, ccount, npoints, genus;
   int n;
 
+  if (ncontours < 1)
+    return -1;
+  for (ccount = 0, n = 0; ccount < ncontours; ccount++)
+    {
+      if ((cntr[ccount] < 3) || (cntr[ccount] >= SEGSIZE - n))
+	return -1;
+      n += cntr[ccount];
+    }
+
   memset((void *)seg, 0, sizeof(seg));
+  failed = FALSE;
   ccount = 0;
   i = 1;
   
@@ -126,10 +139,14 @@ int triangulate_polygon(ncontours, cntr, vertices, triangles)
 
   initialise(n);
   construct_trapezoids(n);
+  if (failed)
+    return -1;
   nmonpoly = monotonate_trapezoids(n);
-  triangulate_monotone_polygons(n, nmonpoly, triangles);
+  if (failed)
+    return -1;
+  n = triangulate_monotone_polygons(n, nmonpoly, ntriangles, triangles);
   
-  return 0;
+  return failed ? -1 : n;
 }
 
 
@@ -4,6 +4,7 @@
 #include <sys/types.h>
 #include <stdlib.h>
 #include <stdio.h>
+#include <string.h>
 
 typedef struct {
   double x, y;
@@ -131,17 +132,29 @@ typedef struct {
 
 
 
+/* Every table is private to the calling thread, so that several */
+/* polygons can be triangulated at the same time. */
+
+#ifdef _MSC_VER
+#define THREAD_LOCAL __declspec(thread)
+#else
+#define THREAD_LOCAL __thread
+#endif
+
+
 /* Global variables */
 
-extern node_t qs[QSIZE];		/* Query structure */
-extern trap_t tr[TRSIZE];		/* Trapezoid structure */
-extern segment_t seg[SEGSIZE];		/* Segment table */
+extern THREAD_LOCAL node_t qs[QSIZE];		/* Query structure */
+extern THREAD_LOCAL trap_t tr[TRSIZE];		/* Trapezoid structure */
+extern THREAD_LOCAL segment_t seg[SEGSIZE];	/* Segment table */
+extern THREAD_LOCAL int failed;	/* set when a table would overflow */
+					/* or the input is inconsistent */
 
 
 /* Functions */
 
 extern int monotonate_trapezoids(int);
-extern int triangulate_monotone_polygons(int, int, int (*)[3]);
+extern int triangulate_monotone_polygons(int, int, int, int (*)[3]);
 
 extern int _greater_than(point_t *, point_t *);
 extern int _equal_to(point_t *, point_t *);
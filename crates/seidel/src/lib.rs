@@ -1,11 +1,11 @@
-#![allow(dead_code)]
 #![warn(rust_2018_idioms)]
 
 use std::fmt;
+use std::ops::Range;
 use std::os::raw::{c_double, c_int};
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct Vertex {
     pub x: c_double,
     pub y: c_double,
@@ -17,18 +17,105 @@ impl From<[c_double; 2]> for Vertex {
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct Triangle(pub [c_int; 3]);
+/// The most vertices an outer contour and its holes can have together, set by the
+/// fixed size tables of the C implementation.
+pub const MAX_VERTICES: usize = 199;
+
+/// Why a set of contours could not be triangulated. Vertices and contours are identified
+/// by their index in the input, and edges by the index of their first vertex.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    NoContours,
+    ContourTooShort { contour: usize, len: usize },
+    VertexCount { expected: usize, actual: usize },
+    TooManyVertices { contour: usize, count: usize },
+    NotFinite { vertex: usize },
+    RepeatedVertex { first: usize, second: usize },
+    Collinear { contour: usize },
+    SelfIntersecting { first: usize, second: usize },
+    Failed { contour: usize },
+}
 
-impl fmt::Debug for Triangle {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        match *self {
+            Error::NoContours => write!(f, "there are no contours"),
+            Error::ContourTooShort { contour, len } => {
+                write!(f, "contour {} has only {} vertices", contour, len)
+            }
+            Error::VertexCount { expected, actual } => write!(
+                f,
+                "the contours have {} vertices, but {} were given",
+                expected, actual
+            ),
+            Error::TooManyVertices { contour, count } => write!(
+                f,
+                "contour {} and its holes have {} vertices, more than the {} supported",
+                contour, count, MAX_VERTICES
+            ),
+            Error::NotFinite { vertex } => write!(f, "vertex {} is not finite", vertex),
+            Error::RepeatedVertex { first, second } => {
+                write!(
+                    f,
+                    "vertices {} and {} are at the same position",
+                    first, second
+                )
+            }
+            Error::Collinear { contour } => {
+                write!(f, "contour {} is collinear and encloses no area", contour)
+            }
+            Error::SelfIntersecting { first, second } => {
+                write!(f, "edges {} and {} intersect", first, second)
+            }
+            Error::Failed { contour } => {
+                write!(f, "the triangulation of contour {} failed", contour)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Renumbers the vertices and contours the error refers to, such as to the caller's
+    /// own numbering when the input was gathered from elsewhere.
+    pub fn renumber(
+        self,
+        vertex: impl Fn(usize) -> usize,
+        contour: impl Fn(usize) -> usize,
+    ) -> Self {
+        match self {
+            Error::ContourTooShort { contour: c, len } => Error::ContourTooShort {
+                contour: contour(c),
+                len,
+            },
+            Error::TooManyVertices { contour: c, count } => Error::TooManyVertices {
+                contour: contour(c),
+                count,
+            },
+            Error::NotFinite { vertex: v } => Error::NotFinite { vertex: vertex(v) },
+            Error::RepeatedVertex { first, second } => Error::RepeatedVertex {
+                first: vertex(first),
+                second: vertex(second),
+            },
+            Error::Collinear { contour: c } => Error::Collinear {
+                contour: contour(c),
+            },
+            Error::SelfIntersecting { first, second } => Error::SelfIntersecting {
+                first: vertex(first),
+                second: vertex(second),
+            },
+            Error::Failed { contour: c } => Error::Failed {
+                contour: contour(c),
+            },
+            error => error,
+        }
     }
 }
 
-static LOCK_INIT: std::sync::Once = std::sync::Once::new();
-static mut LOCK: Option<std::sync::Mutex<()>> = None;
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Triangle([c_int; 3]);
 
 #[link(name = "seidel-triangulate")]
 extern "C" {
@@ -36,41 +123,274 @@ extern "C" {
         ncontours: c_int,
         cntr: *const c_int,
         vertices: *const Vertex,
+        ntriangles: c_int,
         triangles: *mut Triangle,
     ) -> c_int;
     // fn is_point_inside_polygon(vertex: Vertex) -> c_int;
 }
 
-pub fn triangulate(contours: &[c_int], vertices: &[Vertex]) -> Vec<Triangle> {
+/// Triangulates the area enclosed by a set of contours, returning triangles as indices
+/// into `vertices`.
+///
+/// `contours` holds the number of vertices of each contour, whose vertices follow each
+/// other in `vertices`. Contours may wind either way and be nested to any depth: a contour
+/// inside another is a hole, and one inside a hole is an island. They must not touch or
+/// share vertices.
+///
+/// The state of the triangulator is per thread, so this can be called from many threads
+/// at once.
+pub fn triangulate(contours: &[usize], vertices: &[Vertex]) -> Result<Vec<[usize; 3]>, Error> {
+    let ranges = validate(contours, vertices)?;
+    let polygons = ranges
+        .iter()
+        .map(|range| &vertices[range.clone()])
+        .collect::<Vec<_>>();
+
+    // A contour is a hole in the innermost contour containing it, which is the one
+    // contained by the most others.
+    let containers = (0..ranges.len())
+        .map(|i| {
+            let point = polygons[i][0];
+            (0..ranges.len())
+                .filter(|&j| j != i && contains(polygons[j], point))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let depth = |i: usize| containers[i].len();
+    let parent = |i: usize| containers[i].iter().copied().max_by_key(|&j| depth(j));
+
+    let mut triangles = Vec::new();
+    for outer in (0..ranges.len()).filter(|&i| depth(i) % 2 == 0) {
+        let holes = (0..ranges.len()).filter(|&i| depth(i) % 2 == 1 && parent(i) == Some(outer));
+        let component = std::iter::once(outer).chain(holes).collect::<Vec<_>>();
+        triangulate_component(outer, &component, &ranges, vertices, &mut triangles)?;
+    }
+    Ok(triangles)
+}
+
+/// Checks everything the C implementation assumes but doesn't check, returning the range
+/// of vertices of each contour.
+fn validate(contours: &[usize], vertices: &[Vertex]) -> Result<Vec<Range<usize>>, Error> {
     if contours.is_empty() {
-        return vec![];
+        return Err(Error::NoContours);
+    }
+
+    let mut ranges = Vec::with_capacity(contours.len());
+    let mut start = 0;
+    for (contour, &len) in contours.iter().enumerate() {
+        if len < 3 {
+            return Err(Error::ContourTooShort { contour, len });
+        }
+        ranges.push(start..start + len);
+        start += len;
+    }
+    if start != vertices.len() {
+        return Err(Error::VertexCount {
+            expected: start,
+            actual: vertices.len(),
+        });
     }
 
-    LOCK_INIT.call_once(|| {
-        unsafe { LOCK = Some(Default::default()) };
+    if let Some(vertex) = vertices
+        .iter()
+        .position(|v| !v.x.is_finite() || !v.y.is_finite())
+    {
+        return Err(Error::NotFinite { vertex });
+    }
+
+    let mut sorted = (0..vertices.len()).collect::<Vec<_>>();
+    sorted.sort_by(|&a, &b| {
+        let (a, b) = (vertices[a], vertices[b]);
+        (a.x, a.y).partial_cmp(&(b.x, b.y)).unwrap()
     });
-    let _guard = unsafe { LOCK.as_mut().unwrap() }.lock().unwrap();
+    for pair in sorted.windows(2) {
+        if vertices[pair[0]] == vertices[pair[1]] {
+            return Err(Error::RepeatedVertex {
+                first: pair[0].min(pair[1]),
+                second: pair[0].max(pair[1]),
+            });
+        }
+    }
+
+    for (contour, range) in ranges.iter().enumerate() {
+        let polygon = &vertices[range.clone()];
+        let (a, b) = (polygon[0], polygon[1]);
+        if polygon.iter().all(|&c| orientation(a, b, c) == 0.0) {
+            return Err(Error::Collinear { contour });
+        }
+    }
+
+    let edges = ranges
+        .iter()
+        .flat_map(|range| {
+            let range = range.clone();
+            range.clone().map(move |i| {
+                let next = if i + 1 == range.end {
+                    range.start
+                } else {
+                    i + 1
+                };
+                (i, next)
+            })
+        })
+        .collect::<Vec<_>>();
+    for (i, &first) in edges.iter().enumerate() {
+        for &second in &edges[i + 1..] {
+            if edges_intersect(vertices, first, second) {
+                return Err(Error::SelfIntersecting {
+                    first: first.0,
+                    second: second.0,
+                });
+            }
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// Runs the C implementation on an outer contour and its holes, after winding the outer
+/// contour anticlockwise and the holes clockwise as it requires.
+fn triangulate_component(
+    outer: usize,
+    component: &[usize],
+    ranges: &[Range<usize>],
+    vertices: &[Vertex],
+    triangles: &mut Vec<[usize; 3]>,
+) -> Result<(), Error> {
+    // The vertices in the order given to the C implementation, which is 1-based.
+    let mut order = Vec::new();
+    let mut counts = Vec::with_capacity(component.len());
+    for (i, &contour) in component.iter().enumerate() {
+        let range = ranges[contour].clone();
+        let anticlockwise = area(&vertices[range.clone()]) > 0.0;
+        if anticlockwise == (i == 0) {
+            order.extend(range.clone());
+        } else {
+            order.extend(range.clone().rev());
+        }
+        counts.push(range.len() as c_int);
+    }
+    if order.len() > MAX_VERTICES {
+        return Err(Error::TooManyVertices {
+            contour: outer,
+            count: order.len(),
+        });
+    }
 
-    let triangle_count = vertices.len() - 2 + 2 * (contours.len() - 1);
-    let mut result = vec![Triangle([0, 0, 0]); triangle_count];
-    unsafe {
+    let input = std::iter::once(Vertex::default())
+        .chain(order.iter().map(|&i| vertices[i]))
+        .collect::<Vec<_>>();
+    let expected = order.len() + 2 * (component.len() - 1) - 2;
+    let mut output = vec![Triangle::default(); expected];
+    let count = unsafe {
         triangulate_polygon(
-            contours.len() as c_int,
-            contours.as_ptr(),
-            vertices.as_ptr().sub(1), // ignores, and doesn't access vertex at 0
-            result.as_mut_ptr(),
-        );
+            counts.len() as c_int,
+            counts.as_ptr(),
+            input.as_ptr(),
+            output.len() as c_int,
+            output.as_mut_ptr(),
+        )
+    };
+
+    let failed = Error::Failed { contour: outer };
+    if count < 0 || count as usize != expected {
+        return Err(failed);
     }
-    result
+    for triangle in &output {
+        let mut indices = [0; 3];
+        for (index, &vertex) in indices.iter_mut().zip(&triangle.0) {
+            let vertex = vertex as usize;
+            if vertex == 0 || vertex > order.len() {
+                return Err(failed);
+            }
+            *index = order[vertex - 1];
+        }
+        triangles.push(indices);
+    }
+    Ok(())
+}
+
+/// Twice the signed area of a polygon, positive when it winds anticlockwise.
+fn area(polygon: &[Vertex]) -> f64 {
+    let mut previous = polygon[polygon.len() - 1];
+    let mut area = 0.0;
+    for &vertex in polygon {
+        area += previous.x * vertex.y - vertex.x * previous.y;
+        previous = vertex;
+    }
+    area
+}
+
+fn contains(polygon: &[Vertex], point: Vertex) -> bool {
+    let mut inside = false;
+    let mut previous = polygon[polygon.len() - 1];
+    for &vertex in polygon {
+        if (vertex.y > point.y) != (previous.y > point.y)
+            && point.x
+                < (previous.x - vertex.x) * (point.y - vertex.y) / (previous.y - vertex.y)
+                    + vertex.x
+        {
+            inside = !inside;
+        }
+        previous = vertex;
+    }
+    inside
+}
+
+fn orientation(a: Vertex, b: Vertex, c: Vertex) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+/// Whether `p`, collinear with `a` and `b`, lies between them.
+fn between(a: Vertex, b: Vertex, p: Vertex) -> bool {
+    p.x >= a.x.min(b.x) && p.x <= a.x.max(b.x) && p.y >= a.y.min(b.y) && p.y <= a.y.max(b.y)
+}
+
+/// Whether two edges cross or touch anywhere other than a shared end.
+fn edges_intersect(vertices: &[Vertex], (a, b): (usize, usize), (c, d): (usize, usize)) -> bool {
+    // Edges sharing an end only intersect if they fold back over each other.
+    let shared = if b == c {
+        Some((a, b, d))
+    } else if d == a {
+        Some((c, a, b))
+    } else {
+        None
+    };
+    let v = |i: usize| vertices[i];
+    if let Some((start, middle, end)) = shared {
+        let (start, middle, end) = (v(start), v(middle), v(end));
+        return orientation(start, middle, end) == 0.0
+            && (between(start, middle, end) || between(middle, end, start));
+    }
+
+    let (a, b, c, d) = (v(a), v(b), v(c), v(d));
+    let (o1, o2) = (orientation(c, d, a), orientation(c, d, b));
+    let (o3, o4) = (orientation(a, b, c), orientation(a, b, d));
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+    (o1 == 0.0 && between(c, d, a))
+        || (o2 == 0.0 && between(c, d, b))
+        || (o3 == 0.0 && between(a, b, c))
+        || (o4 == 0.0 && between(a, b, d))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::*;
 
+    fn total_area(vertices: &[Vertex], triangles: &[[usize; 3]]) -> f64 {
+        let areas = triangles.iter().map(|t| {
+            let polygon = [vertices[t[0]], vertices[t[1]], vertices[t[2]]];
+            assert!(area(&polygon) > 0.0, "{:?} is not anticlockwise", t);
+            area(&polygon)
+        });
+        areas.sum::<f64>() / 2.0
+    }
+
     #[test]
     fn it_works() {
-        const CONTOURS: [c_int; 4] = [4, 3, 3, 3];
+        const CONTOURS: [usize; 4] = [4, 3, 3, 3];
 
         #[rustfmt::skip]
         let vertices: &[Vertex] = &[
@@ -83,32 +403,144 @@ mod tests {
             [3.0, 3.0].into(), [5.0, 3.5].into(), [5.0, 2.5].into(),
         ];
 
-        let triangles = triangulate(&CONTOURS, vertices);
-        println!("{:?}", triangles);
+        let triangles = triangulate(&CONTOURS, vertices).unwrap();
+        assert_eq!(triangles.len(), 13 + 2 * 3 - 2);
+        assert_eq!(total_area(vertices, &triangles), 36.0 - 0.625 - 0.625 - 1.0);
     }
 
     #[test]
     fn test_2() {
+        // Clockwise, which the C implementation can't handle by itself.
         let contours = &[4];
-        let vertices = &[
-            Vertex {
-                x: 252.0,
-                y: -224.0,
-            },
-            Vertex {
-                x: 236.0,
-                y: -224.0,
-            },
-            Vertex {
-                x: 236.0,
-                y: -228.0,
-            },
-            Vertex {
-                x: 252.0,
-                y: -228.0,
-            },
+        #[rustfmt::skip]
+        let vertices: &[Vertex] = &[
+            [252.0, -224.0].into(), [252.0, -228.0].into(),
+            [236.0, -228.0].into(), [236.0, -224.0].into(),
+        ];
+        let triangles = triangulate(contours, vertices).unwrap();
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(total_area(vertices, &triangles), 64.0);
+    }
+
+    #[test]
+    fn islands() {
+        // A square with a square hole, an island in the hole, and a separate square.
+        #[rustfmt::skip]
+        let vertices: &[Vertex] = &[
+            [0.0, 0.0].into(), [9.0, 0.0].into(), [9.0, 9.0].into(), [0.0, 9.0].into(),
+            [1.0, 1.0].into(), [8.0, 1.0].into(), [8.0, 8.0].into(), [1.0, 8.0].into(),
+            [2.0, 2.0].into(), [7.0, 2.0].into(), [7.0, 7.0].into(), [2.0, 7.0].into(),
+            [10.0, 0.0].into(), [11.0, 0.0].into(), [11.0, 1.0].into(), [10.0, 1.0].into(),
         ];
-        let triangles = triangulate(contours, vertices);
-        println!("{:?}", triangles);
+        let triangles = triangulate(&[4, 4, 4, 4], vertices).unwrap();
+        assert_eq!(triangles.len(), 8 + 2 + 2);
+        assert_eq!(total_area(vertices, &triangles), 81.0 - 49.0 + 25.0 + 1.0);
+    }
+
+    #[test]
+    fn errors() {
+        let square: Vec<Vertex> = vec![
+            [0.0, 0.0].into(),
+            [1.0, 0.0].into(),
+            [1.0, 1.0].into(),
+            [0.0, 1.0].into(),
+        ];
+        assert_eq!(triangulate(&[], &[]), Err(Error::NoContours));
+        assert_eq!(
+            triangulate(&[2, 2], &square),
+            Err(Error::ContourTooShort { contour: 0, len: 2 })
+        );
+        assert_eq!(
+            triangulate(&[5], &square),
+            Err(Error::VertexCount {
+                expected: 5,
+                actual: 4
+            })
+        );
+
+        let mut nan = square.clone();
+        nan[2].y = f64::NAN;
+        assert_eq!(triangulate(&[4], &nan), Err(Error::NotFinite { vertex: 2 }));
+
+        let mut repeated = square.clone();
+        repeated.push(square[1]);
+        repeated.push([2.0, 0.5].into());
+        assert_eq!(
+            triangulate(&[6], &repeated),
+            Err(Error::RepeatedVertex {
+                first: 1,
+                second: 4
+            })
+        );
+
+        let line: &[Vertex] = &[[0.0, 0.0].into(), [1.0, 1.0].into(), [2.0, 2.0].into()];
+        assert_eq!(
+            triangulate(&[3], line),
+            Err(Error::Collinear { contour: 0 })
+        );
+
+        let mut bowtie = square.clone();
+        bowtie.swap(2, 3);
+        assert_eq!(
+            triangulate(&[4], &bowtie),
+            Err(Error::SelfIntersecting {
+                first: 1,
+                second: 3
+            })
+        );
+
+        // A vertex of the second square touching an edge of the first.
+        let mut touching = square.clone();
+        touching.extend_from_slice(&[[1.0, 0.5].into(), [2.0, 0.5].into(), [2.0, 1.5].into()]);
+        assert_eq!(
+            triangulate(&[4, 3], &touching),
+            Err(Error::SelfIntersecting {
+                first: 1,
+                second: 4
+            })
+        );
+
+        let circle = (0..200)
+            .map(|i| {
+                let angle = i as f64 * std::f64::consts::PI / 100.0;
+                Vertex::from([angle.cos(), angle.sin()])
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            triangulate(&[200], &circle),
+            Err(Error::TooManyVertices {
+                contour: 0,
+                count: 200
+            })
+        );
+        assert_eq!(triangulate(&[199], &circle[..199]).unwrap().len(), 197);
+    }
+
+    #[test]
+    fn concurrent() {
+        let polygon = (0..150)
+            .map(|i| {
+                let angle = i as f64 * std::f64::consts::PI / 75.0;
+                let radius = if i % 2 == 0 { 10.0 } else { 4.0 };
+                Vertex::from([radius * angle.cos(), radius * angle.sin()])
+            })
+            .collect::<Vec<_>>();
+        let expected = triangulate(&[150], &polygon).unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let polygon = polygon.clone();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| triangulate(&[150], &polygon).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            for triangles in thread.join().unwrap() {
+                assert_eq!(triangles, expected);
+            }
+        }
     }
 }